@@ -0,0 +1,172 @@
+// writes a line for every listener as they disconnect, for listener
+// statistics tools which read Icecast style access logs, or as JSON
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_derive::Serialize;
+use slog::Logger;
+use uuid::Uuid;
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::schedule;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub struct Entry {
+    pub listener: String,
+    pub stream: String,
+    pub remote_addr: Option<SocketAddr>,
+    // the request line, such as "GET /live.mp3 HTTP/1.1"
+    pub request: String,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub connected_at: SystemTime,
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    pub source_sessions: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: u64,
+    listener: &'a str,
+    stream: &'a str,
+    remote_addr: Option<String>,
+    request: &'a str,
+    user_agent: Option<&'a str>,
+    referer: Option<&'a str>,
+    connected_at: u64,
+    duration_secs: u64,
+    bytes_sent: u64,
+    // the source sessions the listener heard, only in JSON entries as the
+    // combined format has nowhere to put them
+    source_sessions: Vec<String>,
+}
+
+// entries are written on a thread of their own, so that a slow disk never
+// holds up a listener's connection closing
+pub struct AccessLog {
+    entries: mpsc::Sender<Entry>,
+}
+
+impl AccessLog {
+    pub fn write(&self, entry: Entry) {
+        let _ = self.entries.send(entry);
+    }
+}
+
+pub fn start(log: Logger, config: AccessLogConfig) -> AccessLog {
+    let (entries, entries_rx) = mpsc::channel::<Entry>();
+
+    thread::Builder::new()
+        .name("edicast/access-log".to_owned())
+        .spawn(move || {
+            // set after a failed write, so that a full disk is logged once
+            // rather than for every listener
+            let mut failing = false;
+
+            for entry in entries_rx {
+                let line = match config.format {
+                    AccessLogFormat::Json => json_line(&entry),
+                    AccessLogFormat::Combined => combined_line(&entry),
+                };
+
+                match append(&config, &line) {
+                    Ok(()) => {
+                        failing = false;
+                    }
+                    Err(e) => {
+                        if !failing {
+                            slog::error!(log, "Could not write to access log";
+                                "path" => config.path.display(),
+                                "error" => e.to_string(),
+                            );
+                        }
+
+                        failing = true;
+                    }
+                }
+            }
+        })
+        .expect("spawn edicast access log thread");
+
+    AccessLog { entries }
+}
+
+// the file is opened afresh for each line, so that rotating it with
+// logrotate or similar needs nothing more than moving it aside
+fn append(config: &AccessLogConfig, line: &str) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+    file.write_all(line.as_bytes())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn json_line(entry: &Entry) -> String {
+    let json = JsonEntry {
+        time: unix_secs(SystemTime::now()),
+        listener: &entry.listener,
+        stream: &entry.stream,
+        remote_addr: entry.remote_addr.map(|addr| addr.ip().to_string()),
+        request: &entry.request,
+        user_agent: entry.user_agent.as_deref(),
+        referer: entry.referer.as_deref(),
+        connected_at: unix_secs(entry.connected_at),
+        duration_secs: entry.duration.as_secs(),
+        bytes_sent: entry.bytes_sent,
+        source_sessions: entry.source_sessions.iter().map(Uuid::to_string).collect(),
+    };
+
+    let mut line = serde_json::to_string(&json).expect("serialize access log entry");
+    line.push('\n');
+    line
+}
+
+// the combined log format with the seconds connected on the end, as Icecast
+// writes it. like Icecast, the time is when the listener disconnected
+fn combined_line(entry: &Entry) -> String {
+    format!("{} - - [{}] \"{}\" 200 {} \"{}\" \"{}\" {}\n",
+        entry.remote_addr.map(|addr| addr.ip().to_string()).unwrap_or_else(|| "-".to_owned()),
+        clf_time(SystemTime::now()),
+        quote(&entry.request),
+        entry.bytes_sent,
+        quote(entry.referer.as_deref().unwrap_or("-")),
+        quote(entry.user_agent.as_deref().unwrap_or("-")),
+        entry.duration.as_secs())
+}
+
+// such as 01/Jun/2024:20:00:00 +0000
+fn clf_time(time: SystemTime) -> String {
+    let month = schedule::format_utc("%m", time).parse::<usize>().unwrap_or(1);
+    let month = MONTHS[month.clamp(1, 12) - 1];
+
+    format!("{}/{}/{} +0000",
+        schedule::format_utc("%d", time), month, schedule::format_utc("%Y:%H:%M:%S", time))
+}
+
+// clients choose their own user agents and referers, so quotes and
+// anything unprintable in them are escaped to keep one entry per line
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32));
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted
+}
@@ -0,0 +1,219 @@
+// watches each stream's listener count and posts to a webhook when it
+// crosses a threshold, such as the first listener arriving or everyone
+// leaving while the source is still live. an alert which has fired only
+// fires again once the count has moved back past its threshold by the
+// hysteresis, so a count wobbling around the threshold doesn't flood it
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use slog::Logger;
+
+use crate::config::AlertConfig;
+use crate::event::{Event, EventBus};
+use crate::net::tls::{self, ClientConfig};
+use crate::server::Edicast;
+use crate::source::SourceStatus;
+use crate::webhook::{self, Webhooks};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Rises,
+    Falls,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Rises => "rises",
+            Direction::Falls => "falls",
+        }
+    }
+}
+
+struct Alert {
+    config: AlertConfig,
+    direction: Direction,
+    threshold: usize,
+    tls: Option<Arc<ClientConfig>>,
+    // by stream
+    states: HashMap<String, State>,
+}
+
+enum State {
+    // waiting for the threshold to be crossed, and when it was if it has
+    // been but not yet for hold_secs
+    Armed(Option<Instant>),
+    // waiting for the count to move back past the threshold
+    Fired,
+}
+
+pub fn start(log: Logger, configs: Vec<AlertConfig>, edicast: Arc<Edicast>) {
+    let alerts = configs.into_iter()
+        .filter_map(|config| Alert::new(&log, config))
+        .collect::<Vec<_>>();
+
+    if alerts.is_empty() {
+        return;
+    }
+
+    let webhooks = webhook::start(log.clone());
+
+    thread::Builder::new()
+        .name("edicast/alert".to_owned())
+        .spawn(move || {
+            let mut alerts = alerts;
+
+            loop {
+                let listeners = listener_counts(&edicast);
+
+                for alert in &mut alerts {
+                    alert.check(&log, &edicast, &listeners, &webhooks);
+                }
+
+                thread::sleep(CHECK_INTERVAL);
+            }
+        })
+        .expect("spawn edicast alert thread");
+}
+
+// listeners on every server in the cluster, if clustering
+fn listener_counts(edicast: &Edicast) -> HashMap<String, usize> {
+    let mut counts = edicast.streams.list().into_iter()
+        .map(|(name, _)| {
+            let remote = edicast.cluster.as_ref().map_or(0, |cluster| cluster.remote_listeners(&name));
+            (name, remote)
+        })
+        .collect::<HashMap<_, _>>();
+
+    for listener in edicast.listeners.list() {
+        if let Some(count) = counts.get_mut(&listener.stream()) {
+            *count += 1;
+        }
+    }
+
+    counts
+}
+
+impl Alert {
+    fn new(log: &Logger, config: AlertConfig) -> Option<Self> {
+        let log = log.new(slog::o!("alert" => config.name.clone()));
+
+        let (direction, threshold) = match (config.rises_to, config.falls_to) {
+            (Some(threshold), None) => (Direction::Rises, threshold),
+            (None, Some(threshold)) => (Direction::Falls, threshold),
+            _ => {
+                slog::error!(log, "Alert needs one of rises_to or falls_to, ignoring it");
+                return None;
+            }
+        };
+
+        let tls = if config.webhook.tls {
+            match tls::client_config(&config.ca_file) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    slog::error!(log, "Could not set up TLS for alert webhook, ignoring alert"; "error" => e.to_string());
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+
+        Some(Alert { config, direction, threshold, tls, states: HashMap::new() })
+    }
+
+    fn check(&mut self, log: &Logger, edicast: &Edicast, listeners: &HashMap<String, usize>, webhooks: &Webhooks) {
+        self.states.retain(|stream, _| listeners.contains_key(stream));
+
+        for (stream, &count) in listeners {
+            if self.config.stream.as_ref().map_or(false, |watched| watched != stream) {
+                continue;
+            }
+
+            let crossed = match self.direction {
+                Direction::Rises => count >= self.threshold,
+                Direction::Falls => count <= self.threshold
+                    && (!self.config.source_live || source_live(edicast, stream)),
+            };
+
+            let back = match self.direction {
+                Direction::Rises => count + self.config.hysteresis < self.threshold,
+                Direction::Falls => count > self.threshold + self.config.hysteresis,
+            };
+
+            // falls only count once the stream has had more listeners, so
+            // that a quiet stream doesn't fire on startup
+            let state = self.states.entry(stream.clone()).or_insert(match self.direction {
+                Direction::Rises => State::Armed(None),
+                Direction::Falls => State::Fired,
+            });
+
+            let fire = match state {
+                State::Armed(_) if !crossed => {
+                    *state = State::Armed(None);
+                    false
+                }
+                State::Armed(since) => {
+                    let since = *since.get_or_insert_with(Instant::now);
+                    since.elapsed() >= Duration::from_secs(self.config.hold_secs)
+                }
+                State::Fired if back => {
+                    *state = State::Armed(None);
+                    false
+                }
+                State::Fired => false,
+            };
+
+            if fire {
+                *state = State::Fired;
+                self.fire(log, &edicast.events, stream, count, webhooks);
+            }
+        }
+    }
+
+    fn fire(&self, log: &Logger, events: &EventBus, stream: &str, listeners: usize, webhooks: &Webhooks) {
+        let direction = self.direction.as_str();
+
+        slog::info!(log, "Listener alert fired";
+            "alert" => &self.config.name,
+            "stream" => stream,
+            "listeners" => listeners,
+            "threshold" => self.threshold,
+            "direction" => direction,
+        );
+
+        events.publish(Event::ListenerAlertFired {
+            alert: self.config.name.clone(),
+            stream: stream.to_owned(),
+            listeners,
+            threshold: self.threshold,
+            direction,
+        });
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        // text is what chat services such as Slack show
+        let body = json!({
+            "text": format!("{}: {} now has {} listeners", self.config.name, stream, listeners),
+            "alert": self.config.name,
+            "stream": stream,
+            "listeners": listeners,
+            "threshold": self.threshold,
+            "direction": direction,
+            "time": time,
+        });
+
+        webhooks.post(&self.config.webhook, self.tls.clone(), &body);
+    }
+}
+
+fn source_live(edicast: &Edicast, stream: &str) -> bool {
+    edicast.streams.config(stream)
+        .and_then(|config| edicast.sources.status(&config.source))
+        .map_or(false, |status| *status.borrow() == SourceStatus::Live)
+}
@@ -0,0 +1,276 @@
+// records a stream's encoded output to disk as it's sent to listeners, in
+// files started afresh on the clock so that they line up with programmes
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::audio::encode;
+use crate::chapters::Chapters;
+use crate::config::{ArchiveConfig, StreamConfig};
+use crate::event::{Event, EventBus};
+use crate::retention;
+use crate::schedule::{self, Interval};
+use crate::source::{SourceSet, SourceStatus};
+use crate::stream::{EncodedChunk, StreamSubscription};
+use crate::upload;
+
+// audio peaking below this, about -60dBFS, counts as silence
+const SILENCE_PEAK: u16 = 32;
+
+struct Archive {
+    log: Logger,
+    name: String,
+    events: EventBus,
+    stream: StreamConfig,
+    config: ArchiveConfig,
+    title: String,
+    rotate: Interval,
+    segment: Option<Segment>,
+    chapters: Option<Chapters>,
+    // finished files are sent here to be uploaded, if configured
+    uploads: Option<mpsc::Sender<PathBuf>>,
+    // nudged whenever a file is finished, so that limits are kept promptly
+    retention: Option<mpsc::Sender<()>>,
+    // the source's status, if writing pauses while it's offline
+    status: Option<watch::Receiver<SourceStatus>>,
+    // stream position silence started at, if it's silent now
+    silent_since: Option<Duration>,
+    paused: bool,
+    // set after a failed write, so that a full disk is logged once rather
+    // than for every chunk
+    failing: bool,
+}
+
+struct Segment {
+    file: File,
+    path: PathBuf,
+    bytes: u64,
+    ends_at: SystemTime,
+}
+
+// archives run until the stream is removed, which closes its subscription.
+// metadata and status are the stream's source's, for chapters and pausing
+pub fn start(log: Logger, name: &str, stream: StreamConfig, config: ArchiveConfig,
+    subscription: StreamSubscription, sources: &SourceSet)
+{
+    let runtime = Handle::current();
+    let log = log.new(slog::o!("stream" => name.to_owned()));
+
+    let uploads = config.upload.clone().map(|upload| {
+        let mut extensions = vec![encode::extension_from_config(&stream.codec)];
+        extensions.extend(config.chapters.map(|format| format.extension()));
+        upload::start(log.clone(), name, upload, config.path.clone(), extensions)
+    });
+
+    let retention = config.retention.clone().map(|retention| {
+        let extension = encode::extension_from_config(&stream.codec);
+        retention::start(log.clone(), name, config.path.clone(), extension, retention, config.upload.is_some())
+    });
+
+    let status = sources.status(&stream.source).filter(|_| config.pause_offline);
+
+    let chapters = config.chapters.zip(sources.metadata(&stream.source))
+        .map(|(format, metadata)| Chapters::new(log.clone(), format, metadata));
+
+    let archive = Archive {
+        log,
+        name: name.to_owned(),
+        events: sources.events(),
+        title: stream.name.clone().unwrap_or_else(|| name.to_owned()),
+        rotate: Interval::new(Duration::from_secs(config.rotate_mins.max(1) * 60), Duration::ZERO),
+        stream,
+        config,
+        segment: None,
+        chapters,
+        uploads,
+        retention,
+        status,
+        silent_since: None,
+        paused: false,
+        failing: false,
+    };
+
+    thread::Builder::new()
+        .name(format!("edicast/archive: {}", name))
+        .spawn(move || archive_main(archive, runtime, subscription))
+        .expect("spawn edicast archive thread");
+}
+
+fn archive_main(mut archive: Archive, runtime: Handle, mut subscription: StreamSubscription) {
+    slog::info!(archive.log, "Archiving stream"; "path" => archive.config.path.display());
+
+    loop {
+        let chunk = match runtime.block_on(subscription.recv()) {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(skipped)) => {
+                slog::warn!(archive.log, "Archive fell behind stream, recording will have a gap";
+                    "skipped_chunks" => skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        match archive.write(&chunk) {
+            Ok(()) => {
+                archive.failing = false;
+            }
+            Err(e) => {
+                if !archive.failing {
+                    slog::error!(archive.log, "Could not write to archive"; "error" => e.to_string());
+                }
+
+                // start a new file once writing works again
+                archive.close();
+                archive.failing = true;
+            }
+        }
+    }
+
+    archive.close();
+    slog::info!(archive.log, "Stopped archiving stream");
+}
+
+impl Archive {
+    fn write(&mut self, chunk: &EncodedChunk) -> Result<(), io::Error> {
+        if self.should_pause(chunk) {
+            if !self.paused {
+                slog::info!(self.log, "Pausing archive while source is offline or silent");
+                self.close();
+                self.paused = true;
+            }
+
+            return Ok(());
+        }
+
+        // the file closed on pausing means a new one is started below
+        if self.paused {
+            slog::info!(self.log, "Resuming archive");
+            self.paused = false;
+        }
+
+        let now = SystemTime::now();
+        let max_bytes = self.config.rotate_mb.map(|mb| mb * 1024 * 1024);
+
+        let rotate = match &self.segment {
+            Some(segment) => now >= segment.ends_at
+                || max_bytes.map_or(false, |max_bytes| segment.bytes >= max_bytes),
+            None => true,
+        };
+
+        if rotate {
+            // close the old file before starting the next
+            self.close();
+            self.segment = Some(self.open(now)?);
+        }
+
+        if let Some(chapters) = &mut self.chapters {
+            chapters.update(chunk.pts);
+        }
+
+        if let Some(segment) = &mut self.segment {
+            segment.file.write_all(&chunk.data)?;
+            segment.bytes += chunk.data.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self, now: SystemTime) -> Result<Segment, io::Error> {
+        fs::create_dir_all(&self.config.path)?;
+
+        let name = schedule::format_utc(&self.config.name, now);
+        let extension = encode::extension_from_config(&self.stream.codec);
+        let (mut file, path) = create_unique(&self.config.path, &name, extension)?;
+
+        // every file starts with its own header, so that each plays and is
+        // labelled correctly on its own
+        let title = format!("{} {}", self.title, schedule::format_utc("%Y-%m-%d %H:%M UTC", now));
+        let header = encode::file_header_from_config(&self.stream.codec, &title);
+        file.write_all(&header)?;
+
+        slog::info!(self.log, "Started archive file"; "path" => path.display());
+
+        if let Some(chapters) = &mut self.chapters {
+            chapters.start(&path, &title);
+        }
+
+        Ok(Segment {
+            file,
+            path,
+            bytes: header.len() as u64,
+            ends_at: self.rotate.next_after(now),
+        })
+    }
+
+    fn should_pause(&mut self, chunk: &EncodedChunk) -> bool {
+        let offline = self.status.as_ref()
+            .map_or(false, |status| *status.borrow() != SourceStatus::Live);
+
+        let silent = match self.config.pause_silence_secs {
+            Some(secs) if chunk.peak <= SILENCE_PEAK => {
+                let since = *self.silent_since.get_or_insert(chunk.pts);
+                chunk.pts.saturating_sub(since) >= Duration::from_secs(secs)
+            }
+            _ => {
+                self.silent_since = None;
+                false
+            }
+        };
+
+        offline || silent
+    }
+
+    fn close(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            // flushed and closed before it's handed over
+            drop(segment.file);
+
+            let chapters = self.chapters.as_mut().and_then(Chapters::finish);
+
+            // before uploading, which may remove the file
+            self.events.publish(Event::RecordingFinished {
+                stream: self.name.clone(),
+                path: segment.path.display().to_string(),
+                recording: "archive",
+            });
+
+            if let Some(uploads) = &self.uploads {
+                let _ = uploads.send(segment.path);
+
+                if let Some(chapters) = chapters {
+                    let _ = uploads.send(chapters);
+                }
+            }
+
+            if let Some(retention) = &self.retention {
+                let _ = retention.send(());
+            }
+        }
+    }
+}
+
+// files started within the same period, such as after a restart or when
+// rotating by size, are numbered rather than overwriting each other
+pub fn create_unique(dir: &Path, name: &str, extension: &str) -> Result<(File, PathBuf), io::Error> {
+    let mut path = dir.join(format!("{}.{}", name, extension));
+    let mut number = 1;
+
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = dir.join(format!("{}-{}.{}", name, number, extension));
+                number += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
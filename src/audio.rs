@@ -1,29 +1,48 @@
 use std::time::Duration;
 
+use uuid::Uuid;
+
+pub mod convert;
 pub mod encode;
 pub mod decode;
+pub mod pool;
+
+use pool::Samples;
 
 #[derive(Clone)]
 pub struct PcmData {
     pub sample_rate: usize,
     pub channels: usize,
-    pub samples: Box<[i16]>,
+    pub samples: Samples,
+    // presentation time of the first sample, from the start of the audio
+    // this was decoded from. it starts over with each source client
+    pub pts: Duration,
+    // the source client session this was decoded from, for tying together
+    // logs from the source, the streams it fed and the listeners it reached.
+    // None for audio edicast makes up itself, such as silence
+    pub session: Option<Uuid>,
 }
 
 impl PcmData {
-    pub fn silence(duration: Duration) -> Self {
-        let sample_rate = 44100;
-        let channels = 2;
-
+    pub fn silence(duration: Duration, sample_rate: usize, channels: usize) -> Self {
         let channel_sample_count = (duration.as_nanos() * (sample_rate as u128) / 1_000_000_000) as usize;
         let sample_count = channel_sample_count * channels;
 
-        let samples = {
-            let mut samples = Vec::new();
-            samples.resize(sample_count, 0i16);
-            samples.into_boxed_slice()
-        };
+        let samples = Samples::zeroed(sample_count);
+
+        PcmData { sample_rate, channels, samples, pts: Duration::ZERO, session: None }
+    }
+
+    pub fn duration(&self) -> Duration {
+        let frames = (self.samples.len() / self.channels.max(1)) as u64;
+        Duration::from_nanos(frames * 1_000_000_000 / self.sample_rate.max(1) as u64)
+    }
 
-        PcmData { sample_rate, channels, samples }
+    // absolute sample peak across all channels, used for level metering
+    pub fn peak(&self) -> u16 {
+        self.samples.iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap_or(0)
     }
 }
@@ -0,0 +1,113 @@
+// sample layout and format helpers for the per-sample loops in decoding,
+// encoding and mixing. each is a straight loop over zipped slices, which
+// leaves no bounds checks inside for the compiler to trip over, so it turns
+// them into vector instructions itself without needing std::simd or
+// per-target intrinsics
+
+// i16 samples are converted to and from f32 in the range -1.0..1.0
+const SCALE: f32 = 32768.0;
+
+// mixing converts through f32 a block at a time, on the stack
+const MIX_BLOCK: usize = 256;
+
+// interleaves per-channel audio into output, which must have room for every
+// frame of every channel. channels longer than the shortest are cut short
+pub fn interleave<C: AsRef<[i16]>>(channels: &[C], output: &mut [i16]) {
+    let frames = channels.iter()
+        .map(|channel| channel.as_ref().len())
+        .min()
+        .unwrap_or(0);
+
+    let output = &mut output[..frames * channels.len()];
+
+    match channels {
+        [mono] => output.copy_from_slice(&mono.as_ref()[..frames]),
+        [left, right] => {
+            for ((frame, left), right) in output.chunks_exact_mut(2)
+                .zip(left.as_ref())
+                .zip(right.as_ref())
+            {
+                frame[0] = *left;
+                frame[1] = *right;
+            }
+        }
+        _ => {
+            for (index, channel) in channels.iter().enumerate() {
+                for (frame, sample) in output.chunks_exact_mut(channels.len()).zip(channel.as_ref()) {
+                    frame[index] = *sample;
+                }
+            }
+        }
+    }
+}
+
+// splits the first two channels of interleaved audio into left and right,
+// discarding any others. mono audio goes to both sides
+pub fn deinterleave_stereo(input: &[i16], channels: usize, left: &mut Vec<i16>, right: &mut Vec<i16>) {
+    left.clear();
+    right.clear();
+
+    match channels {
+        0 => {}
+        1 => {
+            left.extend_from_slice(input);
+            right.extend_from_slice(input);
+        }
+        _ => {
+            let frames = input.len() / channels;
+            left.resize(frames, 0);
+            right.resize(frames, 0);
+
+            for ((frame, left), right) in input.chunks_exact(channels)
+                .zip(left.iter_mut())
+                .zip(right.iter_mut())
+            {
+                *left = frame[0];
+                *right = frame[1];
+            }
+        }
+    }
+}
+
+pub fn i16_to_f32(input: &[i16], output: &mut [f32]) {
+    for (output, input) in output.iter_mut().zip(input) {
+        *output = f32::from(*input) * (1.0 / SCALE);
+    }
+}
+
+// float to int casts saturate, so samples out of range clip rather than wrap
+pub fn f32_to_i16(input: &[f32], output: &mut [i16]) {
+    for (output, input) in output.iter_mut().zip(input) {
+        *output = (*input * SCALE) as i16;
+    }
+}
+
+pub fn apply_gain(samples: &mut [f32], gain: f32) {
+    for sample in samples {
+        *sample *= gain;
+    }
+}
+
+// mixes other into live, with live's level scaled by gain first. only as
+// many samples as the shorter of the two are mixed
+pub fn mix(live: &mut [i16], other: &[i16], gain: f32) {
+    let mut live_block = [0f32; MIX_BLOCK];
+    let mut other_block = [0f32; MIX_BLOCK];
+
+    for (live, other) in live.chunks_mut(MIX_BLOCK).zip(other.chunks(MIX_BLOCK)) {
+        let len = live.len().min(other.len());
+        let live = &mut live[..len];
+        let live_mixed = &mut live_block[..len];
+        let other_mixed = &mut other_block[..len];
+
+        i16_to_f32(live, live_mixed);
+        i16_to_f32(&other[..len], other_mixed);
+        apply_gain(live_mixed, gain);
+
+        for (live, other) in live_mixed.iter_mut().zip(other_mixed.iter()) {
+            *live += *other;
+        }
+
+        f32_to_i16(live_mixed, live);
+    }
+}
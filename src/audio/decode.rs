@@ -1,4 +1,9 @@
-use std::io;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use super::PcmData;
 
@@ -16,5 +21,137 @@ pub trait PcmRead {
 mod mp3;
 pub use self::mp3::Mp3;
 
+#[cfg(feature = "vorbis")]
 mod ogg;
+#[cfg(feature = "vorbis")]
 pub use self::ogg::Ogg;
+
+// an ingest format, picked by the media type a source client or upstream
+// sends its audio as
+pub trait DecoderType: Send + Sync {
+    // used for the names of source dumps
+    fn extension(&self) -> &'static str;
+    fn open(&self, io: Box<dyn Read + Send>) -> Result<Box<dyn PcmRead + Send>, String>;
+}
+
+struct Mp3Type;
+
+impl DecoderType for Mp3Type {
+    fn extension(&self) -> &'static str {
+        "mp3"
+    }
+
+    fn open(&self, io: Box<dyn Read + Send>) -> Result<Box<dyn PcmRead + Send>, String> {
+        Ok(Box::new(Mp3::new(io)))
+    }
+}
+
+#[cfg(feature = "vorbis")]
+struct OggType;
+
+#[cfg(feature = "vorbis")]
+impl DecoderType for OggType {
+    fn extension(&self) -> &'static str {
+        "ogg"
+    }
+
+    fn open(&self, io: Box<dyn Read + Send>) -> Result<Box<dyn PcmRead + Send>, String> {
+        match Ogg::new(io) {
+            Ok(ogg) => Ok(Box::new(ogg)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "vorbis")]
+const BUILT_IN: &[&str] = &["audio/mpeg", "audio/mp3", "audio/ogg", "application/ogg"];
+#[cfg(not(feature = "vorbis"))]
+const BUILT_IN: &[&str] = &["audio/mpeg", "audio/mp3"];
+
+// keyed by lowercased media type
+static REGISTRY: Mutex<BTreeMap<String, Arc<dyn DecoderType>>> = Mutex::new(BTreeMap::new());
+
+// makes a decoder available for sources sending any of media_types, for
+// plugins adding ingest formats. returns false, registering nothing, if
+// any of the media types is already taken
+pub fn register(media_types: &[&str], decoder: impl DecoderType + 'static) -> bool {
+    let mut registry = REGISTRY.lock().expect("lock decoder registry");
+
+    let media_types = media_types.iter()
+        .map(|media_type| media_type.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    let taken = media_types.iter()
+        .any(|media_type| BUILT_IN.contains(&media_type.as_str()) || registry.contains_key(media_type));
+
+    if taken {
+        return false;
+    }
+
+    let decoder = Arc::new(decoder) as Arc<dyn DecoderType>;
+
+    for media_type in media_types {
+        registry.insert(media_type, decoder.clone());
+    }
+
+    true
+}
+
+// media_type is without parameters, such as "audio/ogg"
+pub fn for_media_type(media_type: &str) -> Option<Arc<dyn DecoderType>> {
+    let media_type = media_type.to_ascii_lowercase();
+
+    match media_type.as_str() {
+        "audio/mpeg" | "audio/mp3" => Some(Arc::new(Mp3Type)),
+        #[cfg(feature = "vorbis")]
+        "audio/ogg" | "application/ogg" => Some(Arc::new(OggType)),
+        _ => REGISTRY.lock().expect("lock decoder registry").get(&media_type).cloned(),
+    }
+}
+
+// for upstreams which don't say what they're sending
+pub fn fallback() -> Arc<dyn DecoderType> {
+    Arc::new(Mp3Type)
+}
+
+pub fn open_file(path: &Path) -> Result<Box<dyn PcmRead + Send>, io::Error> {
+    let file = BufReader::new(File::open(path)?);
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "vorbis")]
+        Some("ogg") | Some("oga") => {
+            match Ogg::new(file) {
+                Ok(ogg) => Ok(Box::new(ogg) as Box<dyn PcmRead + Send>),
+                Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            }
+        }
+        #[cfg(not(feature = "vorbis"))]
+        Some("ogg") | Some("oga") => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "edicast was built without the vorbis feature"))
+        }
+        _ => Ok(Box::new(Mp3::new(file)) as Box<dyn PcmRead + Send>),
+    }
+}
+
+pub fn read_file(path: &Path) -> Result<PcmData, io::Error> {
+    let mut decoder = open_file(path)?;
+    let mut format = None;
+    let mut samples = Vec::new();
+
+    loop {
+        match decoder.read() {
+            Ok(pcm) => {
+                format.get_or_insert((pcm.sample_rate, pcm.channels));
+                samples.extend_from_slice(&pcm.samples);
+            }
+            Err(PcmReadError::Eof) => break,
+            Err(PcmReadError::SkippedData) => {}
+            Err(PcmReadError::Io(e)) => return Err(e),
+        }
+    }
+
+    let (sample_rate, channels) = format.ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidData, "file contains no audio"))?;
+
+    Ok(PcmData { sample_rate, channels, samples: samples.into(), pts: Duration::ZERO, session: None })
+}
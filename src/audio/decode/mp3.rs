@@ -1,26 +1,35 @@
 use std::io::Read;
+use std::time::Duration;
 
 use crate::audio::decode::{PcmRead, PcmReadError};
 use crate::audio::PcmData;
 
 pub struct Mp3<T: Read> {
     mp3: minimp3::Decoder<T>,
+    position: Duration,
 }
 
 impl<T: Read> Mp3<T> {
     pub fn new(io: T) -> Self {
-        Mp3 { mp3: minimp3::Decoder::new(io) }
+        Mp3 { mp3: minimp3::Decoder::new(io), position: Duration::ZERO }
     }
 }
 
 impl<T: Read> PcmRead for Mp3<T> {
     fn read(&mut self) -> Result<PcmData, PcmReadError> {
         match self.mp3.next_frame() {
-            Ok(frame) => Ok(PcmData {
-                sample_rate: frame.sample_rate as usize,
-                channels: frame.channels,
-                samples: frame.data.into_boxed_slice(),
-            }),
+            Ok(frame) => {
+                let pcm = PcmData {
+                    sample_rate: frame.sample_rate as usize,
+                    channels: frame.channels,
+                    samples: frame.data.into(),
+                    pts: self.position,
+                    session: None,
+                };
+
+                self.position += pcm.duration();
+                Ok(pcm)
+            }
             Err(minimp3::Error::Eof) => Err(PcmReadError::Eof),
             Err(minimp3::Error::Io(e)) => Err(PcmReadError::Io(e)),
             Err(minimp3::Error::SkippedData) => Err(PcmReadError::SkippedData),
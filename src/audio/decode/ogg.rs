@@ -1,7 +1,10 @@
 use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
 
 use crate::audio::decode::{PcmRead, PcmReadError};
 use crate::audio::PcmData;
+use crate::audio::convert;
+use crate::audio::pool::Samples;
 
 use ogg::{PacketReader, OggReadError};
 use lewton::VorbisError;
@@ -27,7 +30,7 @@ impl<T> Seek for NonSeekStream<T> where T: Read {
 
 impl<T> NonSeekStream<T> where T: Read {
     pub fn new(stream: T) -> NonSeekStream<T> {
-        NonSeekStream { stream: stream }
+        NonSeekStream { stream }
     }
 }
 
@@ -36,6 +39,7 @@ pub struct Ogg<T: Read> {
     pwr: PreviousWindowRight,
     ident_hdr: IdentHeader,
     setup_hdr: SetupHeader,
+    position: Duration,
 }
 
 impl<T: Read> Ogg<T> {
@@ -50,6 +54,7 @@ impl<T: Read> Ogg<T> {
             pwr: PreviousWindowRight::new(),
             ident_hdr,
             setup_hdr,
+            position: Duration::ZERO,
         })
     }
 }
@@ -73,33 +78,27 @@ impl<T: Read> PcmRead for Ogg<T> {
             Ok(pcm) => {
                 // lewton gives us audio data per channel
                 // interleave it:
-                let mut channel_iters = pcm.into_iter()
-                    .map(|channel| channel.into_iter())
-                    .collect::<Vec<_>>();
+                let frames = pcm.iter().map(Vec::len).min().unwrap_or(0);
+                let mut interleaved_pcm = Samples::zeroed(frames * pcm.len());
+                convert::interleave(&pcm, &mut interleaved_pcm);
 
-                let mut interleaved_pcm = Vec::new();
-
-                'outer: loop {
-                    for channel in &mut channel_iters {
-                        match channel.next() {
-                            Some(sample) => interleaved_pcm.push(sample),
-                            None => break 'outer,
-                        }
-                    }
-                }
-
-                Ok(PcmData {
+                let pcm = PcmData {
                     sample_rate: self.ident_hdr.audio_sample_rate as usize,
                     channels: self.ident_hdr.audio_channels as usize,
-                    samples: interleaved_pcm.into_boxed_slice(),
-                })
+                    samples: interleaved_pcm,
+                    pts: self.position,
+                    session: None,
+                };
+
+                self.position += pcm.duration();
+                Ok(pcm)
             }
             Err(AudioReadError::AudioIsHeader) => {
                 // this is where we would potentially read out stream metadata
-                return Err(PcmReadError::SkippedData);
+                Err(PcmReadError::SkippedData)
             }
             Err(_) => {
-                return Err(PcmReadError::SkippedData);
+                Err(PcmReadError::SkippedData)
             }
         }
     }
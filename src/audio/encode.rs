@@ -1,73 +1,162 @@
-use lame::Lame;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
 
 use crate::audio::PcmData;
-use crate::config::{self, CodecConfig};
+use crate::config::CodecConfig;
+
+#[cfg(feature = "lame")]
+mod mp3;
+#[cfg(feature = "lame")]
+pub use self::mp3::Mp3;
 
-pub trait Codec {
+// codecs are moved onto blocking threads to encode, off the runtime
+pub trait Codec: Send {
     fn describe(&self) -> String;
-    fn encode(&mut self, data: &PcmData) -> Box<[u8]>;
+    fn encode(&mut self, data: &PcmData) -> Bytes;
+    // encodes any audio still buffered inside the encoder, for when the
+    // stream is ending
+    fn flush(&mut self) -> Bytes;
+}
+
+// a codec other than the built in ones, registered by an application
+// embedding edicast. config is the table under the codec's name, such as
+// { bitrate = 96 } for codec = { opus = { bitrate = 96 } }
+pub trait CodecType: Send + Sync {
+    // called as the config is loaded, so that mistakes are reported then
+    // rather than once a stream starts
+    fn check(&self, config: &toml::Value) -> Result<(), String>;
+    fn build(&self, config: &toml::Value) -> Box<dyn Codec>;
+    fn mime_type(&self) -> &'static str;
+    fn extension(&self) -> &'static str;
+    // nominal bitrate in kbps
+    fn bitrate(&self, config: &toml::Value) -> usize;
+
+    // written at the start of each file recorded from a stream
+    fn file_header(&self, _title: &str) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+// without LAME, an application embedding edicast may register its own mp3
+#[cfg(feature = "lame")]
+const BUILT_IN: &[&str] = &["mp3"];
+#[cfg(not(feature = "lame"))]
+const BUILT_IN: &[&str] = &[];
+
+static REGISTRY: Mutex<BTreeMap<String, Arc<dyn CodecType>>> = Mutex::new(BTreeMap::new());
+
+// makes a codec available under name in codec tables. must be called
+// before the config is loaded. returns false if the name is already taken
+pub fn register(name: &str, codec: impl CodecType + 'static) -> bool {
+    let mut registry = REGISTRY.lock().expect("lock codec registry");
+
+    if BUILT_IN.contains(&name) || registry.contains_key(name) {
+        return false;
+    }
+
+    registry.insert(name.to_owned(), Arc::new(codec));
+    true
+}
+
+// every name a codec table can use, built in or registered
+pub fn names() -> Vec<String> {
+    let registry = REGISTRY.lock().expect("lock codec registry");
+
+    BUILT_IN.iter()
+        .map(|name| name.to_string())
+        .chain(registry.keys().cloned())
+        .collect()
+}
+
+// checks the config for a registered codec, while the config is loaded
+pub fn check_registered(name: &str, config: &toml::Value) -> Result<(), String> {
+    match lookup(name) {
+        Some(codec) => codec.check(config),
+        None if name == "mp3" => Err("mp3 needs edicast built with the lame feature".to_owned()),
+        None => Err(format!("unknown codec {:?}, expected one of: {}", name, names().join(", "))),
+    }
+}
+
+fn lookup(name: &str) -> Option<Arc<dyn CodecType>> {
+    REGISTRY.lock().expect("lock codec registry").get(name).cloned()
+}
+
+// registered codecs are checked as the config is loaded, and are never
+// unregistered, so any in a loaded config can be found
+fn registered(name: &str) -> Arc<dyn CodecType> {
+    lookup(name).expect("codec in config is registered")
+}
+
+// the feature edicast would need to be built with to encode with config, if
+// it wasn't. streams which need one are turned away as the config is loaded
+pub fn missing_feature(config: &CodecConfig) -> Option<&'static str> {
+    match config {
+        CodecConfig::Mp3(_) if cfg!(not(feature = "lame")) => Some("lame"),
+        _ => None,
+    }
 }
 
 pub fn from_config(config: &CodecConfig) -> Box<dyn Codec> {
     match config {
+        #[cfg(feature = "lame")]
         CodecConfig::Mp3(mp3) => Box::new(Mp3::new(mp3)) as Box<dyn Codec>,
+        #[cfg(not(feature = "lame"))]
+        CodecConfig::Mp3(_) => unreachable!("mp3 streams are turned away without the lame feature"),
+        CodecConfig::Registered(codec) => registered(&codec.name).build(&codec.config),
     }
 }
 
 pub fn mime_type_from_config(config: &CodecConfig) -> &'static str {
     match config {
         CodecConfig::Mp3(_) => "audio/mpeg",
+        CodecConfig::Registered(codec) => registered(&codec.name).mime_type(),
     }
 }
 
-pub struct Mp3 {
-    lame: Lame,
+pub fn extension_from_config(config: &CodecConfig) -> &'static str {
+    match config {
+        CodecConfig::Mp3(_) => "mp3",
+        CodecConfig::Registered(codec) => registered(&codec.name).extension(),
+    }
 }
 
-impl Mp3 {
-    pub fn new(config: &config::Mp3Config) -> Self {
-        let mut lame = Lame::new().expect("Lame::new");
-        lame.set_quality(config.quality as u8).expect("Lame::set_quality");
-        lame.set_kilobitrate(config.bitrate as i32).expect("Lame::set_kilobitrate");
-        lame.init_params().expect("Lame::init_params");
-        Mp3 { lame }
+// written at the start of each file recorded from a stream, so that players
+// can show what the recording is
+pub fn file_header_from_config(config: &CodecConfig, title: &str) -> Vec<u8> {
+    match config {
+        CodecConfig::Mp3(_) => id3_tag(title),
+        CodecConfig::Registered(codec) => registered(&codec.name).file_header(title),
     }
 }
 
-impl Codec for Mp3 {
-    fn describe(&self) -> String {
-        format!("MP3 (libmp3lame, V{}, {} kbps)",
-            self.lame.quality(),
-            self.lame.kilobitrate())
+// an ID3v2.4 tag with only a title. 2.4 is the first version to allow UTF-8
+// text, and has its sizes in 7 bit "syncsafe" bytes
+fn id3_tag(title: &str) -> Vec<u8> {
+    fn syncsafe(size: usize) -> [u8; 4] {
+        [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]
     }
 
-    fn encode(&mut self, data: &PcmData) -> Box<[u8]> {
-        // we must deinterleave audio data for LAME and discard channels beyond
-        // stereo. LAME does have an interleaved encode function, but it still
-        // bakes in 2 channel left/right assumptions which makes it unsafe to
-        // generalise for arbitrary PcmData which may have >2 channels
-        let mut left = Vec::new();
-        let mut right = Vec::new();
-
-        if data.channels == 1 {
-            left = data.samples.to_vec();
-            right = data.samples.to_vec();
-        } else {
-            for chunk in data.samples.chunks(data.channels) {
-                left.push(chunk[0]);
-                right.push(chunk[1]);
-            }
-        }
-
-        // vector size calculation is a suggestion from lame/lame.h:
-        let mut mp3buff: Vec<u8> = vec![0; (left.len() * 5) / 4 + 7200];
-
-        match self.lame.encode(&left, &right, &mut mp3buff) {
-            Ok(sz) => {
-                mp3buff.resize(sz, 0);
-                mp3buff.into_boxed_slice()
-            }
-            Err(e) => panic!("lame encode error! {:?}", e)
-        }
+    // text encoding 3 is UTF-8
+    let mut frame_content = vec![3];
+    frame_content.extend_from_slice(title.as_bytes());
+
+    let mut frame = b"TIT2".to_vec();
+    frame.extend_from_slice(&syncsafe(frame_content.len()));
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&frame_content);
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(frame.len()));
+    tag.extend_from_slice(&frame);
+    tag
+}
+
+// nominal bitrate in kbps
+pub fn bitrate_from_config(config: &CodecConfig) -> usize {
+    match config {
+        CodecConfig::Mp3(mp3) => mp3.bitrate,
+        CodecConfig::Registered(codec) => registered(&codec.name).bitrate(&codec.config),
     }
 }
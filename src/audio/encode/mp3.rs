@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use lame::Lame;
+
+use crate::audio::PcmData;
+use crate::audio::convert;
+use crate::audio::pool::Samples;
+use crate::config::Mp3Config;
+use super::Codec;
+
+// samples per channel in an MPEG-1 layer III frame
+const MP3_FRAME_SAMPLES: usize = 1152;
+
+// encoded audio is written into one larger allocation and split off chunk
+// by chunk, so the encoder only allocates again once this is used up
+const OUTPUT_BLOCK_SIZE: usize = 64 * 1024;
+
+pub struct Mp3 {
+    lame: Lame,
+    // deinterleaved input, kept between calls to reuse their allocations
+    left: Vec<i16>,
+    right: Vec<i16>,
+    output: BytesMut,
+}
+
+impl Mp3 {
+    pub fn new(config: &Mp3Config) -> Self {
+        let mut lame = Lame::new().expect("Lame::new");
+        lame.set_quality(config.quality as u8).expect("Lame::set_quality");
+        lame.set_kilobitrate(config.bitrate as i32).expect("Lame::set_kilobitrate");
+        lame.init_params().expect("Lame::init_params");
+        Mp3 {
+            lame,
+            left: Vec::new(),
+            right: Vec::new(),
+            output: BytesMut::with_capacity(OUTPUT_BLOCK_SIZE),
+        }
+    }
+}
+
+// LAME's encoder state isn't tied to the thread that created it, and is only
+// ever used by one thread at a time through &mut self
+unsafe impl Send for Mp3 {}
+
+impl Codec for Mp3 {
+    fn describe(&self) -> String {
+        format!("MP3 (libmp3lame, V{}, {} kbps)",
+            self.lame.quality(),
+            self.lame.kilobitrate())
+    }
+
+    fn encode(&mut self, data: &PcmData) -> Bytes {
+        // we must deinterleave audio data for LAME and discard channels beyond
+        // stereo. LAME does have an interleaved encode function, but it still
+        // bakes in 2 channel left/right assumptions which makes it unsafe to
+        // generalise for arbitrary PcmData which may have >2 channels
+        convert::deinterleave_stereo(&data.samples, data.channels, &mut self.left, &mut self.right);
+
+        // buffer size calculation is a suggestion from lame/lame.h:
+        let size = (self.left.len() * 5) / 4 + 7200;
+
+        if self.output.capacity() < size {
+            self.output.reserve(size.max(OUTPUT_BLOCK_SIZE));
+        }
+
+        self.output.resize(size, 0);
+
+        match self.lame.encode(&self.left, &self.right, &mut self.output) {
+            Ok(sz) => {
+                self.output.truncate(sz);
+                self.output.split().freeze()
+            }
+            Err(e) => panic!("lame encode error! {:?}", e)
+        }
+    }
+
+    // the lame crate doesn't expose lame_encode_flush, but encoding a couple
+    // of frames of silence pushes LAME's internal buffer out just the same
+    fn flush(&mut self) -> Bytes {
+        // encode only looks at channels and samples
+        let silence = PcmData {
+            sample_rate: 44100,
+            channels: 2,
+            samples: Samples::zeroed(MP3_FRAME_SAMPLES * 2 * 2),
+            pts: Duration::ZERO,
+            session: None,
+        };
+
+        self.encode(&silence)
+    }
+}
@@ -0,0 +1,87 @@
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+// buffers kept around for reuse. anything returned past this is freed
+const MAX_POOLED: usize = 256;
+
+// buffers larger than this (about three seconds of 44.1kHz stereo) come from
+// whole files being decoded at once and aren't worth holding on to
+const MAX_POOLED_SAMPLES: usize = 1 << 18;
+
+static POOL: Mutex<Vec<Vec<i16>>> = Mutex::new(Vec::new());
+
+// sample storage for PcmData. buffers go back to a shared pool when dropped
+// and are handed out again for the next packet, so that steady state
+// streaming isn't allocating for every chunk of audio it passes along
+pub struct Samples {
+    buffer: Vec<i16>,
+}
+
+impl Samples {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut buffer = POOL.lock().expect("lock sample pool")
+            .pop()
+            .unwrap_or_default();
+
+        buffer.clear();
+        buffer.reserve(capacity);
+        Samples { buffer }
+    }
+
+    pub fn zeroed(len: usize) -> Self {
+        let mut samples = Samples::with_capacity(len);
+        samples.buffer.resize(len, 0);
+        samples
+    }
+
+    pub fn from_slice(slice: &[i16]) -> Self {
+        let mut samples = Samples::with_capacity(slice.len());
+        samples.buffer.extend_from_slice(slice);
+        samples
+    }
+}
+
+impl Clone for Samples {
+    fn clone(&self) -> Self {
+        Samples::from_slice(&self.buffer)
+    }
+}
+
+// adopts a buffer allocated elsewhere, such as by a decoder library, which
+// then joins the pool once dropped
+impl From<Vec<i16>> for Samples {
+    fn from(buffer: Vec<i16>) -> Self {
+        Samples { buffer }
+    }
+}
+
+impl Deref for Samples {
+    type Target = [i16];
+
+    fn deref(&self) -> &[i16] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for Samples {
+    fn deref_mut(&mut self) -> &mut [i16] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for Samples {
+    fn drop(&mut self) {
+        let buffer = mem::take(&mut self.buffer);
+
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_SAMPLES {
+            return;
+        }
+
+        let mut pool = POOL.lock().expect("lock sample pool");
+
+        if pool.len() < MAX_POOLED {
+            pool.push(buffer);
+        }
+    }
+}
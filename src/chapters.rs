@@ -0,0 +1,169 @@
+// chapter files written alongside recordings, marking where each track
+// starts so that a recording can be navigated or split up afterwards
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_derive::Serialize;
+use slog::Logger;
+use tokio::sync::watch;
+
+use crate::config::ChapterFormat;
+use crate::metadata::Metadata;
+
+// the most tracks a cue sheet can hold
+const MAX_CUE_TRACKS: usize = 99;
+
+pub struct Chapters {
+    log: Logger,
+    format: ChapterFormat,
+    metadata: watch::Receiver<Metadata>,
+    sheet: Option<Sheet>,
+}
+
+struct Sheet {
+    path: PathBuf,
+    audio_file: String,
+    title: String,
+    // stream position of the recording's first chunk, which chapter times
+    // are counted from
+    start: Option<Duration>,
+    chapters: Vec<Chapter>,
+    // set once writing has failed, so that it's only logged once per file
+    failed: bool,
+}
+
+#[derive(Serialize)]
+struct Chapter {
+    start_secs: f64,
+    title: String,
+}
+
+impl Chapters {
+    pub fn new(log: Logger, format: ChapterFormat, metadata: watch::Receiver<Metadata>) -> Self {
+        Chapters { log, format, metadata, sheet: None }
+    }
+
+    // begins chapters for a new recording file, replacing any previous
+    pub fn start(&mut self, audio_path: &Path, title: &str) {
+        let audio_file = audio_path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        self.sheet = Some(Sheet {
+            path: audio_path.with_extension(self.format.extension()),
+            audio_file,
+            title: title.to_owned(),
+            start: None,
+            chapters: Vec::new(),
+            failed: false,
+        });
+
+        // whatever is playing already is the first chapter
+        let current = self.metadata.borrow_and_update().title.clone();
+        self.add(current, Duration::ZERO);
+    }
+
+    // called with each chunk's stream position before it's written, starts
+    // a new chapter if the title has changed since the last
+    pub fn update(&mut self, pts: Duration) {
+        let sheet = match &mut self.sheet {
+            Some(sheet) => sheet,
+            None => return,
+        };
+
+        let start = *sheet.start.get_or_insert(pts);
+
+        if !self.metadata.has_changed().unwrap_or(false) {
+            return;
+        }
+
+        let title = self.metadata.borrow_and_update().title.clone();
+        self.add(title, pts.saturating_sub(start));
+    }
+
+    // returns the chapter file's path, if one was written
+    pub fn finish(&mut self) -> Option<PathBuf> {
+        self.sheet.take()
+            .filter(|sheet| !sheet.chapters.is_empty() && !sheet.failed)
+            .map(|sheet| sheet.path)
+    }
+
+    fn add(&mut self, title: Option<String>, offset: Duration) {
+        let sheet = match &mut self.sheet {
+            Some(sheet) => sheet,
+            None => return,
+        };
+
+        // ad breaks and cleared titles don't start chapters
+        let title = match title {
+            Some(title) if !title.is_empty() => title,
+            _ => return,
+        };
+
+        if sheet.chapters.last().map(|chapter| &chapter.title) == Some(&title) {
+            return;
+        }
+
+        sheet.chapters.push(Chapter { start_secs: offset.as_secs_f64(), title });
+
+        // chapters change rarely enough that rewriting the whole file each
+        // time is simplest, and leaves a valid file should edicast stop
+        let contents = match self.format {
+            ChapterFormat::Cue => Ok(cue_sheet(sheet)),
+            ChapterFormat::Json => serde_json::to_string_pretty(&JsonSheet {
+                file: &sheet.audio_file,
+                title: &sheet.title,
+                chapters: &sheet.chapters,
+            }).map_err(io::Error::from),
+        };
+
+        if let Err(e) = contents.and_then(|contents| fs::write(&sheet.path, contents)) {
+            if !sheet.failed {
+                slog::warn!(self.log, "Could not write chapters";
+                    "path" => sheet.path.display(),
+                    "error" => e.to_string(),
+                );
+            }
+
+            sheet.failed = true;
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSheet<'a> {
+    file: &'a str,
+    title: &'a str,
+    chapters: &'a [Chapter],
+}
+
+fn cue_sheet(sheet: &Sheet) -> String {
+    let file_type = match Path::new(&sheet.audio_file).extension().and_then(|ext| ext.to_str()) {
+        Some("mp3") => "MP3",
+        _ => "WAVE",
+    };
+
+    let mut cue = format!("TITLE \"{}\"\nFILE \"{}\" {}\n",
+        cue_escape(&sheet.title), cue_escape(&sheet.audio_file), file_type);
+
+    for (index, chapter) in sheet.chapters.iter().take(MAX_CUE_TRACKS).enumerate() {
+        // cue sheet times are minutes, seconds and frames of 1/75 second
+        let frames = (chapter.start_secs * 75.0) as u64;
+
+        cue.push_str(&format!("  TRACK {:02} AUDIO\n    TITLE \"{}\"\n    INDEX 01 {:02}:{:02}:{:02}\n",
+            index + 1,
+            cue_escape(&chapter.title),
+            frames / 75 / 60,
+            frames / 75 % 60,
+            frames % 75));
+    }
+
+    cue
+}
+
+// cue sheets can't escape quotes, so they're swapped for typographic ones
+fn cue_escape(value: &str) -> String {
+    value.replace('"', "\u{201d}")
+}
@@ -0,0 +1,289 @@
+// shares listener counts and metadata between edicast servers through a
+// redis pub/sub channel, so that each can report station-wide listener
+// numbers and metadata set on any one of them reaches them all
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::config::ClusterConfig;
+use crate::event::Event;
+use crate::metadata::{AdBreak, Metadata};
+use crate::net::redis::{self, Reply};
+use crate::server::Edicast;
+
+const RETRY_WAIT: Duration = Duration::from_secs(5);
+
+// a node's counts are dropped once it's missed this many reports
+const MISSED_REPORTS: u32 = 3;
+
+pub struct Cluster {
+    node: String,
+    config: ClusterConfig,
+    nodes: Mutex<HashMap<String, NodeListeners>>,
+    // metadata last applied from other nodes by source, so that applying it
+    // isn't taken for a local update and sent straight back
+    received: Mutex<HashMap<String, Metadata>>,
+}
+
+struct NodeListeners {
+    reported_at: Instant,
+    streams: HashMap<String, usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Message {
+    #[serde(rename = "listeners")]
+    Listeners {
+        node: String,
+        streams: HashMap<String, usize>,
+    },
+    // streams are those the source feeds on the sending node, so that
+    // metadata reaches nodes whose streams are fed by differently named
+    // sources, such as mirrors
+    #[serde(rename = "metadata")]
+    Metadata {
+        node: String,
+        source: String,
+        streams: Vec<String>,
+        title: Option<String>,
+        ad_break: bool,
+        ad_break_secs: Option<u64>,
+    },
+}
+
+impl Message {
+    fn node(&self) -> &str {
+        match self {
+            Message::Listeners { node, .. } => node,
+            Message::Metadata { node, .. } => node,
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(config: ClusterConfig) -> Self {
+        let node = config.node.clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        Cluster {
+            node,
+            config,
+            nodes: Mutex::new(HashMap::new()),
+            received: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // listeners on the stream connected to other nodes
+    pub fn remote_listeners(&self, stream: &str) -> usize {
+        let stale_after = self.report_interval() * MISSED_REPORTS;
+
+        self.nodes.lock().expect("lock cluster nodes")
+            .values()
+            .filter(|node| node.reported_at.elapsed() < stale_after)
+            .filter_map(|node| node.streams.get(stream))
+            .sum()
+    }
+
+    fn report_interval(&self) -> Duration {
+        Duration::from_secs(self.config.report_secs.max(1))
+    }
+}
+
+pub fn start(log: Logger, cluster: Arc<Cluster>, edicast: Arc<Edicast>) {
+    let log = log.new(slog::o!("node" => cluster.node.clone()));
+
+    slog::info!(log, "Joining cluster";
+        "redis" => &cluster.config.redis,
+        "channel" => &cluster.config.channel,
+    );
+
+    let runtime = Handle::current();
+    let events = edicast.events.subscribe();
+
+    thread::Builder::new()
+        .name("edicast/cluster: publish".to_owned())
+        .spawn({
+            let log = log.clone();
+            let cluster = cluster.clone();
+            let edicast = edicast.clone();
+            move || publish_main(log, runtime, cluster, edicast, events)
+        })
+        .expect("spawn edicast cluster thread");
+
+    thread::Builder::new()
+        .name("edicast/cluster: subscribe".to_owned())
+        .spawn(move || loop {
+            if let Err(e) = subscribe(&log, &cluster, &edicast) {
+                slog::warn!(log, "Lost connection to cluster, reconnecting";
+                    "error" => e.to_string(),
+                    "retry_in_secs" => RETRY_WAIT.as_secs(),
+                );
+            }
+
+            thread::sleep(RETRY_WAIT);
+        })
+        .expect("spawn edicast cluster thread");
+}
+
+fn publish_main(log: Logger, runtime: Handle, cluster: Arc<Cluster>, edicast: Arc<Edicast>,
+    mut events: broadcast::Receiver<Event>)
+{
+    let mut connection = None;
+    let mut next_report = Instant::now();
+
+    loop {
+        let wait = next_report.saturating_duration_since(Instant::now());
+
+        let message = match runtime.block_on(tokio::time::timeout(wait, events.recv())) {
+            Ok(Ok(Event::MetadataUpdated { source, .. })) => {
+                match metadata_message(&cluster, &edicast, &source) {
+                    Some(message) => message,
+                    None => continue,
+                }
+            }
+            Ok(Ok(_)) => continue,
+            Ok(Err(RecvError::Lagged(skipped))) => {
+                slog::warn!(log, "Cluster fell behind events, some metadata updates were not shared";
+                    "skipped_events" => skipped);
+                continue;
+            }
+            Ok(Err(RecvError::Closed)) => return,
+            Err(_) => {
+                next_report = Instant::now() + cluster.report_interval();
+                listeners_message(&cluster, &edicast)
+            }
+        };
+
+        let message = serde_json::to_string(&message).expect("serialize cluster message");
+
+        if let Err(e) = publish(&cluster, &mut connection, &message) {
+            // reconnected on the next message, updates in between are lost
+            slog::warn!(log, "Could not publish to cluster"; "error" => e.to_string());
+            connection = None;
+        }
+    }
+}
+
+fn publish(cluster: &Cluster, connection: &mut Option<redis::Connection>, message: &str) -> Result<(), io::Error> {
+    let connection = match connection {
+        Some(connection) => connection,
+        None => connection.insert(connect(cluster)?),
+    };
+
+    connection.command(&["PUBLISH", &cluster.config.channel, message])?;
+    Ok(())
+}
+
+fn connect(cluster: &Cluster) -> Result<redis::Connection, io::Error> {
+    // the subscriber hears this node's own reports, so it always has
+    // something to read well within this
+    let timeout = cluster.report_interval() * MISSED_REPORTS;
+    redis::Connection::connect(&cluster.config.redis, cluster.config.password.as_deref(), timeout)
+}
+
+fn listeners_message(cluster: &Cluster, edicast: &Edicast) -> Message {
+    let mut streams = HashMap::new();
+
+    for listener in edicast.listeners.list() {
+        *streams.entry(listener.stream()).or_insert(0) += 1;
+    }
+
+    Message::Listeners { node: cluster.node.clone(), streams }
+}
+
+fn metadata_message(cluster: &Cluster, edicast: &Edicast, source: &str) -> Option<Message> {
+    let metadata = edicast.sources.metadata(source)?.borrow().clone();
+
+    let received = cluster.received.lock().expect("lock cluster metadata").remove(source);
+
+    if received.as_ref() == Some(&metadata) {
+        return None;
+    }
+
+    let ad_break = metadata.ad_break.as_ref();
+
+    Some(Message::Metadata {
+        node: cluster.node.clone(),
+        source: source.to_owned(),
+        streams: edicast.streams.streams_for_source(source),
+        ad_break: ad_break.is_some(),
+        ad_break_secs: ad_break.and_then(|ad_break| ad_break.duration).map(|duration| duration.as_secs()),
+        title: metadata.title,
+    })
+}
+
+fn subscribe(log: &Logger, cluster: &Cluster, edicast: &Edicast) -> Result<(), io::Error> {
+    let mut connection = connect(cluster)?;
+    connection.send(&["SUBSCRIBE", &cluster.config.channel])?;
+
+    loop {
+        // messages arrive as ["message", channel, payload]
+        let payload = match connection.read_reply()? {
+            Reply::Array(Some(mut items)) if items.len() == 3 => match (items.pop(), &items[0]) {
+                (Some(Reply::Bulk(Some(payload))), Reply::Bulk(Some(kind))) if kind == b"message" => payload,
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        let message = match serde_json::from_slice::<Message>(&payload) {
+            Ok(message) => message,
+            Err(e) => {
+                slog::warn!(log, "Ignoring malformed cluster message"; "error" => e.to_string());
+                continue;
+            }
+        };
+
+        if message.node() == cluster.node {
+            continue;
+        }
+
+        receive(cluster, edicast, message);
+    }
+}
+
+fn receive(cluster: &Cluster, edicast: &Edicast, message: Message) {
+    match message {
+        Message::Listeners { node, streams } => {
+            cluster.nodes.lock().expect("lock cluster nodes")
+                .insert(node, NodeListeners { reported_at: Instant::now(), streams });
+        }
+        Message::Metadata { source, streams, title, ad_break, ad_break_secs, .. } => {
+            let metadata = Metadata {
+                title,
+                ad_break: if ad_break {
+                    Some(AdBreak { duration: ad_break_secs.map(Duration::from_secs) })
+                } else {
+                    None
+                },
+            };
+
+            // the source of the same name, and whatever feeds the same
+            // streams here
+            let mut sources = streams.iter()
+                .filter_map(|stream| edicast.streams.config(stream))
+                .map(|stream| stream.source)
+                .collect::<HashSet<_>>();
+
+            sources.insert(source);
+
+            for source in sources {
+                cluster.received.lock().expect("lock cluster metadata")
+                    .insert(source.clone(), metadata.clone());
+
+                if !edicast.sources.update_metadata(&source, |current| *current = metadata.clone()) {
+                    cluster.received.lock().expect("lock cluster metadata").remove(&source);
+                }
+            }
+        }
+    }
+}
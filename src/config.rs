@@ -1,29 +1,387 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
+use toml::value::{Table, Value};
 
-#[derive(Deserialize, Debug)]
+use crate::audio::encode;
+
+mod diagnostic;
+pub use self::diagnostic::Location;
+
+#[derive(Deserialize, Debug, JsonSchema)]
 pub struct Config {
     pub listen: ListenConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub crash: CrashConfig,
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+    // read at startup only
+    #[serde(default)]
+    pub log: LogConfig,
+    // externally visible base URL of the public server, eg.
+    // "https://radio.example.com", used wherever edicast generates absolute
+    // URLs. the listen address is rarely what listeners see behind a proxy
+    pub public_url: Option<String>,
+    // read at startup only. the user and group to switch to once listening
+    // sockets are bound, the group defaulting to the user's own. unix only
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub stats: Option<StatsConfig>,
+    // read at startup only
+    pub journal: Option<JournalConfig>,
+    pub metrics: Option<MetricsConfig>,
+    // glob of further config files to merge sources and streams from,
+    // relative to this file
+    pub include: Option<String>,
+    #[serde(default)]
     pub source: HashMap<String, SourceConfig>,
+    #[serde(default)]
     pub stream: HashMap<String, StreamConfig>,
+    // read at startup only, reloading doesn't change them
+    #[serde(default)]
+    pub recording: Vec<RecordingConfig>,
+    pub files: Option<FilesConfig>,
+    // read at startup only
+    pub mirror: Option<MirrorConfig>,
+    pub cluster: Option<ClusterConfig>,
+    pub origin: Option<OriginConfig>,
+    // read at startup only
+    pub access_log: Option<AccessLogConfig>,
+    // read at startup only
+    #[serde(default)]
+    pub alert: Vec<AlertConfig>,
+    // read at startup only
+    #[serde(default)]
+    pub hook: Vec<HookConfig>,
+    // read at startup only
+    pub script: Option<ScriptConfig>,
+}
+
+// values in [defaults.source] and [defaults.stream] apply to every source
+// and stream which doesn't set them itself, including those in included
+// files. tables such as codec are replaced whole rather than merged
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    #[serde(default)]
+    source: Table,
+    #[serde(default)]
+    stream: Table,
+}
+
+impl Defaults {
+    fn parse(document: &mut Value) -> Result<Self, Error> {
+        let defaults = match document.as_table_mut().and_then(|table| table.remove("defaults")) {
+            Some(defaults) => defaults.try_into().map_err(Error::Toml)?,
+            None => Defaults::default(),
+        };
+
+        Ok(defaults)
+    }
+
+    fn apply(&self, document: &mut Value) {
+        for (section, defaults) in [("source", &self.source), ("stream", &self.stream)] {
+            let entries = match document.get_mut(section).and_then(Value::as_table_mut) {
+                Some(entries) => entries,
+                None => continue,
+            };
+
+            for entry in entries.iter_mut().map(|(_, v)| v).filter_map(Value::as_table_mut) {
+                for (key, value) in defaults {
+                    if !entry.contains_key(key) {
+                        entry.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// named codec settings under [codec.<name>], which streams can refer to
+// with codec = "<name>" in place of a codec table
+#[derive(Debug, Default)]
+struct CodecProfiles {
+    profiles: Table,
+}
+
+impl CodecProfiles {
+    fn parse(document: &mut Value) -> Result<Self, Error> {
+        let profiles = match document.as_table_mut().and_then(|table| table.remove("codec")) {
+            Some(Value::Table(profiles)) => profiles,
+            Some(other) => {
+                // let serde produce a proper type error
+                other.try_into::<Table>().map_err(Error::Toml)?
+            }
+            None => Table::new(),
+        };
+
+        // check profiles up front so that mistakes in a profile nobody uses
+        // yet don't go unnoticed
+        for profile in profiles.values() {
+            profile.clone().try_into::<CodecConfig>().map_err(Error::Toml)?;
+        }
+
+        Ok(CodecProfiles { profiles })
+    }
+
+    fn apply(&self, document: &mut Value) -> Result<(), Error> {
+        let streams = match document.get_mut("stream").and_then(Value::as_table_mut) {
+            Some(streams) => streams,
+            None => return Ok(()),
+        };
+
+        for (stream_name, stream) in streams.iter_mut() {
+            let codec = match stream.get_mut("codec") {
+                Some(codec) => codec,
+                None => continue,
+            };
+
+            let profile_name = match codec.as_str() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            match self.profiles.get(profile_name) {
+                Some(profile) => *codec = profile.clone(),
+                None => {
+                    return Err(Error::StreamRefersToInvalidCodec {
+                        stream_name: stream_name.clone(),
+                        codec_name: profile_name.to_owned(),
+                        suggestion: diagnostic::did_you_mean(profile_name, self.profiles.keys()),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_document(contents: &str) -> Result<Value, Error> {
+    toml::from_str(contents).map_err(Error::Toml)
+}
+
+fn read_document(path: &Path) -> Result<Value, Error> {
+    let contents = fs::read_to_string(path).map_err(Error::Io)?;
+    parse_document(&contents)
+}
+
+fn stream_names(document: &Value) -> impl Iterator<Item = String> + '_ {
+    document.get("stream")
+        .and_then(Value::as_table)
+        .into_iter()
+        .flat_map(|streams| streams.keys().cloned())
+}
+
+// tables in the overlay are merged key by key into the base document, at
+// any depth. any other value in the overlay, including arrays, replaces
+// the base value outright
+fn merge_overlay(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_overlay(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(mut document: Value, defaults: &Defaults, profiles: &CodecProfiles) -> Result<T, Error> {
+    defaults.apply(&mut document);
+    profiles.apply(&mut document)?;
+    document.try_into().map_err(Error::Toml)
+}
+
+// included files may only define sources and streams
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct IncludeConfig {
+    #[serde(default)]
+    source: HashMap<String, SourceConfig>,
+    #[serde(default)]
+    stream: HashMap<String, StreamConfig>,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Toml(toml::de::Error),
-    StreamRefersToInvalidSource { stream_name: String, source_name: String },
+    IncludePattern(glob::PatternError),
+    Include { path: PathBuf, error: Box<Error> },
+    Overlay { path: PathBuf, error: Box<Error> },
+    DuplicateSource { name: String, path: PathBuf },
+    DuplicateStream { name: String, path: PathBuf },
+    StreamRefersToInvalidSource {
+        stream_name: String,
+        source_name: String,
+        location: Option<Location>,
+        suggestion: Option<String>,
+    },
+    StreamRefersToInvalidCodec {
+        stream_name: String,
+        codec_name: String,
+        suggestion: Option<String>,
+    },
+    DuplicateStreamPath { path: String, location: Option<Location> },
+    TlsListenWithoutConfig { role: &'static str },
+    // used_by is what encodes with the codec, such as stream "main"
+    CodecNotBuilt { used_by: String, feature: &'static str },
+    RecordingRefersToInvalidStream {
+        show: String,
+        stream_name: String,
+        suggestion: Option<String>,
+    },
+    JingleNeverPlays { stream_name: String },
+    // which would let anyone sending an empty bearer token pull private
+    // streams
+    EmptyOriginSecret,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "could not read config file: {}", e),
+            Error::Toml(e) => write!(f, "could not parse config file: {}", e),
+            Error::IncludePattern(e) => write!(f, "invalid include pattern: {}", e),
+            Error::Include { path, error } => write!(f, "in {}: {}", path.display(), error),
+            Error::Overlay { path, error } => write!(f, "in overlay {}: {}", path.display(), error),
+            Error::DuplicateSource { name, path } => {
+                write!(f, "source {:?} in {} is already defined", name, path.display())
+            }
+            Error::DuplicateStream { name, path } => {
+                write!(f, "stream {:?} in {} is already defined", name, path.display())
+            }
+            Error::StreamRefersToInvalidSource { stream_name, source_name, location, suggestion } => {
+                if let Some(location) = location {
+                    write!(f, "{}: ", location)?;
+                }
+
+                write!(f, "stream {:?} refers to invalid source {:?}", stream_name, source_name)?;
+
+                if let Some(suggestion) = suggestion {
+                    write!(f, "; did you mean {:?}?", suggestion)?;
+                }
+
+                Ok(())
+            }
+            Error::StreamRefersToInvalidCodec { stream_name, codec_name, suggestion } => {
+                write!(f, "stream {:?} refers to undefined codec profile {:?}", stream_name, codec_name)?;
+
+                if let Some(suggestion) = suggestion {
+                    write!(f, "; did you mean {:?}?", suggestion)?;
+                }
+
+                Ok(())
+            }
+            Error::DuplicateStreamPath { path, location } => {
+                if let Some(location) = location {
+                    write!(f, "{}: ", location)?;
+                }
+
+                write!(f, "multiple streams configured with path {:?}", path)
+            }
+            Error::TlsListenWithoutConfig { role } => {
+                write!(f, "listen.{} has ?tls addresses but listen.{}_tls is not set", role, role)
+            }
+            Error::CodecNotBuilt { used_by, feature } => {
+                write!(f, "{} encodes to a codec which needs edicast built with the {} feature", used_by, feature)
+            }
+            Error::RecordingRefersToInvalidStream { show, stream_name, suggestion } => {
+                write!(f, "recording of {:?} refers to invalid stream {:?}", show, stream_name)?;
+
+                if let Some(suggestion) = suggestion {
+                    write!(f, "; did you mean {:?}?", suggestion)?;
+                }
+
+                Ok(())
+            }
+            Error::JingleNeverPlays { stream_name } => {
+                write!(f, "stream {:?} has a jingle with neither every_mins nor times", stream_name)
+            }
+            Error::EmptyOriginSecret => write!(f, "origin.secret is empty"),
+        }
+    }
 }
 
 impl Config {
     pub fn load(file: impl AsRef<Path>) -> Result<Self, Error> {
-        let contents = fs::read_to_string(file).map_err(Error::Io)?;
-        let config = toml::from_str::<Config>(&contents).map_err(Error::Toml)?;
+        Config::load_with_overlays(file, &[])
+    }
+
+    // overlays are merged over the base file in order before anything else
+    // happens, see merge_overlay
+    pub fn load_with_overlays(file: impl AsRef<Path>, overlays: &[PathBuf]) -> Result<Self, Error> {
+        let file = file.as_ref();
+        let mut document = read_document(file)?;
+
+        // which file each stream was defined in, for error reporting
+        let mut origins = stream_names(&document)
+            .map(|name| (name, file.to_owned()))
+            .collect::<HashMap<_, _>>();
+
+        for overlay in overlays {
+            let overlay_document = read_document(overlay)
+                .map_err(|error| Error::Overlay { path: overlay.clone(), error: Box::new(error) })?;
+
+            for name in stream_names(&overlay_document) {
+                origins.insert(name, overlay.clone());
+            }
+
+            merge_overlay(&mut document, overlay_document);
+        }
+
+        let defaults = Defaults::parse(&mut document)?;
+        let profiles = CodecProfiles::parse(&mut document)?;
+        let mut config = deserialize::<Config>(document, &defaults, &profiles)?;
+
+        if let Some(pattern) = &config.include {
+            let base = file.parent().unwrap_or(Path::new(""));
+
+            for path in include_paths(base, pattern)? {
+                let include = load_include(&path, &defaults, &profiles)
+                    .map_err(|error| Error::Include { path: path.clone(), error: Box::new(error) })?;
+
+                for (name, source) in include.source {
+                    if config.source.contains_key(&name) {
+                        return Err(Error::DuplicateSource { name, path });
+                    }
+
+                    config.source.insert(name, source);
+                }
+
+                for (name, stream) in include.stream {
+                    if config.stream.contains_key(&name) {
+                        return Err(Error::DuplicateStream { name, path });
+                    }
+
+                    origins.insert(name.clone(), path.clone());
+                    config.stream.insert(name, stream);
+                }
+            }
+        }
+
+        config.listen.validate()?;
 
         // validate that all stream point to valid sources
         for (name, stream) in config.stream.iter() {
@@ -31,60 +389,1637 @@ impl Config {
                 return Err(Error::StreamRefersToInvalidSource {
                     stream_name: name.to_owned(),
                     source_name: stream.source.to_owned(),
+                    location: origins.get(name)
+                        .and_then(|origin| diagnostic::locate_stream_source(origin, name)),
+                    suggestion: diagnostic::did_you_mean(&stream.source, config.source.keys()),
+                });
+            }
+        }
+
+        for (name, stream) in config.stream.iter() {
+            if let Some(jingle) = &stream.jingle {
+                if jingle.every_mins.is_none() && jingle.times.is_empty() {
+                    return Err(Error::JingleNeverPlays { stream_name: name.to_owned() });
+                }
+            }
+        }
+
+        if config.origin.as_ref().map_or(false, |origin| origin.secret.trim().is_empty()) {
+            return Err(Error::EmptyOriginSecret);
+        }
+
+        // streams encode to mp3 unless they say otherwise, which builds
+        // without LAME can't do
+        for (name, stream) in config.stream.iter() {
+            if let Some(feature) = encode::missing_feature(&stream.codec) {
+                return Err(Error::CodecNotBuilt { used_by: format!("stream {:?}", name), feature });
+            }
+        }
+
+        if let Some(feature) = config.mirror.as_ref().and_then(|mirror| encode::missing_feature(&mirror.codec)) {
+            return Err(Error::CodecNotBuilt { used_by: "mirror".to_owned(), feature });
+        }
+
+        for recording in &config.recording {
+            if !config.stream.contains_key(&recording.stream) {
+                return Err(Error::RecordingRefersToInvalidStream {
+                    show: recording.show.clone(),
+                    stream_name: recording.stream.clone(),
+                    suggestion: diagnostic::did_you_mean(&recording.stream, config.stream.keys()),
+                });
+            }
+        }
+
+        // validate that no two streams share a path on the same host
+        let mut paths = HashSet::new();
+
+        for (name, stream) in config.stream.iter() {
+            if !paths.insert(stream.route_key()) {
+                return Err(Error::DuplicateStreamPath {
+                    path: stream.route_key(),
+                    location: origins.get(name)
+                        .and_then(|origin| diagnostic::locate_stream_path(origin, name)),
                 });
             }
         }
 
         Ok(config)
     }
+
+    // absolute URL for a stream, on its own host if it has one
+    pub fn stream_url(&self, stream: &StreamConfig) -> String {
+        match (&self.public_url, &stream.host) {
+            (Some(base), Some(host)) => {
+                let scheme = base.split_once("://").map(|(scheme, _)| scheme).unwrap_or("https");
+                format!("{}://{}{}", scheme, host, stream.path)
+            }
+            _ => self.public_url_for(&stream.path),
+        }
+    }
+
+    // absolute URL for a path on the public server. without public_url set
+    // the path is returned as is, which browsers resolve against the
+    // address they connected to
+    pub fn public_url_for(&self, path: &str) -> String {
+        match &self.public_url {
+            Some(base) if path.starts_with('/') => format!("{}{}", base.trim_end_matches('/'), path),
+            _ => path.to_owned(),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+// included files are merged in sorted order, so that errors about
+// duplicates are reported consistently
+fn include_paths(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let pattern = base.join(pattern);
+
+    let mut paths = glob::glob(&pattern.to_string_lossy())
+        .map_err(Error::IncludePattern)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Io(e.into()))?;
+
+    paths.sort();
+    Ok(paths)
+}
+
+fn load_include(path: &Path, defaults: &Defaults, profiles: &CodecProfiles) -> Result<IncludeConfig, Error> {
+    deserialize(read_document(path)?, defaults, profiles)
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
 pub struct ListenConfig {
-    pub public: SocketAddr,
-    pub control: SocketAddr,
+    #[schemars(with = "ListenAddrsRepr")]
+    pub public: ListenAddrs,
+    #[schemars(with = "ListenAddrsRepr")]
+    pub control: ListenAddrs,
+    // control API over WebSocket, disabled unless set
+    pub websocket: Option<SocketAddr>,
+    // certificates for public addresses marked ?tls
+    pub public_tls: Option<TlsConfig>,
+    // experimental HTTP/3 listener for public streams, a UDP address. uses
+    // the certificates from public_tls, and needs the http3 feature
+    pub public_quic: Option<SocketAddr>,
+    // certificates for control addresses marked ?tls
+    pub control_tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+    #[serde(default)]
+    pub interface: InterfaceConfig,
+    // requests from these networks may say who they're forwarding for in
+    // the forwarded_header, eg. ["10.0.0.0/8"]
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<IpNet>,
+    // the one header trusted proxies set. the other is ignored, since a
+    // proxy that doesn't know about it passes on whatever the client sent
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+impl ListenConfig {
+    // addresses marked ?tls need certificates for their role
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.public.any_tls() && self.public_tls.is_none() {
+            return Err(Error::TlsListenWithoutConfig { role: "public" });
+        }
+
+        if self.control.any_tls() && self.control_tls.is_none() {
+            return Err(Error::TlsListenWithoutConfig { role: "control" });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum ForwardedHeader {
+    #[default]
+    #[serde(rename = "x-forwarded-for")]
+    XForwardedFor,
+    // RFC 7239
+    #[serde(rename = "forwarded")]
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => "x-forwarded-for",
+            ForwardedHeader::Forwarded => "forwarded",
+        }
+    }
+}
+
+// network interfaces to bind TCP listeners to by name, eg. "eth1", so that
+// only connections arriving on that interface are accepted. for hosts where
+// streaming and management traffic are on separate networks. linux only
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct InterfaceConfig {
+    // applies to the ACME challenge listener too
+    pub public: Option<String>,
+    // applies to the control WebSocket listener too
+    pub control: Option<String>,
+    // connections edicast makes to other servers: relays, pulled sources,
+    // mirroring, webhooks, uploads, redis and graphite metrics
+    pub outbound: Option<String>,
+}
+
+// expect a PROXY protocol v1 or v2 header on every connection, as sent by
+// HAProxy and most cloud load balancers. connections without one are
+// dropped, so only enable this when everything arrives through the proxy
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct ProxyProtocolConfig {
+    #[serde(default)]
+    pub public: bool,
+    // applies to the control WebSocket listener too
+    #[serde(default)]
+    pub control: bool,
+}
+
+// one or more addresses for a listener role to bind, such as
+// "0.0.0.0:8000" or ["0.0.0.0:8000", "[::]:8000", "0.0.0.0:8443?tls"].
+// addresses marked ?tls serve TLS using the role's TLS config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "ListenAddrsRepr")]
+pub struct ListenAddrs(Vec<ListenAddr>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenAddr {
+    pub address: SocketAddr,
+    pub tls: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ListenAddrsRepr {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<ListenAddrsRepr> for ListenAddrs {
+    type Error = String;
+
+    fn try_from(repr: ListenAddrsRepr) -> Result<Self, String> {
+        let addrs = match repr {
+            ListenAddrsRepr::One(addr) => vec![addr.parse()?],
+            ListenAddrsRepr::Many(addrs) => {
+                addrs.iter().map(|addr| addr.parse()).collect::<Result<Vec<_>, _>>()?
+            }
+        };
+
+        if addrs.is_empty() {
+            return Err("at least one listen address is required".to_owned());
+        }
+
+        Ok(ListenAddrs(addrs))
+    }
+}
+
+impl From<ListenAddr> for ListenAddrs {
+    fn from(addr: ListenAddr) -> Self {
+        ListenAddrs(vec![addr])
+    }
+}
+
+impl ListenAddrs {
+    pub fn iter(&self) -> impl Iterator<Item = &ListenAddr> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn any_tls(&self) -> bool {
+        self.0.iter().any(|addr| addr.tls)
+    }
+}
+
+impl fmt::Display for ListenAddrs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, addr) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (address, tls) = match s.strip_suffix("?tls") {
+            Some(address) => (address, true),
+            None => (s, false),
+        };
+
+        let address = address.parse()
+            .map_err(|_| format!("invalid listen address: {}", s))?;
+
+        Ok(ListenAddr { address, tls })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+
+        if self.tls {
+            write!(f, "?tls")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct TlsConfig {
+    // PEM encoded certificate chain, leaf certificate first
+    pub cert: PathBuf,
+    // PEM encoded private key
+    pub key: PathBuf,
+    // obtain and renew the certificate automatically, writing it to cert
+    // and key above
+    pub acme: Option<AcmeConfig>,
+    // further certificates, served to clients asking for one of their hosts.
+    // cert and key above are served to everyone else
+    #[serde(default)]
+    pub sni: Vec<SniCertConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct SniCertConfig {
+    // hostnames to serve this certificate for, such as "radio.example.com"
+    // or "*.example.com"
+    pub hosts: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+pub enum AcmeChallenge {
+    // answered on http_listen, or by the public listener for renewals when
+    // port 80 redirects to it
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    // answered by a TXT record published by dns_hook
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+fn default_acme_renew_after_days() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    // eg. "mailto:ops@example.com"
+    #[serde(default)]
+    pub contact: Vec<String>,
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    // where to keep the ACME account credentials, created on first run
+    pub account: PathBuf,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    // plain http listener answering http-01 challenges and redirecting
+    // everything else to https, usually on port 80
+    pub http_listen: Option<SocketAddr>,
+    // for dns-01, run as `<dns_hook> set <name> <value>` to publish each TXT
+    // record and `<dns_hook> clear <name> <value>` to remove it again. set
+    // should only exit once the record is visible to the ACME server
+    pub dns_hook: Option<PathBuf>,
+    #[serde(default = "default_acme_renew_after_days")]
+    pub renew_after_days: u64,
+}
+
+fn default_control_workers() -> usize {
+    16
+}
+
+fn default_control_queue() -> usize {
+    64
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct ControlConfig {
+    // if set, admin requests to the control server must carry this token as
+    // a bearer token in the Authorization header. if not, only requests from
+    // this machine are allowed
+    pub token: Option<String>,
+    // admin requests are handled on at most this many threads at once, with
+    // up to queue more waiting for one. requests beyond that get a 503
+    #[serde(default = "default_control_workers")]
+    pub workers: usize,
+    #[serde(default = "default_control_queue")]
+    pub queue: usize,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            token: None,
+            workers: default_control_workers(),
+            queue: default_control_queue(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct LimitsConfig {
+    pub max_listeners: Option<usize>,
+}
+
+fn default_upgrade_drain_secs() -> u64 {
+    300
+}
+
+// what listeners hear on SIGTERM or SIGINT before their connections are
+// closed. goodbye is played in full if set, otherwise grace_secs of silence
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct ShutdownConfig {
+    #[serde(default)]
+    pub grace_secs: u64,
+    pub goodbye: Option<PathBuf>,
+    // after handing its sockets to a new process on SIGUSR2, how long to
+    // keep serving connected listeners before shutting down as above
+    #[serde(default = "default_upgrade_drain_secs")]
+    pub upgrade_drain_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_secs: 0,
+            goodbye: None,
+            upgrade_drain_secs: default_upgrade_drain_secs(),
+        }
+    }
+}
+
+// what happens when a thread or task panics, beyond logging it
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct CrashConfig {
+    // run on every panic, with the thread and message in EDICAST_PANIC_THREAD
+    // and EDICAST_PANIC_MESSAGE. edicast doesn't wait for it to finish
+    pub command: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct LogConfig {
+    // overridden by --log-format. errors in the config file itself are
+    // logged before it's read, so they only follow the flag
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+    // overridden by --log-level. the level and filters are applied again on
+    // reload when they've changed, and can be changed through the control
+    // API without reloading
+    #[schemars(with = "Option<String>")]
+    pub level: Option<LogLevel>,
+    #[serde(default)]
+    pub filter: Vec<LogFilterConfig>,
+}
+
+// logs records from some part of edicast at a different level to the rest
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct LogFilterConfig {
+    // a key and value such as "source:studio" or "stream:live", matching
+    // records logged with that key, or a module such as "edicast::net"
+    pub target: String,
+    #[schemars(with = "String")]
+    pub level: LogLevel,
+}
+
+// one of critical, error, warning, info, debug or trace
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct LogLevel(pub slog::Level);
+
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+
+    fn try_from(level: String) -> Result<Self, String> {
+        level.parse()
+            .map(LogLevel)
+            .map_err(|()| format!("invalid log level {:?}", level))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum LogFormat {
+    // for people, on a terminal
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    // one object per line, with every key as a field, for log pipelines
+    #[serde(rename = "json")]
+    Json,
+    // RFC 5424 syslog, with keys as structured data
+    #[serde(rename = "syslog")]
+    Syslog,
+    // the systemd journal, with keys as journal fields
+    #[serde(rename = "journald")]
+    Journald,
+}
+
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct SyslogConfig {
+    // a "host:port" to send to over UDP. without one, logs go to the local
+    // syslog daemon at /dev/log
+    pub address: Option<String>,
+    #[serde(default)]
+    pub facility: SyslogFacility,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum SyslogFacility {
+    #[serde(rename = "user")]
+    User,
+    #[default]
+    #[serde(rename = "daemon")]
+    Daemon,
+    #[serde(rename = "local0")]
+    Local0,
+    #[serde(rename = "local1")]
+    Local1,
+    #[serde(rename = "local2")]
+    Local2,
+    #[serde(rename = "local3")]
+    Local3,
+    #[serde(rename = "local4")]
+    Local4,
+    #[serde(rename = "local5")]
+    Local5,
+    #[serde(rename = "local6")]
+    Local6,
+    #[serde(rename = "local7")]
+    Local7,
+}
+
+impl SyslogFacility {
+    pub fn code(&self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+// a line for every listener when they disconnect
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum AccessLogFormat {
+    // one object per line
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    // the combined log format with seconds connected on the end, as Icecast
+    // writes, for existing log analyzers
+    #[serde(rename = "combined")]
+    Combined,
+}
+
+fn default_watchdog_timeout_secs() -> u64 {
+    30
+}
+
+// source and stream tasks busy with audio for longer than timeout_secs
+// without making progress are reported as stalled, and restarted if restart
+// is set
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct WatchdogConfig {
+    #[serde(default = "default_watchdog_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub restart: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            timeout_secs: default_watchdog_timeout_secs(),
+            restart: false,
+        }
+    }
+}
+
+fn default_stats_retention_days() -> u64 {
+    365
+}
+
+fn default_stats_sample_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct StatsConfig {
+    // path to the SQLite database, created if it does not exist
+    pub database: PathBuf,
+    #[serde(default = "default_stats_retention_days")]
+    pub retention_days: u64,
+    // how often to record the number of listeners on each stream
+    #[serde(default = "default_stats_sample_secs")]
+    pub sample_secs: u64,
+}
+
+fn default_journal_max_entries() -> u64 {
+    10000
+}
+
+// keeps significant events, such as source sessions, reloads and errors,
+// for reconstructing what happened through the control API
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct JournalConfig {
+    // path to the SQLite database, created if it does not exist
+    pub database: PathBuf,
+    // the oldest entries are removed beyond this many
+    #[serde(default = "default_journal_max_entries")]
+    pub max_entries: u64,
+    // event types to keep, every type but listener_connected and
+    // listener_disconnected if left out, as there are so many of those
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub enum MetricsProtocol {
+    #[serde(rename = "statsd")]
+    Statsd,
+    #[serde(rename = "graphite")]
+    Graphite,
+    #[serde(rename = "influx")]
+    Influx,
+}
+
+fn default_metrics_interval_secs() -> u64 {
+    10
+}
+
+fn default_metrics_prefix() -> String {
+    "edicast".to_owned()
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct MetricsConfig {
+    pub protocol: MetricsProtocol,
+    // host:port to push to. statsd and influx are sent over UDP, graphite
+    // over TCP
+    pub target: String,
+    #[serde(default = "default_metrics_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_metrics_prefix")]
+    pub prefix: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
 pub enum OfflineBehaviour {
+    #[default]
     #[serde(rename = "inactive")]
     Inactive,
     #[serde(rename = "silence")]
     Silence,
 }
 
-impl Default for OfflineBehaviour {
+fn default_buffer_ms() -> usize {
+    500
+}
+
+fn default_fanout_buffer() -> usize {
+    1
+}
+
+fn default_fanout_block_timeout_ms() -> u64 {
+    100
+}
+
+// what a source does when a stream isn't keeping up with its audio
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+pub enum BackpressurePolicy {
+    // the stream skips ahead to the oldest audio still buffered
+    #[serde(rename = "drop-oldest")]
+    DropOldest,
+    // new audio isn't published until the slowest stream catches up
+    #[default]
+    #[serde(rename = "drop-newest")]
+    DropNewest,
+    // wait up to block_timeout_ms for the stream, then drop the newest
+    #[serde(rename = "block")]
+    Block,
+    // cut the stream off, it resubscribes from the live edge
+    #[serde(rename = "disconnect")]
+    Disconnect,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct FanoutConfig {
+    // chunks of audio buffered for each stream reading from the source
+    #[serde(default = "default_fanout_buffer")]
+    pub buffer: usize,
+    #[serde(default)]
+    pub policy: BackpressurePolicy,
+    #[serde(default = "default_fanout_block_timeout_ms")]
+    pub block_timeout_ms: u64,
+}
+
+impl Default for FanoutConfig {
     fn default() -> Self {
-        OfflineBehaviour::Inactive
+        FanoutConfig {
+            buffer: default_fanout_buffer(),
+            policy: BackpressurePolicy::default(),
+            block_timeout_ms: default_fanout_block_timeout_ms(),
+        }
     }
 }
 
-fn default_buffer_ms() -> usize {
-    500
+// scheduling for the threads doing a source or stream's audio work. these
+// settings usually need CAP_SYS_NICE, and are only supported on linux
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct SchedulingConfig {
+    // SCHED_RR realtime priority, from 1 to 99
+    pub realtime_priority: Option<u8>,
+    // niceness, from -20 (highest priority) to 19
+    pub nice: Option<i8>,
+    // cpus the threads are pinned to, numbered from 0
+    #[serde(default)]
+    pub cpus: Vec<usize>,
+}
+
+fn default_jitter_max_ms() -> usize {
+    5000
+}
+
+fn default_sample_rate() -> usize {
+    44100
+}
+
+fn default_channels() -> usize {
+    2
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
 pub struct SourceConfig {
+    #[serde(default)]
     pub offline: OfflineBehaviour,
     #[serde(default = "default_buffer_ms")]
     pub buffer_ms: usize,
+    // live audio is held back between these amounts to smooth over bursty
+    // delivery from source clients, growing by buffer_ms each time the
+    // buffer runs dry and shrinking again once delivery is steady
+    #[serde(default)]
+    pub jitter_min_ms: usize,
+    #[serde(default = "default_jitter_max_ms")]
+    pub jitter_max_ms: usize,
+    #[serde(default)]
+    pub fanout: FanoutConfig,
+    pub scheduling: Option<SchedulingConfig>,
+    // directory to keep a copy of exactly what each source client sends,
+    // one file per connection
+    pub dump: Option<PathBuf>,
+    // format of audio generated by edicast for this source, such as silence
+    // while offline. this should match what source clients send, so that
+    // streams don't see the format change when a client connects
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: usize,
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    // pull audio from other servers rather than waiting for a source client
+    pub pull: Option<PullConfig>,
+}
+
+fn default_pull_check_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct PullConfig {
+    // mounts to pull from, such as "https://origin.example.com/live.mp3".
+    // the first is the primary, the rest are failed over to in order
+    #[schemars(with = "Vec<String>")]
+    pub urls: Vec<HttpUrl>,
+    // sent as a bearer token, such as another edicast server's origin.secret
+    pub secret: Option<String>,
+    // while on a fallback, the primary is checked this often and switched
+    // back to once it's serving again
+    #[serde(default = "default_pull_check_secs")]
+    pub check_secs: u64,
+    // certificate authorities to trust for https URLs
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+fn default_mp3_bitrate() -> usize {
+    128
 }
 
-#[derive(Deserialize, Debug, Clone)]
+fn default_mp3_quality() -> usize {
+    2
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct Mp3Config {
+    // kbps
+    #[serde(default = "default_mp3_bitrate")]
     pub bitrate: usize,
+    // 0 (best) to 9 (fastest)
+    #[serde(default = "default_mp3_quality")]
     pub quality: usize,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl Default for Mp3Config {
+    fn default() -> Self {
+        Mp3Config {
+            bitrate: default_mp3_bitrate(),
+            quality: default_mp3_quality(),
+        }
+    }
+}
+
+// a table with one key naming the codec, such as { mp3 = { bitrate = 128 } }.
+// codecs other than mp3 can be registered by applications embedding edicast
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(try_from = "Table")]
 pub enum CodecConfig {
     #[serde(rename = "mp3")]
     Mp3(Mp3Config),
+    #[serde(skip)]
+    Registered(RegisteredCodecConfig),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredCodecConfig {
+    pub name: String,
+    pub config: Value,
+}
+
+impl TryFrom<Table> for CodecConfig {
+    type Error = String;
+
+    fn try_from(table: Table) -> Result<Self, String> {
+        let mut entries = table.into_iter();
+
+        let (name, config) = match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry,
+            _ => return Err(format!("codec needs exactly one key naming it, one of: {}", encode::names().join(", "))),
+        };
+
+        match name.as_str() {
+            #[cfg(feature = "lame")]
+            "mp3" => config.try_into()
+                .map(CodecConfig::Mp3)
+                .map_err(|e: toml::de::Error| e.to_string()),
+            _ => {
+                encode::check_registered(&name, &config)?;
+                Ok(CodecConfig::Registered(RegisteredCodecConfig { name, config }))
+            }
+        }
+    }
+}
+
+// only used to describe the codec field of streams in the config schema,
+// profile names are resolved before streams are deserialized
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum CodecReference {
+    Profile(String),
+    Codec(CodecConfig),
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig::Mp3(Mp3Config::default())
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
 pub struct StreamConfig {
     pub path: String,
+    // only serve path to requests for this hostname, so that several
+    // stations can each have their own domain. streams without a host
+    // serve requests for any hostname not claimed by another stream
+    pub host: Option<String>,
     pub source: String,
+    // station details, sent to listeners in icy-* headers and shown in the
+    // control API. public marks the stream as ok to list in directories
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub genre: Option<String>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub public: bool,
+    // only serve the stream to edge servers presenting origin.secret, so
+    // that listeners can only reach it through the edges
+    #[serde(default)]
+    pub private: bool,
+    // either a codec table or the name of a profile under [codec]
+    #[serde(default)]
+    #[schemars(with = "CodecReference")]
     pub codec: CodecConfig,
+    pub player: Option<PlayerConfig>,
+    // played to each listener before the live audio
+    pub intro: Option<PathBuf>,
+    pub jingle: Option<JingleConfig>,
+    pub plugin: Option<PluginConfig>,
+    #[serde(default)]
+    pub on_source_offline: SourceOfflineAction,
+    pub overflow_redirect: Option<String>,
+    pub max_session_mins: Option<u64>,
+    pub pacing: Option<PacingConfig>,
+    pub scheduling: Option<SchedulingConfig>,
+    pub archive: Option<ArchiveConfig>,
+    pub session_recording: Option<SessionRecordingConfig>,
+    #[serde(default)]
+    pub relay: Vec<RelayConfig>,
+    // everything else the stream's output is sent to, alongside archive and
+    // relay above
+    #[serde(default)]
+    pub sink: Vec<SinkConfig>,
+    // whether listeners can connect to path. streams which only feed their
+    // sinks can turn this off
+    #[serde(default = "default_stream_serve")]
+    pub serve: bool,
+}
+
+fn default_stream_serve() -> bool {
+    true
+}
+
+impl StreamConfig {
+    // archive and relay are shorthands for sinks, from before there were any
+    // other kinds
+    pub fn sinks(&self) -> Vec<SinkConfig> {
+        self.archive.iter().cloned().map(SinkConfig::Archive)
+            .chain(self.relay.iter().cloned().map(SinkConfig::Relay))
+            .chain(self.sink.iter().cloned())
+            .collect()
+    }
+
+    pub fn serves_host(&self, host: Option<&str>) -> bool {
+        match (&self.host, host) {
+            (Some(own), Some(host)) => own.eq_ignore_ascii_case(host),
+            _ => false,
+        }
+    }
+
+    // host and path together, as written in logs and errors
+    pub fn route_key(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}{}", host.to_ascii_lowercase(), self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+fn default_pacing_burst_kb() -> u64 {
+    64
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct PacingConfig {
+    #[serde(default = "default_pacing_burst_kb")]
+    pub burst_kb: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+pub enum SourceOfflineAction {
+    // keep listeners connected, they will hear whatever the source's
+    // offline behaviour produces
+    #[default]
+    #[serde(rename = "hold")]
+    Hold,
+    // disconnect all listeners and turn away new ones
+    #[serde(rename = "close")]
+    Close,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, JsonSchema)]
+pub enum JingleMode {
+    // play the jingle on its own, dropping as much live audio after it as
+    // it's long, so listeners stay as close to the source as they were
+    #[default]
+    #[serde(rename = "insert")]
+    Insert,
+    // mix the jingle over the live audio, ducked by duck
+    #[serde(rename = "overlay")]
+    Overlay,
+}
+
+fn default_jingle_duck() -> f32 {
+    0.3
+}
+
+// a jingle plays every every_mins, at times, or both
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct JingleConfig {
+    pub path: PathBuf,
+    pub every_mins: Option<NonZeroU64>,
+    #[serde(default)]
+    pub offset_mins: u64,
+    // UTC times of day to play at, as "HH:MM"
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub times: Vec<TimeOfDay>,
+    #[serde(default)]
+    pub mode: JingleMode,
+    #[serde(default = "default_jingle_duck")]
+    pub duck: f32,
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_plugin_max_memory_mb() -> usize {
+    64
+}
+
+// a WebAssembly module processing the stream's audio before it's encoded,
+// and rewriting the titles sent to its listeners. needs the wasm feature
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct PluginConfig {
+    // loaded again whenever the file changes
+    pub path: PathBuf,
+    // wasm instructions, roughly, allowed per call into the plugin. calls
+    // which run out are abandoned, so that a stuck plugin can't hold up
+    // the stream
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    #[serde(default = "default_plugin_max_memory_mb")]
+    pub max_memory_mb: usize,
+}
+
+fn default_archive_name() -> String {
+    "%Y-%m-%dT%H".to_owned()
+}
+
+fn default_archive_rotate_mins() -> u64 {
+    60
+}
+
+// records a stream's output to disk
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct ArchiveConfig {
+    // directory to write recordings to
+    pub path: PathBuf,
+    // file name, where %Y %m %d %H %M and %S are replaced with the UTC time
+    // the file was started. the codec's file extension is added on
+    #[serde(default = "default_archive_name")]
+    pub name: String,
+    // a new file is started on the clock every this many minutes, so that
+    // hourly files start at the top of the hour
+    #[serde(default = "default_archive_rotate_mins")]
+    pub rotate_mins: u64,
+    // and whenever a file grows past this size
+    pub rotate_mb: Option<u64>,
+    // finished files are uploaded here, then removed from disk. files left
+    // over from before a restart are uploaded on starting
+    pub upload: Option<UploadConfig>,
+    pub chapters: Option<ChapterFormat>,
+    pub retention: Option<RetentionConfig>,
+    // stop writing while the source is offline, and after this long of
+    // silence, such as from the source's offline behaviour. a new file is
+    // started once audio returns
+    #[serde(default)]
+    pub pause_offline: bool,
+    pub pause_silence_secs: Option<u64>,
+}
+
+// old archive files are deleted, oldest first, to keep within these limits
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct RetentionConfig {
+    pub keep_days: Option<u64>,
+    pub keep_gb: Option<u64>,
+}
+
+// sidecar files written next to recordings, with a chapter for each track
+// title the source sent while recording
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum ChapterFormat {
+    #[serde(rename = "cue")]
+    Cue,
+    #[serde(rename = "json")]
+    Json,
+}
+
+impl ChapterFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChapterFormat::Cue => "cue",
+            ChapterFormat::Json => "json",
+        }
+    }
+}
+
+fn default_upload_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_upload_retries() -> u32 {
+    5
+}
+
+fn default_upload_retry_secs() -> u64 {
+    30
+}
+
+// windows has no CA bundle file of its own, ca_file needs setting there
+fn default_ca_file() -> PathBuf {
+    PathBuf::from("/etc/ssl/certs/ca-certificates.crt")
+}
+
+// S3 compatible object storage to keep recordings in
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct UploadConfig {
+    // such as "https://s3.eu-west-1.amazonaws.com". buckets are addressed
+    // by path, which every S3 compatible service supports
+    #[schemars(with = "String")]
+    pub endpoint: HttpUrl,
+    pub bucket: String,
+    #[serde(default = "default_upload_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // put in front of file names to make object keys, such as "live/"
+    #[serde(default)]
+    pub prefix: String,
+    // failed uploads are retried this many times, waiting retry_secs then
+    // twice as long again after each failure
+    #[serde(default = "default_upload_retries")]
+    pub retries: u32,
+    #[serde(default = "default_upload_retry_secs")]
+    pub retry_secs: u64,
+    // keep files on disk after they've been uploaded, with an empty
+    // .uploaded file next to each so that they aren't uploaded again
+    #[serde(default)]
+    pub keep_local: bool,
+    // certificate authorities to trust for https endpoints
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+// an http or https URL, for servers edicast connects out to
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct HttpUrl {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    // always starts with a slash
+    pub path: String,
+}
+
+impl HttpUrl {
+    // the default port is left out, as some servers, S3 among them, expect
+    // the Host header exactly as it would be written in the URL
+    pub fn authority(&self) -> String {
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => self.host.clone(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// for logs and the control API
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{}://{}{}", scheme, self.authority(), self.path)
+    }
+}
+
+impl TryFrom<String> for HttpUrl {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, String> {
+        let invalid = || format!("invalid URL {:?}, expected a URL like https://host[:port]/path", url);
+
+        let (tls, rest) = match url.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(invalid()),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+
+        Ok(HttpUrl { tls, host: host.to_owned(), port, path: path.to_owned() })
+    }
+}
+
+fn default_relay_username() -> String {
+    "source".to_owned()
+}
+
+fn default_relay_retry_secs() -> u64 {
+    5
+}
+
+// pushes a stream's output to another server, as a source client would
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct RelayConfig {
+    // mount on the other server, such as "http://ingest.example.com:8000/live"
+    #[schemars(with = "String")]
+    pub url: HttpUrl,
+    #[serde(default = "default_relay_username")]
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub protocol: RelayProtocol,
+    // waits this long to reconnect after a failure, doubling each time up
+    // to five minutes
+    #[serde(default = "default_relay_retry_secs")]
+    pub retry_secs: u64,
+    // certificate authorities to trust for https URLs
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum RelayProtocol {
+    // HTTP PUT, for Icecast 2.4 and later
+    #[default]
+    #[serde(rename = "put")]
+    Put,
+    // the older SOURCE method, for earlier Icecast and SHOUTcast 2
+    #[serde(rename = "source")]
+    Source,
+}
+
+// somewhere a stream's encoded output is sent besides its listeners
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum SinkConfig {
+    #[serde(rename = "archive")]
+    Archive(ArchiveConfig),
+    #[serde(rename = "relay")]
+    Relay(RelayConfig),
+    #[serde(rename = "hls")]
+    Hls(HlsConfig),
+    #[serde(rename = "rtp")]
+    Rtp(RtpConfig),
+}
+
+fn default_hls_segment_secs() -> u64 {
+    6
+}
+
+fn default_hls_list_size() -> usize {
+    6
+}
+
+// writes the stream as HLS segments and a rolling playlist, for serving
+// from [files] or any other web server
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct HlsConfig {
+    // directory to write index.m3u8 and its segments to
+    pub path: PathBuf,
+    // segments are cut at the first chunk after this long
+    #[serde(default = "default_hls_segment_secs")]
+    pub segment_secs: u64,
+    // segments listed in the playlist. older ones are deleted once they've
+    // been out of it for as long again
+    #[serde(default = "default_hls_list_size")]
+    pub list_size: usize,
+}
+
+fn default_rtp_ttl() -> u32 {
+    1
+}
+
+// sends the stream as RTP over UDP, for receivers on the local network
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct RtpConfig {
+    // a multicast group such as "239.255.10.1", or a single receiver
+    pub group: IpAddr,
+    pub port: u16,
+    // routers packets may cross, the default keeps them on the local network.
+    // IPv6 packets always use the system default
+    #[serde(default = "default_rtp_ttl")]
+    pub ttl: u32,
+}
+
+// posts to a webhook when a stream's listener count crosses a threshold,
+// counting listeners across the cluster if clustering. exactly one of
+// rises_to and falls_to is needed
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct AlertConfig {
+    // shown in notifications
+    pub name: String,
+    // the stream to watch, or every stream if left out
+    pub stream: Option<String>,
+    // fire once listeners rise to at least this many
+    pub rises_to: Option<usize>,
+    // or once they fall to at most this many
+    pub falls_to: Option<usize>,
+    // only fire for falls while the stream's source is live, to notice
+    // listeners being unable to hear a live show
+    #[serde(default)]
+    pub source_live: bool,
+    // the alert fires again only once the count has moved back this far
+    // past the threshold
+    #[serde(default)]
+    pub hysteresis: usize,
+    // the threshold must stay crossed for this long before firing
+    #[serde(default)]
+    pub hold_secs: u64,
+    // posted a JSON object with text, alert, stream, listeners, threshold,
+    // direction and time
+    #[schemars(with = "String")]
+    pub webhook: HttpUrl,
+    // certificate authorities to trust for https URLs
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    30
+}
+
+// runs a command or posts to a webhook for events, or both
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct HookConfig {
+    // event types, such as "source_connected" or "recording_finished", or
+    // every event if left out
+    #[serde(default)]
+    pub events: Vec<String>,
+    // run with the event's fields in EDICAST_* environment variables
+    pub command: Option<PathBuf>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // commands still running after this long are killed
+    #[serde(default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+    // posted the event as JSON
+    #[schemars(with = "Option<String>")]
+    pub webhook: Option<HttpUrl>,
+    // certificate authorities to trust for https URLs
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+// a Rhai script making decisions for station specific policies, such as
+// which sources and listeners to let in. needs the scripting feature
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct ScriptConfig {
+    // loaded again whenever the file changes
+    pub path: PathBuf,
+    // calls into the script which run longer than this are abandoned, and
+    // edicast carries on as if the script had no say
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_mirror_poll_secs() -> u64 {
+    30
+}
+
+// mirrors the streams of another edicast server, pulling each from its
+// public server into a source named mirror-<stream>
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct MirrorConfig {
+    // the master's control server, such as "http://master.example.com:8001"
+    #[schemars(with = "String")]
+    pub control_url: HttpUrl,
+    // the master's control token, if it has one
+    pub token: Option<String>,
+    // the master's public server. without this streams are pulled from the
+    // listen URLs the master gives, which needs public_url set there
+    #[schemars(with = "Option<String>")]
+    pub public_url: Option<HttpUrl>,
+    // public servers of standby origins, pulled from in order should the
+    // master's fail
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub failover_urls: Vec<HttpUrl>,
+    // the master's origin.secret, for pulling its private streams
+    pub secret: Option<String>,
+    // names of streams to mirror, or all of them if empty
+    #[serde(default)]
+    pub streams: Vec<String>,
+    // how often the master is checked for added and removed streams
+    #[serde(default = "default_mirror_poll_secs")]
+    pub poll_secs: u64,
+    // while pulling from a standby, the master is checked this often and
+    // switched back to once it's serving again
+    #[serde(default = "default_pull_check_secs")]
+    pub check_secs: u64,
+    // mirrored streams are decoded and encoded again with this codec
+    #[serde(default)]
+    pub codec: CodecConfig,
+    // certificate authorities to trust for https URLs
+    #[serde(default = "default_ca_file")]
+    pub ca_file: PathBuf,
+}
+
+// lets edge servers pull private streams from this server
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct OriginConfig {
+    // sent by edges as a bearer token, see mirror.secret
+    pub secret: String,
+}
+
+fn default_cluster_channel() -> String {
+    "edicast".to_owned()
+}
+
+fn default_cluster_report_secs() -> u64 {
+    5
+}
+
+// shares listener counts and metadata updates with other edicast servers
+// through redis pub/sub
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct ClusterConfig {
+    // redis server, such as "127.0.0.1:6379"
+    pub redis: String,
+    pub password: Option<String>,
+    // servers publishing to the same channel make up a cluster
+    #[serde(default = "default_cluster_channel")]
+    pub channel: String,
+    // how this server is identified to the others, random if unset
+    pub node: Option<String>,
+    // listener counts are sent this often, and a server's counts are
+    // dropped once it's missed three
+    #[serde(default = "default_cluster_report_secs")]
+    pub report_secs: u64,
+}
+
+fn default_session_recording_name() -> String {
+    "{dj} %Y-%m-%d %H-%M".to_owned()
+}
+
+// records a stream to its own file each time its source goes live
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct SessionRecordingConfig {
+    // directory to write recordings to
+    pub path: PathBuf,
+    // file name, where {dj} is replaced with the name the source client
+    // gave, or the source's name if it gave none, and %Y %m %d %H %M and %S
+    // with the UTC time the session started
+    #[serde(default = "default_session_recording_name")]
+    pub name: String,
+    pub chapters: Option<ChapterFormat>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Weekday {
+    #[serde(rename = "mon")]
+    Mon,
+    #[serde(rename = "tue")]
+    Tue,
+    #[serde(rename = "wed")]
+    Wed,
+    #[serde(rename = "thu")]
+    Thu,
+    #[serde(rename = "fri")]
+    Fri,
+    #[serde(rename = "sat")]
+    Sat,
+    #[serde(rename = "sun")]
+    Sun,
+}
+
+impl Weekday {
+    // days from monday
+    pub fn index(self) -> u64 {
+        self as u64
+    }
+}
+
+// a time of day written as "HH:MM"
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    pub secs: u64,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(time: String) -> Result<Self, String> {
+        let invalid = || format!("invalid time of day {:?}, expected HH:MM", time);
+
+        let (hours, mins) = time.split_once(':').ok_or_else(invalid)?;
+        let hours = hours.parse::<u64>().map_err(|_| invalid())?;
+        let mins = mins.parse::<u64>().map_err(|_| invalid())?;
+
+        if hours >= 24 || mins >= 60 {
+            return Err(invalid());
+        }
+
+        Ok(TimeOfDay { secs: hours * 3600 + mins * 60 })
+    }
+}
+
+fn default_files_path() -> String {
+    "/recordings".to_owned()
+}
+
+// serves a directory read only on the public server, so that listeners can
+// download or catch up on recordings
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct FilesConfig {
+    // usually where archives and recordings are written
+    pub dir: PathBuf,
+    // URL path the directory appears under
+    #[serde(default = "default_files_path")]
+    pub path: String,
+}
+
+fn default_recording_name() -> String {
+    "{show} %Y-%m-%d".to_owned()
+}
+
+// records a stream for a show which airs at the same time each week
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct RecordingConfig {
+    pub show: String,
+    pub stream: String,
+    // directory to write recordings to
+    pub path: PathBuf,
+    // file name, where {show} is replaced with the show and %Y %m %d %H %M
+    // and %S with the UTC time recording started. the codec's file
+    // extension is added on
+    #[serde(default = "default_recording_name")]
+    pub name: String,
+    // days the show is on, from "mon" to "sun". every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    // UTC time the show starts, as "HH:MM"
+    #[schemars(with = "String")]
+    pub start: TimeOfDay,
+    pub duration_mins: u64,
+    pub chapters: Option<ChapterFormat>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, JsonSchema)]
+pub struct PlayerConfig {
+    pub title: Option<String>,
+    pub stylesheet: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(base: &str, overlay: &str) -> Value {
+        let mut base = base.parse::<Value>().unwrap();
+        merge_overlay(&mut base, overlay.parse::<Value>().unwrap());
+        base
+    }
+
+    #[test]
+    fn merges_overlay_tables_at_any_depth() {
+        let document = merged(r#"
+            [listen]
+            public = "0.0.0.0:8000"
+            control = "127.0.0.1:8001"
+
+            [stream.radio]
+            source = "live"
+            codec = { mp3 = { bitrate = 128 } }
+        "#, r#"
+            [listen]
+            public = "0.0.0.0:80"
+
+            [stream.radio.codec.mp3]
+            bitrate = 192
+            quality = 2
+
+            [stream.backup]
+            source = "fallback"
+        "#);
+
+        assert_eq!(document, r#"
+            [listen]
+            public = "0.0.0.0:80"
+            control = "127.0.0.1:8001"
+
+            [stream.radio]
+            source = "live"
+            codec = { mp3 = { bitrate = 192, quality = 2 } }
+
+            [stream.backup]
+            source = "fallback"
+        "#.parse::<Value>().unwrap());
+    }
+
+    #[test]
+    fn replaces_arrays_and_scalars_outright() {
+        let document = merged(r#"
+            trusted_proxies = ["10.0.0.0/8", "::1/128"]
+            limit = 10
+        "#, r#"
+            trusted_proxies = ["192.0.2.0/24"]
+        "#);
+
+        assert_eq!(document, r#"
+            trusted_proxies = ["192.0.2.0/24"]
+            limit = 10
+        "#.parse::<Value>().unwrap());
+    }
+
+    #[test]
+    fn replaces_values_of_a_different_type() {
+        let document = merged(r#"
+            [limits]
+            max_listeners = 100
+        "#, r#"
+            limits = "none"
+        "#);
+
+        assert_eq!(document, r#"limits = "none""#.parse::<Value>().unwrap());
+
+        let document = merged(r#"intro = "intro.mp3""#, r#"
+            [intro]
+            path = "intro.mp3"
+        "#);
+
+        assert_eq!(document, r#"intro = { path = "intro.mp3" }"#.parse::<Value>().unwrap());
+    }
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde_derive::Deserialize;
+use toml::Spanned;
+
+// a position in a config file, for pointing at the key an error is about
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path.display(), self.line, self.column)
+    }
+}
+
+impl Location {
+    fn from_offset(path: &Path, contents: &str, offset: usize) -> Self {
+        let before = &contents[..offset.min(contents.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+        Location { path: path.to_owned(), line, column }
+    }
+}
+
+// the main config is deserialized without spans so that the rest of edicast
+// can use plain values. when validation fails, the offending file is parsed
+// again with just enough structure to find where a value came from
+#[derive(Deserialize)]
+struct SpanDocument {
+    #[serde(default)]
+    stream: HashMap<String, SpanStream>,
+}
+
+#[derive(Deserialize)]
+struct SpanStream {
+    source: Option<Spanned<String>>,
+    path: Option<Spanned<String>>,
+}
+
+fn locate(path: &Path, find: impl FnOnce(&SpanDocument) -> Option<Range<usize>>) -> Option<Location> {
+    let contents = fs::read_to_string(path).ok()?;
+    let document = toml::from_str::<SpanDocument>(&contents).ok()?;
+    let span = find(&document)?;
+    Some(Location::from_offset(path, &contents, span.start))
+}
+
+pub fn locate_stream_source(path: &Path, stream: &str) -> Option<Location> {
+    locate(path, |document| {
+        let source = document.stream.get(stream)?.source.as_ref()?;
+        Some(source.start()..source.end())
+    })
+}
+
+pub fn locate_stream_path(path: &Path, stream: &str) -> Option<Location> {
+    locate(path, |document| {
+        let stream_path = document.stream.get(stream)?.path.as_ref()?;
+        Some(stream_path.start()..stream_path.end())
+    })
+}
+
+// suggests the closest candidate to a misspelled name, if any is close enough
+// to plausibly be what was meant
+pub fn did_you_mean<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<String> {
+    let threshold = (name.chars().count() / 3).max(2);
+
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + if a_char == *b_char { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggest(name: &str, candidates: &[&str]) -> Option<String> {
+        let candidates = candidates.iter().map(|candidate| candidate.to_string()).collect::<Vec<_>>();
+        did_you_mean(name, candidates.iter())
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        assert_eq!(suggest("sorce", &["source", "stream"]).as_deref(), Some("source"));
+        assert_eq!(suggest("stram", &["source", "stream"]).as_deref(), Some("stream"));
+        assert_eq!(suggest("bitrat", &["bitrate", "quality"]).as_deref(), Some("bitrate"));
+    }
+
+    #[test]
+    fn suggests_the_closest_name() {
+        assert_eq!(suggest("live2", &["live", "live-2", "live22"]).as_deref(), Some("live"));
+        assert_eq!(suggest("breakfast-shw", &["breakfast-show", "breakfast"]).as_deref(), Some("breakfast-show"));
+    }
+
+    #[test]
+    fn suggests_nothing_when_nothing_is_close() {
+        assert_eq!(suggest("listen", &["source", "stream"]), None);
+        assert_eq!(suggest("x", &["source", "stream"]), None);
+        assert_eq!(suggest("source", &[]), None);
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("source", "source"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+}
@@ -0,0 +1,74 @@
+// panics anywhere in edicast are logged through slog with a backtrace and
+// counted, as well as written straight to stderr in case the panic takes the
+// process down before the async logger catches up
+use std::backtrace::Backtrace;
+use std::io::{self, Write};
+use std::panic;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use slog::Logger;
+
+use crate::config::CrashConfig;
+use crate::thread::panic_message;
+
+static CRASHES: AtomicU64 = AtomicU64::new(0);
+
+// number of panics since edicast started. most happen in supervised tasks,
+// which are restarted, so this can go up while edicast carries on running
+pub fn count() -> u64 {
+    CRASHES.load(Ordering::Relaxed)
+}
+
+pub fn install(log: Logger, config: &CrashConfig) {
+    let command = config.command.clone();
+
+    panic::set_hook(Box::new(move |info| {
+        let message = panic_message(info.payload());
+        let location = info.location().map(|location| location.to_string());
+        on_panic(&log, command.as_ref(), message, location);
+    }));
+}
+
+fn on_panic(log: &Logger, command: Option<&PathBuf>, message: String, location: Option<String>) {
+    CRASHES.fetch_add(1, Ordering::Relaxed);
+
+    let thread = thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>").to_owned();
+    let backtrace = Backtrace::force_capture().to_string();
+
+    // locked so that panics on several threads at once don't interleave
+    let _ = writeln!(io::stderr().lock(), "edicast: thread {} panicked at {}: {}\n{}",
+        thread, location.as_deref().unwrap_or("<unknown>"), message, backtrace);
+
+    slog::crit!(log, "Thread panicked";
+        "thread" => &thread,
+        "error" => &message,
+        "location" => location,
+        "backtrace" => backtrace,
+    );
+
+    if let Some(command) = command {
+        let result = Command::new(command)
+            .env("EDICAST_PANIC_THREAD", &thread)
+            .env("EDICAST_PANIC_MESSAGE", &message)
+            .spawn();
+
+        match result {
+            Ok(mut child) => {
+                // reap the command without holding up the panicking thread
+                let _ = thread::Builder::new()
+                    .name("edicast/crash-command".to_owned())
+                    .spawn(move || child.wait());
+            }
+            Err(e) => {
+                slog::error!(log, "Could not run crash command";
+                    "command" => command.display(),
+                    "error" => e.to_string(),
+                );
+            }
+        }
+    }
+}
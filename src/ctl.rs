@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+#[cfg(feature = "tls")]
+use std::fs::File;
+use std::io::{self, Read, Write};
+#[cfg(feature = "tls")]
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use percent_encoding::{utf8_percent_encode, USERINFO_ENCODE_SET};
+use serde_json::Value;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+
+use edicast_core::config::{Config, ListenAddr};
+#[cfg(feature = "tls")]
+use edicast_core::config::TlsConfig;
+
+const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:3030";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// entries shown by `edicast ctl journal`
+const JOURNAL_LIMIT: u32 = 100;
+
+percent_encoding::define_encode_set! {
+    pub COMPONENT_ENCODE_SET = [USERINFO_ENCODE_SET] | {'&', '+'}
+}
+
+const USAGE: &str = "\
+usage: edicast ctl [--config <config file>] <command> [args...]
+
+commands:
+    stats                       listener counts per stream
+    listeners [stream]          list connected listeners
+    journal [kind]              recent events from the journal, optionally only
+                                of a kind such as source or config_reloaded
+    kick-source <source>        disconnect the client streaming to a source
+    metadata <source> <title>   set the current title of a source
+    pause <stream>              stop sending audio to a stream's listeners
+    resume <stream>             resume a paused stream
+    reload                      reload sources, streams and log levels from the
+                                config file
+    log                         show the log level and filters
+    log-level <level>           change the log level without restarting
+    log-filter <target> <level> log a source, stream or module at another level,
+                                such as `log-filter source:studio debug`
+    log-unfilter <target>       remove a log filter
+
+the control server address and token are read from the config file if
+given, otherwise from EDICAST_CONTROL and EDICAST_TOKEN. a plain control
+address on loopback is used over any other, addresses marked ?tls are only
+used if there are no others";
+
+struct Client {
+    address: SocketAddr,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    token: Option<String>,
+}
+
+// the control server's own certificates are trusted, so that self-signed
+// ones work as well as those from a CA
+#[cfg(feature = "tls")]
+struct Tls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+}
+
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+struct Reply {
+    status: u16,
+    body: String,
+}
+
+// entry point for `edicast ctl`, returns the process exit code
+pub fn main(args: Vec<OsString>) -> i32 {
+    let args = args.into_iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let (config_path, args) = match args.as_slice() {
+        [flag, path, rest @ ..] if flag == "-c" || flag == "--config" => {
+            (Some(PathBuf::from(path)), rest)
+        }
+        args => (None, args),
+    };
+
+    let client = match Client::new(config_path) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("edicast ctl: {}", e);
+            return 1;
+        }
+    };
+
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let result = match args.as_slice() {
+        ["stats"] => stats(&client),
+        ["listeners"] => print(client.request("GET", "/listeners")),
+        ["listeners", stream] => {
+            print(client.request("GET", &format!("/listeners?stream={}", encode(stream))))
+        }
+        ["journal"] => print(client.request("GET", &format!("/events?limit={}", JOURNAL_LIMIT))),
+        ["journal", kind] => {
+            print(client.request("GET", &format!("/events?kind={}&limit={}", encode(kind), JOURNAL_LIMIT)))
+        }
+        ["kick-source", source] => {
+            print(client.request("DELETE", &format!("/sources/{}/client", encode(source))))
+        }
+        ["metadata", source, title] => {
+            print(client.request("POST", &format!("/sources/{}/metadata?title={}",
+                encode(source), encode(title))))
+        }
+        ["pause", stream] => {
+            print(client.request("POST", &format!("/streams/{}/pause", encode(stream))))
+        }
+        ["resume", stream] => {
+            print(client.request("POST", &format!("/streams/{}/resume", encode(stream))))
+        }
+        ["reload"] => print(client.request("POST", "/reload")),
+        ["log"] => print(client.request("GET", "/log")),
+        ["log-level", level] => {
+            print(client.request("PUT", &format!("/log/level?level={}", encode(level))))
+        }
+        ["log-filter", target, level] => {
+            print(client.request("PUT", &format!("/log/filters/{}?level={}", encode(target), encode(level))))
+        }
+        ["log-unfilter", target] => {
+            print(client.request("DELETE", &format!("/log/filters/{}", encode(target))))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return 1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("edicast ctl: {}", e);
+            1
+        }
+    }
+}
+
+fn encode(component: &str) -> String {
+    utf8_percent_encode(component, COMPONENT_ENCODE_SET).to_string()
+}
+
+fn print(reply: Result<Reply, io::Error>) -> Result<(), io::Error> {
+    let reply = reply?;
+
+    if !reply.body.is_empty() {
+        println!("{}", reply.body.trim_end());
+    }
+
+    Ok(())
+}
+
+fn stats(client: &Client) -> Result<(), io::Error> {
+    let reply = client.request("GET", "/listeners")?;
+
+    let listeners = serde_json::from_str::<Vec<Value>>(&reply.body)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut streams = BTreeMap::new();
+
+    for listener in &listeners {
+        let stream = listener["stream"].as_str().unwrap_or_default().to_owned();
+        *streams.entry(stream).or_insert(0) += 1;
+    }
+
+    for (stream, count) in &streams {
+        println!("{:<24} {}", stream, count);
+    }
+
+    println!("{:<24} {}", "total", listeners.len());
+    Ok(())
+}
+
+impl Client {
+    fn new(config_path: Option<PathBuf>) -> Result<Self, String> {
+        if let Some(path) = config_path {
+            let config = Config::load(&path)
+                .map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+
+            if config.listen.proxy_protocol.control {
+                return Err(format!("{} expects a PROXY protocol header on control connections, \
+                    which edicast ctl doesn't send", path.display()));
+            }
+
+            let listen = control_address(config.listen.control.iter())
+                .ok_or_else(|| format!("{} has no control address", path.display()))?;
+
+            let address = connect_address(listen.address);
+
+            #[cfg(feature = "tls")]
+            let tls = match (listen.tls, &config.listen.control_tls) {
+                (true, Some(tls_config)) => Some(Tls::new(tls_config, address)?),
+                (true, None) => {
+                    return Err(format!("{} has a control address with ?tls but no listen.control_tls", path.display()));
+                }
+                (false, _) => None,
+            };
+
+            #[cfg(not(feature = "tls"))]
+            if listen.tls {
+                return Err(format!("{} only has control addresses with ?tls, and edicast was built \
+                    without the tls feature", path.display()));
+            }
+
+            return Ok(Client {
+                address,
+                #[cfg(feature = "tls")]
+                tls,
+                token: config.control.token,
+            });
+        }
+
+        let address = env::var("EDICAST_CONTROL")
+            .unwrap_or_else(|_| DEFAULT_CONTROL_ADDR.to_owned());
+
+        let address = address.parse()
+            .map_err(|_| format!("invalid control address: {}", address))?;
+
+        Ok(Client {
+            address,
+            #[cfg(feature = "tls")]
+            tls: None,
+            token: env::var("EDICAST_TOKEN").ok(),
+        })
+    }
+
+    fn connect(&self) -> Result<Box<dyn Connection>, io::Error> {
+        let stream = TcpStream::connect_timeout(&self.address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let connection = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+            return Ok(Box::new(StreamOwned::new(connection, stream)));
+        }
+
+        Ok(Box::new(stream))
+    }
+
+    // the control server only ever sends short responses, so a bare
+    // HTTP/1.1 request with Connection: close is all we need here
+    fn request(&self, method: &str, path: &str) -> Result<Reply, io::Error> {
+        let mut stream = self.connect()?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: 0\r\n",
+            method, path, self.address);
+
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let (head, body) = response.split_once("\r\n\r\n")
+            .unwrap_or((&response, ""));
+
+        let status = head.split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))?;
+
+        let reply = Reply { status, body: body.to_owned() };
+
+        if reply.status >= 400 {
+            let message = format!("{} {}", reply.status, reply.body.trim());
+            return Err(io::Error::new(io::ErrorKind::Other, message));
+        }
+
+        Ok(reply)
+    }
+}
+
+// plain addresses are preferred to TLS, then those reachable over loopback
+fn control_address<'a>(addrs: impl Iterator<Item = &'a ListenAddr>) -> Option<&'a ListenAddr> {
+    addrs.min_by_key(|addr| {
+        let ip = addr.address.ip();
+        (addr.tls, !(ip.is_loopback() || ip.is_unspecified()))
+    })
+}
+
+// a wildcard bind is reached over loopback
+fn connect_address(address: SocketAddr) -> SocketAddr {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+
+    SocketAddr::new(ip, address.port())
+}
+
+#[cfg(feature = "tls")]
+impl Tls {
+    // the certificate is checked against the first ACME domain if there is
+    // one, otherwise against the address connected to
+    fn new(config: &TlsConfig, address: SocketAddr) -> Result<Self, String> {
+        let certs = read_certs(&config.cert)
+            .map_err(|e| format!("could not read {}: {}", config.cert.display(), e))?;
+
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(&certs);
+
+        let name = config.acme.as_ref()
+            .and_then(|acme| acme.domains.first().cloned())
+            .unwrap_or_else(|| address.ip().to_string());
+
+        let server_name = ServerName::try_from(name.as_str())
+            .map_err(|_| format!("invalid server name: {}", name))?;
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Tls { config: Arc::new(config), server_name })
+    }
+}
+
+#[cfg(feature = "tls")]
+fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>, io::Error> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+}
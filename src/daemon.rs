@@ -0,0 +1,136 @@
+// running in the background for init scripts which expect edicast to fork,
+// and pidfiles for them to find it by. the process started in the
+// foreground waits until the daemon is listening, so that config and bind
+// errors still reach the terminal and its exit status says whether edicast
+// started
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// the forked process, still attached to the terminal until it detaches
+#[cfg(unix)]
+pub struct Daemon {
+    ready: File,
+}
+
+#[cfg(unix)]
+pub fn daemonize() -> Result<Daemon, io::Error> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+
+    // safety: pipe fills fds with two new fds, which the Files take
+    // ownership of
+    let (mut started, ready) = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+
+    // safety: called before the runtime or logger start any threads, so
+    // this process is single threaded
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(started);
+
+            // a session of its own, so that the terminal closing doesn't
+            // send it SIGHUP
+            if unsafe { libc::setsid() } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Daemon { ready })
+        }
+        _ => {
+            drop(ready);
+
+            // the daemon exits without writing anything if it couldn't
+            // start, having already reported why on the terminal
+            let mut byte = [0u8; 1];
+
+            match started.read(&mut byte) {
+                Ok(1) => process::exit(0),
+                _ => process::exit(1),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Daemon {
+    // points stdin at /dev/null, and stdout and stderr at output or
+    // /dev/null, then lets the foreground process exit
+    pub fn detach(self, output: Option<&Path>) -> Result<(), io::Error> {
+        use std::fs::OpenOptions;
+        use std::os::unix::io::AsRawFd;
+
+        let null = File::open("/dev/null")?;
+
+        let output = match output {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+            None => OpenOptions::new().write(true).open("/dev/null")?,
+        };
+
+        for (file, fd) in [(&null, 0), (&output, 1), (&output, 2)] {
+            // safety: dup2 replaces fd with a copy of a file we hold open
+            if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        (&self.ready).write_all(&[0])
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Daemon {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<Daemon, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        "--daemon is only available on unix, see edicast service install for windows"))
+}
+
+#[cfg(not(unix))]
+impl Daemon {
+    pub fn detach(self, _output: Option<&Path>) -> Result<(), io::Error> {
+        match self.never {}
+    }
+}
+
+// removes the pidfile as edicast exits, unless another process has since
+// written its own pid there, as a new process does on upgrade
+pub struct Pidfile {
+    path: PathBuf,
+    pid: String,
+}
+
+impl Pidfile {
+    pub fn create(path: &Path) -> Result<Self, io::Error> {
+        let pid = process::id().to_string();
+
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", pid)?;
+
+        Ok(Pidfile { path: path.to_owned(), pid })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.path)
+            .map(|contents| contents.trim() == self.pid)
+            .unwrap_or(false);
+
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
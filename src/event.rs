@@ -0,0 +1,201 @@
+use std::sync::{mpsc, Arc, Mutex};
+
+use serde_derive::Serialize;
+use tokio::sync::broadcast;
+
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    SourceConnected {
+        source: String,
+        // new for each time a source client connects, and carried by
+        // the listener events of those who heard it
+        session: String,
+        // who the source client said it was, if it did
+        dj: Option<String>,
+    },
+    SourceDisconnected {
+        source: String,
+        session: String,
+        duration_secs: u64,
+        error: Option<String>,
+    },
+    SourceError {
+        source: String,
+        error: String,
+    },
+    // a source client was disconnected through the control API
+    SourceKicked {
+        source: String,
+    },
+    ListenerConnected {
+        listener: String,
+        stream: String,
+        remote_addr: Option<String>,
+    },
+    ListenerDisconnected {
+        listener: String,
+        stream: String,
+        duration_secs: u64,
+        bytes_sent: u64,
+        // the source sessions the listener heard, in order
+        source_sessions: Vec<String>,
+    },
+    MetadataUpdated {
+        source: String,
+        title: Option<String>,
+        ad_break: bool,
+    },
+    // a recording file was finished, by a stream's archive, a scheduled
+    // recording or a session recording
+    RecordingFinished {
+        stream: String,
+        path: String,
+        recording: &'static str,
+    },
+    ConfigReloaded {
+        sources_added: Vec<String>,
+        sources_removed: Vec<String>,
+        sources_restarted: Vec<String>,
+        streams_added: Vec<String>,
+        streams_removed: Vec<String>,
+        streams_restarted: Vec<String>,
+        streams_rewired: Vec<String>,
+    },
+    ConfigReloadFailed {
+        error: String,
+    },
+    // a stream's source was removed or replaced, and the stream is waiting
+    // for a source by that name to come back
+    StreamSourceLost {
+        stream: String,
+        source: String,
+    },
+    StreamSourceReturned {
+        stream: String,
+        source: String,
+    },
+    ListenerAlertFired {
+        alert: String,
+        stream: String,
+        listeners: usize,
+        threshold: usize,
+        direction: &'static str,
+    },
+    // a supervised task, such as a source or stream, panicked or stalled
+    // and is being restarted
+    TaskRestarted {
+        task: String,
+        error: String,
+    },
+    // a supervised task panicked too often and was given up on
+    TaskFailed {
+        task: String,
+        error: String,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::SourceConnected { .. } => "source_connected",
+            Event::SourceDisconnected { .. } => "source_disconnected",
+            Event::SourceError { .. } => "source_error",
+            Event::SourceKicked { .. } => "source_kicked",
+            Event::ListenerConnected { .. } => "listener_connected",
+            Event::ListenerDisconnected { .. } => "listener_disconnected",
+            Event::MetadataUpdated { .. } => "metadata_updated",
+            Event::RecordingFinished { .. } => "recording_finished",
+            Event::ConfigReloaded { .. } => "config_reloaded",
+            Event::ConfigReloadFailed { .. } => "config_reload_failed",
+            Event::StreamSourceLost { .. } => "stream_source_lost",
+            Event::StreamSourceReturned { .. } => "stream_source_returned",
+            Event::ListenerAlertFired { .. } => "listener_alert_fired",
+            Event::TaskRestarted { .. } => "task_restarted",
+            Event::TaskFailed { .. } => "task_failed",
+        }
+    }
+
+    // the source or stream the event is about, if any
+    pub fn subject(&self) -> Option<&str> {
+        match self {
+            Event::SourceConnected { source, .. } |
+            Event::SourceDisconnected { source, .. } |
+            Event::SourceError { source, .. } |
+            Event::SourceKicked { source } |
+            Event::MetadataUpdated { source, .. } => Some(source),
+            Event::ListenerConnected { stream, .. } |
+            Event::ListenerDisconnected { stream, .. } |
+            Event::RecordingFinished { stream, .. } |
+            Event::StreamSourceLost { stream, .. } |
+            Event::StreamSourceReturned { stream, .. } |
+            Event::ListenerAlertFired { stream, .. } => Some(stream),
+            Event::ConfigReloaded { .. } |
+            Event::ConfigReloadFailed { .. } |
+            Event::TaskRestarted { .. } |
+            Event::TaskFailed { .. } => None,
+        }
+    }
+}
+
+// every kind above, for checking names given in config
+pub const KINDS: &[&str] = &[
+    "source_connected",
+    "source_disconnected",
+    "source_error",
+    "source_kicked",
+    "listener_connected",
+    "listener_disconnected",
+    "metadata_updated",
+    "recording_finished",
+    "config_reloaded",
+    "config_reload_failed",
+    "stream_source_lost",
+    "stream_source_returned",
+    "listener_alert_fired",
+    "task_restarted",
+    "task_failed",
+];
+
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    // subscribers which must see every event however far behind they fall,
+    // such as stats, rather than skipping ahead
+    unbounded: Arc<Mutex<Vec<mpsc::Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { tx, unbounded: Arc::default() }
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.unbounded.lock().expect("lock event subscribers")
+            .retain(|tx| tx.send(event.clone()).is_ok());
+
+        // an error here just means there are no subscribers
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    // a subscription which is never lagged, for blocking threads. events
+    // queue up without limit until they're received
+    pub fn subscribe_unbounded(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.unbounded.lock().expect("lock event subscribers").push(tx);
+        rx
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
@@ -1,63 +1,145 @@
-use std::sync::{Arc, RwLock};
-use std::sync::mpsc::{self, TrySendError};
-
-const BUFFER_SIZE: usize = 1;
+// live audio from a source is published once into a ring buffer, which
+// each subscribed stream reads from at its own cursor. a subscriber which
+// falls a whole ring behind is handled according to the source's
+// backpressure policy
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::config::{BackpressurePolicy, FanoutConfig};
+
+struct Ring<T> {
+    slots: Box<[RwLock<Slot<T>>]>,
+    // sequence number the next published value is written with
+    tail: AtomicU64,
+    closed: AtomicBool,
+    config: FanoutConfig,
+    // wakes subscribers waiting for the next value
+    published: Notify,
+    // cursors of live subscribers, which publishers only consult when
+    // their policy won't overwrite unread values
+    cursors: Mutex<Vec<Weak<AtomicU64>>>,
+    // wakes a publisher blocked on the slowest subscriber
+    space: Condvar,
+    // count of values dropped because a subscriber wasn't keeping up
+    dropped: AtomicU64,
+    // count of subscribers disconnected for not keeping up
+    disconnected: AtomicU64,
+}
 
-struct LiveChannel<T> {
-    txs: RwLock<Option<Vec<mpsc::SyncSender<T>>>>,
+struct Slot<T> {
+    seq: u64,
+    value: Option<T>,
 }
 
 pub struct LivePublisher<T> {
-    chan: Arc<LiveChannel<T>>,
+    ring: Arc<Ring<T>>,
 }
 
 pub struct LiveSubscriber<T> {
-    chan: Arc<LiveChannel<T>>,
+    ring: Arc<Ring<T>>,
+}
+
+pub struct LiveReceiver<T> {
+    ring: Arc<Ring<T>>,
+    next: u64,
+    cursor: Arc<AtomicU64>,
 }
 
-pub fn live_channel<T>() -> (LivePublisher<T>, LiveSubscriber<T>) {
-    let chan = Arc::new(LiveChannel {
-        txs: RwLock::new(Some(Vec::new())),
+pub fn live_channel<T>(config: &FanoutConfig) -> (LivePublisher<T>, LiveSubscriber<T>) {
+    let slots = (0..config.buffer.max(1))
+        .map(|_| RwLock::new(Slot { seq: 0, value: None }))
+        .collect();
+
+    let ring = Arc::new(Ring {
+        slots,
+        tail: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        config: config.clone(),
+        published: Notify::new(),
+        cursors: Mutex::new(Vec::new()),
+        space: Condvar::new(),
+        dropped: AtomicU64::new(0),
+        disconnected: AtomicU64::new(0),
     });
 
-    let publisher = LivePublisher { chan: Arc::clone(&chan) };
-    let subscriber = LiveSubscriber { chan: Arc::clone(&chan) };
+    let publisher = LivePublisher { ring: Arc::clone(&ring) };
+    let subscriber = LiveSubscriber { ring: Arc::clone(&ring) };
 
     (publisher, subscriber)
 }
 
+impl<T> Ring<T> {
+    fn capacity(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    // the cursor of the furthest behind subscriber, if there are any
+    fn slowest(cursors: &mut Vec<Weak<AtomicU64>>) -> Option<u64> {
+        cursors.retain(|cursor| cursor.strong_count() > 0);
+
+        cursors.iter()
+            .filter_map(Weak::upgrade)
+            .map(|cursor| cursor.load(Ordering::Acquire))
+            .min()
+    }
+}
+
 impl<T> LivePublisher<T> where T: Clone {
+    // under the block policy this may wait for slow subscribers, so it
+    // should only be called from a blocking thread
     pub fn publish(&self, data: T) {
-        let mut dead_txs = Vec::new();
+        let ring = &*self.ring;
+        let seq = ring.tail.load(Ordering::Relaxed);
+
+        // writing this value overwrites the one a whole ring ago, which
+        // these policies won't do while a subscriber still hasn't read it
+        if seq >= ring.capacity() {
+            let oldest = seq - ring.capacity();
+
+            match ring.config.policy {
+                BackpressurePolicy::DropOldest | BackpressurePolicy::Disconnect => {}
+                BackpressurePolicy::DropNewest => {
+                    let mut cursors = ring.cursors.lock().expect("lock fanout cursors");
+
+                    if Ring::<T>::slowest(&mut cursors).map_or(false, |slowest| slowest <= oldest) {
+                        ring.dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+                BackpressurePolicy::Block => {
+                    let deadline = Instant::now() + Duration::from_millis(ring.config.block_timeout_ms);
+                    let mut cursors = ring.cursors.lock().expect("lock fanout cursors");
 
-        let mut txs_lock = self.chan.txs.write()
-            .expect("writer lock on txs");
+                    while Ring::<T>::slowest(&mut cursors).map_or(false, |slowest| slowest <= oldest) {
+                        let timeout = deadline.saturating_duration_since(Instant::now());
 
-        let txs = txs_lock.as_mut()
-            .expect("txs should always be Some while LivePublisher alive");
+                        if timeout.is_zero() {
+                            ring.dropped.fetch_add(1, Ordering::Relaxed);
+                            return;
+                        }
 
-        for (index, tx) in txs.iter().enumerate() {
-            match tx.try_send(data.clone()) {
-                Ok(()) => {}
-                Err(TrySendError::Full(_)) => {
-                    // receiver is not keeping up with the data, back off for
-                    // now and drop this packet
-                }
-                Err(TrySendError::Disconnected(_)) => {
-                    dead_txs.push(index);
+                        cursors = ring.space.wait_timeout(cursors, timeout)
+                            .expect("lock fanout cursors").0;
+                    }
                 }
             }
         }
 
-        for dead_tx_index in dead_txs.into_iter().rev() {
-            txs.swap_remove(dead_tx_index);
-        }
+        *ring.slots[(seq % ring.capacity()) as usize].write().expect("write fanout slot") =
+            Slot { seq, value: Some(data) };
+
+        ring.tail.store(seq + 1, Ordering::Release);
+        ring.published.notify_waiters();
     }
 }
 
 impl<T> Drop for LivePublisher<T> {
     fn drop(&mut self) {
-        *self.chan.txs.write().expect("writer lock on txs") = None;
+        self.ring.closed.store(true, Ordering::Release);
+        self.ring.published.notify_waiters();
     }
 }
 
@@ -66,15 +148,103 @@ pub enum SubscribeError {
 }
 
 impl<T> LiveSubscriber<T> where T: Clone {
-    pub fn subscribe(&self) -> Result<mpsc::Receiver<T>, SubscribeError> {
-        let (tx, rx) = mpsc::sync_channel(BUFFER_SIZE);
+    // new subscribers start from the next value published
+    pub fn subscribe(&self) -> Result<LiveReceiver<T>, SubscribeError> {
+        if self.ring.closed.load(Ordering::Acquire) {
+            return Err(SubscribeError::NoPublisher);
+        }
+
+        let mut cursors = self.ring.cursors.lock().expect("lock fanout cursors");
+        let next = self.ring.tail.load(Ordering::Acquire);
+        let cursor = Arc::new(AtomicU64::new(next));
+        cursors.push(Arc::downgrade(&cursor));
+
+        Ok(LiveReceiver { ring: Arc::clone(&self.ring), next, cursor })
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let mut cursors = self.ring.cursors.lock().expect("lock fanout cursors");
+        cursors.retain(|cursor| cursor.strong_count() > 0);
+        cursors.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnected(&self) -> u64 {
+        self.ring.disconnected.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> LiveReceiver<T> where T: Clone {
+    // returns None once the publisher has gone, or has disconnected this
+    // subscriber for falling behind
+    pub async fn recv(&mut self) -> Option<T> {
+        let ring = Arc::clone(&self.ring);
+
+        loop {
+            // registered before checking the tail, so that a value published
+            // in between still wakes us
+            let published = ring.published.notified();
+            tokio::pin!(published);
+            published.as_mut().enable();
+
+            if self.next < ring.tail.load(Ordering::Acquire) {
+                let slot = ring.slots[(self.next % ring.capacity()) as usize]
+                    .read().expect("read fanout slot");
+
+                if slot.seq == self.next {
+                    let value = slot.value.clone();
+                    drop(slot);
+                    self.advance(self.next + 1);
+                    return value;
+                }
 
-        self.chan.txs.write()
-            .expect("writer lock on txs")
-            .as_mut()
-            .ok_or(SubscribeError::NoPublisher)?
-            .push(tx);
+                // the slot has been written again since we last read, so
+                // we're a whole ring behind
+                drop(slot);
 
-        Ok(rx)
+                if ring.config.policy == BackpressurePolicy::Disconnect {
+                    ring.disconnected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+
+                let oldest = ring.tail.load(Ordering::Acquire)
+                    .saturating_sub(ring.capacity());
+
+                ring.dropped.fetch_add(oldest.saturating_sub(self.next), Ordering::Relaxed);
+                self.advance(oldest);
+                continue;
+            }
+
+            if ring.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            published.await;
+        }
+    }
+
+    fn advance(&mut self, next: u64) {
+        self.next = next;
+        self.cursor.store(next, Ordering::Release);
+
+        if self.ring.config.policy == BackpressurePolicy::Block {
+            self.ring.space.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for LiveReceiver<T> {
+    fn drop(&mut self) {
+        // a publisher may be blocked waiting on this subscriber
+        if self.ring.config.policy == BackpressurePolicy::Block {
+            self.ring.space.notify_all();
+        }
     }
 }
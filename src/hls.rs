@@ -0,0 +1,341 @@
+// writes a stream's encoded output as HLS, a rolling playlist of short
+// segment files, for players and CDNs which would rather fetch files than
+// hold a connection open. segments are the stream's own frames cut at chunk
+// boundaries, so nothing is encoded again
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::audio::encode;
+use crate::config::{HlsConfig, StreamConfig};
+use crate::metadata::Metadata;
+use crate::stream::{EncodedChunk, StreamSubscription};
+
+const PLAYLIST: &str = "index.m3u8";
+
+// written first then renamed over the playlist, so that it's never served
+// half written. hidden files aren't served by [files]
+const PLAYLIST_TEMP: &str = ".index.m3u8.tmp";
+
+// packed audio segments carry their start time in this ID3 frame, so that
+// players can line them up
+const TIMESTAMP_OWNER: &[u8] = b"com.apple.streaming.transportStreamTimestamp\0";
+
+struct Hls {
+    log: Logger,
+    config: HlsConfig,
+    extension: &'static str,
+    sequence: u64,
+    segment: Option<Segment>,
+    listed: VecDeque<Listed>,
+    // segments which have left the playlist, kept a while longer for
+    // players still working from an older copy of it
+    unlisted: VecDeque<PathBuf>,
+    // segments with a discontinuity which have left the playlist
+    discontinuity_sequence: u64,
+    // set after chunks are missed, so that the next segment is marked
+    discontinuity: bool,
+    // the stream's source's metadata, for ad break cues
+    metadata: Option<watch::Receiver<Metadata>>,
+    // whether the last segment opened was in an ad break
+    ad_break: bool,
+    // the most recent chunk's presentation time
+    position: Duration,
+    // set after a failed write, so that a full disk is logged once rather
+    // than for every chunk
+    failing: bool,
+}
+
+struct Segment {
+    file: File,
+    sequence: u64,
+    starts_at: Duration,
+    discontinuity: bool,
+    cue: Option<Cue>,
+}
+
+struct Listed {
+    sequence: u64,
+    duration: Duration,
+    discontinuity: bool,
+    cue: Option<Cue>,
+}
+
+// ad breaks are cued at the first segment boundary after they start or end
+#[derive(Clone, Copy)]
+enum Cue {
+    // the ad break's duration, if it was given one
+    Out(Option<Duration>),
+    In,
+}
+
+// segmenters run until the stream is removed, which closes its subscription.
+// metadata is the stream's source's, for cueing ad breaks
+pub fn start(log: Logger, name: &str, stream: StreamConfig, config: HlsConfig, subscription: StreamSubscription,
+    metadata: Option<watch::Receiver<Metadata>>)
+{
+    let runtime = Handle::current();
+    let log = log.new(slog::o!("stream" => name.to_owned()));
+
+    // numbered from the time rather than from zero, so that players don't
+    // mistake segments from after a restart for ones they've already had
+    let sequence = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+
+    let hls = Hls {
+        log,
+        extension: encode::extension_from_config(&stream.codec),
+        config,
+        sequence,
+        segment: None,
+        listed: VecDeque::new(),
+        unlisted: VecDeque::new(),
+        discontinuity_sequence: 0,
+        discontinuity: false,
+        metadata,
+        ad_break: false,
+        position: Duration::ZERO,
+        failing: false,
+    };
+
+    thread::Builder::new()
+        .name(format!("edicast/hls: {}", name))
+        .spawn(move || hls_main(hls, runtime, subscription))
+        .expect("spawn edicast hls thread");
+}
+
+fn hls_main(mut hls: Hls, runtime: Handle, mut subscription: StreamSubscription) {
+    slog::info!(hls.log, "Writing stream as HLS"; "path" => hls.config.path.display());
+
+    loop {
+        let chunk = match runtime.block_on(subscription.recv()) {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(skipped)) => {
+                slog::warn!(hls.log, "HLS fell behind stream, playlist will have a discontinuity";
+                    "skipped_chunks" => skipped);
+
+                hls.finish(false);
+                hls.discontinuity = true;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        match hls.write(&chunk) {
+            Ok(()) => {
+                hls.failing = false;
+            }
+            Err(e) => {
+                if !hls.failing {
+                    slog::error!(hls.log, "Could not write HLS segment"; "error" => e.to_string());
+                }
+
+                // the broken segment is left out of the playlist, and the
+                // next one marked as not following on from the last
+                if let Some(segment) = hls.segment.take() {
+                    let _ = fs::remove_file(hls.segment_path(segment.sequence));
+                }
+
+                hls.discontinuity = true;
+                hls.failing = true;
+            }
+        }
+    }
+
+    hls.finish(true);
+    slog::info!(hls.log, "Stopped writing stream as HLS");
+}
+
+impl Hls {
+    fn write(&mut self, chunk: &EncodedChunk) -> Result<(), io::Error> {
+        let target = Duration::from_secs(self.config.segment_secs.max(1));
+
+        let cut = match &self.segment {
+            Some(segment) => chunk.pts.saturating_sub(segment.starts_at) >= target,
+            None => true,
+        };
+
+        if cut {
+            self.position = chunk.pts;
+            self.finish(false);
+            self.segment = Some(self.open(chunk.pts)?);
+        }
+
+        if let Some(segment) = &mut self.segment {
+            segment.file.write_all(&chunk.data)?;
+        }
+
+        self.position = chunk.pts;
+        Ok(())
+    }
+
+    fn open(&mut self, pts: Duration) -> Result<Segment, io::Error> {
+        fs::create_dir_all(&self.config.path)?;
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        let mut file = File::create(self.segment_path(sequence))?;
+        file.write_all(&timestamp_tag(pts))?;
+
+        Ok(Segment {
+            file,
+            sequence,
+            starts_at: pts,
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            cue: self.cue(),
+        })
+    }
+
+    // the cue for a segment starting now, if an ad break has started or
+    // ended since the last one
+    fn cue(&mut self) -> Option<Cue> {
+        let ad_break = self.metadata.as_ref()
+            .and_then(|metadata| metadata.borrow().ad_break.clone());
+
+        let cue = match (&ad_break, self.ad_break) {
+            (Some(ad_break), false) => Some(Cue::Out(ad_break.duration)),
+            (None, true) => Some(Cue::In),
+            _ => None,
+        };
+
+        self.ad_break = ad_break.is_some();
+        cue
+    }
+
+    // lists the segment being written, if any, and writes the playlist.
+    // the playlist is ended for good once the stream is gone
+    fn finish(&mut self, end: bool) {
+        if let Some(segment) = self.segment.take() {
+            drop(segment.file);
+
+            self.listed.push_back(Listed {
+                sequence: segment.sequence,
+                duration: self.position.saturating_sub(segment.starts_at),
+                discontinuity: segment.discontinuity,
+                cue: segment.cue,
+            });
+        }
+
+        while self.listed.len() > self.config.list_size.max(1) {
+            if let Some(listed) = self.listed.pop_front() {
+                if listed.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+
+                self.unlisted.push_back(self.segment_path(listed.sequence));
+            }
+        }
+
+        while self.unlisted.len() > self.config.list_size.max(1) {
+            if let Some(path) = self.unlisted.pop_front() {
+                if let Err(e) = fs::remove_file(&path) {
+                    slog::warn!(self.log, "Could not remove old HLS segment";
+                        "path" => path.display(),
+                        "error" => e.to_string(),
+                    );
+                }
+            }
+        }
+
+        if self.listed.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.write_playlist(end) {
+            slog::error!(self.log, "Could not write HLS playlist"; "error" => e.to_string());
+        }
+    }
+
+    fn write_playlist(&self, end: bool) -> Result<(), io::Error> {
+        let target = self.listed.iter()
+            .map(|listed| listed.duration.as_secs_f64().ceil() as u64)
+            .fold(self.config.segment_secs.max(1), u64::max);
+
+        let first = self.listed.front().map_or(self.sequence, |listed| listed.sequence);
+
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:3");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first);
+        let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", self.discontinuity_sequence);
+
+        for listed in &self.listed {
+            if listed.discontinuity {
+                let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+            }
+
+            match listed.cue {
+                Some(Cue::Out(Some(duration))) => {
+                    let _ = writeln!(playlist, "#EXT-X-CUE-OUT:DURATION={:.3}", duration.as_secs_f64());
+                }
+                Some(Cue::Out(None)) => {
+                    let _ = writeln!(playlist, "#EXT-X-CUE-OUT");
+                }
+                Some(Cue::In) => {
+                    let _ = writeln!(playlist, "#EXT-X-CUE-IN");
+                }
+                None => {}
+            }
+
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", listed.duration.as_secs_f64());
+            let _ = writeln!(playlist, "{}", self.segment_name(listed.sequence));
+        }
+
+        if end {
+            let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+        }
+
+        let temp = self.config.path.join(PLAYLIST_TEMP);
+        fs::write(&temp, playlist)?;
+        fs::rename(&temp, self.config.path.join(PLAYLIST))
+    }
+
+    fn segment_name(&self, sequence: u64) -> String {
+        format!("{}.{}", sequence, self.extension)
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        self.config.path.join(self.segment_name(sequence))
+    }
+}
+
+// an ID3v2.4 tag holding just the timestamp frame: the segment's start as a
+// 33 bit count of 90kHz ticks, in 8 big endian bytes
+fn timestamp_tag(pts: Duration) -> Vec<u8> {
+    let ticks = (pts.as_secs_f64() * 90_000.0) as u64 & ((1 << 33) - 1);
+
+    let frame_size = TIMESTAMP_OWNER.len() + 8;
+    let tag_size = 10 + frame_size;
+
+    let mut tag = Vec::with_capacity(10 + tag_size);
+    tag.extend_from_slice(b"ID3\x04\x00\x00");
+    tag.extend_from_slice(&synchsafe(tag_size));
+    tag.extend_from_slice(b"PRIV");
+    tag.extend_from_slice(&synchsafe(frame_size));
+    tag.extend_from_slice(&[0, 0]);
+    tag.extend_from_slice(TIMESTAMP_OWNER);
+    tag.extend_from_slice(&ticks.to_be_bytes());
+    tag
+}
+
+// ID3 sizes use seven bits of each byte
+fn synchsafe(size: usize) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]
+}
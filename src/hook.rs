@@ -0,0 +1,201 @@
+// runs local commands or posts to webhooks on events, such as a source
+// connecting or a recording being finished, for studio automation. each
+// hook handles its events one at a time and in order, on its own thread
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::HookConfig;
+use crate::event::{self, Event, EventBus};
+use crate::net::tls::{self, ClientConfig};
+use crate::webhook::{self, Webhooks};
+
+// how often a running command is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Hook {
+    log: Logger,
+    config: HookConfig,
+    tls: Option<Arc<ClientConfig>>,
+    webhooks: Webhooks,
+}
+
+pub fn start(log: Logger, configs: Vec<HookConfig>, events: &EventBus) {
+    if configs.is_empty() {
+        return;
+    }
+
+    let webhooks = webhook::start(log.clone());
+
+    let hooks = configs.into_iter()
+        .enumerate()
+        .filter_map(|(index, config)| {
+            let log = log.new(slog::o!("hook" => index));
+            let hook = Hook::new(log, config, webhooks.clone())?;
+            Some(spawn(index, hook))
+        })
+        .collect::<Vec<_>>();
+
+    let runtime = Handle::current();
+    let mut events = events.subscribe();
+
+    thread::Builder::new()
+        .name("edicast/hook: events".to_owned())
+        .spawn(move || loop {
+            let event = match runtime.block_on(events.recv()) {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    slog::warn!(log, "Hooks fell behind events, some were not run"; "skipped_events" => skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for (events, queue) in &hooks {
+                if events.is_empty() || events.iter().any(|kind| kind == event.kind()) {
+                    let _ = queue.send(event.clone());
+                }
+            }
+        })
+        .expect("spawn edicast hook thread");
+}
+
+// returns the events the hook wants along with its queue
+fn spawn(index: usize, hook: Hook) -> (Vec<String>, mpsc::Sender<Event>) {
+    let (queue, queue_rx) = mpsc::channel::<Event>();
+    let events = hook.config.events.clone();
+
+    thread::Builder::new()
+        .name(format!("edicast/hook: {}", index))
+        .spawn(move || {
+            for event in queue_rx {
+                hook.run(&event);
+            }
+        })
+        .expect("spawn edicast hook thread");
+
+    (events, queue)
+}
+
+impl Hook {
+    fn new(log: Logger, config: HookConfig, webhooks: Webhooks) -> Option<Self> {
+        if config.command.is_none() && config.webhook.is_none() {
+            slog::error!(log, "Hook has neither a command nor a webhook, ignoring it");
+            return None;
+        }
+
+        for kind in &config.events {
+            if !event::KINDS.contains(&kind.as_str()) {
+                slog::warn!(log, "Hook is for an unknown event, it will never run for it";
+                    "event" => kind,
+                    "known" => event::KINDS.join(", "),
+                );
+            }
+        }
+
+        let tls = match &config.webhook {
+            Some(url) if url.tls => match tls::client_config(&config.ca_file) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    slog::error!(log, "Could not set up TLS for hook webhook, ignoring hook"; "error" => e.to_string());
+                    return None;
+                }
+            },
+            _ => None,
+        };
+
+        Some(Hook { log, config, tls, webhooks })
+    }
+
+    fn run(&self, event: &Event) {
+        let json = serde_json::to_value(event).expect("serialize event");
+
+        if let Some(url) = &self.config.webhook {
+            self.webhooks.post(url, self.tls.clone(), &json);
+        }
+
+        if let Some(command) = &self.config.command {
+            self.exec(command, event, &json);
+        }
+    }
+
+    // event fields are passed as EDICAST_<FIELD>, such as EDICAST_SOURCE,
+    // along with EDICAST_EVENT and the whole event as EDICAST_EVENT_JSON
+    fn exec(&self, command: &Path, event: &Event, json: &serde_json::Value) {
+        let mut process = Command::new(command);
+
+        process.args(&self.config.args)
+            .env("EDICAST_EVENT", event.kind())
+            .env("EDICAST_EVENT_JSON", json.to_string());
+
+        if let Some(fields) = json.as_object() {
+            for (key, value) in fields {
+                let value = match value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+
+                if key != "type" {
+                    process.env(format!("EDICAST_{}", key.to_ascii_uppercase()), value);
+                }
+            }
+        }
+
+        let child = match process.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                slog::error!(self.log, "Could not run hook command";
+                    "command" => command.display(),
+                    "event" => event.kind(),
+                    "error" => e.to_string(),
+                );
+                return;
+            }
+        };
+
+        self.wait(child, command, event);
+    }
+
+    // commands taking longer than timeout_secs are killed, so that one
+    // which hangs doesn't hold up every event after it
+    fn wait(&self, mut child: Child, command: &Path, event: &Event) {
+        let until = Instant::now() + Duration::from_secs(self.config.timeout_secs);
+
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => return,
+                Ok(Some(status)) => {
+                    slog::warn!(self.log, "Hook command failed";
+                        "command" => command.display(),
+                        "event" => event.kind(),
+                        "status" => status.to_string(),
+                    );
+                    return;
+                }
+                Ok(None) if Instant::now() < until => thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    slog::warn!(self.log, "Hook command timed out, killing it";
+                        "command" => command.display(),
+                        "event" => event.kind(),
+                        "timeout_secs" => self.config.timeout_secs,
+                    );
+
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return;
+                }
+                Err(e) => {
+                    slog::error!(self.log, "Could not wait for hook command"; "error" => e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: edicast init [--force] <config file>";
+
+const TEMPLATE: &str = r#"# edicast configuration
+#
+# start edicast with:
+#
+#     edicast <this file>
+#
+# then point a source client (eg. butt, liquidsoap, ices) at
+# http://127.0.0.1:3030/source/main, and listen at
+# http://127.0.0.1:8000/live.mp3
+
+[listen]
+# listeners connect to the public address
+public = "127.0.0.1:8000"
+# source clients and admin requests connect to the control address. keep
+# this private, or set a token below
+control = "127.0.0.1:3030"
+
+[control]
+# require admin requests to carry "Authorization: Bearer <token>"
+# token = "change me"
+
+[limits]
+# turn away new listeners once this many are connected
+max_listeners = 1000
+
+# a source is somewhere a source client can stream audio to
+[source.main]
+# what streams hear while no source client is connected: "silence" keeps
+# sending silent audio so listeners stay connected, "inactive" sends nothing
+offline = "silence"
+# audio is passed from sources to streams in chunks of this many
+# milliseconds
+buffer_ms = 500
+# live audio is buffered by an adaptive amount between these, growing when
+# a source connection is jittery and shrinking once it settles down
+# jitter_min_ms = 0
+# jitter_max_ms = 5000
+
+# a stream encodes audio from a source and serves it to listeners
+[stream.live]
+path = "/live.mp3"
+source = "main"
+# bitrate is in kbps, quality ranges from 0 (best) to 9 (fastest)
+codec = { mp3 = { bitrate = 192, quality = 2 } }
+"#;
+
+// entry point for `edicast init`, returns the process exit code
+pub fn main(args: Vec<OsString>) -> i32 {
+    let (force, path) = match args.as_slice() {
+        [path] => (false, PathBuf::from(path)),
+        [flag, path] if flag == "--force" => (true, PathBuf::from(path)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 1;
+        }
+    };
+
+    match write_config(&path, force) {
+        Ok(()) => {
+            println!("wrote starter config to {}", path.display());
+            println!("run it with: edicast {}", path.display());
+            0
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!("edicast init: {} already exists, use --force to overwrite it", path.display());
+            1
+        }
+        Err(e) => {
+            eprintln!("edicast init: could not write {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+fn write_config(path: &Path, force: bool) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!force)
+        .truncate(true)
+        .open(path)?;
+
+    file.write_all(TEMPLATE.as_bytes())
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+use uuid::Uuid;
+
+use crate::audio::PcmData;
+use crate::audio::convert;
+use crate::audio::decode;
+use crate::audio::pool::Samples;
+use crate::config::{JingleConfig, JingleMode};
+use crate::schedule::{Daily, Interval};
+
+pub struct Jingle {
+    log: Logger,
+    pcm: PcmData,
+    mode: JingleMode,
+    interval: Option<Interval>,
+    times: Daily,
+    next_due: SystemTime,
+    // gain applied to live audio while an overlaid jingle is playing
+    duck: f32,
+    // frame position within an overlaid jingle, or None if not playing
+    position: Option<usize>,
+    // live audio still to be dropped to make room for an inserted jingle,
+    // so that listeners don't fall further behind the source with each one
+    owed: Duration,
+    session: Option<Uuid>,
+}
+
+impl Jingle {
+    pub fn load(log: Logger, config: &JingleConfig) -> Result<Self, io::Error> {
+        if config.every_mins.is_none() && config.times.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "jingle has neither every_mins nor times"));
+        }
+
+        let pcm = decode::read_file(&config.path)?;
+
+        let interval = config.every_mins.map(|every_mins| Interval::new(
+            Duration::from_secs(every_mins.get() * 60),
+            Duration::from_secs(config.offset_mins * 60)));
+
+        let times = Daily::new(config.times.iter()
+            .map(|time| Duration::from_secs(time.secs))
+            .collect());
+
+        let mut jingle = Jingle {
+            log,
+            pcm,
+            mode: config.mode.clone(),
+            interval,
+            times,
+            next_due: SystemTime::UNIX_EPOCH,
+            duck: config.duck,
+            position: None,
+            owed: Duration::ZERO,
+            session: None,
+        };
+
+        jingle.next_due = jingle.next_after(SystemTime::now());
+        Ok(jingle)
+    }
+
+    fn next_after(&self, time: SystemTime) -> SystemTime {
+        let interval = self.interval.as_ref().map(|interval| interval.next_after(time));
+        let times = self.times.next_after(time);
+
+        match (interval, times) {
+            (Some(interval), Some(times)) => interval.min(times),
+            (interval, times) => interval.or(times).expect("jingle is scheduled"),
+        }
+    }
+
+    // the audio to encode in place of pcm. an overlaid jingle is mixed into
+    // pcm while it plays. an inserted jingle is played in full in place of
+    // pcm, and as much live audio as the jingle is long is dropped after it
+    pub fn process(&mut self, pcm: Arc<PcmData>) -> Vec<Arc<PcmData>> {
+        // presentation times start over with each source session
+        if pcm.session != self.session {
+            self.session = pcm.session;
+            self.owed = Duration::ZERO;
+        }
+
+        let mut output = Vec::new();
+
+        if self.position.is_none() && self.owed.is_zero() && self.due(&pcm) {
+            slog::info!(self.log, "Playing jingle");
+
+            match self.mode {
+                JingleMode::Insert => output.extend(self.insert(&pcm)),
+                JingleMode::Overlay => self.position = Some(0),
+            }
+        }
+
+        let pcm = match self.repay(pcm) {
+            Some(pcm) => pcm,
+            None => return output,
+        };
+
+        let pcm = match self.position {
+            Some(position) => self.overlay(pcm, position),
+            None => pcm,
+        };
+
+        output.push(pcm);
+        output
+    }
+
+    // whether the jingle should start playing before or over pcm. a jingle
+    // in a different format to the live audio is skipped, edicast doesn't
+    // resample or remix audio
+    fn due(&mut self, pcm: &PcmData) -> bool {
+        let now = SystemTime::now();
+
+        if now < self.next_due {
+            return false;
+        }
+
+        self.next_due = self.next_after(now);
+
+        if pcm.sample_rate != self.pcm.sample_rate || pcm.channels != self.pcm.channels {
+            slog::warn!(self.log, "Skipping jingle, its audio format doesn't match the stream's";
+                "jingle_sample_rate" => self.pcm.sample_rate,
+                "jingle_channels" => self.pcm.channels,
+                "stream_sample_rate" => pcm.sample_rate,
+                "stream_channels" => pcm.channels,
+            );
+
+            return false;
+        }
+
+        true
+    }
+
+    // the whole jingle, cut into buffers the size of pcm and stamped to play
+    // in its place
+    fn insert(&mut self, pcm: &PcmData) -> Vec<Arc<PcmData>> {
+        let channels = self.pcm.channels;
+        let frames = (pcm.samples.len() / channels).max(1);
+
+        let mut pts = pcm.pts;
+
+        let buffers = self.pcm.samples.chunks(frames * channels)
+            .map(|samples| {
+                let buffer = Arc::new(PcmData {
+                    sample_rate: self.pcm.sample_rate,
+                    channels,
+                    samples: Samples::from_slice(samples),
+                    pts,
+                    session: pcm.session,
+                });
+
+                pts += buffer.duration();
+                buffer
+            })
+            .collect();
+
+        self.owed = self.pcm.duration();
+        buffers
+    }
+
+    // what's left of pcm once the live audio owed to an inserted jingle is
+    // dropped from it, or None if all of it was
+    fn repay(&mut self, pcm: Arc<PcmData>) -> Option<Arc<PcmData>> {
+        if self.owed.is_zero() {
+            return Some(pcm);
+        }
+
+        let duration = pcm.duration();
+
+        if duration <= self.owed {
+            self.owed -= duration;
+            return None;
+        }
+
+        let owed = std::mem::replace(&mut self.owed, Duration::ZERO);
+        let frames = (owed.as_nanos() * pcm.sample_rate as u128 / 1_000_000_000) as usize;
+        let skip = (frames * pcm.channels).min(pcm.samples.len());
+
+        Some(Arc::new(PcmData {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels,
+            samples: Samples::from_slice(&pcm.samples[skip..]),
+            pts: pcm.pts + owed,
+            session: pcm.session,
+        }))
+    }
+
+    fn overlay(&mut self, pcm: Arc<PcmData>, position: usize) -> Arc<PcmData> {
+        // the source came back in another format partway through
+        if pcm.sample_rate != self.pcm.sample_rate || pcm.channels != self.pcm.channels {
+            self.position = None;
+            return pcm;
+        }
+
+        let channels = self.pcm.channels;
+        let jingle_frames = self.pcm.samples.len() / channels;
+        let frames = (pcm.samples.len() / channels).min(jingle_frames.saturating_sub(position));
+
+        let mut samples = Samples::from_slice(&pcm.samples);
+        let jingle = &self.pcm.samples[position * channels..][..frames * channels];
+        convert::mix(&mut samples[..frames * channels], jingle, self.duck);
+
+        self.position = Some(position + frames).filter(|position| *position < jingle_frames);
+
+        Arc::new(PcmData {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels,
+            samples,
+            pts: pcm.pts,
+            session: pcm.session,
+        })
+    }
+}
@@ -0,0 +1,183 @@
+// keeps significant events in a SQLite database, so that what happened
+// around an incident can be put together afterwards from the control API
+// without shipping logs anywhere. the journal is bounded by entry count
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::JournalConfig;
+use crate::event::{self, Event, EventBus};
+use crate::stats::unix_now;
+
+// entries returned by a query when it doesn't give a limit
+pub const DEFAULT_LIMIT: u32 = 1000;
+
+// left out unless the config lists which events to keep, as listeners come
+// and go far too often to be worth keeping
+const LEFT_OUT: &[&str] = &["listener_connected", "listener_disconnected"];
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        time INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        category TEXT NOT NULL,
+        subject TEXT,
+        data TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS journal_time
+        ON journal (time);
+";
+
+pub struct Journal {
+    config: JournalConfig,
+}
+
+// times are unix timestamps in seconds. kind is an event type such as
+// "source_connected", or the first part of one such as "source" to match
+// every source event. name matches the source or stream events are about
+#[derive(Default)]
+pub struct Query {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub limit: Option<u32>,
+}
+
+fn connect(path: &Path) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+
+    // WAL lets control requests read while the journal thread is writing
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+
+    Ok(conn)
+}
+
+// such as "source" for "source_connected"
+fn category(kind: &str) -> &str {
+    kind.split('_').next().unwrap_or(kind)
+}
+
+impl Journal {
+    pub fn open(config: &JournalConfig) -> Result<Self, rusqlite::Error> {
+        let conn = connect(&config.database)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Journal { config: config.clone() })
+    }
+
+    // spawns the thread which writes events to the journal
+    pub fn start(&self, log: Logger, events: &EventBus) -> Result<(), rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+        let config = self.config.clone();
+        let runtime = Handle::current();
+        let mut events = events.subscribe();
+
+        if let Some(kinds) = &config.events {
+            for kind in kinds {
+                if !event::KINDS.contains(&kind.as_str()) {
+                    slog::warn!(log, "Journal is configured to keep an unknown event";
+                        "event" => kind,
+                        "known" => event::KINDS.join(", "),
+                    );
+                }
+            }
+        }
+
+        thread::Builder::new()
+            .name("edicast/journal".to_owned())
+            .spawn(move || {
+                slog::info!(log, "Keeping event journal"; "database" => config.database.display());
+
+                loop {
+                    let event = match runtime.block_on(events.recv()) {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(skipped)) => {
+                            slog::warn!(log, "Journal fell behind, some events were not kept"; "skipped" => skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return,
+                    };
+
+                    let keep = match &config.events {
+                        Some(kinds) => kinds.iter().any(|kind| kind == event.kind()),
+                        None => !LEFT_OUT.contains(&event.kind()),
+                    };
+
+                    if !keep {
+                        continue;
+                    }
+
+                    if let Err(e) = record(&conn, &event, config.max_entries) {
+                        slog::error!(log, "Could not write event to journal"; "error" => e.to_string());
+                    }
+                }
+            })
+            .expect("spawn edicast journal thread");
+
+        Ok(())
+    }
+
+    // the most recent entries matching the query, oldest first. each entry
+    // is the event as the event stream sends it, with its id and time
+    pub fn query(&self, query: &Query) -> Result<Vec<Value>, rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+
+        let mut stmt = conn.prepare("
+            SELECT id, time, data FROM (
+                SELECT id, time, data
+                FROM journal
+                WHERE (?1 IS NULL OR time >= ?1)
+                  AND (?2 IS NULL OR time < ?2)
+                  AND (?3 IS NULL OR kind = ?3 OR category = ?3)
+                  AND (?4 IS NULL OR subject = ?4)
+                ORDER BY id DESC
+                LIMIT ?5
+            )
+            ORDER BY id
+        ")?;
+
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+
+        let rows = stmt.query_map(params![query.since, query.until, query.kind, query.name, limit], |row| {
+            let id: i64 = row.get(0)?;
+            let time: i64 = row.get(1)?;
+            let data: String = row.get(2)?;
+
+            let mut entry = serde_json::from_str::<Value>(&data).unwrap_or(Value::Null);
+
+            if let Value::Object(fields) = &mut entry {
+                fields.insert("id".to_owned(), id.into());
+                fields.insert("time".to_owned(), time.into());
+            }
+
+            Ok(entry)
+        })?;
+
+        rows.collect()
+    }
+}
+
+fn record(conn: &Connection, event: &Event, max_entries: u64) -> Result<(), rusqlite::Error> {
+    let data = serde_json::to_string(event).expect("serialize event");
+
+    conn.prepare_cached("
+        INSERT INTO journal (time, kind, category, subject, data) VALUES (?1, ?2, ?3, ?4, ?5)
+    ")?.execute(params![unix_now(), event.kind(), category(event.kind()), event.subject(), data])?;
+
+    // ids only ever go up, so everything more than max_entries behind the
+    // newest is older than the rest
+    conn.prepare_cached("
+        DELETE FROM journal WHERE id <= last_insert_rowid() - ?1
+    ")?.execute(params![max_entries as i64])?;
+
+    Ok(())
+}
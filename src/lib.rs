@@ -0,0 +1,55 @@
+// the streaming engine behind the edicast binary, for embedding in another
+// application. build an Edicast from a Config and hand it to serve, then
+// call Edicast::stop to shut it down gracefully. what happens while it
+// runs, such as sources coming and going, alerts firing and tasks being
+// restarted, can be followed by subscribing to Edicast::events. run is what
+// the binary uses, and handles signals as well, run_until stops on a future
+// of the caller's instead. the binary installs jemalloc as the global
+// allocator except with msvc, memory stats are only reported when the
+// embedding application does the same
+mod access_log;
+mod alert;
+mod archive;
+pub mod audio;
+mod chapters;
+mod cluster;
+pub mod config;
+pub mod crash;
+pub mod event;
+mod fanout;
+mod hls;
+mod hook;
+mod jingle;
+mod journal;
+mod listener;
+pub mod logging;
+mod memory;
+mod metadata;
+mod metrics;
+#[cfg(feature = "ingest-pull")]
+mod mirror;
+mod net;
+mod plugin;
+#[cfg(feature = "ingest-pull")]
+mod pull;
+mod recording;
+mod relay;
+mod retention;
+mod rtp;
+mod schedule;
+mod script;
+mod server;
+mod sink;
+mod source;
+mod stats;
+mod stream;
+mod supervise;
+mod sync;
+mod thread;
+mod upload;
+mod webhook;
+
+pub use event::{Event, EventBus};
+pub use server::{chown_to, run, run_until, serve, Edicast, StartError, Startup};
+pub use source::SourceSet;
+pub use stream::StreamSet;
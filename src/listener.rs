@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::access_log::{self, AccessLog};
+use crate::config::PacingConfig;
+use crate::event::{Event, EventBus};
+use crate::metadata::Metadata;
+use crate::plugin::Plugin;
+use crate::stream::StreamSubscription;
+
+pub struct ListenerRegistry {
+    events: EventBus,
+    access_log: Option<AccessLog>,
+    listeners: Mutex<HashMap<Uuid, Arc<ListenerInfo>>>,
+}
+
+pub struct ListenerLimitReached;
+
+pub struct ListenerInfo {
+    pub id: Uuid,
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    // the request line, for the access log
+    pub request: String,
+    pub connected_at: SystemTime,
+    stream: Mutex<String>,
+    bytes_sent: AtomicU64,
+    // the source sessions heard, in order
+    source_sessions: Mutex<Vec<Uuid>>,
+    moves: mpsc::UnboundedSender<StreamMove>,
+}
+
+// sent to a listener's stream body to switch it over to another stream
+// without disconnecting it, along with everything about the listener which
+// depends on the stream
+pub struct StreamMove {
+    pub stream: String,
+    pub subscription: StreamSubscription,
+    pub metadata: Option<watch::Receiver<Metadata>>,
+    pub plugin: Option<Arc<Plugin>>,
+    // watches the new stream's source, if it closes listeners when that goes
+    // offline
+    pub source_offline: Option<SourceOffline>,
+    pub pacing: Option<PacingConfig>,
+    pub bitrate: usize,
+}
+
+// resolves when a stream's source goes offline. this is held in the body
+// rather than recreated on every poll so that its waker stays registered
+pub type SourceOffline = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+impl ListenerInfo {
+    pub fn new(
+        id: Uuid,
+        stream: String,
+        remote_addr: Option<SocketAddr>,
+        user_agent: Option<String>,
+        referer: Option<String>,
+        request: String,
+        moves: mpsc::UnboundedSender<StreamMove>,
+    ) -> Self {
+        ListenerInfo {
+            id,
+            remote_addr,
+            user_agent,
+            referer,
+            request,
+            connected_at: SystemTime::now(),
+            stream: Mutex::new(stream),
+            bytes_sent: AtomicU64::new(0),
+            source_sessions: Mutex::new(Vec::new()),
+            moves,
+        }
+    }
+
+    pub fn stream(&self) -> String {
+        self.stream.lock().expect("lock listener stream").clone()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn source_sessions(&self) -> Vec<Uuid> {
+        self.source_sessions.lock().expect("lock listener source sessions").clone()
+    }
+
+    // returns false if the listener has already gone away
+    pub fn move_to(&self, stream_move: StreamMove) -> bool {
+        let stream = stream_move.stream.clone();
+
+        match self.moves.send(stream_move) {
+            Ok(()) => {
+                *self.stream.lock().expect("lock listener stream") = stream;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl ListenerRegistry {
+    pub fn new(events: EventBus, access_log: Option<AccessLog>) -> Arc<Self> {
+        Arc::new(ListenerRegistry {
+            events,
+            access_log,
+            listeners: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.listeners.lock().expect("lock listeners").len()
+    }
+
+    pub fn list(&self) -> Vec<Arc<ListenerInfo>> {
+        self.listeners.lock().expect("lock listeners")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // registers a new listener, unless doing so would exceed limit. the
+    // listener remains registered until the returned handle is dropped
+    pub fn register(self: &Arc<Self>, info: ListenerInfo, limit: Option<usize>)
+        -> Result<ListenerHandle, ListenerLimitReached>
+    {
+        let mut listeners = self.listeners.lock().expect("lock listeners");
+
+        if listeners.len() >= limit.unwrap_or(usize::MAX) {
+            return Err(ListenerLimitReached);
+        }
+
+        let info = Arc::new(info);
+        listeners.insert(info.id, Arc::clone(&info));
+
+        self.events.publish(Event::ListenerConnected {
+            listener: info.id.to_string(),
+            stream: info.stream(),
+            remote_addr: info.remote_addr.map(|addr| addr.to_string()),
+        });
+
+        Ok(ListenerHandle { registry: Arc::clone(self), info })
+    }
+}
+
+pub struct ListenerHandle {
+    registry: Arc<ListenerRegistry>,
+    info: Arc<ListenerInfo>,
+}
+
+impl ListenerHandle {
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.info.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // records that the listener was sent audio from a source session,
+    // returns true if it's a different session from the last one sent
+    pub fn heard(&self, session: Uuid) -> bool {
+        let mut sessions = self.info.source_sessions.lock().expect("lock listener source sessions");
+
+        if sessions.last() == Some(&session) {
+            return false;
+        }
+
+        sessions.push(session);
+        true
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        self.registry.listeners.lock().expect("lock listeners")
+            .remove(&self.info.id);
+
+        let duration = SystemTime::now().duration_since(self.info.connected_at)
+            .unwrap_or_default();
+
+        self.registry.events.publish(Event::ListenerDisconnected {
+            listener: self.info.id.to_string(),
+            stream: self.info.stream(),
+            duration_secs: duration.as_secs(),
+            bytes_sent: self.info.bytes_sent(),
+            source_sessions: self.info.source_sessions().iter().map(Uuid::to_string).collect(),
+        });
+
+        if let Some(access_log) = &self.registry.access_log {
+            access_log.write(access_log::Entry {
+                listener: self.info.id.to_string(),
+                stream: self.info.stream(),
+                remote_addr: self.info.remote_addr,
+                request: self.info.request.clone(),
+                user_agent: self.info.user_agent.clone(),
+                referer: self.info.referer.clone(),
+                connected_at: self.info.connected_at,
+                duration,
+                bytes_sent: self.info.bytes_sent(),
+                source_sessions: self.info.source_sessions(),
+            });
+        }
+    }
+}
@@ -0,0 +1,513 @@
+// log drains which hand records to the system's logging rather than writing
+// them out ourselves, for hosts which collect everything through syslog or
+// the systemd journal. each record's keys are sent as structured fields.
+// also the level filter in front of every drain, which can be changed while
+// edicast runs
+use std::fmt;
+use std::io;
+use std::net::UdpSocket;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use slog::{Drain, Level, OwnedKVList, Record, KV};
+
+use crate::config::{LogConfig, LogFilterConfig, SyslogConfig};
+use crate::schedule;
+
+const APP_NAME: &str = "edicast";
+
+// the local syslog daemon, when no address is configured
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// structured data needs an SD-ID with an enterprise number, this is the
+// one set aside for examples and private use
+const SD_ID: &str = "edicast@32473";
+
+// syslog severities, which journald uses for PRIORITY too
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// the record's own keys followed by those of its logger, as text
+fn fields(record: &Record, values: &OwnedKVList) -> Vec<(String, String)> {
+    let mut fields = Fields(Vec::new());
+    let _ = record.kv().serialize(record, &mut fields);
+    let _ = values.serialize(record, &mut fields);
+    fields.0
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl slog::Serializer for Fields {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+// RFC 5424 messages over UDP, or to the local daemon's socket
+pub struct Syslog {
+    socket: SyslogSocket,
+    facility: u8,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn open(config: &SyslogConfig) -> Result<Self, io::Error> {
+        let socket = match &config.address {
+            Some(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address.as_str())?;
+                SyslogSocket::Udp(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                SyslogSocket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "no local syslog, set log.syslog.address"));
+            }
+        };
+
+        Ok(Syslog {
+            socket,
+            facility: config.facility.code(),
+            hostname: hostname().unwrap_or_else(|| "-".to_owned()),
+        })
+    }
+
+    fn format(&self, record: &Record, values: &OwnedKVList) -> String {
+        let now = SystemTime::now();
+        let millis = now.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_millis();
+
+        let mut message = format!("<{}>1 {}.{:03}Z {} {} {} - ",
+            self.facility * 8 + severity(record.level()),
+            schedule::format_utc("%Y-%m-%dT%H:%M:%S", now), millis,
+            self.hostname, APP_NAME, process::id());
+
+        let fields = fields(record, values);
+
+        if fields.is_empty() {
+            message.push('-');
+        } else {
+            message.push('[');
+            message.push_str(SD_ID);
+
+            for (key, value) in fields {
+                message.push(' ');
+                message.push_str(&sd_name(&key));
+                message.push_str("=\"");
+                message.push_str(&sd_escape(&value));
+                message.push('"');
+            }
+
+            message.push(']');
+        }
+
+        message.push(' ');
+        message.push_str(&record.msg().to_string());
+        message
+    }
+}
+
+impl Drain for Syslog {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), io::Error> {
+        let message = self.format(record, values);
+
+        match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes())?,
+            #[cfg(unix)]
+            SyslogSocket::Unix(socket) => socket.send(message.as_bytes())?,
+        };
+
+        Ok(())
+    }
+}
+
+// parameter names are printable ASCII up to 32 characters, without = ] "
+// or spaces
+fn sd_name(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            '=' | ']' | '"' | ' ' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect()
+}
+
+fn sd_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+
+    if result != 0 {
+        return None;
+    }
+
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8(buffer[..length].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+// the journal's native protocol, which keeps each key as a field of its own
+// that journalctl can filter on, such as journalctl STREAM=live
+#[cfg(unix)]
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Journald {
+    pub fn open() -> Result<Self, io::Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(Journald { socket })
+    }
+}
+
+#[cfg(unix)]
+impl Drain for Journald {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), io::Error> {
+        let mut entry = Vec::new();
+
+        journal_field(&mut entry, "MESSAGE", &record.msg().to_string());
+        journal_field(&mut entry, "PRIORITY", &severity(record.level()).to_string());
+        journal_field(&mut entry, "SYSLOG_IDENTIFIER", APP_NAME);
+        journal_field(&mut entry, "CODE_FILE", record.file());
+        journal_field(&mut entry, "CODE_LINE", &record.line().to_string());
+        journal_field(&mut entry, "CODE_MODULE", record.module());
+
+        for (key, value) in fields(record, values) {
+            if let Some(name) = journal_name(&key) {
+                journal_field(&mut entry, &name, &value);
+            }
+        }
+
+        self.socket.send(&entry)?;
+        Ok(())
+    }
+}
+
+// values with newlines in are sent with their length instead of after =
+#[cfg(unix)]
+fn journal_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+// field names are upper case letters, digits and underscores, not starting
+// with an underscore or digit, which are kept for the journal's own fields
+#[cfg(unix)]
+fn journal_name(key: &str) -> Option<String> {
+    let name = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .skip_while(|c| *c == '_' || c.is_ascii_digit())
+        .take(64)
+        .collect::<String>();
+
+    if name.is_empty() { None } else { Some(name) }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Target {
+    // records logged with this key and value, on the record or its logger
+    Key { key: String, value: String },
+    // records logged from this module or those inside it
+    Module(String),
+}
+
+impl Target {
+    // module paths have :: in them, anything else with a : is a key
+    fn parse(target: &str) -> Self {
+        match target.split_once(':') {
+            Some((key, value)) if !target.contains("::") => {
+                Target::Key { key: key.to_owned(), value: value.to_owned() }
+            }
+            _ => Target::Module(target.to_owned()),
+        }
+    }
+
+    fn matches_module(&self, module: &str) -> bool {
+        match self {
+            Target::Module(prefix) => {
+                module.strip_prefix(prefix.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            }
+            Target::Key { .. } => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct Filter {
+    target: String,
+    parsed: Target,
+    level: Level,
+}
+
+#[derive(Clone)]
+struct LevelState {
+    level: Level,
+    filters: Vec<Filter>,
+    // the least and most verbose of the level and filters, so that most
+    // records pass or fail without looking at their keys
+    least_verbose: Level,
+    most_verbose: Level,
+}
+
+impl LevelState {
+    fn new(level: Level, filters: Vec<Filter>) -> Self {
+        let levels = filters.iter().map(|filter| filter.level).chain([level]);
+        let least_verbose = levels.clone().min_by_key(|level| level.as_usize()).unwrap_or(level);
+        let most_verbose = levels.max_by_key(|level| level.as_usize()).unwrap_or(level);
+
+        LevelState { level, filters, least_verbose, most_verbose }
+    }
+
+    // records matching a filter are logged at that filter's level, or the
+    // most verbose of them if several match, the rest at the overall level
+    fn allows(&self, record: &Record, values: &OwnedKVList) -> bool {
+        if record.level().is_at_least(self.least_verbose) {
+            return true;
+        }
+
+        if !record.level().is_at_least(self.most_verbose) {
+            return false;
+        }
+
+        let mut matched = self.filters.iter()
+            .filter(|filter| filter.parsed.matches_module(record.module()))
+            .map(|filter| filter.level)
+            .collect::<Vec<_>>();
+
+        if self.filters.iter().any(|filter| matches!(filter.parsed, Target::Key { .. })) {
+            let mut keys = KeyMatch { filters: &self.filters, matched: &mut matched };
+            let _ = record.kv().serialize(record, &mut keys);
+            let _ = values.serialize(record, &mut keys);
+        }
+
+        let level = matched.into_iter()
+            .max_by_key(|level| level.as_usize())
+            .unwrap_or(self.level);
+
+        record.level().is_at_least(level)
+    }
+}
+
+struct KeyMatch<'a> {
+    filters: &'a [Filter],
+    matched: &'a mut Vec<Level>,
+}
+
+impl slog::Serializer for KeyMatch<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        let mut formatted = None;
+
+        for filter in self.filters {
+            if let Target::Key { key: filter_key, value: filter_value } = &filter.parsed {
+                if filter_key.as_str() == key {
+                    let formatted = formatted.get_or_insert_with(|| value.to_string());
+
+                    if *formatted == *filter_value {
+                        self.matched.push(filter.level);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// the log level and filters, shared between the drain and whatever changes
+// them, such as the control API
+#[derive(Clone)]
+pub struct Levels {
+    state: Arc<RwLock<LevelState>>,
+    // set by --log-level, which takes precedence over the config file
+    flag: Option<Level>,
+    // the level and filters last taken from the config file, so that a
+    // reload only replaces changes made at runtime if the file changed
+    configured: Arc<RwLock<Option<Configured>>>,
+}
+
+type Configured = (Level, Vec<Filter>);
+
+pub struct LevelSummary {
+    pub level: Level,
+    // targets and their levels
+    pub filters: Vec<(String, Level)>,
+}
+
+impl Levels {
+    pub fn new(flag: Option<Level>) -> Self {
+        Levels {
+            state: Arc::new(RwLock::new(LevelState::new(flag.unwrap_or(Level::Info), Vec::new()))),
+            flag,
+            configured: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // applies the config file's level and filters, unless they're the same
+    // as were applied last time. returns whether anything changed
+    pub fn configure(&self, config: &LogConfig) -> bool {
+        let level = self.flag
+            .or(config.level.map(|level| level.0))
+            .unwrap_or(Level::Info);
+
+        let filters = config.filter.iter().map(filter).collect::<Vec<_>>();
+        let configured = Some((level, filters.clone()));
+
+        let mut last = self.configured.write().expect("write configured log levels");
+
+        if *last == configured {
+            return false;
+        }
+
+        *last = configured;
+        *self.state.write().expect("write log levels") = LevelState::new(level, filters);
+        true
+    }
+
+    pub fn set_level(&self, level: Level) {
+        let mut state = self.state.write().expect("write log levels");
+        *state = LevelState::new(level, state.filters.clone());
+    }
+
+    // replaces any filter for the same target
+    pub fn set_filter(&self, target: &str, level: Level) {
+        let mut state = self.state.write().expect("write log levels");
+
+        let mut filters = state.filters.clone();
+        filters.retain(|filter| filter.target != target);
+        filters.push(Filter { target: target.to_owned(), parsed: Target::parse(target), level });
+
+        *state = LevelState::new(state.level, filters);
+    }
+
+    // returns false if there was no filter for the target
+    pub fn remove_filter(&self, target: &str) -> bool {
+        let mut state = self.state.write().expect("write log levels");
+
+        let mut filters = state.filters.clone();
+        filters.retain(|filter| filter.target != target);
+
+        if filters.len() == state.filters.len() {
+            return false;
+        }
+
+        *state = LevelState::new(state.level, filters);
+        true
+    }
+
+    pub fn summary(&self) -> LevelSummary {
+        let state = self.state.read().expect("read log levels");
+
+        LevelSummary {
+            level: state.level,
+            filters: state.filters.iter()
+                .map(|filter| (filter.target.clone(), filter.level))
+                .collect(),
+        }
+    }
+}
+
+fn filter(config: &LogFilterConfig) -> Filter {
+    Filter {
+        target: config.target.clone(),
+        parsed: Target::parse(&config.target),
+        level: config.level.0,
+    }
+}
+
+// passes on the records allowed by levels
+pub struct LevelFilter<D> {
+    drain: D,
+    levels: Levels,
+}
+
+impl<D> LevelFilter<D> {
+    pub fn new(drain: D, levels: Levels) -> Self {
+        LevelFilter { drain, levels }
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), D::Err> {
+        let allowed = self.levels.state.read().expect("read log levels").allows(record, values);
+
+        if allowed {
+            self.drain.log(record, values)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        level.is_at_least(self.levels.state.read().expect("read log levels").most_verbose)
+            && self.drain.is_enabled(level)
+    }
+}
+
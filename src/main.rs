@@ -1,42 +1,230 @@
-mod audio;
-mod config;
-mod fanout;
-mod net;
-mod server;
-mod source;
-mod stream;
-mod sync;
-mod thread;
+mod ctl;
+mod daemon;
+mod init;
+mod schema;
+#[cfg(windows)]
+mod service;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use std::env;
+use std::ffi::OsString;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
 
-use slog::{Drain, Logger};
+use futures::future::BoxFuture;
+use slog::{Drain, Level, Logger};
 
-use config::Config;
+use edicast_core::config::{self, Config, ListenAddr, LogConfig, LogFormat};
+use edicast_core::{crash, logging, Startup};
 
-fn logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    Logger::root(drain, slog::o!())
+use crate::daemon::{Daemon, Pidfile};
+
+const USAGE: &str = "\
+usage: edicast [options] <config file>
+       edicast ctl [--config <config file>] <command> [args...]
+       edicast init [--force] <config file>
+       edicast schema
+       edicast service install [--name <name>] [options] <config file>
+       edicast service uninstall [--name <name>]
+
+options:
+    --overlay <path>            merge another config file over the first,
+                                may be given more than once
+    --public-listen <addr>      override listen.public from the config file
+    --control-listen <addr>     override listen.control from the config file
+    --log-level <level>         one of critical, error, warning, info, debug,
+                                trace, overrides log.level from the config
+                                file (default info)
+    --log-format <format>       text, json, syslog or journald, overrides
+                                log.format from the config file (default text)
+    --daemon                    fork into the background once edicast is
+                                listening, unix only
+    --output <path>             with --daemon, append stdout and stderr to
+                                path instead of discarding them
+    --pidfile <path>            write edicast's pid to path, removed again
+                                when edicast exits
+
+on windows, service install registers edicast as a service which starts with
+windows and runs with the options and config file given. services have no
+console to log to, set log.format = \"syslog\" and log.syslog.address in the
+config file";
+
+// command line flags take precedence over the config file, so that one
+// config can be shared between several instances
+struct Args {
+    config_path: PathBuf,
+    overlays: Vec<PathBuf>,
+    public_listen: Option<ListenAddr>,
+    control_listen: Option<ListenAddr>,
+    log_level: Option<Level>,
+    log_format: Option<LogFormat>,
+    daemon: bool,
+    output: Option<PathBuf>,
+    pidfile: Option<PathBuf>,
 }
 
-fn config_path() -> PathBuf {
-    match env::args_os().nth(1) {
-        Some(path) => path.into(),
-        None => {
-            eprintln!("usage: edicast <config file>");
-            process::exit(1);
+impl Args {
+    fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, String> {
+        let mut config_path = None;
+        let mut overlays = Vec::new();
+        let mut public_listen = None;
+        let mut control_listen = None;
+        let mut log_level = None;
+        let mut log_format = None;
+        let mut daemon = false;
+        let mut output = None;
+        let mut pidfile = None;
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let arg = match arg.to_str() {
+                Some(arg) if arg.starts_with("--") => arg.to_owned(),
+                _ => {
+                    if config_path.is_some() {
+                        return Err("more than one config file given".to_owned());
+                    }
+                    config_path = Some(PathBuf::from(arg));
+                    continue;
+                }
+            };
+
+            // the only flag without a value
+            if arg == "--daemon" {
+                daemon = true;
+                continue;
+            }
+
+            // accept both --flag value and --flag=value
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), value.to_owned()),
+                None => {
+                    let value = args.next()
+                        .and_then(|value| value.into_string().ok())
+                        .ok_or_else(|| format!("missing value for {}", arg))?;
+                    (arg, value)
+                }
+            };
+
+            match flag.as_str() {
+                "--overlay" => {
+                    overlays.push(PathBuf::from(value));
+                }
+                "--public-listen" => {
+                    public_listen = Some(parse_addr(&flag, &value)?);
+                }
+                "--control-listen" => {
+                    control_listen = Some(parse_addr(&flag, &value)?);
+                }
+                "--log-level" => {
+                    log_level = Some(value.parse()
+                        .map_err(|()| format!("invalid log level: {}", value))?);
+                }
+                "--log-format" => {
+                    log_format = Some(match value.as_str() {
+                        "text" => LogFormat::Text,
+                        "json" => LogFormat::Json,
+                        "syslog" => LogFormat::Syslog,
+                        "journald" => LogFormat::Journald,
+                        _ => return Err(format!("invalid log format: {}", value)),
+                    });
+                }
+                "--output" => {
+                    output = Some(PathBuf::from(value));
+                }
+                "--pidfile" => {
+                    pidfile = Some(PathBuf::from(value));
+                }
+                _ => return Err(format!("unknown option: {}", flag)),
+            }
+        }
+
+        if output.is_some() && !daemon {
+            return Err("--output needs --daemon".to_owned());
+        }
+
+        Ok(Args {
+            config_path: config_path.ok_or_else(|| "no config file given".to_owned())?,
+            overlays,
+            public_listen,
+            control_listen,
+            log_level,
+            log_format,
+            daemon,
+            output,
+            pidfile,
+        })
+    }
+
+    fn apply(&self, config: &mut Config) {
+        if let Some(addr) = self.public_listen {
+            config.listen.public = addr.into();
+        }
+
+        if let Some(addr) = self.control_listen {
+            config.listen.control = addr.into();
         }
     }
 }
 
+fn parse_addr(flag: &str, value: &str) -> Result<ListenAddr, String> {
+    value.parse().map_err(|_| format!("invalid address for {}: {}", flag, value))
+}
+
+// a daemon's text logs are never coloured, its stderr moves off the
+// terminal once it detaches
+fn logger(levels: &logging::Levels, format: LogFormat, config: &LogConfig, daemon: bool) -> Logger {
+    let text_drain = || text_drain(daemon);
+
+    let drain = match format {
+        LogFormat::Text => text_drain(),
+        LogFormat::Json => {
+            // ts, level and msg alongside the record's own keys
+            let drain = slog_json::Json::new(io::stderr()).add_default_keys().build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+        LogFormat::Syslog => match logging::Syslog::open(&config.syslog) {
+            Ok(drain) => slog_async::Async::new(drain.ignore_res()).build().fuse(),
+            Err(e) => {
+                eprintln!("edicast: could not connect to syslog, logging to the terminal instead: {}", e);
+                text_drain()
+            }
+        },
+        #[cfg(unix)]
+        LogFormat::Journald => match logging::Journald::open() {
+            Ok(drain) => slog_async::Async::new(drain.ignore_res()).build().fuse(),
+            Err(e) => {
+                eprintln!("edicast: could not connect to journald, logging to the terminal instead: {}", e);
+                text_drain()
+            }
+        },
+        #[cfg(not(unix))]
+        LogFormat::Journald => {
+            eprintln!("edicast: journald is only available on unix, logging to the terminal instead");
+            text_drain()
+        }
+    };
+
+    let drain = logging::LevelFilter::new(drain, levels.clone()).fuse();
+    Logger::root(drain, slog::o!())
+}
+
+fn text_drain(plain: bool) -> slog::Fuse<slog_async::Async> {
+    if plain {
+        let decorator = slog_term::PlainDecorator::new(io::stderr());
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        slog_async::Async::new(drain).build().fuse()
+    } else {
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        slog_async::Async::new(drain).build().fuse()
+    }
+}
+
 fn handle_config_error(log: &Logger, config_path: &Path, err: config::Error) {
     use config::Error;
 
@@ -48,52 +236,242 @@ fn handle_config_error(log: &Logger, config_path: &Path, err: config::Error) {
             );
         }
         Error::Toml(err) => {
+            // toml reports zero based line and column numbers
+            let (line, column) = err.line_col()
+                .map(|(line, column)| (Some(line + 1), Some(column + 1)))
+                .unwrap_or_default();
+
             slog::error!(log, "Could not parse config file";
                 "path" => config_path.display(),
+                "line" => line,
+                "column" => column,
                 "error" => err.to_string(),
             );
         }
-        Error::StreamRefersToInvalidSource { stream_name, source_name } => {
+        Error::IncludePattern(err) => {
+            slog::error!(log, "Invalid include pattern in config file";
+                "path" => config_path.display(),
+                "error" => err.to_string(),
+            );
+        }
+        Error::Include { path, error } | Error::Overlay { path, error } => {
+            handle_config_error(log, &path, *error);
+        }
+        Error::DuplicateSource { name, path } => {
+            slog::error!(log, "Source defined more than once";
+                "path" => path.display(),
+                "source" => name,
+            );
+        }
+        Error::DuplicateStream { name, path } => {
+            slog::error!(log, "Stream defined more than once";
+                "path" => path.display(),
+                "stream" => name,
+            );
+        }
+        Error::StreamRefersToInvalidSource { stream_name, source_name, location, suggestion } => {
             slog::error!(log, "Invalid source in stream config";
                 "path" => config_path.display(),
+                "location" => location.map(|location| location.to_string()),
                 "source" => source_name,
                 "stream" => stream_name,
+                "hint" => suggestion.map(|suggestion| format!("did you mean {:?}?", suggestion)),
+            );
+        }
+        Error::StreamRefersToInvalidCodec { stream_name, codec_name, suggestion } => {
+            slog::error!(log, "Invalid codec profile in stream config";
+                "path" => config_path.display(),
+                "codec" => codec_name,
+                "stream" => stream_name,
+                "hint" => suggestion.map(|suggestion| format!("did you mean {:?}?", suggestion)),
+            );
+        }
+        Error::JingleNeverPlays { stream_name } => {
+            slog::error!(log, "Stream jingle needs every_mins or times";
+                "path" => config_path.display(),
+                "stream" => stream_name,
+            );
+        }
+        Error::EmptyOriginSecret => {
+            slog::error!(log, "Origin secret must not be empty";
+                "path" => config_path.display(),
+            );
+        }
+        Error::DuplicateStreamPath { path, location } => {
+            slog::error!(log, "Multiple streams configured with same path";
+                "path" => config_path.display(),
+                "location" => location.map(|location| location.to_string()),
+                "stream_path" => path,
+            );
+        }
+        Error::TlsListenWithoutConfig { role } => {
+            slog::error!(log, "TLS listen address configured without certificates";
+                "path" => config_path.display(),
+                "listen" => role,
+            );
+        }
+        Error::RecordingRefersToInvalidStream { show, stream_name, suggestion } => {
+            slog::error!(log, "Invalid stream in recording config";
+                "path" => config_path.display(),
+                "show" => show,
+                "stream" => stream_name,
+                "hint" => suggestion.map(|suggestion| format!("did you mean {:?}?", suggestion)),
+            );
+        }
+        Error::CodecNotBuilt { used_by, feature } => {
+            slog::error!(log, "Codec not built into edicast, set codec to one which is";
+                "path" => config_path.display(),
+                "used_by" => used_by,
+                "feature" => feature,
             );
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    // this inner function makes sure Logger instance is cleanly dropped and
-    // any logged errors are properly flushed before we call process::exit
-    async fn run() -> Result<(), ()> {
-        let log = logger();
-        let _ = slog_scope::set_global_logger(log.clone());
+// this function makes sure Logger instance is cleanly dropped and any
+// logged errors are properly flushed before we call process::exit. edicast
+// stops on SIGTERM or SIGINT, or once stop resolves if it's given
+async fn run(args: Args, mut daemon: Option<Daemon>, stop: Option<BoxFuture<'static, ()>>) -> Result<(), ()> {
+    let levels = logging::Levels::new(args.log_level);
+    let log = logger(&levels, args.log_format.unwrap_or_default(), &LogConfig::default(), daemon.is_some());
+    let _ = slog_scope::set_global_logger(log.clone());
 
-        let config_path = config_path();
+    let config_path = args.config_path.clone();
 
-        let config = match Config::load(&config_path) {
-            Ok(config) => config,
-            Err(e) => {
+    let config = match Config::load_with_overlays(&config_path, &args.overlays) {
+        Ok(mut config) => {
+            // flags may add ?tls addresses the file didn't check for
+            args.apply(&mut config);
+
+            if let Err(e) = config.listen.validate() {
                 handle_config_error(&log, &config_path, e);
                 slog::crit!(log, "Error loading initial config");
                 return Err(());
             }
-        };
 
-        match server::run(log.clone(), config).await {
-            Ok(()) => {}
-            Err(error) => {
-                slog::crit!(log, "Error running server: {}", error);
-                return Err(());
+            config
+        }
+        Err(e) => {
+            handle_config_error(&log, &config_path, e);
+            slog::crit!(log, "Error loading initial config");
+            return Err(());
+        }
+    };
+
+    // the config file can only choose the format and levels, and give
+    // settings such as the syslog address, once it's been read
+    levels.configure(&config.log);
+
+    let format = args.log_format.unwrap_or(config.log.format);
+
+    let log = if format != LogFormat::Text {
+        let log = logger(&levels, format, &config.log, daemon.is_some());
+        let _ = slog_scope::set_global_logger(log.clone());
+        log
+    } else {
+        log
+    };
+
+    crash::install(log.clone(), &config.crash);
+
+    // the pidfile is written once listeners are bound, while still root so
+    // that it can go anywhere root's can, then handed to the user edicast
+    // runs as so that a new process can rewrite it on upgrade. the process
+    // waiting in the foreground exits once privileges are dropped too, when
+    // nothing can go wrong on the terminal that isn't logged
+    let mut pidfile = None;
+    let (user, group) = (config.user.clone(), config.group.clone());
+
+    let startup = |stage| {
+        match stage {
+            Startup::Bound => {
+                if let Some(path) = &args.pidfile {
+                    let created = Pidfile::create(path).map_err(|e| {
+                        io::Error::new(e.kind(), format!("could not write pidfile {}: {}", path.display(), e))
+                    })?;
+
+                    pidfile = Some(created);
+
+                    edicast_core::chown_to(path, user.as_deref(), group.as_deref())
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                }
+            }
+            Startup::Ready => {
+                if let Some(daemon) = daemon.take() {
+                    daemon.detach(args.output.as_deref()).map_err(|e| {
+                        io::Error::new(e.kind(), format!("could not detach from terminal: {}", e))
+                    })?;
+                }
             }
         }
 
         Ok(())
+    };
+
+    let result = match stop {
+        Some(stop) => edicast_core::run_until(log.clone(), levels, config_path, args.overlays, config, stop, startup).await,
+        None => edicast_core::run(log.clone(), levels, config_path, args.overlays, config, startup).await,
+    };
+
+    drop(pidfile);
+
+    match result {
+        Ok(()) => {}
+        Err(error) => {
+            slog::crit!(log, "Error running server: {}", error);
+            return Err(());
+        }
     }
 
-    match run().await {
+    Ok(())
+}
+
+fn main() {
+    match env::args_os().nth(1) {
+        Some(arg) if arg == "ctl" => {
+            process::exit(ctl::main(env::args_os().skip(2).collect()));
+        }
+        Some(arg) if arg == "init" => {
+            process::exit(init::main(env::args_os().skip(2).collect()));
+        }
+        Some(arg) if arg == "schema" => {
+            process::exit(schema::main(env::args_os().skip(2).collect()));
+        }
+        #[cfg(windows)]
+        Some(arg) if arg == "service" => {
+            process::exit(service::main(env::args_os().skip(2).collect()));
+        }
+        _ => {}
+    }
+
+    let args = match Args::parse(env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("edicast: {}", e);
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    // forking has to happen before the runtime or logger start threads
+    let daemon = if args.daemon {
+        match daemon::daemonize() {
+            Ok(daemon) => Some(daemon),
+            Err(e) => {
+                eprintln!("edicast: could not daemonize: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    match runtime.block_on(run(args, daemon, None)) {
         Ok(()) => {}
         Err(()) => process::exit(1),
     }
@@ -0,0 +1,76 @@
+// allocator statistics, only available when running on jemalloc
+pub struct AllocatorStats {
+    pub allocated: usize,
+    pub active: usize,
+    pub resident: usize,
+    pub mapped: usize,
+    pub retained: usize,
+    pub metadata: usize,
+    pub arenas: Vec<ArenaStats>,
+}
+
+// jemalloc spreads threads across several arenas, one arena growing on its
+// own points at whichever threads are using it
+pub struct ArenaStats {
+    pub index: u32,
+    pub threads: u32,
+    pub allocated: usize,
+    pub active: usize,
+    pub dirty: usize,
+    pub resident: usize,
+}
+
+#[cfg(not(target_env = "msvc"))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    use jemalloc_ctl::{arenas, epoch, stats};
+
+    // jemalloc caches its statistics, advancing the epoch refreshes them
+    epoch::advance().ok()?;
+
+    let arenas = (0..arenas::narenas::read().ok()?)
+        .filter_map(arena_stats)
+        .collect();
+
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()?,
+        active: stats::active::read().ok()?,
+        resident: stats::resident::read().ok()?,
+        mapped: stats::mapped::read().ok()?,
+        retained: stats::retained::read().ok()?,
+        metadata: stats::metadata::read().ok()?,
+        arenas,
+    })
+}
+
+// per arena statistics aren't wrapped by jemalloc_ctl, so are read by name.
+// arenas which were never initialised have none and are left out
+#[cfg(not(target_env = "msvc"))]
+fn arena_stats(index: u32) -> Option<ArenaStats> {
+    use jemalloc_ctl::raw;
+
+    fn read(index: u32, stat: &str) -> Option<usize> {
+        let name = format!("stats.arenas.{}.{}\0", index, stat);
+        // safety: all of the stats read through here are size_t
+        unsafe { raw::read::<usize>(name.as_bytes()).ok() }
+    }
+
+    // safety: arenas.page is a size_t and nthreads an unsigned int
+    let page: usize = unsafe { raw::read(b"arenas.page\0").ok()? };
+    let threads: u32 = unsafe {
+        raw::read(format!("stats.arenas.{}.nthreads\0", index).as_bytes()).ok()?
+    };
+
+    Some(ArenaStats {
+        index,
+        threads,
+        allocated: read(index, "small.allocated")? + read(index, "large.allocated")?,
+        active: read(index, "pactive")? * page,
+        dirty: read(index, "pdirty")? * page,
+        resident: read(index, "resident")?,
+    })
+}
+
+#[cfg(target_env = "msvc")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+// interval in bytes of audio data between ICY metadata blocks
+pub const ICY_METAINT: usize = 16000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub ad_break: Option<AdBreak>,
+}
+
+// an ad break is a cue-out from programme content. ending the ad break
+// (cue-in) is signalled by the ad_break field returning to None
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdBreak {
+    pub duration: Option<Duration>,
+}
+
+impl Metadata {
+    pub fn icy_text(&self) -> String {
+        let mut text = format!("StreamTitle='{}';",
+            icy_escape(self.title.as_deref().unwrap_or_default()));
+
+        // ad cue points follow the adw_ad convention understood by most
+        // downstream ad insertion services
+        if let Some(ad_break) = &self.ad_break {
+            text.push_str("adw_ad='true';");
+
+            if let Some(duration) = ad_break.duration {
+                text.push_str(&format!("durationMilliseconds='{}';", duration.as_millis()));
+            }
+        }
+
+        text
+    }
+
+    // encodes metadata as an ICY metadata block: a length byte counting
+    // 16 byte units, followed by the zero padded metadata text
+    pub fn icy_block(&self) -> Bytes {
+        let text = self.icy_text();
+        let text = &text.as_bytes()[..text.len().min(255 * 16)];
+        let units = (text.len() + 15) / 16;
+
+        let mut block = BytesMut::with_capacity(1 + units * 16);
+        block.put_u8(units as u8);
+        block.put_slice(text);
+        block.resize(1 + units * 16, 0);
+        block.freeze()
+    }
+}
+
+// the ICY format has no escaping mechanism for quotes, so replace them with
+// a typographic apostrophe rather than let them terminate the value early
+fn icy_escape(value: &str) -> String {
+    value.replace('\'', "\u{2019}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn titled(title: &str) -> Metadata {
+        Metadata { title: Some(title.to_owned()), ad_break: None }
+    }
+
+    #[test]
+    fn icy_text() {
+        assert_eq!(Metadata::default().icy_text(), "StreamTitle='';");
+        assert_eq!(titled("It's Late").icy_text(), "StreamTitle='It\u{2019}s Late';");
+
+        let ad_break = Metadata {
+            title: None,
+            ad_break: Some(AdBreak { duration: Some(Duration::from_secs(30)) }),
+        };
+
+        assert_eq!(ad_break.icy_text(), "StreamTitle='';adw_ad='true';durationMilliseconds='30000';");
+    }
+
+    #[test]
+    fn icy_block_is_padded_to_sixteen_bytes() {
+        // 15 bytes of text
+        let block = Metadata::default().icy_block();
+        assert_eq!(block.len(), 17);
+        assert_eq!(block[0], 1);
+        assert_eq!(&block[1..16], b"StreamTitle='';");
+        assert_eq!(block[16], 0);
+
+        // exactly 32 bytes of text, which needs no padding
+        let block = titled("0123456789abcdefg").icy_block();
+        assert_eq!(block.len(), 33);
+        assert_eq!(block[0], 2);
+        assert_eq!(&block[1..], b"StreamTitle='0123456789abcdefg';");
+    }
+
+    #[test]
+    fn icy_block_is_truncated_to_the_longest_length() {
+        let block = titled(&"x".repeat(5000)).icy_block();
+        assert_eq!(block[0], 255);
+        assert_eq!(block.len(), 1 + 255 * 16);
+    }
+}
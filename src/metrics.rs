@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use slog::Logger;
+
+use crate::config::{MetricsConfig, MetricsProtocol};
+use crate::crash;
+use crate::memory::{self, AllocatorStats, ArenaStats};
+use crate::net::client;
+use crate::server::Edicast;
+use crate::source::SourceStatus;
+
+// keep statsd and influx datagrams under a typical ethernet MTU
+const MAX_DATAGRAM: usize = 1400;
+
+struct StreamMetrics {
+    listeners: u64,
+    // across every server in the cluster, if clustering
+    cluster_listeners: Option<u64>,
+    bytes_sent: u64,
+    uptime_secs: u64,
+    source_lost: bool,
+}
+
+struct SourceMetrics {
+    live: bool,
+    level: u16,
+    uptime_secs: u64,
+    restarts: u32,
+}
+
+fn elapsed_secs(since: Option<SystemTime>) -> u64 {
+    since.and_then(|time| time.elapsed().ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// point in time view of the gauges also available from the control API
+struct Snapshot {
+    streams: BTreeMap<String, StreamMetrics>,
+    sources: BTreeMap<String, SourceMetrics>,
+    crashes: u64,
+    allocator: Option<AllocatorStats>,
+    arenas: BTreeMap<String, ArenaStats>,
+}
+
+impl Snapshot {
+    fn collect(edicast: &Edicast) -> Self {
+        let mut streams = edicast.streams.list().into_iter()
+            .map(|(name, _)| {
+                let uptime_secs = elapsed_secs(edicast.streams.started_at(&name));
+                let source_lost = edicast.streams.is_source_lost(&name);
+                (name, StreamMetrics { listeners: 0, cluster_listeners: None, bytes_sent: 0, uptime_secs, source_lost })
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        for listener in edicast.listeners.list() {
+            if let Some(stream) = streams.get_mut(&listener.stream()) {
+                stream.listeners += 1;
+                stream.bytes_sent += listener.bytes_sent();
+            }
+        }
+
+        if let Some(cluster) = &edicast.cluster {
+            for (name, stream) in &mut streams {
+                stream.cluster_listeners = Some(stream.listeners + cluster.remote_listeners(name) as u64);
+            }
+        }
+
+        let sources = edicast.sources.names().into_iter()
+            .filter_map(|name| {
+                let live = *edicast.sources.status(&name)?.borrow() == SourceStatus::Live;
+                let level = edicast.sources.level(&name)?;
+                let uptime_secs = elapsed_secs(edicast.sources.uptime(&name)?.live_since);
+                let restarts = edicast.sources.restarts(&name)?;
+                Some((name, SourceMetrics { live, level, uptime_secs, restarts }))
+            })
+            .collect();
+
+        let mut allocator = memory::allocator_stats();
+
+        let arenas = allocator.iter_mut()
+            .flat_map(|allocator| std::mem::take(&mut allocator.arenas))
+            .map(|arena| (arena.index.to_string(), arena))
+            .collect();
+
+        Snapshot { streams, sources, crashes: crash::count(), allocator, arenas }
+    }
+
+    fn gauges(&self) -> Vec<Gauge<'_>> {
+        let mut gauges = Vec::new();
+
+        for (name, stream) in &self.streams {
+            gauges.push(Gauge { group: "stream", name, field: "listeners", value: stream.listeners });
+
+            if let Some(cluster_listeners) = stream.cluster_listeners {
+                gauges.push(Gauge { group: "stream", name, field: "cluster_listeners", value: cluster_listeners });
+            }
+
+            gauges.push(Gauge { group: "stream", name, field: "bytes_sent", value: stream.bytes_sent });
+            gauges.push(Gauge { group: "stream", name, field: "uptime_secs", value: stream.uptime_secs });
+            gauges.push(Gauge { group: "stream", name, field: "source_lost", value: stream.source_lost as u64 });
+        }
+
+        for (name, source) in &self.sources {
+            gauges.push(Gauge { group: "source", name, field: "live", value: source.live as u64 });
+            gauges.push(Gauge { group: "source", name, field: "level", value: source.level as u64 });
+            gauges.push(Gauge { group: "source", name, field: "uptime_secs", value: source.uptime_secs });
+            gauges.push(Gauge { group: "source", name, field: "restarts", value: source.restarts as u64 });
+        }
+
+        gauges.push(Gauge { group: "process", name: "edicast", field: "crashes", value: self.crashes });
+
+        if let Some(allocator) = &self.allocator {
+            gauges.push(Gauge { group: "process", name: "edicast", field: "allocated_bytes", value: allocator.allocated as u64 });
+            gauges.push(Gauge { group: "process", name: "edicast", field: "active_bytes", value: allocator.active as u64 });
+            gauges.push(Gauge { group: "process", name: "edicast", field: "resident_bytes", value: allocator.resident as u64 });
+            gauges.push(Gauge { group: "process", name: "edicast", field: "mapped_bytes", value: allocator.mapped as u64 });
+            gauges.push(Gauge { group: "process", name: "edicast", field: "retained_bytes", value: allocator.retained as u64 });
+            gauges.push(Gauge { group: "process", name: "edicast", field: "metadata_bytes", value: allocator.metadata as u64 });
+        }
+
+        for (name, arena) in &self.arenas {
+            gauges.push(Gauge { group: "arena", name, field: "threads", value: arena.threads as u64 });
+            gauges.push(Gauge { group: "arena", name, field: "allocated_bytes", value: arena.allocated as u64 });
+            gauges.push(Gauge { group: "arena", name, field: "active_bytes", value: arena.active as u64 });
+            gauges.push(Gauge { group: "arena", name, field: "dirty_bytes", value: arena.dirty as u64 });
+            gauges.push(Gauge { group: "arena", name, field: "resident_bytes", value: arena.resident as u64 });
+        }
+
+        gauges
+    }
+}
+
+// a single value for a stream or source, eg. the listeners field of the
+// stream named "live"
+struct Gauge<'a> {
+    group: &'static str,
+    name: &'a str,
+    field: &'static str,
+    value: u64,
+}
+
+// metric path components can't contain the separators used by statsd and
+// graphite, so replace anything unusual
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+fn influx_escape(value: &str) -> String {
+    value.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+#[cfg(feature = "control")]
+fn prometheus_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(feature = "control")]
+// the same gauges as are pushed, in prometheus' text format for scraping
+// from the control API instead. each gauge is named after its group and
+// field, and labelled with the name of its stream, source or arena
+pub fn prometheus(edicast: &Edicast) -> String {
+    let snapshot = Snapshot::collect(edicast);
+    let mut metrics = BTreeMap::<String, Vec<String>>::new();
+
+    for gauge in snapshot.gauges() {
+        metrics.entry(format!("edicast_{}_{}", gauge.group, gauge.field))
+            .or_default()
+            .push(format!("{{{}=\"{}\"}} {}", gauge.group, prometheus_escape(gauge.name), gauge.value));
+    }
+
+    let mut output = String::new();
+
+    for (metric, samples) in metrics {
+        output.push_str(&format!("# TYPE {} gauge\n", metric));
+
+        for sample in samples {
+            output.push_str(&metric);
+            output.push_str(&sample);
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+pub fn start(log: Logger, config: MetricsConfig, edicast: Arc<Edicast>) {
+    thread::Builder::new()
+        .name("edicast/metrics".to_owned())
+        .spawn(move || metrics_thread_main(log, config, edicast))
+        .expect("spawn edicast metrics thread");
+}
+
+fn metrics_thread_main(log: Logger, config: MetricsConfig, edicast: Arc<Edicast>) {
+    let interval = Duration::from_secs(config.interval_secs.max(1));
+
+    slog::info!(log, "Pushing metrics";
+        "protocol" => format!("{:?}", config.protocol),
+        "target" => &config.target,
+    );
+
+    loop {
+        thread::sleep(interval);
+
+        let snapshot = Snapshot::collect(&edicast);
+
+        if let Err(e) = push(&config, &snapshot) {
+            slog::warn!(log, "Could not push metrics"; "error" => e.to_string());
+        }
+    }
+}
+
+fn push(config: &MetricsConfig, snapshot: &Snapshot) -> Result<(), io::Error> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let prefix = &config.prefix;
+
+    let lines = snapshot.gauges().into_iter()
+        .map(|gauge| match config.protocol {
+            MetricsProtocol::Statsd => {
+                format!("{}.{}.{}.{}:{}|g\n",
+                    prefix, gauge.group, sanitize(gauge.name), gauge.field, gauge.value)
+            }
+            MetricsProtocol::Graphite => {
+                format!("{}.{}.{}.{} {} {}\n",
+                    prefix, gauge.group, sanitize(gauge.name), gauge.field, gauge.value,
+                    timestamp.as_secs())
+            }
+            MetricsProtocol::Influx => {
+                format!("{}_{},{}={} {}={}i {}\n",
+                    prefix, gauge.group, gauge.group, influx_escape(gauge.name), gauge.field,
+                    gauge.value, timestamp.as_nanos())
+            }
+        })
+        .collect::<Vec<_>>();
+
+    match config.protocol {
+        MetricsProtocol::Statsd | MetricsProtocol::Influx => {
+            let target = config.target.to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "could not resolve target"))?;
+
+            let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(target)?;
+
+            for datagram in datagrams(&lines) {
+                socket.send(datagram.as_bytes())?;
+            }
+        }
+        MetricsProtocol::Graphite => {
+            let mut stream = client::tcp_connect(config.target.as_str())?;
+            stream.write_all(lines.concat().as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+
+    for line in lines {
+        if !current.is_empty() && current.len() + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+
+        current.push_str(line);
+    }
+
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+
+    datagrams
+}
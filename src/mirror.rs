@@ -0,0 +1,265 @@
+// mirrors streams from a master edicast server, so that an edge server only
+// needs to be told where the master is. the master's control API is polled
+// for the streams it has, and each is pulled from the master's public server
+// into a source of its own, then re-encoded and served like any other stream
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::Logger;
+
+use crate::config::{HttpUrl, MirrorConfig, SourceConfig, StreamConfig};
+use crate::net::{client, tls};
+use crate::net::tls::ClientConfig;
+use crate::pull::{self, Pull, Upstream};
+use crate::server::Edicast;
+
+// the master is taken to be gone if it doesn't respond within this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// the stream list is small, anything past this is dropped
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+// the parts of the master's GET /streams response used here
+#[derive(Deserialize)]
+struct MasterStream {
+    name: String,
+    host: Option<String>,
+    path: String,
+    listen_url: String,
+    station_name: Option<String>,
+    description: Option<String>,
+    genre: Option<String>,
+    url: Option<String>,
+    public: bool,
+}
+
+struct Mirror {
+    log: Logger,
+    config: MirrorConfig,
+    edicast: Arc<Edicast>,
+    tls: Option<Arc<ClientConfig>>,
+    pulls: HashMap<String, Pull>,
+    // streams which couldn't be added, so that they're only logged once
+    conflicts: HashSet<String>,
+}
+
+pub fn start(log: Logger, config: MirrorConfig, edicast: Arc<Edicast>) {
+    let log = log.new(slog::o!("master" => config.control_url.to_string()));
+
+    // listen URLs given by the master may be https even when the control
+    // URL isn't, so this is only an error once one is used
+    let tls = match tls::client_config(&config.ca_file) {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            slog::warn!(log, "Could not set up TLS for mirroring, https URLs won't work";
+                "error" => e.to_string());
+            None
+        }
+    };
+
+    let mut mirror = Mirror {
+        log,
+        config,
+        edicast,
+        tls,
+        pulls: HashMap::new(),
+        conflicts: HashSet::new(),
+    };
+
+    thread::Builder::new()
+        .name("edicast/mirror".to_owned())
+        .spawn(move || loop {
+            match mirror.fetch_streams() {
+                Ok(streams) => mirror.sync(streams),
+                Err(e) => {
+                    // mirrored streams are left as they are, their pulls
+                    // retry on their own while the master is unreachable
+                    slog::warn!(mirror.log, "Could not fetch streams from master"; "error" => e.to_string());
+                }
+            }
+
+            thread::sleep(Duration::from_secs(mirror.config.poll_secs.max(1)));
+        })
+        .expect("spawn edicast mirror thread");
+}
+
+impl Mirror {
+    fn fetch_streams(&self) -> Result<Vec<MasterStream>, io::Error> {
+        let url = &self.config.control_url;
+
+        let mut request = format!(
+            "GET {}/streams HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            url.path.trim_end_matches('/'), url.authority());
+
+        if let Some(token) = &self.config.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+
+        request.push_str("\r\n");
+
+        let mut connection = client::connect(url, self.tls.as_ref(), TIMEOUT)?;
+        connection.write_all(request.as_bytes())?;
+        connection.flush()?;
+
+        let (status, _) = client::read_head(&mut connection)?;
+        let body = client::read_body(&mut connection, MAX_RESPONSE_SIZE)?;
+
+        if status != 200 {
+            let message = format!("{} {}", status, String::from_utf8_lossy(&body).trim());
+            return Err(io::Error::new(io::ErrorKind::Other, message));
+        }
+
+        serde_json::from_slice(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn sync(&mut self, streams: Vec<MasterStream>) {
+        let wanted = streams.into_iter()
+            .filter(|stream| self.config.streams.is_empty() || self.config.streams.contains(&stream.name))
+            .collect::<Vec<_>>();
+
+        for stream in &wanted {
+            self.mirror(stream);
+        }
+
+        let gone = self.pulls.keys()
+            .filter(|name| !wanted.iter().any(|stream| &stream.name == *name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in gone {
+            self.unmirror(&name);
+        }
+
+        self.conflicts.retain(|name| wanted.iter().any(|stream| &stream.name == name));
+    }
+
+    // adds whatever of the stream is missing, so that streams removed by a
+    // config reload or through the control API come back on the next poll
+    fn mirror(&mut self, stream: &MasterStream) {
+        let source = source_name(&stream.name);
+
+        match self.edicast.streams.config(&stream.name) {
+            Some(config) if config.source == source => {}
+            Some(_) => {
+                self.conflict(stream, "a stream of the same name already exists");
+                return;
+            }
+            None => {
+                if self.edicast.sources.config(&source).is_none() {
+                    let config = serde_json::from_value::<SourceConfig>(json!({}))
+                        .expect("default source config");
+
+                    // a source which already exists is left for the pull
+                    // to go live on
+                    let _ = self.edicast.sources.add_source(&source, &config);
+                }
+
+                let mut config = match self.stream_config(stream, &source) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        self.conflict(stream, &e.to_string());
+                        return;
+                    }
+                };
+
+                config.codec = self.config.codec.clone();
+
+                if let Err(e) = self.edicast.streams.add_stream(&stream.name, config, &self.edicast.sources) {
+                    self.conflict(stream, &format!("{:?}", e));
+                    return;
+                }
+
+                slog::info!(self.log, "Mirroring stream"; "stream" => &stream.name, "path" => &stream.path);
+            }
+        }
+
+        self.conflicts.remove(&stream.name);
+
+        let running = self.pulls.get(&stream.name)
+            .map_or(false, |pull| !pull.is_finished());
+
+        if running {
+            return;
+        }
+
+        let url = match &self.config.public_url {
+            Some(public_url) => Ok(HttpUrl { path: stream.path.clone(), ..public_url.clone() }),
+            None => HttpUrl::try_from(stream.listen_url.clone()),
+        };
+
+        let url = match url {
+            Ok(url) => url,
+            Err(_) => {
+                self.conflict(stream, "master gave a relative listen URL, mirror.public_url must be set");
+                return;
+            }
+        };
+
+        let urls = std::iter::once(url)
+            .chain(self.config.failover_urls.iter()
+                .map(|failover_url| HttpUrl { path: stream.path.clone(), ..failover_url.clone() }))
+            .collect();
+
+        let upstream = Upstream {
+            urls,
+            host: stream.host.clone(),
+            secret: self.config.secret.clone(),
+            check_interval: Duration::from_secs(self.config.check_secs.max(1)),
+        };
+
+        let log = self.log.new(slog::o!("stream" => stream.name.clone()));
+        let pull = pull::start(log, &source, upstream, self.tls.clone(), self.edicast.clone());
+        self.pulls.insert(stream.name.clone(), pull);
+    }
+
+    fn unmirror(&mut self, name: &str) {
+        let source = source_name(name);
+
+        if let Some(pull) = self.pulls.remove(name) {
+            pull.stop();
+        }
+
+        // only streams the mirror added itself are removed
+        if self.edicast.streams.config(name).map_or(false, |config| config.source == source) {
+            self.edicast.streams.remove_stream(name);
+        }
+
+        if self.edicast.streams.streams_for_source(&source).is_empty() {
+            self.edicast.sources.remove_source(&source);
+        }
+
+        slog::info!(self.log, "Stopped mirroring stream, it's gone from the master"; "stream" => name);
+    }
+
+    fn stream_config(&self, stream: &MasterStream, source: &str) -> Result<StreamConfig, serde_json::Error> {
+        serde_json::from_value(json!({
+            "path": stream.path,
+            "host": stream.host,
+            "source": source,
+            "name": stream.station_name,
+            "description": stream.description,
+            "genre": stream.genre,
+            "url": stream.url,
+            "public": stream.public,
+        }))
+    }
+
+    fn conflict(&mut self, stream: &MasterStream, reason: &str) {
+        if self.conflicts.insert(stream.name.clone()) {
+            slog::warn!(self.log, "Could not mirror stream";
+                "stream" => &stream.name,
+                "error" => reason,
+            );
+        }
+    }
+}
+
+fn source_name(stream: &str) -> String {
+    format!("mirror-{}", stream)
+}
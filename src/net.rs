@@ -1,7 +1,20 @@
+use std::io;
 use std::net::SocketAddr;
 use thiserror::Error;
 use tokio::net::TcpListener;
 
+#[cfg(feature = "tls")]
+pub mod acme;
+pub mod base64;
+pub mod client;
+pub mod forwarded;
+#[cfg(unix)]
+pub mod handoff;
+pub mod proxy;
+pub mod redis;
+pub mod s3;
+pub mod tls;
+
 #[derive(Error, Debug)]
 #[error("could not bind {address}")]
 pub struct BindError {
@@ -10,10 +23,74 @@ pub struct BindError {
     pub error: std::io::Error,
 }
 
-pub async fn bind(address: SocketAddr) -> Result<TcpListener, BindError> {
-    TcpListener::bind(address).await
-        .map_err(|error| BindError { address, error })
+// takes over a socket inherited from a previous edicast process if there is
+// one listening on address, otherwise binds a new one
+pub async fn bind(address: SocketAddr, interface: Option<&str>) -> Result<TcpListener, BindError> {
+    let listener = match inherited(address) {
+        Some(listener) => listener.set_nonblocking(true).and_then(|()| TcpListener::from_std(listener)),
+        None => match interface {
+            Some(interface) => bind_device(address, interface),
+            None => TcpListener::bind(address).await,
+        },
+    };
+
+    let listener = listener.map_err(|error| BindError { address, error })?;
+
+    #[cfg(unix)]
+    handoff::register(&listener);
+
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn inherited(address: SocketAddr) -> Option<std::net::TcpListener> {
+    handoff::take(address)
+}
+
+#[cfg(not(unix))]
+fn inherited(_: SocketAddr) -> Option<std::net::TcpListener> {
+    None
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(address: SocketAddr, interface: &str) -> Result<TcpListener, io::Error> {
+    use tokio::net::TcpSocket;
+
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    // same as TcpListener::bind does
+    socket.set_reuseaddr(true)?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_: SocketAddr, _: &str) -> Result<TcpListener, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "binding to an interface is not supported on this platform"))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn connect_device(address: SocketAddr, interface: &str) -> Result<std::net::TcpStream, io::Error> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.connect(&address.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn connect_device(_: SocketAddr, _: &str) -> Result<std::net::TcpStream, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "binding to an interface is not supported on this platform"))
 }
 
 #[derive(Debug)]
 pub struct SocketPeer(pub SocketAddr);
+
+#[cfg(feature = "control")]
+#[derive(Debug)]
+pub struct ConnectionPeer(pub SocketAddr);
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus,
+};
+use bytes::Bytes;
+use futures::future::OptionFuture;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::{body, Request, Response, StatusCode};
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+use slog::Logger;
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+use crate::config::{AcmeChallenge, AcmeConfig, TlsConfig};
+use super::tls::Acceptor;
+use super::BindError;
+
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// how often to check whether the certificate is due for renewal, and how
+// soon to try again after a failed attempt
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// how long to wait for the ACME server to validate challenges and issue the
+// certificate, polling with exponential backoff
+const POLL_ATTEMPTS: usize = 10;
+const POLL_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum AcmeError {
+    #[error("ACME request failed: {0}")]
+    Acme(#[from] instant_acme::Error),
+    #[error("could not read or write file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid account credentials: {0}")]
+    Credentials(#[from] serde_json::Error),
+    #[error("could not generate certificate request: {0}")]
+    Csr(#[from] rcgen::RcgenError),
+    #[error("authorization for {0} is {1}")]
+    Authorization(String, String),
+    #[error("server offered no {1} challenge for {0}")]
+    NoChallenge(String, &'static str),
+    #[error("dns-01 challenge requires dns_hook")]
+    NoDnsHook,
+    #[error("dns_hook exited with {0}")]
+    DnsHook(std::process::ExitStatus),
+    #[error("order is invalid")]
+    OrderInvalid,
+    #[error("timed out waiting for the ACME server")]
+    Timeout,
+}
+
+// key authorizations for pending http-01 challenges, by token. these are
+// served by the public listener under CHALLENGE_PATH
+#[derive(Clone, Default)]
+pub struct Challenges(Arc<Mutex<HashMap<String, String>>>);
+
+impl Challenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0.lock().expect("lock challenges").get(token).cloned()
+    }
+
+    fn insert(&self, token: String, key_authorization: String) {
+        self.0.lock().expect("lock challenges").insert(token, key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.0.lock().expect("lock challenges").remove(token);
+    }
+}
+
+// keeps the certificate in tls issued and renewed, swapping each new
+// certificate into acceptor as it arrives
+pub async fn start(
+    log: Logger,
+    tls: TlsConfig,
+    acme: AcmeConfig,
+    acceptor: Acceptor,
+    challenges: Challenges,
+    interface: Option<&str>,
+) -> Result<impl Future<Output = ()>, BindError> {
+    let http_listener = match acme.http_listen {
+        Some(address) => Some(super::bind(address, interface).await?),
+        None => None,
+    };
+
+    Ok(crate::thread::spawn_worker("edicast/acme", async move {
+        let http = OptionFuture::from(http_listener.map(|listener| {
+            serve_challenges(listener, log.clone(), challenges.clone())
+        }));
+
+        let renew = async {
+            loop {
+                if !renewal_due(&tls, &acme) {
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                    continue;
+                }
+
+                slog::info!(log, "Requesting certificate"; "domains" => acme.domains.join(", "));
+
+                match issue(&log, &tls, &acme, &challenges).await {
+                    Ok(()) => {
+                        match acceptor.reload(&tls) {
+                            Ok(()) => slog::info!(log, "Installed new certificate"),
+                            Err(e) => slog::error!(log, "Could not load new certificate"; "error" => e.to_string()),
+                        }
+                    }
+                    Err(e) => {
+                        slog::error!(log, "Could not obtain certificate"; "error" => e.to_string());
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        };
+
+        futures::future::join(http, renew).await;
+    }))
+}
+
+// answers http-01 challenges over plain http, and redirects everything else
+// to https on the same host
+async fn serve_challenges(listener: TcpListener, log: Logger, challenges: Challenges) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(result) => result,
+            Err(err) => {
+                slog::warn!(log, "error accepting connection: {}", err);
+                continue;
+            }
+        };
+
+        let service = hyper::service::service_fn({
+            let challenges = challenges.clone();
+            move |req| {
+                let response = challenge_response(&req, &challenges);
+                async move { Ok::<_, Infallible>(response) }
+            }
+        });
+
+        let log = log.clone();
+
+        tokio::task::spawn_local(async move {
+            if let Err(err) = http1::Builder::new().serve_connection(stream, service).await {
+                slog::debug!(log, "error serving connection: {}", err);
+            }
+        });
+    }
+}
+
+fn challenge_response(req: &Request<body::Incoming>, challenges: &Challenges) -> Response<Full<Bytes>> {
+    let path = req.uri().path();
+
+    if let Some(key_authorization) = path.strip_prefix(CHALLENGE_PATH).and_then(|token| challenges.get(token)) {
+        return Response::builder()
+            .header("content-type", "application/octet-stream")
+            .status(StatusCode::OK)
+            .body(Full::new(Bytes::from(key_authorization)))
+            .expect("build response");
+    }
+
+    let host = req.headers().get("host").and_then(|host| host.to_str().ok());
+
+    match host {
+        Some(host) => {
+            let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+            Response::builder()
+                .header("location", format!("https://{}{}", host, path_and_query))
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .body(Full::new(Bytes::new()))
+                .expect("build response")
+        }
+        None => {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::new(Bytes::new()))
+                .expect("build response")
+        }
+    }
+}
+
+// the certificate file is only written on issue, so its age tells us how
+// long ago the certificate was issued without having to parse it
+fn renewal_due(tls: &TlsConfig, acme: &AcmeConfig) -> bool {
+    let renew_after = Duration::from_secs(acme.renew_after_days * 24 * 60 * 60);
+
+    fs::metadata(&tls.cert)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| {
+            SystemTime::now().duration_since(modified).unwrap_or_default() >= renew_after
+        })
+        .unwrap_or(true)
+}
+
+async fn account(acme: &AcmeConfig) -> Result<Account, AcmeError> {
+    if acme.account.exists() {
+        let json = fs::read_to_string(&acme.account)?;
+        let credentials = serde_json::from_str::<AccountCredentials>(&json)?;
+        return Ok(Account::from_credentials(credentials)?);
+    }
+
+    let contact = acme.contact.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let account = Account::create(&NewAccount {
+        contact: &contact,
+        terms_of_service_agreed: true,
+        only_return_existing: false,
+    }, &acme.directory, None).await?;
+
+    let json = serde_json::to_string(&account.credentials())?;
+    write_private(&acme.account, json.as_bytes())?;
+
+    Ok(account)
+}
+
+async fn issue(log: &Logger, tls: &TlsConfig, acme: &AcmeConfig, challenges: &Challenges)
+    -> Result<(), AcmeError>
+{
+    let account = account(acme).await?;
+
+    let identifiers = acme.domains.iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect::<Vec<_>>();
+
+    let mut order = account.new_order(&NewOrder { identifiers: &identifiers }).await?;
+    let authorizations = order.authorizations().await?;
+
+    // withdrawn again whichever way this function returns
+    let mut published = Published {
+        challenges,
+        dns_hook: acme.dns_hook.as_deref(),
+        log,
+        tokens: Vec::new(),
+        records: Vec::new(),
+    };
+
+    let (challenge_type, challenge_name) = match acme.challenge {
+        AcmeChallenge::Http01 => (ChallengeType::Http01, "http-01"),
+        AcmeChallenge::Dns01 => (ChallengeType::Dns01, "dns-01"),
+    };
+
+    for authorization in &authorizations {
+        let Identifier::Dns(domain) = &authorization.identifier;
+
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            _ => {
+                let status = format!("{:?}", authorization.status);
+                return Err(AcmeError::Authorization(domain.clone(), status));
+            }
+        }
+
+        let challenge = authorization.challenges.iter()
+            .find(|challenge| challenge.r#type == challenge_type)
+            .ok_or_else(|| AcmeError::NoChallenge(domain.clone(), challenge_name))?;
+
+        let key_authorization = order.key_authorization(challenge);
+
+        match acme.challenge {
+            AcmeChallenge::Http01 => {
+                published.token(challenge.token.clone(), key_authorization.as_str().to_owned());
+            }
+            AcmeChallenge::Dns01 => {
+                // wildcard domains are validated on the base domain
+                let name = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+                published.record(name, key_authorization.dns_value())?;
+            }
+        }
+
+        order.set_challenge_ready(&challenge.url).await?;
+    }
+
+    let mut delay = POLL_INITIAL_DELAY;
+    let mut ready = false;
+
+    for _ in 0..POLL_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+
+        match order.refresh().await?.status {
+            OrderStatus::Ready => {
+                ready = true;
+                break;
+            }
+            OrderStatus::Invalid => return Err(AcmeError::OrderInvalid),
+            _ => delay *= 2,
+        }
+    }
+
+    if !ready {
+        return Err(AcmeError::Timeout);
+    }
+
+    let mut params = CertificateParams::new(acme.domains.clone());
+    params.distinguished_name = DistinguishedName::new();
+    let certificate = Certificate::from_params(params)?;
+
+    order.finalize(&certificate.serialize_request_der()?).await?;
+
+    let mut delay = POLL_INITIAL_DELAY;
+    let mut chain = None;
+
+    for _ in 0..POLL_ATTEMPTS {
+        if let Some(pem) = order.certificate().await? {
+            chain = Some(pem);
+            break;
+        }
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+
+    let chain = chain.ok_or(AcmeError::Timeout)?;
+
+    // the key goes first so that the certificate is never newer than it
+    write_private(&tls.key, certificate.serialize_private_key_pem().as_bytes())?;
+    write_atomic(&tls.cert, chain.as_bytes())?;
+
+    Ok(())
+}
+
+// challenge responses published for one order
+struct Published<'a> {
+    challenges: &'a Challenges,
+    dns_hook: Option<&'a Path>,
+    log: &'a Logger,
+    tokens: Vec<String>,
+    records: Vec<(String, String)>,
+}
+
+impl<'a> Published<'a> {
+    fn token(&mut self, token: String, key_authorization: String) {
+        self.challenges.insert(token.clone(), key_authorization);
+        self.tokens.push(token);
+    }
+
+    fn record(&mut self, name: String, value: String) -> Result<(), AcmeError> {
+        let hook = self.dns_hook.ok_or(AcmeError::NoDnsHook)?;
+        let status = Command::new(hook).arg("set").arg(&name).arg(&value).status()?;
+
+        if !status.success() {
+            return Err(AcmeError::DnsHook(status));
+        }
+
+        self.records.push((name, value));
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Published<'a> {
+    fn drop(&mut self) {
+        for token in &self.tokens {
+            self.challenges.remove(token);
+        }
+
+        let hook = match self.dns_hook {
+            Some(hook) => hook,
+            None => return,
+        };
+
+        for (name, value) in &self.records {
+            let result = Command::new(hook).arg("clear").arg(name).arg(value).status();
+
+            if !matches!(result, Ok(status) if status.success()) {
+                slog::warn!(self.log, "Could not clear ACME challenge record"; "name" => name);
+            }
+        }
+    }
+}
+
+// writes to a temporary file first so that a crash never leaves a
+// truncated certificate behind
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let tmp = path.with_extension("tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(&tmp)?.write_all(contents)?;
+    fs::rename(&tmp, path)
+}
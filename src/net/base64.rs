@@ -0,0 +1,107 @@
+// standard base64 with padding, as used in HTTP basic authentication
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+
+    for chunk in input.chunks(3) {
+        let mut bytes = [0; 4];
+        bytes[1..][..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes(bytes);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(feature = "ingest-icecast")]
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.trim_end_matches('=').as_bytes();
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+
+    for chunk in input.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+
+        let mut bits = 0;
+
+        for (i, c) in chunk.iter().enumerate() {
+            bits |= value(*c)? << (18 - 6 * i);
+        }
+
+        let bytes = bits.to_be_bytes();
+        output.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the test vectors from RFC 4648
+    const VECTORS: &[(&str, &str)] = &[
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_rfc_4648_vectors() {
+        for (input, output) in VECTORS {
+            assert_eq!(encode(input.as_bytes()), *output, "encoding {:?}", input);
+        }
+    }
+
+    #[test]
+    fn encodes_the_whole_alphabet() {
+        assert_eq!(encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+        assert_eq!(encode(b"user:pass"), "dXNlcjpwYXNz");
+    }
+
+    #[cfg(feature = "ingest-icecast")]
+    #[test]
+    fn decodes_rfc_4648_vectors() {
+        for (output, input) in VECTORS {
+            assert_eq!(decode(input).as_deref(), Some(output.as_bytes()), "decoding {:?}", input);
+        }
+    }
+
+    #[cfg(feature = "ingest-icecast")]
+    #[test]
+    fn decodes_without_padding() {
+        assert_eq!(decode("Zm9vYg").as_deref(), Some(&b"foob"[..]));
+    }
+
+    #[cfg(feature = "ingest-icecast")]
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(decode("Zm9v!"), None);
+        assert_eq!(decode("Zm9vY"), None);
+        assert_eq!(decode("Zm 9v"), None);
+    }
+}
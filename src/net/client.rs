@@ -0,0 +1,175 @@
+// blocking connections out to other servers, over TCP or TLS. these are
+// used from dedicated threads, for occasional requests and for relays which
+// write to one connection for hours
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "ingest-pull")]
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{ClientConnection, ServerName, StreamOwned};
+
+use crate::config::HttpUrl;
+use super::tls::ClientConfig;
+
+// response heads longer than this are rejected
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// the network interface connections out are made on, from
+// listen.interface.outbound. set once as edicast starts
+static INTERFACE: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_interface(interface: Option<String>) {
+    *INTERFACE.lock().expect("lock outbound interface") = interface;
+}
+
+// connects on the outbound interface if there is one, trying each address
+// in turn as TcpStream::connect does
+pub fn tcp_connect(address: impl ToSocketAddrs) -> Result<TcpStream, io::Error> {
+    let interface = match INTERFACE.lock().expect("lock outbound interface").clone() {
+        Some(interface) => interface,
+        None => return TcpStream::connect(address),
+    };
+
+    let mut last_error = None;
+
+    for address in address.to_socket_addrs()? {
+        match super::connect_device(address, &interface) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+    }))
+}
+
+pub enum Connection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+// reads and writes taking longer than timeout fail, so that a server which
+// stops responding is noticed
+pub fn connect(url: &HttpUrl, tls: Option<&Arc<ClientConfig>>, timeout: Duration) -> Result<Connection, io::Error> {
+    let stream = tcp_connect((url.host.as_str(), url.port))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    match (url.tls, tls) {
+        (false, _) => Ok(Connection::Plain(stream)),
+        #[cfg(feature = "tls")]
+        (true, Some(tls)) => {
+            let server_name = ServerName::try_from(url.host.as_str())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+            let connection = ClientConnection::new(tls.clone(), server_name)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+            Ok(Connection::Tls(Box::new(StreamOwned::new(connection, stream))))
+        }
+        #[cfg(not(feature = "tls"))]
+        (true, Some(tls)) => match **tls {},
+        (true, None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "no TLS config for https URL")),
+    }
+}
+
+#[cfg(feature = "ingest-pull")]
+impl Connection {
+    // another handle on the underlying socket, for shutting it down from
+    // another thread while this one is blocked reading it
+    pub fn socket(&self) -> Result<TcpStream, io::Error> {
+        match self {
+            Connection::Plain(stream) => stream.try_clone(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.sock.try_clone(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// reads a response head, leaving anything after it unread, and returns its
+// status code along with the head
+pub fn read_head(connection: &mut impl Read) -> Result<(u16, String), io::Error> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too long"));
+        }
+
+        connection.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head).into_owned();
+
+    let status = head.split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))?;
+
+    Ok((status, head))
+}
+
+// reads the rest of a response that isn't chunked, up to max bytes
+pub fn read_body(connection: &mut impl Read, max: usize) -> Result<Vec<u8>, io::Error> {
+    let mut body = Vec::new();
+    let mut buffer = [0; 4096];
+
+    while body.len() < max {
+        match connection.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => body.extend_from_slice(&buffer[..n]),
+            // some servers close the connection without ending the TLS
+            // session, which is fine once the response is in
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(body)
+}
+
+#[cfg(feature = "ingest-pull")]
+// value of a header in a head returned by read_head
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
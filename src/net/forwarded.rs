@@ -0,0 +1,144 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+use crate::config::ForwardedHeader;
+
+// works out the real client address of a request which came through one or
+// more trusted proxies, from the one forwarding header they're configured to
+// set. each proxy appends the address it received the request from, so the
+// list is walked from the right and the first address we don't trust is the
+// client. addresses in these headers rarely carry a port, in which case it's 0
+pub fn client_addr(
+    peer: SocketAddr,
+    header: ForwardedHeader,
+    value: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> SocketAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(&ip));
+
+    if !is_trusted(peer.ip()) {
+        return peer;
+    }
+
+    let chain = match (header, value) {
+        (_, None) => return peer,
+        (ForwardedHeader::Forwarded, Some(forwarded)) => forwarded_chain(forwarded),
+        (ForwardedHeader::XForwardedFor, Some(x_forwarded_for)) => x_forwarded_for.split(',').map(parse_node).collect(),
+    };
+
+    let mut client = peer;
+
+    for node in chain.into_iter().rev() {
+        // an address we can't make sense of was either added by an
+        // untrusted client or obfuscated, so stop at the last known hop
+        let addr = match node {
+            Some(addr) => addr,
+            None => break,
+        };
+
+        client = addr;
+
+        if !is_trusted(addr.ip()) {
+            break;
+        }
+    }
+
+    client
+}
+
+// the for= parameter of each element of a Forwarded header (RFC 7239)
+fn forwarded_chain(header: &str) -> Vec<Option<SocketAddr>> {
+    header.split(',')
+        .map(|element| {
+            element.split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value))
+        })
+        .collect()
+}
+
+// accepts "1.2.3.4", "1.2.3.4:80", "2001:db8::1", "[2001:db8::1]" and
+// "[2001:db8::1]:80", optionally in double quotes
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let peer = addr("192.0.2.1:1234");
+        assert_eq!(client_addr(peer, ForwardedHeader::XForwardedFor, Some("198.51.100.1"), &trusted()), peer);
+        assert_eq!(client_addr(peer, ForwardedHeader::Forwarded, Some("for=198.51.100.1"), &trusted()), peer);
+    }
+
+    #[test]
+    fn uses_the_peer_without_headers() {
+        let peer = addr("10.0.0.1:1234");
+        assert_eq!(client_addr(peer, ForwardedHeader::XForwardedFor, None, &trusted()), peer);
+        assert_eq!(client_addr(peer, ForwardedHeader::Forwarded, None, &trusted()), peer);
+    }
+
+    #[test]
+    fn parses_x_forwarded_for() {
+        let peer = addr("10.0.0.1:1234");
+        let xff = |value| client_addr(peer, ForwardedHeader::XForwardedFor, Some(value), &trusted());
+
+        assert_eq!(xff("198.51.100.1"), addr("198.51.100.1:0"));
+        assert_eq!(xff("198.51.100.1, 10.0.0.2"), addr("198.51.100.1:0"));
+        assert_eq!(xff("198.51.100.1:5678"), addr("198.51.100.1:5678"));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_address() {
+        let peer = addr("10.0.0.1:1234");
+
+        // the client can put whatever it likes at the start of the list
+        assert_eq!(client_addr(peer, ForwardedHeader::XForwardedFor, Some("203.0.113.1, 198.51.100.1, 10.0.0.2"), &trusted()), addr("198.51.100.1:0"));
+    }
+
+    #[test]
+    fn stops_at_unparseable_addresses() {
+        let peer = addr("10.0.0.1:1234");
+
+        assert_eq!(client_addr(peer, ForwardedHeader::XForwardedFor, Some("198.51.100.1, garbage, 10.0.0.2"), &trusted()), addr("10.0.0.2:0"));
+        assert_eq!(client_addr(peer, ForwardedHeader::Forwarded, Some("for=_hidden"), &trusted()), peer);
+    }
+
+    #[test]
+    fn parses_forwarded() {
+        let peer = addr("10.0.0.1:1234");
+        let forwarded = |value| client_addr(peer, ForwardedHeader::Forwarded, Some(value), &trusted());
+
+        assert_eq!(forwarded("for=198.51.100.1"), addr("198.51.100.1:0"));
+        assert_eq!(forwarded("For=\"198.51.100.1:5678\";proto=https"), addr("198.51.100.1:5678"));
+        assert_eq!(forwarded("proto=https;for=\"[2001:db8::1]:5678\""), addr("[2001:db8::1]:5678"));
+        assert_eq!(forwarded("for=\"[2001:db8::1]\""), addr("[2001:db8::1]:0"));
+        assert_eq!(forwarded("for=203.0.113.1, for=198.51.100.1;by=10.0.0.1, for=10.0.0.2"), addr("198.51.100.1:0"));
+    }
+
+    #[test]
+    fn stops_at_the_last_trusted_proxy_when_all_are_trusted() {
+        let peer = addr("10.0.0.1:1234");
+        assert_eq!(client_addr(peer, ForwardedHeader::XForwardedFor, Some("10.0.0.3, 10.0.0.2"), &trusted()), addr("10.0.0.3:0"));
+    }
+}
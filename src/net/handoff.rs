@@ -0,0 +1,199 @@
+// listening sockets passed between processes, so that a new edicast binary
+// can take over accepting connections from a running one without the
+// sockets ever closing. sockets from systemd socket activation are taken
+// the same way. the new process says when it's ready over a pipe, as a
+// daemon does to the process waiting on it in the foreground
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::{Child, Command};
+use std::sync::Mutex;
+
+use slog::Logger;
+
+const LISTEN_FDS_VAR: &str = "EDICAST_LISTEN_FDS";
+const READY_FD_VAR: &str = "EDICAST_READY_FD";
+
+// systemd passes sockets starting from this fd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+static INHERITED: Mutex<Vec<TcpListener>> = Mutex::new(Vec::new());
+static BOUND: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+static READY: Mutex<Option<File>> = Mutex::new(None);
+
+// takes ownership of listening sockets passed by a previous edicast process
+// or by systemd. call once at startup, before any other files are opened
+pub fn inherit(log: &Logger) {
+    let mut inherited = INHERITED.lock().expect("lock inherited sockets");
+
+    for fd in inherited_fds() {
+        // safety: these fds were passed for us to own, and nothing else in
+        // this process has touched them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+        match listener.local_addr() {
+            Ok(address) => {
+                slog::info!(log, "Inherited listening socket"; "address" => address.to_string(), "fd" => fd);
+                inherited.push(listener);
+            }
+            Err(e) => {
+                slog::warn!(log, "Ignoring inherited fd"; "fd" => fd, "error" => e.to_string());
+            }
+        }
+    }
+
+    if let Some(fd) = env::var(READY_FD_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        // safety: as for the listening sockets
+        let ready = unsafe { File::from_raw_fd(fd) };
+
+        // a command run by a hook mustn't hold it open after we exit
+        let _ = set_cloexec(fd, true);
+        *READY.lock().expect("lock ready pipe") = Some(ready);
+    }
+
+    // not for any processes we start
+    env::remove_var(LISTEN_FDS_VAR);
+    env::remove_var(READY_FD_VAR);
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+}
+
+fn inherited_fds() -> Vec<RawFd> {
+    if let Ok(fds) = env::var(LISTEN_FDS_VAR) {
+        return fds.split(',').filter_map(|fd| fd.parse().ok()).collect();
+    }
+
+    let for_us = env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+
+    if !for_us {
+        return Vec::new();
+    }
+
+    let count = env::var("LISTEN_FDS").ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+}
+
+// an inherited socket already listening on address, if there is one
+pub fn take(address: SocketAddr) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().expect("lock inherited sockets");
+    let index = inherited.iter().position(|listener| listener.local_addr().ok() == Some(address))?;
+    Some(inherited.swap_remove(index))
+}
+
+// records a listening socket to hand on to the next process on upgrade
+pub fn register(listener: &impl AsRawFd) {
+    BOUND.lock().expect("lock bound sockets").push(listener.as_raw_fd());
+}
+
+// tells the process which handed us its sockets that we're ready to accept
+// on them, so that it can stop. does nothing if there wasn't one, or it has
+// already gone away
+pub fn notify_ready() {
+    if let Some(mut ready) = READY.lock().expect("lock ready pipe").take() {
+        let _ = ready.write_all(&[0]);
+    }
+}
+
+// a new edicast process which has been handed every listening socket
+pub struct Successor {
+    child: Child,
+    ready: File,
+}
+
+impl Successor {
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    // blocks until the new process is ready to accept connections. returns
+    // false if it exited without getting that far, having reported why
+    pub fn wait_ready(mut self) -> bool {
+        let mut byte = [0u8; 1];
+
+        match self.ready.read(&mut byte) {
+            Ok(1) => true,
+            _ => {
+                let _ = self.child.wait();
+                false
+            }
+        }
+    }
+}
+
+// starts the current executable again with the same arguments, passing it
+// every listening socket and a pipe to say when it's ready on
+pub fn spawn_successor() -> Result<Successor, io::Error> {
+    let (ready, ready_tx) = pipe()?;
+
+    let mut fds = BOUND.lock().expect("lock bound sockets").clone();
+    let fds_var = fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join(",");
+
+    fds.push(ready_tx.as_raw_fd());
+
+    for fd in &fds {
+        set_cloexec(*fd, false)?;
+    }
+
+    let result = Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, fds_var)
+        .env(READY_FD_VAR, ready_tx.as_raw_fd().to_string())
+        .spawn();
+
+    // keep the sockets out of anything else we start
+    for fd in &fds {
+        let _ = set_cloexec(*fd, true);
+    }
+
+    // only the new process may hold the write end, so that reading from
+    // the pipe ends if it exits
+    drop(ready_tx);
+
+    Ok(Successor { child: result?, ready })
+}
+
+// both ends close on exec unless cleared for a particular child
+fn pipe() -> Result<(File, File), io::Error> {
+    let mut fds = [0; 2];
+
+    // safety: pipe fills fds with two new fds, which the Files take
+    // ownership of
+    let (rx, tx) = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+
+    set_cloexec(rx.as_raw_fd(), true)?;
+    set_cloexec(tx.as_raw_fd(), true)?;
+
+    Ok((rx, tx))
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<(), io::Error> {
+    // safety: fcntl with F_GETFD and F_SETFD only reads and sets fd flags
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let flags = if cloexec { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+
+        if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
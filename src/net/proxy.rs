@@ -0,0 +1,235 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+// PROXY protocol headers, as sent by HAProxy and cloud load balancers ahead
+// of the proxied connection. see
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// a proxy sends the header immediately, so anything slower is not a proxy
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// reads the PROXY header from the start of a connection, leaving the stream
+// positioned at the first byte of the proxied data. returns the client
+// address, or None if the proxy didn't send one, as for health checks
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, io::Error> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(stream)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out reading PROXY header"))?
+}
+
+async fn read_header_inner<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error> {
+    // read a byte at a time so as not to consume any proxied data
+    let mut line = Vec::new();
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not ASCII"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip = source.parse::<IpAddr>()
+                .map_err(|_| invalid("invalid source address in PROXY header"))?;
+
+            let port = source_port.parse::<u16>()
+                .map_err(|_| invalid("invalid source port in PROXY header"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error> {
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("missing PROXY header"));
+    }
+
+    let version_command = rest[6];
+    let family = rest[7];
+    let length = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // LOCAL connections come from the proxy itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+    match family >> 4 {
+        // AF_INET
+        1 if length >= 12 => {
+            let octets: [u8; 4] = addresses[0..4].try_into().expect("4 bytes");
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(octets).into(), port(8))))
+        }
+        // AF_INET6
+        2 if length >= 36 => {
+            let octets: [u8; 16] = addresses[0..16].try_into().expect("16 bytes");
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port(32))))
+        }
+        1 | 2 => Err(invalid("PROXY header addresses are truncated")),
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(header: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+        let mut stream = header;
+        read_header_inner(&mut stream).await
+    }
+
+    fn v2(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn parses_v1_headers() {
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap()));
+
+        assert_eq!(parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(parse(b"PROXY UNKNOWN 192.0.2.1 198.51.100.1 56324 443\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn leaves_proxied_data_unread() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        read_header_inner(&mut stream).await.unwrap();
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut header = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        header.extend_from_slice(b"GET");
+        let mut stream = &header[..];
+        read_header_inner(&mut stream).await.unwrap();
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 not-an-address 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443 \xff\r\n",
+        ] {
+            assert_eq!(parse(header).await.unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_v1_headers() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.extend(std::iter::repeat(b'1').take(200));
+        header.extend_from_slice(b"\r\n");
+
+        assert_eq!(parse(&header).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_v1_headers() {
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(parse(b"PRO").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn parses_v2_headers() {
+        let header = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(parse(&header).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2(0x21, 0x21, &addresses);
+        assert_eq!(parse(&header).await.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // TLVs after the addresses are skipped
+        let header = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb, 0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(parse(&header).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn parses_v2_local_and_unspecified_headers() {
+        assert_eq!(parse(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(parse(&v2(0x20, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb])).await.unwrap(), None);
+        assert_eq!(parse(&v2(0x21, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2_headers() {
+        assert_eq!(parse(&v2(0x11, 0x11, &[0; 12])).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse(&v2(0x21, 0x11, &[192, 0, 2, 1])).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse(&v2(0x21, 0x21, &[0; 12])).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header[7] = b'X';
+        assert_eq!(parse(&header).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_v2_headers() {
+        let header = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+
+        for length in [8, 15, 20] {
+            assert_eq!(parse(&header[..length]).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{} bytes", length);
+        }
+    }
+}
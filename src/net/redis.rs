@@ -0,0 +1,217 @@
+// just enough of the redis protocol for nodes in a cluster to publish to
+// and subscribe to a channel, over a blocking connection
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// bulk strings and arrays longer than this are taken to be a broken server
+const MAX_LENGTH: usize = 16 * 1024 * 1024;
+
+// error replies are read as errors. nothing here needs the text of a status
+// reply or the value of an integer one, only that they arrived
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status,
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    // reads taking longer than timeout fail, so that a dead server is
+    // noticed by anything expecting to hear from it regularly
+    pub fn connect(address: &str, password: Option<&str>, timeout: Duration) -> Result<Self, io::Error> {
+        let stream = super::client::tcp_connect(address)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut connection = Connection { stream: BufReader::new(stream) };
+
+        if let Some(password) = password {
+            match connection.command(&["AUTH", password])? {
+                Reply::Status => {}
+                _ => return Err(invalid_data()),
+            }
+        }
+
+        Ok(connection)
+    }
+
+    // sends a command and returns its reply, with error replies as errors
+    pub fn command(&mut self, args: &[&str]) -> Result<Reply, io::Error> {
+        self.send(args)?;
+        self.read_reply()
+    }
+
+    // sends a command without waiting for a reply, for SUBSCRIBE which
+    // replies with a stream of messages
+    pub fn send(&mut self, args: &[&str]) -> Result<(), io::Error> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg.as_bytes());
+            request.extend_from_slice(b"\r\n");
+        }
+
+        let stream = self.stream.get_mut();
+        stream.write_all(&request)?;
+        stream.flush()
+    }
+
+    // error replies are returned as errors carrying redis's message
+    pub fn read_reply(&mut self) -> Result<Reply, io::Error> {
+        read_reply(&mut self.stream)
+    }
+}
+
+fn read_reply(stream: &mut impl BufRead) -> Result<Reply, io::Error> {
+    let line = read_line(stream)?;
+    let kind = line.as_bytes()[0];
+    let value = line.get(1..).ok_or_else(invalid_data)?;
+
+    match kind {
+        b'+' => Ok(Reply::Status),
+        b'-' => Err(io::Error::new(io::ErrorKind::Other, value.to_owned())),
+        b':' => parse(value).map(|_| Reply::Integer),
+        b'$' => {
+            let length = match length(value)? {
+                Some(length) => length,
+                None => return Ok(Reply::Bulk(None)),
+            };
+
+            // the value is followed by a CRLF of its own
+            let mut data = vec![0; length + 2];
+            stream.read_exact(&mut data)?;
+
+            if !data.ends_with(b"\r\n") {
+                return Err(invalid_data());
+            }
+
+            data.truncate(length);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        b'*' => {
+            let length = match length(value)? {
+                Some(length) => length,
+                None => return Ok(Reply::Array(None)),
+            };
+
+            let items = (0..length)
+                .map(|_| read_reply(stream))
+                .collect::<Result<_, _>>()?;
+
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(invalid_data()),
+    }
+}
+
+fn read_line(stream: &mut impl BufRead) -> Result<String, io::Error> {
+    let mut line = String::new();
+
+    // a line cut off before its end means the connection closed partway
+    if stream.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "redis closed the connection"));
+    }
+
+    match line.strip_suffix("\r\n") {
+        Some(line) if !line.is_empty() => Ok(line.to_owned()),
+        _ => Err(invalid_data()),
+    }
+}
+
+fn parse(value: &str) -> Result<i64, io::Error> {
+    value.parse().map_err(|_| invalid_data())
+}
+
+// lengths of -1 mean null
+fn length(value: &str) -> Result<Option<usize>, io::Error> {
+    match parse(value)? {
+        -1 => Ok(None),
+        length if (0..=MAX_LENGTH as i64).contains(&length) => Ok(Some(length as usize)),
+        _ => Err(invalid_data()),
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed redis reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> Result<Reply, io::Error> {
+        read_reply(&mut &input[..])
+    }
+
+    #[test]
+    fn simple_replies() {
+        assert_eq!(read(b"+OK\r\n").unwrap(), Reply::Status);
+        assert_eq!(read(b":42\r\n").unwrap(), Reply::Integer);
+        assert_eq!(read(b":-1\r\n").unwrap(), Reply::Integer);
+    }
+
+    #[test]
+    fn error_reply_is_an_error() {
+        let error = read(b"-ERR unknown command\r\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(error.to_string(), "ERR unknown command");
+    }
+
+    #[test]
+    fn bulk_strings() {
+        assert_eq!(read(b"$5\r\nhello\r\n").unwrap(), Reply::Bulk(Some(b"hello".to_vec())));
+        assert_eq!(read(b"$0\r\n\r\n").unwrap(), Reply::Bulk(Some(Vec::new())));
+        assert_eq!(read(b"$-1\r\n").unwrap(), Reply::Bulk(None));
+
+        // bulk strings may contain CRLF themselves
+        assert_eq!(read(b"$4\r\na\r\nb\r\n").unwrap(), Reply::Bulk(Some(b"a\r\nb".to_vec())));
+    }
+
+    #[test]
+    fn arrays() {
+        let message = b"*3\r\n$7\r\nmessage\r\n$7\r\nedicast\r\n$2\r\n{}\r\n";
+
+        assert_eq!(read(message).unwrap(), Reply::Array(Some(vec![
+            Reply::Bulk(Some(b"message".to_vec())),
+            Reply::Bulk(Some(b"edicast".to_vec())),
+            Reply::Bulk(Some(b"{}".to_vec())),
+        ])));
+
+        assert_eq!(read(b"*0\r\n").unwrap(), Reply::Array(Some(Vec::new())));
+        assert_eq!(read(b"*-1\r\n").unwrap(), Reply::Array(None));
+        assert_eq!(read(b"*2\r\n:1\r\n*1\r\n+OK\r\n").unwrap(),
+            Reply::Array(Some(vec![Reply::Integer, Reply::Array(Some(vec![Reply::Status]))])));
+    }
+
+    #[test]
+    fn malformed_replies() {
+        for input in [
+            &b"?what\r\n"[..],
+            b"+OK\n",
+            b"\r\n",
+            b":forty\r\n",
+            b"$-2\r\n",
+            b"$3\r\nabcd\r\n",
+            b"*-5\r\n",
+        ] {
+            assert_eq!(read(input).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", input);
+        }
+
+        let too_long = format!("${}\r\n", MAX_LENGTH + 1);
+        assert_eq!(read(too_long.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_replies() {
+        for input in [&b""[..], b"+OK", b"$5\r\nhel", b"*2\r\n:1\r\n"] {
+            assert_eq!(read(input).unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{:?}", input);
+        }
+    }
+}
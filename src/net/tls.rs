@@ -0,0 +1,64 @@
+// TLS for edicast's own listeners, and for connections it makes to other
+// servers. builds without the tls feature still read TLS config, but fail
+// to start with it, and can only reach http URLs
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[cfg(feature = "tls")]
+mod rustls;
+#[cfg(feature = "tls")]
+pub use self::rustls::{client_config, watch, Acceptor};
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls::ClientConfig;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("edicast was built without the tls feature")]
+    Unsupported,
+    #[error("could not read {}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+    #[error("no certificates found in {}", .0.display())]
+    NoCertificates(PathBuf),
+    #[error("no private key found in {}", .0.display())]
+    NoPrivateKey(PathBuf),
+    #[error("unsupported private key type in {}", .0.display())]
+    UnsupportedKey(PathBuf),
+}
+
+// never constructed without the tls feature, so connections which would
+// need one can't be made
+#[cfg(not(feature = "tls"))]
+pub enum ClientConfig {}
+
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub struct Acceptor {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(feature = "tls"))]
+impl Acceptor {
+    pub fn new(_config: &crate::config::TlsConfig) -> Result<Self, TlsError> {
+        Err(TlsError::Unsupported)
+    }
+
+    pub async fn accept<IO>(&self, _stream: IO) -> Result<IO, io::Error> {
+        match self.never {}
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn client_config(_ca_file: &std::path::Path) -> Result<std::sync::Arc<ClientConfig>, TlsError> {
+    Err(TlsError::Unsupported)
+}
+
+#[cfg(not(feature = "tls"))]
+pub async fn watch(_log: slog::Logger, _config: crate::config::TlsConfig, acceptor: Acceptor) {
+    match acceptor.never {}
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+use super::TlsError;
+
+// how often to check certificate files for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// clients which connect and never finish the handshake would otherwise hold
+// their connection open forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the certificates currently being served, chosen by the server name the
+// client asks for. they can be replaced while running, and the default is
+// empty while waiting for ACME to issue the first one
+#[derive(Default)]
+struct Certificates {
+    current: RwLock<CertificateSet>,
+}
+
+#[derive(Default)]
+struct CertificateSet {
+    default: Option<Arc<CertifiedKey>>,
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertificateSet {
+    // exact hostnames take precedence over wildcards, and clients which
+    // don't send SNI or ask for an unknown name get the default
+    fn resolve(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = match server_name {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return self.default.clone(),
+        };
+
+        let wildcard = host.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+
+        self.by_host.get(&host)
+            .or_else(|| wildcard.and_then(|wildcard| self.by_host.get(&wildcard)))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().expect("lock certificates").resolve(client_hello.server_name())
+    }
+}
+
+#[derive(Clone)]
+pub struct Acceptor {
+    inner: TlsAcceptor,
+    certificates: Arc<Certificates>,
+}
+
+impl Acceptor {
+    pub fn new(config: &TlsConfig) -> Result<Self, TlsError> {
+        let certificates = Arc::new(Certificates::default());
+
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(certificates.clone());
+
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let acceptor = Acceptor {
+            inner: TlsAcceptor::from(Arc::new(server_config)),
+            certificates,
+        };
+
+        acceptor.reload(config)?;
+        Ok(acceptor)
+    }
+
+    // loads all certificates and keys again from disk, new connections use
+    // them from then on. if any fail to load the previous set stays in use
+    pub fn reload(&self, config: &TlsConfig) -> Result<(), TlsError> {
+        // with ACME configured, the certificate may not have been issued yet
+        let default = if config.acme.is_some() && !config.cert.exists() {
+            None
+        } else {
+            Some(load_certified_key(&config.cert, &config.key)?)
+        };
+
+        let mut by_host = HashMap::new();
+
+        for sni in &config.sni {
+            let certified = load_certified_key(&sni.cert, &sni.key)?;
+
+            for host in &sni.hosts {
+                by_host.insert(host.to_ascii_lowercase(), certified.clone());
+            }
+        }
+
+        *self.certificates.current.write().expect("lock certificates") = CertificateSet {
+            default,
+            by_host,
+        };
+
+        Ok(())
+    }
+
+    // config for QUIC connections, serving the same certificates
+    #[cfg(feature = "http3")]
+    pub fn quic_server_config(&self) -> ServerConfig {
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.certificates.clone());
+
+        server_config.alpn_protocols = vec![b"h3".to_vec()];
+        server_config
+    }
+
+    pub async fn accept<IO>(&self, stream: IO) -> Result<TlsStream<IO>, io::Error>
+        where IO: AsyncRead + AsyncWrite + Unpin
+    {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.inner.accept(stream)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>, TlsError> {
+    let certs = load_certs(cert)?;
+
+    let signing_key = rustls::sign::any_supported_type(&load_key(key)?)
+        .map_err(|_| TlsError::UnsupportedKey(key.to_owned()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+// reloads certificates whenever any of their files change, so that renewals
+// by certbot or similar are picked up without a restart
+pub async fn watch(log: Logger, config: TlsConfig, acceptor: Acceptor) {
+    let mut last_modified = modified_times(&config);
+
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+
+        let modified = modified_times(&config);
+
+        if modified == last_modified {
+            continue;
+        }
+
+        last_modified = modified;
+
+        match acceptor.reload(&config) {
+            Ok(()) => slog::info!(log, "Reloaded TLS certificates"; "cert" => config.cert.display()),
+            Err(e) => slog::error!(log, "Could not reload TLS certificates"; "error" => e.to_string()),
+        }
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let paths = [&config.cert, &config.key].into_iter()
+        .chain(config.sni.iter().flat_map(|sni| [&sni.cert, &sni.key]));
+
+    paths
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| TlsError::Read { path: path.to_owned(), error })
+}
+
+// for connections edicast makes to other servers, trusting the certificate
+// authorities in ca_file, usually the system's bundle
+pub fn client_config(ca_file: &Path) -> Result<Arc<ClientConfig>, TlsError> {
+    let certs = load_certs(ca_file)?
+        .into_iter()
+        .map(|cert| cert.0)
+        .collect::<Vec<_>>();
+
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(&certs);
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|error| TlsError::Read { path: path.to_owned(), error })?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_owned()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// accepts the first PKCS#8, PKCS#1 (RSA) or SEC1 (EC) key in the file
+fn load_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let items = rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|error| TlsError::Read { path: path.to_owned(), error })?;
+
+    items.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) |
+            rustls_pemfile::Item::RSAKey(key) |
+            rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_owned()))
+}
@@ -0,0 +1,53 @@
+// stream plugins are WebAssembly modules which stations can use to add their
+// own audio processing and title rewriting without changing edicast. they
+// are given no imports, so can only see the audio and titles passed to them,
+// and every call is limited by fuel and memory.
+//
+// a plugin exports its memory as "memory", and "alloc", which is given a
+// length in bytes and returns a pointer to that much memory for edicast to
+// write into. then either or both of:
+//
+//   process_pcm(ptr: i32, samples: i32, channels: i32, sample_rate: i32)
+//       processes interleaved 16 bit little endian samples in place
+//
+//   rewrite_title(ptr: i32, len: i32) -> i64
+//       given a UTF-8 title, returns the pointer to a new title in the high
+//       32 bits and its length in the low 32 bits, or -1 to leave it as is
+use thiserror::Error;
+
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "wasm")]
+pub use self::wasm::Plugin;
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("edicast was built without the wasm feature")]
+    Unsupported,
+    #[error("could not read plugin: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Wasm(String),
+    #[error("plugin does not export {0}")]
+    MissingExport(&'static str),
+}
+
+#[cfg(not(feature = "wasm"))]
+pub struct Plugin {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugin {
+    pub fn load(_log: slog::Logger, _config: &crate::config::PluginConfig) -> Result<Self, PluginError> {
+        Err(PluginError::Unsupported)
+    }
+
+    pub fn process(&self, _pcm: std::sync::Arc<crate::audio::PcmData>) -> std::sync::Arc<crate::audio::PcmData> {
+        match self.never {}
+    }
+
+    pub fn rewrite_title(&self, _title: &str) -> Option<String> {
+        match self.never {}
+    }
+}
@@ -0,0 +1,317 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use slog::Logger;
+use wasmtime::{Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc, WasmParams, WasmResults};
+
+use crate::audio::PcmData;
+use crate::audio::pool::Samples;
+use crate::config::PluginConfig;
+use super::PluginError;
+
+// how often the module file is checked for changes, and a plugin which
+// failed is instantiated again
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Plugin {
+    log: Logger,
+    config: PluginConfig,
+    engine: Engine,
+    state: Mutex<State>,
+}
+
+struct State {
+    module: Module,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+    // None once a call has failed, as the instance may have been left in
+    // any state, until it's instantiated again
+    instance: Option<Loaded>,
+    // the last title rewritten and what it was rewritten to, as every
+    // listener on the stream asks for the same title in turn
+    last_title: Option<(String, Option<String>)>,
+}
+
+struct Loaded {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    process_pcm: Option<TypedFunc<(i32, i32, i32, i32), ()>>,
+    rewrite_title: Option<TypedFunc<(i32, i32), i64>>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn wasm_error(error: impl ToString) -> PluginError {
+    PluginError::Wasm(error.to_string())
+}
+
+impl Plugin {
+    pub fn load(log: Logger, config: &PluginConfig) -> Result<Self, PluginError> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config).map_err(wasm_error)?;
+
+        let modified = modified(&config.path);
+        let module = Module::new(&engine, fs::read(&config.path)?).map_err(wasm_error)?;
+        let instance = Loaded::new(&engine, &module, config)?;
+
+        Ok(Plugin {
+            log,
+            config: config.clone(),
+            engine,
+            state: Mutex::new(State {
+                module,
+                modified,
+                checked_at: Instant::now(),
+                instance: Some(instance),
+                last_title: None,
+            }),
+        })
+    }
+
+    // runs the plugin over pcm, or returns it as it was if the plugin has
+    // no process_pcm or has failed
+    pub fn process(&self, pcm: Arc<PcmData>) -> Arc<PcmData> {
+        self.check();
+
+        let mut state = self.state.lock().expect("lock plugin");
+
+        let loaded = match &mut state.instance {
+            Some(loaded) => loaded,
+            None => return pcm,
+        };
+
+        let process_pcm = match &loaded.process_pcm {
+            Some(process_pcm) => process_pcm.clone(),
+            None => return pcm,
+        };
+
+        let mut bytes = Vec::with_capacity(pcm.samples.len() * 2);
+
+        for sample in pcm.samples.iter() {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        if let Err(e) = loaded.process(&process_pcm, &mut bytes, pcm.channels, pcm.sample_rate, self.config.fuel) {
+            slog::warn!(self.log, "Stream plugin failed, passing audio through until it's loaded again";
+                "path" => self.config.path.display(),
+                "error" => e.to_string(),
+            );
+
+            state.instance = None;
+            return pcm;
+        }
+
+        let samples = bytes.chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect::<Vec<_>>();
+
+        Arc::new(PcmData {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels,
+            samples: Samples::from_slice(&samples),
+            pts: pcm.pts,
+            session: pcm.session,
+        })
+    }
+
+    // the plugin's title in place of title, or None to leave it as is
+    pub fn rewrite_title(&self, title: &str) -> Option<String> {
+        let mut state = self.state.lock().expect("lock plugin");
+
+        if let Some((last, rewritten)) = &state.last_title {
+            if last == title {
+                return rewritten.clone();
+            }
+        }
+
+        let loaded = state.instance.as_mut()?;
+        let rewrite_title = loaded.rewrite_title.clone()?;
+
+        let rewritten = match loaded.rewrite(&rewrite_title, title, self.config.fuel) {
+            Ok(rewritten) => rewritten,
+            Err(e) => {
+                slog::warn!(self.log, "Stream plugin failed to rewrite title";
+                    "path" => self.config.path.display(),
+                    "error" => e.to_string(),
+                );
+
+                state.instance = None;
+                return None;
+            }
+        };
+
+        state.last_title = Some((title.to_owned(), rewritten.clone()));
+        rewritten
+    }
+
+    // loads the module again if its file has changed, or instantiates it
+    // again if it failed. compiling happens without holding the lock, so
+    // that listeners asking for titles aren't held up by it
+    fn check(&self) {
+        let (module, previous) = {
+            let mut state = self.state.lock().expect("lock plugin");
+
+            if state.checked_at.elapsed() < CHECK_INTERVAL {
+                return;
+            }
+
+            state.checked_at = Instant::now();
+            (state.module.clone(), state.modified)
+        };
+
+        let modified = modified(&self.config.path);
+        let changed = modified != previous;
+
+        let loaded = if changed {
+            fs::read(&self.config.path)
+                .map_err(PluginError::from)
+                .and_then(|wasm| Module::new(&self.engine, wasm).map_err(wasm_error))
+                .and_then(|module| Ok((Loaded::new(&self.engine, &module, &self.config)?, module)))
+        } else if self.state.lock().expect("lock plugin").instance.is_none() {
+            Loaded::new(&self.engine, &module, &self.config).map(|loaded| (loaded, module))
+        } else {
+            return;
+        };
+
+        let mut state = self.state.lock().expect("lock plugin");
+
+        match loaded {
+            Ok((loaded, module)) => {
+                if changed {
+                    slog::info!(self.log, "Reloaded stream plugin"; "path" => self.config.path.display());
+                }
+
+                state.module = module;
+                state.modified = modified;
+                state.instance = Some(loaded);
+                state.last_title = None;
+            }
+            Err(e) if changed => {
+                slog::error!(self.log, "Could not reload stream plugin, keeping the previous one";
+                    "path" => self.config.path.display(),
+                    "error" => e.to_string(),
+                );
+
+                // not tried again until the file changes again
+                state.modified = modified;
+            }
+            Err(e) => {
+                slog::error!(self.log, "Could not instantiate stream plugin";
+                    "path" => self.config.path.display(),
+                    "error" => e.to_string(),
+                );
+            }
+        }
+    }
+}
+
+impl Loaded {
+    fn new(engine: &Engine, module: &Module, config: &PluginConfig) -> Result<Self, PluginError> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.max_memory_mb * 1024 * 1024)
+            .build();
+
+        let mut store = Store::new(engine, limits);
+        store.limiter(|limits| limits);
+
+        // for the module's start function, if it has one
+        refuel(&mut store, config.fuel);
+
+        // no imports are given, plugins can only see what's passed to them
+        let instance = Instance::new(&mut store, module, &[]).map_err(wasm_error)?;
+
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or(PluginError::MissingExport("memory"))?;
+
+        let alloc = optional_func(&mut store, &instance, "alloc")?
+            .ok_or(PluginError::MissingExport("alloc"))?;
+
+        let process_pcm = optional_func(&mut store, &instance, "process_pcm")?;
+        let rewrite_title = optional_func(&mut store, &instance, "rewrite_title")?;
+
+        if process_pcm.is_none() && rewrite_title.is_none() {
+            return Err(PluginError::MissingExport("process_pcm or rewrite_title"));
+        }
+
+        Ok(Loaded { store, memory, alloc, process_pcm, rewrite_title })
+    }
+
+    // copies data into the plugin's memory, returning where it is
+    fn write(&mut self, data: &[u8], fuel: u64) -> Result<usize, PluginError> {
+        refuel(&mut self.store, fuel);
+
+        let ptr = self.alloc.call(&mut self.store, data.len() as i32).map_err(wasm_error)?;
+        let ptr = ptr as u32 as usize;
+
+        self.memory.write(&mut self.store, ptr, data).map_err(wasm_error)?;
+        Ok(ptr)
+    }
+
+    fn process(&mut self, func: &TypedFunc<(i32, i32, i32, i32), ()>, bytes: &mut [u8],
+        channels: usize, sample_rate: usize, fuel: u64) -> Result<(), PluginError>
+    {
+        let ptr = self.write(bytes, fuel)?;
+        let samples = (bytes.len() / 2) as i32;
+
+        refuel(&mut self.store, fuel);
+
+        func.call(&mut self.store, (ptr as i32, samples, channels as i32, sample_rate as i32))
+            .map_err(wasm_error)?;
+
+        self.memory.read(&self.store, ptr, bytes).map_err(wasm_error)
+    }
+
+    fn rewrite(&mut self, func: &TypedFunc<(i32, i32), i64>, title: &str, fuel: u64)
+        -> Result<Option<String>, PluginError>
+    {
+        let ptr = self.write(title.as_bytes(), fuel)?;
+
+        refuel(&mut self.store, fuel);
+
+        let result = func.call(&mut self.store, (ptr as i32, title.len() as i32))
+            .map_err(wasm_error)?;
+
+        if result < 0 {
+            return Ok(None);
+        }
+
+        let ptr = (result >> 32) as u32 as usize;
+        let len = result as u32 as usize;
+
+        let mut rewritten = vec![0; len];
+        self.memory.read(&self.store, ptr, &mut rewritten).map_err(wasm_error)?;
+
+        Ok(Some(String::from_utf8_lossy(&rewritten).into_owned()))
+    }
+}
+
+fn optional_func<Params, Results>(store: &mut Store<StoreLimits>, instance: &Instance, name: &str)
+    -> Result<Option<TypedFunc<Params, Results>>, PluginError>
+where
+    Params: WasmParams,
+    Results: WasmResults,
+{
+    if instance.get_export(&mut *store, name).is_none() {
+        return Ok(None);
+    }
+
+    instance.get_typed_func(&mut *store, name)
+        .map(Some)
+        .map_err(|e| PluginError::Wasm(format!("{}: {}", name, e)))
+}
+
+// every call gets the same budget, fuel left over from the last call isn't
+// kept for the next
+fn refuel(store: &mut Store<StoreLimits>, fuel: u64) {
+    let remaining = store.consume_fuel(0).unwrap_or(0);
+
+    if remaining < fuel {
+        let _ = store.add_fuel(fuel - remaining);
+    }
+}
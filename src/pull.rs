@@ -0,0 +1,376 @@
+// pulls a source from other servers, connecting to their public mounts as a
+// listener would instead of waiting for a source client. upstreams are
+// tried in order with the first as primary, and while on a fallback the
+// primary is checked regularly and switched back to once it's serving again
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+
+use crate::audio::decode;
+use crate::config::{HttpUrl, PullConfig};
+use crate::net::{client, tls};
+use crate::net::tls::ClientConfig;
+use crate::server::Edicast;
+use crate::source::{interruptible, ConnectSourceError, Interrupt, StartSource};
+
+// the upstream is taken to be gone if it sends nothing for this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const INITIAL_RETRY_WAIT: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+// pulls which stayed up this long start backing off afresh when they fail
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+// how often a running pull checks whether it's been stopped
+const TICK: Duration = Duration::from_secs(1);
+
+// a source's previous connection may take a moment to wind down after
+// it's interrupted, this is how long a new one waits for it
+const SOURCE_BUSY_WAIT: Duration = Duration::from_secs(5);
+
+// sources with a pull table are checked for this often, to follow reloads
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Upstream {
+    // the primary first, then fallbacks in order
+    pub urls: Vec<HttpUrl>,
+    // sent as the Host header instead of each URL's own host
+    pub host: Option<String>,
+    // sent as a bearer token, such as another edicast server's origin.secret
+    pub secret: Option<String>,
+    pub check_interval: Duration,
+}
+
+pub struct Pull {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Pull {
+    // the source is disconnected within a second or so
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    // pulls finish once stopped, or once their source is removed
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+pub fn start(log: Logger, source: &str, upstream: Upstream, tls: Option<Arc<ClientConfig>>, edicast: Arc<Edicast>)
+    -> Pull
+{
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let puller = Puller {
+        log,
+        source: source.to_owned(),
+        upstream,
+        tls,
+        edicast,
+        stop: stop.clone(),
+    };
+
+    let thread = thread::Builder::new()
+        .name(format!("edicast/pull: {}", source))
+        .spawn(move || puller.run())
+        .expect("spawn edicast pull thread");
+
+    Pull { stop, thread }
+}
+
+// keeps a pull running for each source with a pull table, restarting it
+// whenever the source's config changes
+pub fn start_sources(log: Logger, edicast: Arc<Edicast>) {
+    thread::Builder::new()
+        .name("edicast/pull: sources".to_owned())
+        .spawn(move || {
+            // pulls which couldn't be started are kept as None, so that
+            // they're only retried once their config changes
+            let mut pulls = HashMap::<String, (PullConfig, Option<Pull>)>::new();
+
+            loop {
+                let configs = edicast.sources.names().into_iter()
+                    .filter_map(|name| {
+                        let config = edicast.sources.config(&name)?.pull?;
+                        Some((name, config))
+                    })
+                    .collect::<HashMap<_, _>>();
+
+                pulls.retain(|name, (config, pull)| {
+                    let keep = configs.get(name) == Some(&*config)
+                        && pull.as_ref().map_or(true, |pull| !pull.is_finished());
+
+                    if let (false, Some(pull)) = (keep, pull) {
+                        pull.stop();
+                    }
+
+                    keep
+                });
+
+                for (name, config) in configs {
+                    if pulls.contains_key(&name) {
+                        continue;
+                    }
+
+                    let pull = start_source(&log, &name, &config, &edicast);
+                    pulls.insert(name, (config, pull));
+                }
+
+                thread::sleep(SYNC_INTERVAL);
+            }
+        })
+        .expect("spawn edicast pull thread");
+}
+
+fn start_source(log: &Logger, name: &str, config: &PullConfig, edicast: &Arc<Edicast>) -> Option<Pull> {
+    let log = log.new(slog::o!("source" => name.to_owned()));
+
+    if config.urls.is_empty() {
+        slog::warn!(log, "Source has a pull table without any urls, not pulling");
+        return None;
+    }
+
+    let tls = if config.urls.iter().any(|url| url.tls) {
+        match tls::client_config(&config.ca_file) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                slog::error!(log, "Could not set up TLS for pulling source"; "error" => e.to_string());
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    let upstream = Upstream {
+        urls: config.urls.clone(),
+        host: None,
+        secret: config.secret.clone(),
+        check_interval: Duration::from_secs(config.check_secs.max(1)),
+    };
+
+    Some(start(log, name, upstream, tls, edicast.clone()))
+}
+
+struct Puller {
+    log: Logger,
+    source: String,
+    upstream: Upstream,
+    tls: Option<Arc<ClientConfig>>,
+    edicast: Arc<Edicast>,
+    stop: Arc<AtomicBool>,
+}
+
+enum PullError {
+    Io(io::Error),
+    NoSuchSource,
+}
+
+impl From<io::Error> for PullError {
+    fn from(e: io::Error) -> Self {
+        PullError::Io(e)
+    }
+}
+
+// why a pull which got as far as going live finished
+enum PullEnd {
+    Closed,
+    Stopped,
+    PrimaryBack,
+}
+
+impl Puller {
+    fn run(self) {
+        let mut wait = INITIAL_RETRY_WAIT;
+        let mut index = 0;
+
+        while !self.stopped() {
+            let url = &self.upstream.urls[index];
+            let started = Instant::now();
+
+            match self.pull(url, index > 0) {
+                Ok(PullEnd::Closed) => {
+                    slog::info!(self.log, "Upstream ended"; "url" => url.to_string());
+                }
+                Ok(PullEnd::Stopped) => return,
+                Ok(PullEnd::PrimaryBack) => {
+                    slog::info!(self.log, "Primary upstream is back, switching to it";
+                        "url" => self.upstream.urls[0].to_string());
+
+                    index = 0;
+                    wait = INITIAL_RETRY_WAIT;
+                    continue;
+                }
+                Err(PullError::Io(e)) => {
+                    slog::warn!(self.log, "Could not pull from upstream";
+                        "url" => url.to_string(),
+                        "error" => e.to_string(),
+                    );
+                }
+                Err(PullError::NoSuchSource) => return,
+            }
+
+            if started.elapsed() >= STABLE_AFTER {
+                wait = INITIAL_RETRY_WAIT;
+            }
+
+            // the next upstream is tried straight away, backing off only
+            // once every one has failed
+            index = (index + 1) % self.upstream.urls.len();
+
+            if index == 0 {
+                self.sleep(wait);
+                wait = (wait * 2).min(MAX_RETRY_WAIT);
+            }
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+
+        while !self.stopped() {
+            match until.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => thread::sleep(remaining.min(TICK)),
+                _ => return,
+            }
+        }
+    }
+
+    // sends the request and reads the response head, returning the
+    // connection ready to read the stream from
+    fn request(&self, url: &HttpUrl) -> Result<(client::Connection, String), io::Error> {
+        let mut connection = client::connect(url, self.tls.as_ref(), TIMEOUT)?;
+
+        // HTTP/1.0 so that the upstream doesn't send the stream chunked
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: edicast\r\n",
+            url.path, self.upstream.host.clone().unwrap_or_else(|| url.authority()));
+
+        if let Some(secret) = &self.upstream.secret {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", secret));
+        }
+
+        request.push_str("\r\n");
+
+        connection.write_all(request.as_bytes())?;
+        connection.flush()?;
+
+        let (status, head) = client::read_head(&mut connection)?;
+
+        if status != 200 {
+            let message = format!("upstream responded {}", status);
+            return Err(io::Error::new(io::ErrorKind::Other, message));
+        }
+
+        Ok((connection, head))
+    }
+
+    // returns once the source is done with the connection
+    fn pull(&self, url: &HttpUrl, on_fallback: bool) -> Result<PullEnd, PullError> {
+        let (connection, head) = self.request(url)?;
+        let start = self.connect_source()?;
+
+        let socket = connection.socket()?;
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let io = Watched { io: connection, _done: done_tx };
+        let (io, interrupt) = interruptible(&self.source, io, move || {
+            let _ = socket.shutdown(Shutdown::Both);
+        });
+
+        let content_type = client::header(&head, "content-type")
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+
+        let decoder = content_type.and_then(decode::for_media_type)
+            .unwrap_or_else(decode::fallback)
+            .open(Box::new(io))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if start.start(decoder, interrupt.clone(), None).is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "source went away before it could go live").into());
+        }
+
+        slog::info!(self.log, "Pulling from upstream"; "url" => url.to_string(), "fallback" => on_fallback);
+
+        let mut next_check = Instant::now() + self.upstream.check_interval;
+
+        // the connection is dropped once it closes or the source stops
+        // reading from it, such as when it's kicked
+        loop {
+            match done_rx.recv_timeout(TICK) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return Ok(PullEnd::Closed),
+            }
+
+            if self.stopped() {
+                interrupt.interrupt();
+                return Ok(PullEnd::Stopped);
+            }
+
+            if on_fallback && Instant::now() >= next_check {
+                next_check = Instant::now() + self.upstream.check_interval;
+
+                if self.primary_healthy() {
+                    finish(&interrupt, &done_rx);
+                    return Ok(PullEnd::PrimaryBack);
+                }
+            }
+        }
+    }
+
+    // the primary is healthy once it responds to a request for the stream,
+    // the connection is closed again straight away
+    fn primary_healthy(&self) -> bool {
+        self.request(&self.upstream.urls[0]).is_ok()
+    }
+
+    fn connect_source(&self) -> Result<StartSource, PullError> {
+        let until = Instant::now() + SOURCE_BUSY_WAIT;
+
+        loop {
+            match self.edicast.sources.connect_source(&self.source, self.log.clone()) {
+                Ok(start) => return Ok(start),
+                Err(ConnectSourceError::NoSuchSource) => return Err(PullError::NoSuchSource),
+                Err(ConnectSourceError::AlreadyConnected) if Instant::now() < until => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(ConnectSourceError::AlreadyConnected) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "source already connected").into());
+                }
+            }
+        }
+    }
+}
+
+// disconnects the source and waits for it to let go of the connection
+fn finish(interrupt: &Interrupt, done: &mpsc::Receiver<()>) {
+    interrupt.interrupt();
+    let _ = done.recv_timeout(TIMEOUT);
+}
+
+// a connection which signals when it's dropped
+struct Watched {
+    io: client::Connection,
+    _done: mpsc::Sender<()>,
+}
+
+impl Read for Watched {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
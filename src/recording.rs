@@ -0,0 +1,286 @@
+// records streams for shows at the times they air each week, each airing
+// to its own file named after the show, and for each session a live source
+// is connected, named after its DJ
+use std::fs;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::archive;
+use crate::audio::encode;
+use crate::chapters::Chapters;
+use crate::config::{ChapterFormat, RecordingConfig, SessionRecordingConfig, StreamConfig};
+use crate::event::Event;
+use crate::schedule::{self, Weekly};
+use crate::server::Edicast;
+use crate::source::SourceStatus;
+use crate::stream::StreamSubscription;
+
+pub fn start(log: Logger, config: RecordingConfig, edicast: Arc<Edicast>) {
+    let runtime = Handle::current();
+    let log = log.new(slog::o!("show" => config.show.clone(), "stream" => config.stream.clone()));
+
+    thread::Builder::new()
+        .name(format!("edicast/recording: {}", config.show))
+        .spawn(move || recording_main(log, runtime, config, edicast))
+        .expect("spawn edicast recording thread");
+}
+
+fn recording_main(log: Logger, runtime: Handle, config: RecordingConfig, edicast: Arc<Edicast>) {
+    let days = config.days.iter().map(|day| day.index()).collect();
+
+    let schedule = Weekly::new(days,
+        Duration::from_secs(config.start.secs),
+        Duration::from_secs(config.duration_mins * 60));
+
+    loop {
+        let (start, end) = schedule.next_window(SystemTime::now());
+
+        if let Ok(wait) = start.duration_since(SystemTime::now()) {
+            slog::info!(log, "Next recording scheduled"; "in_secs" => wait.as_secs());
+            thread::sleep(wait);
+        }
+
+        // the stream may have been removed or replaced since startup
+        let stream = edicast.streams.config(&config.stream);
+        let subscription = edicast.streams.subscribe_stream(&config.stream);
+
+        let (stream, subscription) = match (stream, subscription) {
+            (Some(stream), Some(subscription)) => (stream, subscription),
+            _ => {
+                slog::warn!(log, "Stream for scheduled recording does not exist, skipping");
+                sleep_until(end);
+                continue;
+            }
+        };
+
+        let now = SystemTime::now();
+        let name = schedule::format_utc(&config.name, now).replace("{show}", &config.show);
+        let extension = encode::extension_from_config(&stream.codec);
+
+        let mut chapters = source_chapters(&log, config.chapters, &edicast, &stream);
+
+        let result = fs::create_dir_all(&config.path)
+            .and_then(|()| archive::create_unique(&config.path, &name, extension))
+            .and_then(|(mut file, path)| {
+                slog::info!(log, "Started scheduled recording"; "path" => path.display());
+
+                let title = format!("{} {}", config.show, schedule::format_utc("%Y-%m-%d", now));
+                file.write_all(&encode::file_header_from_config(&stream.codec, &title))?;
+
+                if let Some(chapters) = &mut chapters {
+                    chapters.start(&path, &title);
+                }
+
+                record(&log, &runtime, subscription, &mut file, chapters.as_mut(), end)?;
+                Ok(path)
+            });
+
+        match result {
+            Ok(path) => {
+                slog::info!(log, "Finished scheduled recording");
+
+                edicast.events.publish(Event::RecordingFinished {
+                    stream: config.stream.clone(),
+                    path: path.display().to_string(),
+                    recording: "scheduled",
+                });
+            }
+            Err(e) => {
+                slog::error!(log, "Scheduled recording failed"; "error" => e.to_string());
+                sleep_until(end);
+            }
+        }
+    }
+}
+
+fn record(log: &Logger, runtime: &Handle, mut subscription: StreamSubscription,
+    file: &mut impl Write, mut chapters: Option<&mut Chapters>, end: SystemTime) -> Result<(), io::Error>
+{
+    loop {
+        let remaining = match end.duration_since(SystemTime::now()) {
+            Ok(remaining) if !remaining.is_zero() => remaining,
+            _ => return Ok(()),
+        };
+
+        let chunk = runtime.block_on(async {
+            tokio::time::timeout(remaining, subscription.recv()).await
+        });
+
+        match chunk {
+            Ok(Ok(chunk)) => {
+                if let Some(chapters) = &mut chapters {
+                    chapters.update(chunk.pts);
+                }
+
+                file.write_all(&chunk.data)?;
+            }
+            Ok(Err(RecvError::Lagged(skipped))) => {
+                slog::warn!(log, "Recording fell behind stream, it will have a gap";
+                    "skipped_chunks" => skipped);
+            }
+            Ok(Err(RecvError::Closed)) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "stream was removed"));
+            }
+            // the show is over
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+fn source_chapters(log: &Logger, format: Option<ChapterFormat>, edicast: &Edicast, stream: &StreamConfig)
+    -> Option<Chapters>
+{
+    let metadata = edicast.sources.metadata(&stream.source)?;
+    Some(Chapters::new(log.clone(), format?, metadata))
+}
+
+fn sleep_until(time: SystemTime) {
+    if let Ok(wait) = time.duration_since(SystemTime::now()) {
+        thread::sleep(wait);
+    }
+}
+
+// watches for sources going live and records every stream they feed that
+// has session recording configured, until the source disconnects. streams
+// are looked up as each session starts, so this follows config reloads
+pub fn start_sessions(log: Logger, edicast: Arc<Edicast>) {
+    let runtime = Handle::current();
+    let mut events = edicast.events.subscribe();
+
+    thread::Builder::new()
+        .name("edicast/recording: sessions".to_owned())
+        .spawn(move || loop {
+            let (source, dj) = match runtime.block_on(events.recv()) {
+                Ok(Event::SourceConnected { source, dj, .. }) => (source, dj),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    slog::warn!(log, "Session recorder missed events, some sessions may not be recorded";
+                        "skipped_events" => skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            for (name, stream) in edicast.streams.list() {
+                if stream.source != source {
+                    continue;
+                }
+
+                if let Some(config) = stream.session_recording.clone() {
+                    start_session(&log, &runtime, &edicast, &name, stream, config, dj.as_deref().unwrap_or(&source));
+                }
+            }
+        })
+        .expect("spawn edicast recording thread");
+}
+
+fn start_session(log: &Logger, runtime: &Handle, edicast: &Edicast, name: &str,
+    stream: StreamConfig, config: SessionRecordingConfig, dj: &str)
+{
+    let log = log.new(slog::o!("stream" => name.to_owned(), "dj" => dj.to_owned()));
+
+    let (subscription, status) = match (edicast.streams.subscribe_stream(name), edicast.sources.status(&stream.source)) {
+        (Some(subscription), Some(status)) => (subscription, status),
+        _ => return,
+    };
+
+    let now = SystemTime::now();
+    let file_name = schedule::format_utc(&config.name, now)
+        .replace("{dj}", &dj.replace('/', "_"));
+    let extension = encode::extension_from_config(&stream.codec);
+    let title = format!("{} {}", dj, schedule::format_utc("%Y-%m-%d %H:%M UTC", now));
+
+    let file = fs::create_dir_all(&config.path)
+        .and_then(|()| archive::create_unique(&config.path, &file_name, extension))
+        .and_then(|(mut file, path)| {
+            file.write_all(&encode::file_header_from_config(&stream.codec, &title))?;
+            Ok((file, path))
+        });
+
+    let (mut file, path) = match file {
+        Ok(file) => file,
+        Err(e) => {
+            slog::error!(log, "Could not start session recording"; "error" => e.to_string());
+            return;
+        }
+    };
+
+    slog::info!(log, "Started session recording"; "path" => path.display());
+
+    let mut chapters = source_chapters(&log, config.chapters, edicast, &stream);
+
+    if let Some(chapters) = &mut chapters {
+        chapters.start(&path, &title);
+    }
+
+    let runtime = runtime.clone();
+    let events = edicast.events.clone();
+    let name = name.to_owned();
+
+    thread::Builder::new()
+        .name(format!("edicast/recording: {}", name))
+        .spawn(move || {
+            match record_session(&log, &runtime, subscription, status, &mut file, chapters.as_mut()) {
+                Ok(()) => {
+                    slog::info!(log, "Finished session recording");
+
+                    events.publish(Event::RecordingFinished {
+                        stream: name,
+                        path: path.display().to_string(),
+                        recording: "session",
+                    });
+                }
+                Err(e) => slog::error!(log, "Session recording failed"; "error" => e.to_string()),
+            }
+        })
+        .expect("spawn edicast recording thread");
+}
+
+fn record_session(log: &Logger, runtime: &Handle, mut subscription: StreamSubscription,
+    mut status: watch::Receiver<SourceStatus>, file: &mut impl Write, mut chapters: Option<&mut Chapters>)
+    -> Result<(), io::Error>
+{
+    loop {
+        let chunk = runtime.block_on(async {
+            tokio::select! {
+                chunk = subscription.recv() => Some(chunk),
+                () = offline(&mut status) => None,
+            }
+        });
+
+        match chunk {
+            Some(Ok(chunk)) => {
+                if let Some(chapters) = &mut chapters {
+                    chapters.update(chunk.pts);
+                }
+
+                file.write_all(&chunk.data)?;
+            }
+            Some(Err(RecvError::Lagged(skipped))) => {
+                slog::warn!(log, "Recording fell behind stream, it will have a gap";
+                    "skipped_chunks" => skipped);
+            }
+            Some(Err(RecvError::Closed)) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "stream was removed"));
+            }
+            // the DJ has disconnected
+            None => return Ok(()),
+        }
+    }
+}
+
+// resolves once the source is no longer live, or has been removed
+async fn offline(status: &mut watch::Receiver<SourceStatus>) {
+    while *status.borrow() == SourceStatus::Live {
+        if status.changed().await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,247 @@
+// pushes a stream's encoded output to other servers, connecting to each as
+// a source client, so that a station can be simulcast through a CDN or
+// another Icecast server
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::audio::encode;
+use crate::config::{RelayConfig, RelayProtocol, StreamConfig};
+use crate::net::{base64, client, tls};
+use crate::net::tls::ClientConfig;
+use crate::stream::StreamSubscription;
+
+// the other server is taken to be gone if a write blocks for this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(300);
+
+// connections which stayed up this long start backing off afresh when they
+// fail, rather than from where the last failure left off
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RelayStatus {
+    pub url: String,
+    pub state: RelayState,
+    pub connected_at: Option<SystemTime>,
+    pub bytes_sent: u64,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayState {
+    Connecting,
+    Connected,
+    Waiting,
+    // won't be retried, such as for bad TLS settings
+    Failed,
+}
+
+impl RelayState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayState::Connecting => "connecting",
+            RelayState::Connected => "connected",
+            RelayState::Waiting => "waiting",
+            RelayState::Failed => "failed",
+        }
+    }
+}
+
+pub type SharedRelayStatus = Arc<Mutex<RelayStatus>>;
+
+struct Relay {
+    log: Logger,
+    stream: StreamConfig,
+    config: RelayConfig,
+    status: SharedRelayStatus,
+}
+
+// relays run until the stream is removed, which closes its subscription
+pub fn start(log: Logger, name: &str, stream: StreamConfig, config: RelayConfig, subscription: StreamSubscription)
+    -> SharedRelayStatus
+{
+    let runtime = Handle::current();
+
+    let status = Arc::new(Mutex::new(RelayStatus {
+        url: config.url.to_string(),
+        state: RelayState::Connecting,
+        connected_at: None,
+        bytes_sent: 0,
+        reconnects: 0,
+        last_error: None,
+    }));
+
+    let relay = Relay {
+        log: log.new(slog::o!("stream" => name.to_owned(), "relay" => config.url.to_string())),
+        stream,
+        config,
+        status: status.clone(),
+    };
+
+    thread::Builder::new()
+        .name(format!("edicast/relay: {}", name))
+        .spawn(move || relay_main(relay, runtime, subscription))
+        .expect("spawn edicast relay thread");
+
+    status
+}
+
+fn relay_main(relay: Relay, runtime: Handle, mut subscription: StreamSubscription) {
+    let tls = if relay.config.url.tls {
+        match tls::client_config(&relay.config.ca_file) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                slog::error!(relay.log, "Could not set up TLS for relay"; "error" => e.to_string());
+                relay.update(|status| {
+                    status.state = RelayState::Failed;
+                    status.last_error = Some(e.to_string());
+                });
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let initial_wait = Duration::from_secs(relay.config.retry_secs.max(1));
+    let mut wait = initial_wait;
+
+    loop {
+        relay.update(|status| status.state = RelayState::Connecting);
+
+        let mut connection = match relay.connect(tls.as_ref()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                slog::warn!(relay.log, "Could not connect relay";
+                    "error" => e.to_string(),
+                    "retry_in_secs" => wait.as_secs(),
+                );
+
+                relay.wait(&e, wait);
+                wait = (wait * 2).min(MAX_RETRY_WAIT);
+                continue;
+            }
+        };
+
+        slog::info!(relay.log, "Relay connected");
+
+        let connected_at = SystemTime::now();
+
+        relay.update(|status| {
+            status.state = RelayState::Connected;
+            status.connected_at = Some(connected_at);
+        });
+
+        // anything which queued up while disconnected is stale by now
+        subscription = subscription.resubscribe();
+
+        let error = loop {
+            let chunk = match runtime.block_on(subscription.recv()) {
+                Ok(chunk) => chunk,
+                Err(RecvError::Lagged(skipped)) => {
+                    slog::warn!(relay.log, "Relay fell behind stream, skipping ahead";
+                        "skipped_chunks" => skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    slog::info!(relay.log, "Stopping relay as stream was removed");
+                    return;
+                }
+            };
+
+            if let Err(e) = connection.write_all(&chunk.data) {
+                break e;
+            }
+
+            relay.update(|status| status.bytes_sent += chunk.data.len() as u64);
+        };
+
+        if connected_at.elapsed().map_or(false, |elapsed| elapsed >= STABLE_AFTER) {
+            wait = initial_wait;
+        }
+
+        slog::warn!(relay.log, "Relay disconnected";
+            "error" => error.to_string(),
+            "retry_in_secs" => wait.as_secs(),
+        );
+
+        relay.wait(&error, wait);
+        wait = (wait * 2).min(MAX_RETRY_WAIT);
+    }
+}
+
+impl Relay {
+    fn update(&self, f: impl FnOnce(&mut RelayStatus)) {
+        f(&mut self.status.lock().expect("lock relay status"));
+    }
+
+    fn wait(&self, error: &io::Error, wait: Duration) {
+        self.update(|status| {
+            status.state = RelayState::Waiting;
+            status.connected_at = None;
+            status.reconnects += 1;
+            status.last_error = Some(error.to_string());
+        });
+
+        thread::sleep(wait);
+    }
+
+    fn connect(&self, tls: Option<&Arc<ClientConfig>>) -> Result<client::Connection, io::Error> {
+        let url = &self.config.url;
+        let mut connection = client::connect(url, tls, TIMEOUT)?;
+
+        let (method, version) = match self.config.protocol {
+            RelayProtocol::Put => ("PUT", "HTTP/1.1"),
+            RelayProtocol::Source => ("SOURCE", "HTTP/1.0"),
+        };
+
+        let credentials = format!("{}:{}", self.config.username, self.config.password);
+
+        let mut head = format!("{} {} {}\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: {}\r\n\
+            User-Agent: edicast/{}\r\n",
+            method, url.path, version, url.authority(), base64::encode(credentials.as_bytes()),
+            encode::mime_type_from_config(&self.stream.codec), env!("CARGO_PKG_VERSION"));
+
+        // icecast answers PUT requests with 100 Continue before any audio
+        // is sent, and then reads audio until the connection closes
+        if self.config.protocol == RelayProtocol::Put {
+            head.push_str("Expect: 100-continue\r\n");
+        }
+
+        let station = [
+            ("ice-name", self.stream.name.as_deref()),
+            ("ice-description", self.stream.description.as_deref()),
+            ("ice-genre", self.stream.genre.as_deref()),
+            ("ice-url", self.stream.url.as_deref()),
+            ("ice-public", Some(if self.stream.public { "1" } else { "0" })),
+        ];
+
+        for (name, value) in station {
+            // values with line breaks would end the header early
+            if let Some(value) = value.filter(|value| !value.contains(['\r', '\n'])) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+
+        head.push_str("\r\n");
+        connection.write_all(head.as_bytes())?;
+
+        let (status, head) = client::read_head(&mut connection)?;
+
+        match status {
+            100 | 200 => Ok(connection),
+            _ => {
+                let line = head.lines().next().unwrap_or_default().to_owned();
+                Err(io::Error::new(io::ErrorKind::Other, format!("server responded {}", line)))
+            }
+        }
+    }
+}
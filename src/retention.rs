@@ -0,0 +1,152 @@
+// deletes an archive's oldest files once they're past its age limit, or the
+// archive has grown past its size limit, so that a long running station
+// doesn't fill its disk
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+
+use crate::config::RetentionConfig;
+use crate::upload;
+
+// limits are checked this often, as well as whenever a file is finished
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+// chapter files are removed along with their recordings
+const SIDECAR_EXTENSIONS: &[&str] = &["cue", "json"];
+
+struct Recording {
+    path: PathBuf,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+// only files in dir with the archive's extension, and their chapter files,
+// are ever removed. when the archive uploads, only files which have been
+// uploaded are, so nothing waiting in the queue or to be retried is lost.
+// the thread exits once the sender is dropped
+pub fn start(log: Logger, name: &str, dir: PathBuf, extension: &'static str, config: RetentionConfig, uploads: bool)
+    -> mpsc::Sender<()>
+{
+    let (tx, rx) = mpsc::channel();
+
+    thread::Builder::new()
+        .name(format!("edicast/retention: {}", name))
+        .spawn(move || loop {
+            if let Err(e) = enforce(&log, &dir, extension, &config, uploads) {
+                slog::warn!(log, "Could not apply archive retention";
+                    "path" => dir.display(),
+                    "error" => e.to_string(),
+                );
+            }
+
+            match rx.recv_timeout(CHECK_INTERVAL) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        })
+        .expect("spawn edicast retention thread");
+
+    tx
+}
+
+fn enforce(log: &Logger, dir: &Path, extension: &str, config: &RetentionConfig, uploads: bool)
+    -> Result<(), io::Error>
+{
+    let mut recordings = match list(dir, extension) {
+        Ok(recordings) => recordings,
+        // nothing has been archived yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut total_bytes = recordings.iter().map(|recording| recording.bytes).sum::<u64>();
+
+    recordings.sort_by_key(|recording| recording.modified);
+
+    // the newest recording is most likely still being written
+    let current = recordings.iter()
+        .rev()
+        .find(|recording| has_extension(&recording.path, extension))
+        .and_then(|recording| recording.path.file_stem())
+        .map(|stem| stem.to_owned());
+
+    let max_age = config.keep_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let max_bytes = config.keep_gb.map(|gb| gb * 1024 * 1024 * 1024);
+    let now = SystemTime::now();
+
+    for recording in recordings {
+        if recording.path.file_stem() == current.as_deref() {
+            continue;
+        }
+
+        if uploads && !upload::is_uploaded(&recording.path) {
+            continue;
+        }
+
+        let expired = max_age.map_or(false, |max_age| {
+            now.duration_since(recording.modified).map_or(false, |age| age > max_age)
+        });
+
+        let oversize = max_bytes.map_or(false, |max_bytes| total_bytes > max_bytes);
+
+        // oldest first, so nothing after this is due yet either
+        if !expired && !oversize {
+            break;
+        }
+
+        match fs::remove_file(&recording.path) {
+            Ok(()) => {
+                slog::info!(log, "Removed old recording"; "path" => recording.path.display());
+                total_bytes -= recording.bytes;
+                upload::unmark(&recording.path);
+            }
+            Err(e) => {
+                slog::warn!(log, "Could not remove old recording";
+                    "path" => recording.path.display(),
+                    "error" => e.to_string(),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list(dir: &Path, extension: &str) -> Result<Vec<Recording>, io::Error> {
+    let mut recordings = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        let ours = has_extension(&path, extension)
+            || SIDECAR_EXTENSIONS.iter().any(|sidecar| has_extension(&path, sidecar));
+
+        if !ours {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+
+        if !metadata.is_file() {
+            continue;
+        }
+
+        recordings.push(Recording {
+            path,
+            bytes: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+
+    Ok(recordings)
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().map_or(false, |ext| ext == extension)
+}
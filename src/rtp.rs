@@ -0,0 +1,250 @@
+// sends a stream's encoded output as RTP over UDP, usually to a multicast
+// group so that any number of receivers on the local network can play it,
+// such as studio monitors or in-building speakers. MP3 is sent as payload
+// type 14 (RFC 2250), one frame per packet
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::config::RtpConfig;
+use crate::stream::{EncodedChunk, StreamSubscription};
+
+// MPEG audio, for any layer
+const PAYLOAD_TYPE: u8 = 14;
+
+// RTP timestamps for MPEG audio count 90kHz ticks, whatever the sample rate
+const CLOCK_RATE: u64 = 90_000;
+
+// payload bytes per packet, keeping packets within an ethernet MTU. larger
+// frames are sent in fragments
+const MAX_PAYLOAD: usize = 1400;
+
+const RTP_HEADER_SIZE: usize = 12;
+const MPA_HEADER_SIZE: usize = 4;
+
+struct Rtp {
+    log: Logger,
+    socket: UdpSocket,
+    target: SocketAddr,
+    ssrc: u32,
+    sequence: u16,
+    // 90kHz ticks at the start of the next frame
+    timestamp: u64,
+    // bytes of a frame split across chunks
+    pending: Vec<u8>,
+    // set after a failed send, so that a broken network is logged once
+    // rather than for every packet
+    failing: bool,
+}
+
+// senders run until the stream is removed, which closes their subscription
+pub fn start(log: Logger, name: &str, config: RtpConfig, subscription: StreamSubscription) {
+    let runtime = Handle::current();
+    let log = log.new(slog::o!("stream" => name.to_owned()));
+
+    let socket = match open(&config) {
+        Ok(socket) => socket,
+        Err(e) => {
+            slog::error!(log, "Could not open socket for RTP, not sending"; "error" => e.to_string());
+            return;
+        }
+    };
+
+    let random = Uuid::new_v4();
+    let random = random.as_bytes();
+
+    let rtp = Rtp {
+        log,
+        socket,
+        target: SocketAddr::new(config.group, config.port),
+        // random, so that receivers can tell senders apart
+        ssrc: u32::from_be_bytes([random[0], random[1], random[2], random[3]]),
+        sequence: 0,
+        timestamp: 0,
+        pending: Vec::new(),
+        failing: false,
+    };
+
+    thread::Builder::new()
+        .name(format!("edicast/rtp: {}", name))
+        .spawn(move || rtp_main(rtp, runtime, subscription))
+        .expect("spawn edicast rtp thread");
+}
+
+fn open(config: &RtpConfig) -> Result<UdpSocket, io::Error> {
+    let bind = match config.group {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+
+    let socket = UdpSocket::bind(bind)?;
+
+    // std can't set hop limits for IPv6, which stay at the system default
+    // of one router
+    if config.group.is_ipv4() {
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        socket.set_ttl(config.ttl)?;
+    }
+
+    Ok(socket)
+}
+
+fn rtp_main(mut rtp: Rtp, runtime: Handle, mut subscription: StreamSubscription) {
+    slog::info!(rtp.log, "Sending stream over RTP"; "target" => rtp.target.to_string());
+
+    let mut resync = true;
+
+    loop {
+        let chunk = match runtime.block_on(subscription.recv()) {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(skipped)) => {
+                slog::warn!(rtp.log, "RTP fell behind stream, receivers will hear a gap";
+                    "skipped_chunks" => skipped);
+
+                rtp.pending.clear();
+                resync = true;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        // timestamps follow on frame by frame, and are only taken from the
+        // stream again after a gap
+        if resync {
+            rtp.timestamp = ticks(chunk.pts);
+            resync = false;
+        }
+
+        rtp.send_chunk(&chunk);
+    }
+
+    slog::info!(rtp.log, "Stopped sending stream over RTP");
+}
+
+impl Rtp {
+    fn send_chunk(&mut self, chunk: &EncodedChunk) {
+        self.pending.extend_from_slice(&chunk.data);
+
+        let mut offset = 0;
+
+        loop {
+            match find_frame(&self.pending[offset..]) {
+                Some(skip) => offset += skip,
+                None => {
+                    // a trailing 0xff may be the start of the next sync
+                    offset = self.pending.len().saturating_sub(1).max(offset);
+                    break;
+                }
+            }
+
+            let frame = match Frame::parse(&self.pending[offset..]) {
+                Some(frame) if offset + frame.length <= self.pending.len() => frame,
+                // the rest of the frame is in the next chunk
+                Some(_) => break,
+                None if self.pending.len() - offset < 4 => break,
+                // not a frame after all, look for the next sync
+                None => {
+                    offset += 1;
+                    continue;
+                }
+            };
+
+            let data = self.pending[offset..offset + frame.length].to_vec();
+            self.send_frame(&data);
+
+            self.timestamp += frame.samples * CLOCK_RATE / frame.sample_rate;
+            offset += frame.length;
+        }
+
+        self.pending.drain(..offset);
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) {
+        for (index, fragment) in frame.chunks(MAX_PAYLOAD).enumerate() {
+            let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + MPA_HEADER_SIZE + fragment.len());
+
+            // version 2, no padding, extension or contributing sources
+            packet.push(0x80);
+            packet.push(PAYLOAD_TYPE);
+            packet.extend_from_slice(&self.sequence.to_be_bytes());
+            packet.extend_from_slice(&(self.timestamp as u32).to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+
+            // two zero bytes, then the fragment's offset into the frame
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&((index * MAX_PAYLOAD) as u16).to_be_bytes());
+            packet.extend_from_slice(fragment);
+
+            self.sequence = self.sequence.wrapping_add(1);
+
+            match self.socket.send_to(&packet, self.target) {
+                Ok(_) => {
+                    self.failing = false;
+                }
+                Err(e) => {
+                    if !self.failing {
+                        slog::warn!(self.log, "Could not send RTP packet"; "error" => e.to_string());
+                    }
+
+                    self.failing = true;
+                }
+            }
+        }
+    }
+}
+
+fn ticks(pts: Duration) -> u64 {
+    (pts.as_secs_f64() * CLOCK_RATE as f64) as u64
+}
+
+// how far into data the next frame sync is, if there is one
+fn find_frame(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|pair| pair[0] == 0xff && pair[1] & 0xe0 == 0xe0)
+}
+
+// just enough of an MPEG audio layer III frame header to split a stream
+// into frames
+struct Frame {
+    length: usize,
+    samples: u64,
+    sample_rate: u64,
+}
+
+const BITRATES_V1: [u64; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const BITRATES_V2: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const SAMPLE_RATES_V1: [u64; 3] = [44100, 48000, 32000];
+
+impl Frame {
+    fn parse(data: &[u8]) -> Option<Frame> {
+        let header = data.get(..4)?;
+
+        // layer III only, as that's all the encoder makes
+        if header[0] != 0xff || header[1] & 0xe0 != 0xe0 || (header[1] >> 1) & 0x03 != 0x01 {
+            return None;
+        }
+
+        // MPEG 1, 2 or 2.5, by how far the sample rate is divided
+        let divisor = match (header[1] >> 3) & 0x03 {
+            0x03 => 1,
+            0x02 => 2,
+            0x00 => 4,
+            _ => return None,
+        };
+
+        let bitrates = if divisor == 1 { &BITRATES_V1 } else { &BITRATES_V2 };
+        let bitrate = *bitrates.get((header[2] >> 4) as usize).filter(|&&bitrate| bitrate != 0)?;
+        let sample_rate = SAMPLE_RATES_V1.get(((header[2] >> 2) & 0x03) as usize)? / divisor;
+        let padding = ((header[2] >> 1) & 0x01) as u64;
+
+        let (samples, coefficient) = if divisor == 1 { (1152, 144_000) } else { (576, 72_000) };
+        let length = (coefficient * bitrate / sample_rate + padding) as usize;
+
+        Some(Frame { length, samples, sample_rate })
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// a repeating interval aligned to the unix epoch, so that eg. an hourly
+// interval with no offset always fires at the top of the hour
+#[derive(Debug, Clone)]
+pub struct Interval {
+    every: Duration,
+    offset: Duration,
+}
+
+impl Interval {
+    pub fn new(every: Duration, offset: Duration) -> Self {
+        Interval { every, offset }
+    }
+
+    pub fn next_after(&self, time: SystemTime) -> SystemTime {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let every = self.every.as_secs().max(1);
+        let offset = self.offset.as_secs() % every;
+
+        let next = if secs < offset {
+            offset
+        } else {
+            ((secs - offset) / every + 1) * every + offset
+        };
+
+        UNIX_EPOCH + Duration::from_secs(next)
+    }
+}
+
+// times of day, in UTC
+#[derive(Debug, Clone)]
+pub struct Daily {
+    times: Vec<Duration>,
+}
+
+impl Daily {
+    pub fn new(times: Vec<Duration>) -> Self {
+        Daily { times }
+    }
+
+    // None if there are no times
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let today = secs / 86400 * 86400;
+
+        [today, today + 86400].iter()
+            .flat_map(|day| self.times.iter().map(move |time| day + time.as_secs() % 86400))
+            .filter(|next| *next > secs)
+            .min()
+            .map(|next| UNIX_EPOCH + Duration::from_secs(next))
+    }
+}
+
+// a window of time on some days of each week, such as 20:00 to 22:00 every
+// saturday. days are numbered from monday, and times are in UTC
+#[derive(Debug, Clone)]
+pub struct Weekly {
+    days: Vec<u64>,
+    start: Duration,
+    length: Duration,
+}
+
+impl Weekly {
+    // every day if days is empty
+    pub fn new(days: Vec<u64>, start: Duration, length: Duration) -> Self {
+        Weekly { days, start, length }
+    }
+
+    // the next window which hasn't ended by time, which may already be
+    // under way
+    pub fn next_window(&self, time: SystemTime) -> (SystemTime, SystemTime) {
+        let today = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+
+        // a window which started yesterday may still be running
+        (today.saturating_sub(1)..today + 8)
+            // 1970-01-01 was a thursday
+            .filter(|day| self.days.is_empty() || self.days.contains(&((day + 3) % 7)))
+            .map(|day| {
+                let start = UNIX_EPOCH + Duration::from_secs(day * 86400) + self.start;
+                (start, start + self.length)
+            })
+            .find(|(_, end)| *end > time)
+            .expect("a window every week")
+    }
+}
+
+// formats time in UTC according to pattern, where %Y %m %d %H %M and %S are
+// replaced by the year, month, day, hour, minute and second
+pub fn format_utc(pattern: &str, time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    let mut output = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+
+        let _ = match chars.next() {
+            Some('Y') => write!(output, "{:04}", year),
+            Some('m') => write!(output, "{:02}", month),
+            Some('d') => write!(output, "{:02}", day),
+            Some('H') => write!(output, "{:02}", secs_of_day / 3600),
+            Some('M') => write!(output, "{:02}", secs_of_day / 60 % 60),
+            Some('S') => write!(output, "{:02}", secs_of_day % 60),
+            Some('%') => write!(output, "%"),
+            Some(other) => write!(output, "%{}", other),
+            None => write!(output, "%"),
+        };
+    }
+
+    output
+}
+
+// days since 1970-01-01 to a year, month and day in the proleptic gregorian
+// calendar, after Howard Hinnant's civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn mins(mins: u64) -> Duration {
+        Duration::from_secs(mins * 60)
+    }
+
+    #[test]
+    fn interval_is_aligned_to_the_epoch() {
+        let hourly = Interval::new(mins(60), Duration::ZERO);
+
+        assert_eq!(hourly.next_after(at(0)), at(3600));
+        assert_eq!(hourly.next_after(at(1)), at(3600));
+        assert_eq!(hourly.next_after(at(3599)), at(3600));
+        assert_eq!(hourly.next_after(at(3600)), at(7200));
+    }
+
+    #[test]
+    fn interval_with_offset() {
+        let quarter_past = Interval::new(mins(60), mins(15));
+
+        assert_eq!(quarter_past.next_after(at(0)), at(900));
+        assert_eq!(quarter_past.next_after(at(900)), at(4500));
+        assert_eq!(quarter_past.next_after(at(1000)), at(4500));
+
+        // offsets longer than the interval wrap around
+        let wrapped = Interval::new(mins(60), mins(75));
+        assert_eq!(wrapped.next_after(at(1000)), at(4500));
+    }
+
+    #[test]
+    fn interval_is_at_least_a_second() {
+        let zero = Interval::new(Duration::ZERO, Duration::ZERO);
+        assert_eq!(zero.next_after(at(10)), at(11));
+    }
+
+    #[test]
+    fn daily_times() {
+        let daily = Daily::new(vec![mins(7 * 60), mins(12 * 60)]);
+        let day = 86400 * 100;
+
+        assert_eq!(daily.next_after(at(day)), Some(at(day + 7 * 3600)));
+        assert_eq!(daily.next_after(at(day + 7 * 3600)), Some(at(day + 12 * 3600)));
+        assert_eq!(daily.next_after(at(day + 13 * 3600)), Some(at(day + 86400 + 7 * 3600)));
+        assert_eq!(Daily::new(Vec::new()).next_after(at(day)), None);
+    }
+
+    #[test]
+    fn weekly_windows() {
+        // saturdays from 20:00 to 22:00. 1970-01-03 was a saturday
+        let weekly = Weekly::new(vec![5], mins(20 * 60), mins(120));
+        let saturday = 2 * 86400;
+
+        assert_eq!(weekly.next_window(at(0)), (at(saturday + 20 * 3600), at(saturday + 22 * 3600)));
+
+        // a window under way is still the next one
+        assert_eq!(weekly.next_window(at(saturday + 21 * 3600)).0, at(saturday + 20 * 3600));
+        assert_eq!(weekly.next_window(at(saturday + 22 * 3600)).0, at(saturday + 7 * 86400 + 20 * 3600));
+
+        // windows past midnight run into the next day
+        let overnight = Weekly::new(Vec::new(), mins(23 * 60), mins(120));
+        assert_eq!(overnight.next_window(at(86400 + 1800)), (at(82800), at(82800 + 7200)));
+    }
+
+    #[test]
+    fn formats_utc_times() {
+        // 2024-02-29 13:05:09
+        let time = at(1709211909);
+
+        assert_eq!(format_utc("%Y-%m-%d %H:%M:%S", time), "2024-02-29 13:05:09");
+        assert_eq!(format_utc("100%% %q %", time), "100% %q %");
+    }
+}
@@ -0,0 +1,73 @@
+use std::ffi::OsString;
+
+use schemars::gen::SchemaSettings;
+use schemars::schema::{InstanceType, ObjectValidation, RootSchema, Schema, SchemaObject};
+
+use edicast_core::config::{CodecConfig, Config, SourceConfig, StreamConfig};
+
+const USAGE: &str = "usage: edicast schema";
+
+// entry point for `edicast schema`, returns the process exit code
+pub fn main(args: Vec<OsString>) -> i32 {
+    if !args.is_empty() {
+        eprintln!("{}", USAGE);
+        return 1;
+    }
+
+    match serde_json::to_string_pretty(&schema()) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        }
+        Err(e) => {
+            eprintln!("edicast schema: {}", e);
+            1
+        }
+    }
+}
+
+// JSON Schema for config files, generated from the config types. the
+// [defaults] and [codec] sections are taken out of the document before it
+// is deserialized into Config, so they're described here by hand
+pub fn schema() -> RootSchema {
+    let mut generator = SchemaSettings::draft07().into_generator();
+
+    let source = generator.subschema_for::<SourceConfig>();
+    let stream = generator.subschema_for::<StreamConfig>();
+    let codec = generator.subschema_for::<CodecConfig>();
+
+    let mut root = generator.root_schema_for::<Config>();
+
+    // any field of a source or stream may come from [defaults] instead,
+    // so nothing can be required of each entry on its own
+    for name in ["SourceConfig", "StreamConfig"] {
+        if let Some(Schema::Object(definition)) = root.definitions.get_mut(name) {
+            definition.object().required.clear();
+        }
+    }
+
+    let properties = &mut root.schema.object().properties;
+
+    properties.insert("defaults".to_owned(), object(ObjectValidation {
+        properties: [("source".to_owned(), source), ("stream".to_owned(), stream)]
+            .into_iter()
+            .collect(),
+        additional_properties: Some(Box::new(Schema::Bool(false))),
+        ..Default::default()
+    }));
+
+    properties.insert("codec".to_owned(), object(ObjectValidation {
+        additional_properties: Some(Box::new(codec)),
+        ..Default::default()
+    }));
+
+    root
+}
+
+fn object(validation: ObjectValidation) -> Schema {
+    Schema::Object(SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        object: Some(Box::new(validation)),
+        ..Default::default()
+    })
+}
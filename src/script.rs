@@ -0,0 +1,133 @@
+// a script can be given a say in decisions which vary from station to
+// station: letting source clients and listeners in, rewriting titles, and
+// which source to fall back to while a stream's source is offline. scripts
+// are Rhai, see [script] in edicast.toml for the functions they can define
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use slog::Logger;
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::event::Event;
+use crate::server::Edicast;
+use crate::stream::RewireStreamError;
+
+#[cfg(feature = "scripting")]
+mod engine;
+#[cfg(feature = "scripting")]
+pub use self::engine::Script;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("edicast was built without the scripting feature")]
+    Unsupported,
+    #[error("{0}")]
+    Compile(String),
+}
+
+#[cfg(not(feature = "scripting"))]
+pub struct Script {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn load(_log: Logger, _config: &crate::config::ScriptConfig) -> Result<Self, ScriptError> {
+        Err(ScriptError::Unsupported)
+    }
+
+    pub fn accept_source(&self, _source: &str, _dj: Option<&str>, _address: Option<std::net::SocketAddr>) -> bool {
+        match self.never {}
+    }
+
+    pub fn accept_listener(&self, _stream: &str, _address: Option<std::net::SocketAddr>, _user_agent: Option<&str>) -> bool {
+        match self.never {}
+    }
+
+    pub fn rewrite_title(&self, _source: &str, _title: Option<String>) -> Option<String> {
+        match self.never {}
+    }
+
+    pub fn choose_fallback(&self, _stream: &str, _source: &str) -> Option<String> {
+        match self.never {}
+    }
+}
+
+// moves streams to the source the script falls back to when theirs goes
+// offline, and back again once it returns
+pub fn start(log: Logger, script: Arc<Script>, edicast: Arc<Edicast>) {
+    let runtime = Handle::current();
+    let mut events = edicast.events.subscribe();
+
+    thread::Builder::new()
+        .name("edicast/script".to_owned())
+        .spawn(move || {
+            // streams on a fallback, and the source they were moved from
+            let mut moved = HashMap::<String, String>::new();
+
+            loop {
+                let event = match runtime.block_on(events.recv()) {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        slog::warn!(log, "Script fell behind events, some were not handled"; "skipped_events" => skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                match event {
+                    Event::SourceDisconnected { source, .. } => {
+                        for stream in edicast.streams.streams_for_source(&source) {
+                            let fallback = match script.choose_fallback(&stream, &source) {
+                                Some(fallback) => fallback,
+                                None => continue,
+                            };
+
+                            match edicast.rewire_stream(&stream, &fallback) {
+                                Ok(_) => {
+                                    slog::info!(log, "Moved stream to fallback source chosen by script";
+                                        "stream" => &stream,
+                                        "from_source" => &source,
+                                        "to_source" => &fallback,
+                                    );
+
+                                    // a fallback failing over again still
+                                    // goes back to the original source
+                                    moved.entry(stream).or_insert_with(|| source.clone());
+                                }
+                                Err(RewireStreamError::NoSuchSource) => {
+                                    slog::warn!(log, "Script chose a fallback source which doesn't exist";
+                                        "stream" => &stream,
+                                        "source" => &fallback,
+                                    );
+                                }
+                                Err(RewireStreamError::NoSuchStream) => {}
+                            }
+                        }
+                    }
+                    Event::SourceConnected { source, .. } => {
+                        let returning = moved.iter()
+                            .filter(|(_, from)| **from == source)
+                            .map(|(stream, _)| stream.clone())
+                            .collect::<Vec<_>>();
+
+                        for stream in returning {
+                            moved.remove(&stream);
+
+                            if edicast.rewire_stream(&stream, &source).is_ok() {
+                                slog::info!(log, "Moved stream back from fallback source";
+                                    "stream" => &stream,
+                                    "source" => &source,
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .expect("spawn edicast script thread");
+}
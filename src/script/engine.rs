@@ -0,0 +1,162 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Scope, AST};
+use slog::Logger;
+
+use crate::config::ScriptConfig;
+use super::ScriptError;
+
+// how often the script file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Script {
+    log: Logger,
+    config: ScriptConfig,
+    engine: Engine,
+    state: Mutex<State>,
+}
+
+struct State {
+    ast: Arc<AST>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// arguments edicast doesn't know are passed to the script as ()
+fn optional(value: Option<impl ToString>) -> Dynamic {
+    match value {
+        Some(value) => Dynamic::from(value.to_string()),
+        None => Dynamic::UNIT,
+    }
+}
+
+impl Script {
+    pub fn load(log: Logger, config: &ScriptConfig) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+
+        engine.on_print({
+            let log = log.clone();
+            move |message| slog::info!(log, "Script: {}", message)
+        });
+
+        let modified = modified(&config.path);
+        let ast = engine.compile_file(config.path.clone())
+            .map_err(|e| ScriptError::Compile(e.to_string()))?;
+
+        Ok(Script {
+            log,
+            config: config.clone(),
+            engine,
+            state: Mutex::new(State {
+                ast: Arc::new(ast),
+                modified,
+                checked_at: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn accept_source(&self, source: &str, dj: Option<&str>, address: Option<SocketAddr>) -> bool {
+        let args = (source.to_owned(), optional(dj), optional(address));
+
+        match self.call("on_source_connect", args) {
+            Some(accept) => accept.as_bool().unwrap_or(true),
+            None => true,
+        }
+    }
+
+    pub fn accept_listener(&self, stream: &str, address: Option<SocketAddr>, user_agent: Option<&str>) -> bool {
+        let args = (stream.to_owned(), optional(address), optional(user_agent));
+
+        match self.call("on_listener_connect", args) {
+            Some(accept) => accept.as_bool().unwrap_or(true),
+            None => true,
+        }
+    }
+
+    // the title to set in place of title, which is only replaced if the
+    // script returns a string
+    pub fn rewrite_title(&self, source: &str, title: Option<String>) -> Option<String> {
+        let args = (source.to_owned(), optional(title.as_ref()));
+
+        match self.call("on_metadata", args) {
+            Some(rewritten) if rewritten.is_string() => rewritten.into_string().ok(),
+            _ => title,
+        }
+    }
+
+    // the source to move stream to while source is offline, if any
+    pub fn choose_fallback(&self, stream: &str, source: &str) -> Option<String> {
+        let args = (stream.to_owned(), source.to_owned());
+
+        self.call("on_source_offline", args)
+            .filter(|fallback| fallback.is_string())
+            .and_then(|fallback| fallback.into_string().ok())
+    }
+
+    // None if the script doesn't define name, or failed
+    fn call(&self, name: &str, args: impl FuncArgs) -> Option<Dynamic> {
+        let ast = self.ast();
+
+        if !ast.iter_functions().any(|function| function.name == name) {
+            return None;
+        }
+
+        // the script's top level statements only run as it's loaded
+        let options = CallFnOptions::new().eval_ast(false);
+
+        match self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &ast, name, args) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                slog::warn!(self.log, "Script failed, carrying on without it";
+                    "function" => name,
+                    "error" => e.to_string(),
+                );
+                None
+            }
+        }
+    }
+
+    // the current script, compiled again first if its file has changed
+    fn ast(&self) -> Arc<AST> {
+        let mut state = self.state.lock().expect("lock script");
+
+        if state.checked_at.elapsed() < CHECK_INTERVAL {
+            return state.ast.clone();
+        }
+
+        state.checked_at = Instant::now();
+
+        let modified = modified(&self.config.path);
+
+        if modified == state.modified {
+            return state.ast.clone();
+        }
+
+        // not tried again until the file changes again, if it fails
+        state.modified = modified;
+
+        match self.engine.compile_file(self.config.path.clone()) {
+            Ok(ast) => {
+                slog::info!(self.log, "Reloaded script"; "path" => self.config.path.display());
+                state.ast = Arc::new(ast);
+            }
+            Err(e) => {
+                slog::error!(self.log, "Could not reload script, keeping the previous one";
+                    "path" => self.config.path.display(),
+                    "error" => e.to_string(),
+                );
+            }
+        }
+
+        state.ast.clone()
+    }
+}
@@ -1,102 +1,468 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use slog::Logger;
 use thiserror::Error;
 
+use crate::access_log;
+use crate::cluster::Cluster;
 use crate::config::Config;
-use crate::net;
+#[cfg(feature = "tls")]
+use crate::config::TlsConfig;
+use crate::event::EventBus;
+use crate::journal::Journal;
+use crate::listener::ListenerRegistry;
+use crate::logging::Levels;
+use crate::net::{self, tls};
+#[cfg(feature = "tls")]
+use crate::net::acme;
+use crate::script::{self, Script, ScriptError};
 use crate::source::SourceSet;
-use crate::stream::StreamSet;
+use crate::stats::Stats;
+use crate::stream::{RewireStreamError, StreamSet};
 
+#[cfg(feature = "control")]
+mod admin;
 mod common;
+#[cfg(feature = "control")]
 mod control;
+mod files;
+#[cfg(feature = "http3")]
+mod http3;
+mod player;
+mod privileges;
 mod public;
+mod reload;
+mod shutdown;
+#[cfg(unix)]
+mod upgrade;
+#[cfg(feature = "control")]
+mod websocket;
+
+pub use privileges::chown_to;
 
 pub struct Edicast {
     pub config: Config,
-    pub public_routes: HashMap<String, String>,
+    pub config_path: PathBuf,
+    pub config_overlays: Vec<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub acme_challenges: acme::Challenges,
+    pub cluster: Option<Arc<Cluster>>,
+    pub events: EventBus,
+    pub journal: Option<Journal>,
+    pub listeners: Arc<ListenerRegistry>,
+    pub log_levels: Levels,
+    pub script: Option<Arc<Script>>,
+    pub shutdown: shutdown::Shutdown,
     pub sources: SourceSet,
+    pub stats: Option<Stats>,
     pub streams: StreamSet,
+    #[cfg(feature = "control")]
+    control_pool: control::WorkerPool,
+    log: Logger,
+    reload_lock: Mutex<()>,
 }
 
 impl Edicast {
-    pub fn new(log: Logger, config: Config) -> Self {
-        let sources = SourceSet::new(log.clone(), &config.source);
+    pub fn new(log: Logger, log_levels: Levels, config_path: PathBuf, config_overlays: Vec<PathBuf>, config: Config)
+        -> Result<Self, StartError>
+    {
+        net::client::set_interface(config.listen.interface.outbound.clone());
+
+        let events = EventBus::new();
+
+        let access_log = config.access_log.clone()
+            .map(|access_log_config| access_log::start(log.clone(), access_log_config));
+
+        let listeners = ListenerRegistry::new(events.clone(), access_log);
 
-        let streams = StreamSet::new(log.clone(), &config.stream, &sources);
+        let stats = match &config.stats {
+            Some(stats_config) => {
+                let stats = Stats::open(stats_config)?;
+                stats.start(log.clone(), &events, listeners.clone())?;
+                Some(stats)
+            }
+            None => None,
+        };
+
+        // started before sources, so that the journal has their first events
+        let journal = match &config.journal {
+            Some(journal_config) => {
+                let journal = Journal::open(journal_config).map_err(StartError::Journal)?;
+                journal.start(log.clone(), &events).map_err(StartError::Journal)?;
+                Some(journal)
+            }
+            None => None,
+        };
 
-        let public_routes = config.stream.iter().map(|(name, config)| {
-            (config.path.to_string(), name.to_string())
-        }).collect();
+        let script = config.script.as_ref()
+            .map(|script_config| Script::load(log.clone(), script_config).map(Arc::new))
+            .transpose()?;
 
-        Edicast {
+        let sources = SourceSet::new(log.clone(), events.clone(), &config.source);
+
+        let streams = StreamSet::new(log.clone(), events.clone(), &config.stream, &sources);
+
+        #[cfg(feature = "control")]
+        let control_pool = control::WorkerPool::new(&config.control);
+
+        let cluster = config.cluster.clone().map(|cluster_config| Arc::new(Cluster::new(cluster_config)));
+
+        Ok(Edicast {
             config,
-            public_routes,
+            config_path,
+            config_overlays,
+            #[cfg(feature = "tls")]
+            acme_challenges: acme::Challenges::default(),
+            cluster,
+            events,
+            journal,
+            listeners,
+            log_levels,
+            script,
+            shutdown: shutdown::Shutdown::new(),
             sources,
+            stats,
             streams,
+            #[cfg(feature = "control")]
+            control_pool,
+            log,
+            reload_lock: Mutex::new(()),
+        })
+    }
+
+    // shuts down gracefully, ending streams with the configured goodbye and
+    // disconnecting sources, then listeners once they've been sent the end
+    // of their stream. resolves once shutdown is complete
+    pub async fn stop(&self) {
+        shutdown::shutdown(self.log.clone(), self).await
+    }
+
+    // points a stream at a different source, and resubscribes its existing
+    // listeners so that they pick up metadata from the new source. returns
+    // the name of the previous source
+    pub fn rewire_stream(&self, stream: &str, source: &str) -> Result<String, RewireStreamError> {
+        let previous = self.streams.rewire_stream(stream, source, &self.sources)?;
+
+        for listener in self.listeners.list() {
+            if listener.stream() != stream {
+                continue;
+            }
+
+            let stream_move = match public::stream_move(self, stream) {
+                Some(stream_move) => stream_move,
+                None => break,
+            };
+
+            listener.move_to(stream_move);
         }
+
+        Ok(previous)
     }
 }
 
 #[derive(Error, Debug)]
 pub enum StartError {
-    #[error("could not bind {0}: {1}")]
-    Bind(SocketAddr, Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error(transparent)]
     Public(#[from] net::BindError),
+    #[error("could not load public TLS certificate: {0}")]
+    PublicTls(#[from] tls::TlsError),
+    #[error("could not load control TLS certificate: {0}")]
+    ControlTls(tls::TlsError),
+    #[cfg(feature = "http3")]
+    #[error("listen.public_quic requires listen.public_tls")]
+    QuicWithoutTls,
+    #[error("could not install signal handler: {0}")]
+    Signal(std::io::Error),
+    #[error("could not open stats database: {0}")]
+    Stats(#[from] rusqlite::Error),
+    #[error("could not open event journal: {0}")]
+    Journal(rusqlite::Error),
+    #[error("could not load script: {0}")]
+    Script(#[from] ScriptError),
+    #[error("could not drop privileges: {0}")]
+    Privileges(#[from] privileges::PrivilegeError),
+    #[error("could not finish starting: {0}")]
+    Startup(std::io::Error),
+}
+
+// how far serve has got in starting, for its startup callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Startup {
+    // every listener is bound, and edicast is still running as the user it
+    // was started as
+    Bound,
+    // privileges are dropped, and nothing has been accepted yet
+    Ready,
+}
+
+// runs edicast until SIGTERM or SIGINT, or a console event on windows,
+// reloading config on SIGHUP and handing over to a new process on SIGUSR2.
+// startup is called as for serve
+pub async fn run(log: Logger, log_levels: Levels, config_path: PathBuf, config_overlays: Vec<PathBuf>, config: Config,
+    startup: impl FnMut(Startup) -> Result<(), std::io::Error>) -> Result<(), StartError>
+{
+    let terminate = shutdown::terminate_signal()
+        .map_err(StartError::Signal)?;
+
+    run_until(log, log_levels, config_path, config_overlays, config, terminate, startup).await
 }
 
-pub async fn run(log: Logger, config: Config) -> Result<(), StartError> {
+// as run, but shuts down gracefully once stop resolves rather than on
+// SIGTERM or SIGINT, for when something else decides when edicast stops,
+// such as the windows service manager
+pub async fn run_until(log: Logger, log_levels: Levels, config_path: PathBuf, config_overlays: Vec<PathBuf>, config: Config,
+    stop: impl Future<Output = ()>, mut startup: impl FnMut(Startup) -> Result<(), std::io::Error>)
+    -> Result<(), StartError>
+{
+    // sockets from a previous edicast process on upgrade, or systemd
+    #[cfg(unix)]
+    net::handoff::inherit(&log);
+
     slog::info!(log, "Starting edicast";
-        "public" => config.listen.public,
-        "control" => config.listen.control,
+        "public" => config.listen.public.to_string(),
+        "public_tls" => config.listen.public_tls.is_some(),
+        "control" => config.listen.control.to_string(),
+        "control_tls" => config.listen.control_tls.is_some(),
+        "public_quic" => config.listen.public_quic.map(|addr| addr.to_string()),
+        "websocket" => config.listen.websocket.map(|addr| addr.to_string()),
     );
 
-    let edicast = Arc::new(Edicast::new(log.clone(), config));
+    let edicast = Arc::new(Edicast::new(log.clone(), log_levels, config_path, config_overlays, config)?);
+
+    #[cfg(unix)]
+    reload::on_sighup(edicast.clone())
+        .map_err(StartError::Signal)?;
+
+    let terminate = {
+        let log = log.clone();
+        let edicast = edicast.clone();
+
+        async move {
+            stop.await;
+            shutdown::shutdown(log, &edicast).await;
+        }
+    };
+
+    #[cfg(unix)]
+    let upgrade = upgrade::on_sigusr2(log.clone(), edicast.clone())
+        .map_err(StartError::Signal)?;
+
+    #[cfg(not(unix))]
+    let upgrade = futures::future::pending::<()>();
+
+    // the process we took over from, if any, stops accepting once we're
+    // ready to
+    let startup = move |stage| {
+        startup(stage)?;
+
+        #[cfg(unix)]
+        if stage == Startup::Ready {
+            net::handoff::notify_ready();
+        }
+
+        Ok(())
+    };
+
+    // shutdown and upgrade both finish by stopping edicast, which serve
+    // returns on, but the signal futures are what drive them
+    tokio::select! {
+        result = serve(log, edicast, startup) => result?,
+        _ = terminate => {}
+        _ = upgrade => {}
+    }
+
+    Ok(())
+}
+
+// starts edicast's servers and background tasks, and runs them until
+// Edicast::stop has finished. signals are left to the caller, so that
+// edicast can be embedded in another application. startup is called once
+// every listener is bound, and again once privileges are dropped, before
+// anything is accepted. edicast doesn't start if it fails
+pub async fn serve(log: Logger, edicast: Arc<Edicast>, mut startup: impl FnMut(Startup) -> Result<(), std::io::Error>)
+    -> Result<(), StartError>
+{
+    tokio::task::spawn(crate::supervise::watchdog(log.clone(), edicast.config.watchdog.clone()));
 
     // run public server
-    let public = public::start(edicast.config.listen.public, edicast.clone()).await?;
-
-    // setup + run control server
-    let control_listener = tiny_http::Server::http(&edicast.config.listen.control)
-        .map_err(|e| StartError::Bind(edicast.config.listen.control, e))?;
-
-    let control = crate::thread::spawn_worker("edicast/control", async move {
-        crossbeam::scope(|scope| {
-            for req in control_listener.incoming_requests() {
-                let thread_name = thread_name(&req);
-
-                let result = scope.builder()
-                    .name(thread_name.clone())
-                    .spawn({
-                        let edicast = &edicast;
-                        let log = log.clone();
-                        move |_| control::dispatch(req, log, edicast)
-                    });
-
-                if let Err(e) = result {
-                    slog::crit!(log, "Could not spawn thread";
-                        "error" => format!("{:?}", e),
-                        "name" => thread_name,
-                    );
-                }
-            }
-        }).expect("scoped thread panicked");
-    });
+    let public_tls = edicast.config.listen.public_tls.as_ref()
+        .map(tls::Acceptor::new)
+        .transpose()?;
+
+    // keep the public certificate issued and renewed if configured
+    #[cfg(feature = "tls")]
+    let public_acme = match (&edicast.config.listen.public_tls, &public_tls) {
+        (Some(tls_config @ TlsConfig { acme: Some(acme_config), .. }), Some(acceptor)) => {
+            Some(acme::start(
+                log.clone(),
+                tls_config.clone(),
+                acme_config.clone(),
+                acceptor.clone(),
+                edicast.acme_challenges.clone(),
+                edicast.config.listen.interface.public.as_deref(),
+            ).await?)
+        }
+        _ => None,
+    };
+
+    #[cfg(not(feature = "tls"))]
+    let public_acme = None::<futures::future::Pending<()>>;
+
+    if let (Some(tls_config), Some(acceptor)) = (&edicast.config.listen.public_tls, &public_tls) {
+        tokio::task::spawn(tls::watch(log.clone(), tls_config.clone(), acceptor.clone()));
+    }
+
+    #[cfg(feature = "http3")]
+    let public_quic = match (edicast.config.listen.public_quic, &public_tls) {
+        (Some(address), Some(acceptor)) => Some((address, http3::bind(address)?, acceptor.clone())),
+        (Some(_), None) => return Err(StartError::QuicWithoutTls),
+        (None, _) => None,
+    };
+
+    #[cfg(not(feature = "http3"))]
+    if edicast.config.listen.public_quic.is_some() {
+        slog::warn!(log, "Ignoring listen.public_quic, edicast was built without the http3 feature");
+    }
+
+    let mut public = Vec::new();
+
+    for addr in edicast.config.listen.public.iter() {
+        let tls = if addr.tls { public_tls.clone() } else { None };
+        public.push(public::start(addr.address, tls, edicast.clone()).await?);
+    }
+
+    let public = futures::future::join_all(public);
+
+    let control = start_control(&log, &edicast).await?;
+
+    // every listener is bound by now, and nothing has been accepted on them
+    // until the servers below are polled
+    startup(Startup::Bound).map_err(StartError::Startup)?;
+    privileges::drop_to(&log, edicast.config.user.as_deref(), edicast.config.group.as_deref())?;
+
+    #[cfg(feature = "http3")]
+    let public_quic = match public_quic {
+        Some((address, socket, acceptor)) => Some(http3::start(address, socket, acceptor, edicast.clone())?),
+        None => None,
+    };
+
+    startup(Startup::Ready).map_err(StartError::Startup)?;
+
+    if let Some(metrics_config) = edicast.config.metrics.clone() {
+        crate::metrics::start(log.clone(), metrics_config, edicast.clone());
+    }
+
+    for recording_config in edicast.config.recording.clone() {
+        crate::recording::start(log.clone(), recording_config, edicast.clone());
+    }
+
+    crate::recording::start_sessions(log.clone(), edicast.clone());
+
+    if let Some(cluster) = edicast.cluster.clone() {
+        crate::cluster::start(log.clone(), cluster, edicast.clone());
+    }
+
+    #[cfg(feature = "ingest-pull")]
+    crate::pull::start_sources(log.clone(), edicast.clone());
+
+    #[cfg(feature = "ingest-pull")]
+    if let Some(mirror_config) = edicast.config.mirror.clone() {
+        crate::mirror::start(log.clone(), mirror_config, edicast.clone());
+    }
+
+    #[cfg(not(feature = "ingest-pull"))]
+    {
+        let pulled = edicast.sources.names().into_iter()
+            .filter(|name| edicast.sources.config(name).map_or(false, |config| config.pull.is_some()))
+            .collect::<Vec<_>>();
+
+        if !pulled.is_empty() {
+            slog::warn!(log, "Ignoring source pull config, edicast was built without the ingest-pull feature";
+                "sources" => pulled.join(", "));
+        }
+
+        if edicast.config.mirror.is_some() {
+            slog::warn!(log, "Ignoring mirror, edicast was built without the ingest-pull feature");
+        }
+    }
+
+    crate::alert::start(log.clone(), edicast.config.alert.clone(), edicast.clone());
+
+    crate::hook::start(log.clone(), edicast.config.hook.clone(), &edicast.events);
+
+    if let Some(script) = edicast.script.clone() {
+        script::start(log.clone(), script, edicast.clone());
+    }
+
+    #[cfg(feature = "http3")]
+    let public = futures::future::join(public, futures::future::OptionFuture::from(public_quic));
+
+    let servers = futures::future::join3(
+        public,
+        control,
+        futures::future::OptionFuture::from(public_acme),
+    );
+
+    // the servers keep accepting until edicast is stopped, so this returns
+    // once a graceful shutdown has completed
+    tokio::select! {
+        _ = servers => {}
+        _ = edicast.shutdown.finished() => {}
+    }
 
-    futures::future::join(public, control).await;
     Ok(())
 }
 
-fn thread_name(req: &tiny_http::Request) -> String {
-        let remote_addr = req.remote_addr()
-            .map(|a| a.to_string())
-            .unwrap_or_default();
+// the control server and control WebSocket server, resolving once both have
+// stopped accepting
+#[cfg(feature = "control")]
+async fn start_control(log: &Logger, edicast: &Arc<Edicast>) -> Result<impl Future<Output = ()>, StartError> {
+    // run control WebSocket server if configured
+    let websocket = match edicast.config.listen.websocket {
+        Some(address) => Some(websocket::start(address, edicast.clone()).await?),
+        None => None,
+    };
+
+    // run control server
+    if edicast.config.control.token.is_none() {
+        slog::warn!(log, "No control token set, control requests will only be accepted from localhost");
+    }
+
+    let control_tls = match &edicast.config.listen.control_tls {
+        Some(tls_config) => {
+            let acceptor = tls::Acceptor::new(tls_config)
+                .map_err(StartError::ControlTls)?;
+
+            tokio::task::spawn(tls::watch(log.clone(), tls_config.clone(), acceptor.clone()));
+            Some(acceptor)
+        }
+        None => None,
+    };
+
+    let mut control = Vec::new();
+
+    for addr in edicast.config.listen.control.iter() {
+        let tls = if addr.tls { control_tls.clone() } else { None };
+        control.push(control::start(addr.address, tls, edicast.clone()).await?);
+    }
+
+    let control = futures::future::join(
+        futures::future::join_all(control),
+        futures::future::OptionFuture::from(websocket),
+    );
+
+    Ok(async move { control.await; })
+}
+
+#[cfg(not(feature = "control"))]
+async fn start_control(log: &Logger, edicast: &Arc<Edicast>) -> Result<impl Future<Output = ()>, StartError> {
+    slog::info!(log, "Not serving listen.control, edicast was built without the control feature");
 
-        let method = req.method();
-        let url = req.url();
+    if edicast.config.listen.websocket.is_some() {
+        slog::warn!(log, "Ignoring listen.websocket, edicast was built without the control feature");
+    }
 
-        format!("edicast/control: {remote_addr} {method} {url:40}")
+    Ok(futures::future::ready(()))
 }
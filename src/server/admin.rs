@@ -0,0 +1,1131 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use http_body_util::combinators::UnsyncBoxBody;
+use hyper::body::{Body, Frame, Incoming};
+use hyper::{Method, Response, StatusCode};
+use serde_derive::Serialize;
+use slog::{Level, Logger};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::config::{SourceConfig, StreamConfig};
+use crate::crash;
+use crate::event::Event;
+use crate::journal;
+use crate::memory;
+use crate::metrics;
+use crate::metadata::AdBreak;
+use crate::source::{AddSourceError, KickSourceError, SourceStatus};
+use crate::stats::{self, Stats};
+use crate::stream::{AddStreamError, RewireStreamError};
+use crate::supervise::{self, State};
+use super::common;
+use super::control::PoolError;
+use super::public;
+use super::Edicast;
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+const EVENT_STREAM_BUFFER: usize = 16;
+const EVENT_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+pub type ControlResponse = Response<UnsyncBoxBody<Bytes, Infallible>>;
+
+fn boxed<B>(response: Response<B>) -> ControlResponse
+    where B: Body<Data = Bytes, Error = Infallible> + Send + 'static
+{
+    response.map(|body| body.boxed_unsync())
+}
+
+// a control request with its body read in full, so that it can be handled
+// on a blocking thread
+struct Request {
+    url: String,
+    body: Bytes,
+}
+
+impl Request {
+    fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+pub async fn dispatch(req: hyper::Request<Incoming>, log: Logger, edicast: Arc<Edicast>) -> ControlResponse {
+    // the UI page itself is static and carries no data, it prompts for the
+    // token and sends it with each API request it makes
+    #[cfg(feature = "admin-ui")]
+    if req.method() == Method::GET && req.uri().path() == "/" {
+        return boxed(admin_ui());
+    }
+
+    if !authorized(&req, &edicast) {
+        slog::warn!(log, "Unauthorized control request";
+            common::request_log_keys(&req));
+
+        return boxed(common::unauthorized());
+    }
+
+    // the event stream lives as long as its client, everything else is
+    // handled on the blocking pool once its body has been read, as many
+    // control requests decode audio or query the stats database. /events
+    // with a query asks the journal for past events instead
+    if req.method() == Method::GET && req.uri().path() == "/events" && req.uri().query().is_none() {
+        return boxed(event_stream(log, edicast));
+    }
+
+    let method = req.method().clone();
+
+    let url = req.uri().path_and_query()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "/".to_owned());
+
+    let body = match read_body(req.into_body()).await {
+        Ok(body) => body,
+        Err(response) => { return boxed(response); }
+    };
+
+    let req = Request { url, body };
+
+    let result = edicast.control_pool.run({
+        let log = log.clone();
+        let edicast = edicast.clone();
+        move || route(&method, &req, log, &edicast)
+    }).await;
+
+    match result {
+        Ok(response) => boxed(response),
+        Err(PoolError::Full) => {
+            slog::warn!(log, "Control worker pool is full, turning request away");
+            boxed(common::status(StatusCode::SERVICE_UNAVAILABLE))
+        }
+        Err(PoolError::Panicked(e)) => {
+            slog::error!(log, "Control request handler failed"; "error" => e.to_string());
+            boxed(common::internal_server_error())
+        }
+    }
+}
+
+fn route(method: &Method, req: &Request, log: Logger, edicast: &Edicast) -> Response<Full<Bytes>> {
+    let segments = common::url_path(req.url())
+        .trim_start_matches('/')
+        .split('/')
+        .map(common::decode_component)
+        .collect::<Vec<_>>();
+
+    let segments = segments.iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["events"]) => {
+            query_journal(req, log, edicast)
+        }
+        (&Method::GET, ["health"]) => {
+            health()
+        }
+        (&Method::GET, ["listeners"]) => {
+            list_listeners(req, edicast)
+        }
+        (&Method::GET, ["buffers"]) => {
+            buffer_stats(edicast)
+        }
+        (&Method::GET, ["memory"]) => {
+            memory_stats()
+        }
+        (&Method::GET, ["metrics"]) => {
+            prometheus_metrics(edicast)
+        }
+        (&Method::POST, ["reload"]) => {
+            reload(log, edicast)
+        }
+        (&Method::GET, ["log"]) => {
+            log_levels(edicast)
+        }
+        (&Method::PUT, ["log", "level"]) => {
+            set_log_level(req, log, edicast)
+        }
+        (&Method::PUT, ["log", "filters", target]) => {
+            set_log_filter(req, log, edicast, target)
+        }
+        (&Method::DELETE, ["log", "filters", target]) => {
+            remove_log_filter(log, edicast, target)
+        }
+        (&Method::GET, ["sources"]) => {
+            list_sources(edicast)
+        }
+        (&Method::GET, ["streams"]) => {
+            list_streams(edicast)
+        }
+        (&Method::GET, ["stats", "listener-sessions"]) => {
+            query_stats(req, log, edicast, "stream", Stats::listener_sessions)
+        }
+        (&Method::GET, ["stats", "source-sessions"]) => {
+            query_stats(req, log, edicast, "source", Stats::source_sessions)
+        }
+        (&Method::GET, ["stats", "listener-samples"]) => {
+            query_stats(req, log, edicast, "stream", Stats::listener_samples)
+        }
+        (&Method::GET, ["stats", "summary"]) => {
+            stats_summary(req, log, edicast)
+        }
+        (&Method::PUT, ["streams", stream]) => {
+            add_stream(req, log, edicast, stream)
+        }
+        (&Method::DELETE, ["streams", stream]) => {
+            remove_stream(log, edicast, stream)
+        }
+        (&Method::PUT, ["streams", stream, "source"]) => {
+            rewire_stream(req, log, edicast, stream)
+        }
+        (&Method::POST, ["streams", stream, "pause"]) => {
+            set_paused(log, edicast, stream, true)
+        }
+        (&Method::POST, ["streams", stream, "resume"]) => {
+            set_paused(log, edicast, stream, false)
+        }
+        (&Method::POST, ["streams", stream, "move-listeners"]) => {
+            move_listeners(req, log, edicast, stream)
+        }
+        (&Method::PUT, ["sources", source]) => {
+            add_source(req, log, edicast, source)
+        }
+        (&Method::DELETE, ["sources", source]) => {
+            remove_source(log, edicast, source)
+        }
+        (&Method::DELETE, ["sources", source, "client"]) => {
+            kick_source(log, edicast, source)
+        }
+        (&Method::POST, ["sources", source, "metadata"]) => {
+            update_metadata(req, log, edicast, source)
+        }
+        (&Method::POST, ["sources", source, "ad-break"]) => {
+            start_ad_break(req, log, edicast, source)
+        }
+        (&Method::DELETE, ["sources", source, "ad-break"]) => {
+            end_ad_break(log, edicast, source)
+        }
+        (_, ["events"]) |
+        (_, ["health"]) |
+        (_, ["listeners"]) |
+        (_, ["buffers"]) |
+        (_, ["memory"]) |
+        (_, ["metrics"]) |
+        (_, ["reload"]) |
+        (_, ["log"]) |
+        (_, ["log", "level"]) |
+        (_, ["log", "filters", _]) |
+        (_, ["sources"]) |
+        (_, ["streams"]) |
+        (_, ["stats", "listener-sessions"]) |
+        (_, ["stats", "source-sessions"]) |
+        (_, ["stats", "listener-samples"]) |
+        (_, ["stats", "summary"]) |
+        (_, ["streams", _]) |
+        (_, ["streams", _, "source"]) |
+        (_, ["streams", _, "pause"]) |
+        (_, ["streams", _, "resume"]) |
+        (_, ["streams", _, "move-listeners"]) |
+        (_, ["sources", _]) |
+        (_, ["sources", _, "client"]) |
+        (_, ["sources", _, "metadata"]) |
+        (_, ["sources", _, "ad-break"]) => {
+            common::method_not_allowed()
+        }
+        _ => common::not_found(),
+    }
+}
+
+fn authorized(req: &hyper::Request<Incoming>, edicast: &Edicast) -> bool {
+    let given = req.headers().get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim());
+
+    common::authorized(common::connection_peer(req), edicast.config.control.token.as_deref(), given)
+}
+
+async fn read_body(mut body: Incoming) -> Result<Bytes, Response<Full<Bytes>>> {
+    let mut buffer = BytesMut::new();
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| common::bad_request(&e.to_string()))?;
+
+        if let Ok(data) = frame.into_data() {
+            if buffer.len() + data.len() > MAX_BODY_SIZE {
+                return Err(common::status(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+
+            buffer.extend_from_slice(&data);
+        }
+    }
+
+    Ok(buffer.freeze())
+}
+
+fn event_stream(log: Logger, edicast: Arc<Edicast>) -> Response<EventStreamBody> {
+    let mut events = edicast.events.subscribe();
+    let (tx, rx) = mpsc::channel(EVENT_STREAM_BUFFER);
+
+    slog::info!(log, "Event stream client connected");
+
+    tokio::task::spawn(async move {
+        loop {
+            let chunk = match tokio::time::timeout(EVENT_STREAM_KEEPALIVE, events.recv()).await {
+                Ok(Ok(event)) => format_event(&event),
+                Ok(Err(RecvError::Lagged(count))) => format!(": lagged, skipped {} events\n\n", count),
+                Ok(Err(RecvError::Closed)) => break,
+                Err(_) => ": keepalive\n\n".to_owned(),
+            };
+
+            // the body is dropped once the client disconnects
+            if tx.send(Bytes::from(chunk)).await.is_err() {
+                break;
+            }
+        }
+
+        slog::info!(log, "Event stream client disconnected");
+    });
+
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(EventStreamBody { rx })
+        .expect("build response")
+}
+
+fn format_event(event: &Event) -> String {
+    let data = serde_json::to_string(event)
+        .expect("serialize event");
+
+    format!("event: {}\ndata: {}\n\n", event.kind(), data)
+}
+
+struct EventStreamBody {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl Body for EventStreamBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Frame<Bytes>, Infallible>>>
+    {
+        self.rx.poll_recv(cx).map(|chunk| chunk.map(|data| Ok(Frame::data(data))))
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn elapsed_secs(since: Option<SystemTime>) -> u64 {
+    since.and_then(|time| time.elapsed().ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize)]
+pub struct ListenerSummary {
+    id: String,
+    stream: String,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+    connected_at: u64,
+    connected_secs: u64,
+    bytes_sent: u64,
+}
+
+pub fn listener_summaries(edicast: &Edicast, stream: Option<&str>) -> Vec<ListenerSummary> {
+    let now = SystemTime::now();
+
+    edicast.listeners.list().into_iter()
+        .filter(|listener| stream.map(|stream| stream == listener.stream()).unwrap_or(true))
+        .map(|listener| ListenerSummary {
+            id: listener.id.to_string(),
+            stream: listener.stream(),
+            remote_addr: listener.remote_addr.map(|addr| addr.to_string()),
+            user_agent: listener.user_agent.clone(),
+            connected_at: unix_secs(listener.connected_at),
+            connected_secs: now.duration_since(listener.connected_at).unwrap_or_default().as_secs(),
+            bytes_sent: listener.bytes_sent(),
+        })
+        .collect()
+}
+
+fn list_listeners(req: &Request, edicast: &Edicast) -> Response<Full<Bytes>> {
+    let stream = common::query_param(req.url(), "stream");
+    let listeners = listener_summaries(edicast, stream.as_deref());
+    common::json(&listeners)
+}
+
+#[derive(Serialize)]
+struct BufferStats {
+    streams: Vec<StreamBuffers>,
+    sources: Vec<SourceBuffers>,
+    allocator: Option<AllocatorSummary>,
+}
+
+#[derive(Serialize)]
+struct StreamBuffers {
+    name: String,
+    queued: usize,
+    capacity: usize,
+    receivers: usize,
+}
+
+#[derive(Serialize)]
+struct SourceBuffers {
+    name: String,
+    subscribers: usize,
+    capacity: usize,
+    dropped: u64,
+    disconnected: u64,
+    buffered_samples: usize,
+    jitter_depth_ms: usize,
+    jitter_target_ms: usize,
+    jitter_underruns: u64,
+}
+
+#[derive(Serialize)]
+struct AllocatorSummary {
+    allocated: usize,
+    active: usize,
+    resident: usize,
+    mapped: usize,
+    retained: usize,
+}
+
+fn buffer_stats(edicast: &Edicast) -> Response<Full<Bytes>> {
+    let mut streams = edicast.streams.buffer_stats().into_iter()
+        .map(|stats| StreamBuffers {
+            name: stats.name,
+            queued: stats.queued,
+            capacity: stats.capacity,
+            receivers: stats.receivers,
+        })
+        .collect::<Vec<_>>();
+
+    let mut sources = edicast.sources.buffer_stats().into_iter()
+        .map(|stats| SourceBuffers {
+            name: stats.name,
+            subscribers: stats.subscribers,
+            capacity: stats.capacity,
+            dropped: stats.dropped,
+            disconnected: stats.disconnected,
+            buffered_samples: stats.buffered_samples,
+            jitter_depth_ms: stats.jitter_depth_ms,
+            jitter_target_ms: stats.jitter_target_ms,
+            jitter_underruns: stats.jitter_underruns,
+        })
+        .collect::<Vec<_>>();
+
+    streams.sort_by(|a, b| a.name.cmp(&b.name));
+    sources.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let allocator = memory::allocator_stats().map(|stats| AllocatorSummary {
+        allocated: stats.allocated,
+        active: stats.active,
+        resident: stats.resident,
+        mapped: stats.mapped,
+        retained: stats.retained,
+    });
+
+    common::json(&BufferStats { streams, sources, allocator })
+}
+
+#[derive(Serialize)]
+struct MemoryStats {
+    allocated: usize,
+    active: usize,
+    resident: usize,
+    mapped: usize,
+    retained: usize,
+    metadata: usize,
+    arenas: Vec<ArenaSummary>,
+}
+
+#[derive(Serialize)]
+struct ArenaSummary {
+    index: u32,
+    threads: u32,
+    allocated: usize,
+    active: usize,
+    dirty: usize,
+    resident: usize,
+}
+
+fn memory_stats() -> Response<Full<Bytes>> {
+    let stats = match memory::allocator_stats() {
+        Some(stats) => stats,
+        // not running on jemalloc
+        None => { return common::status(StatusCode::NOT_IMPLEMENTED); }
+    };
+
+    let arenas = stats.arenas.into_iter()
+        .map(|arena| ArenaSummary {
+            index: arena.index,
+            threads: arena.threads,
+            allocated: arena.allocated,
+            active: arena.active,
+            dirty: arena.dirty,
+            resident: arena.resident,
+        })
+        .collect();
+
+    common::json(&MemoryStats {
+        allocated: stats.allocated,
+        active: stats.active,
+        resident: stats.resident,
+        mapped: stats.mapped,
+        retained: stats.retained,
+        metadata: stats.metadata,
+        arenas,
+    })
+}
+
+fn prometheus_metrics(edicast: &Edicast) -> Response<Full<Bytes>> {
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(metrics::prometheus(edicast))))
+        .expect("build response")
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    // panics since startup, including restarted ones
+    crashes: u64,
+    threads: Vec<ThreadHealth>,
+}
+
+#[derive(Serialize)]
+struct ThreadHealth {
+    name: String,
+    state: &'static str,
+    restarts: u32,
+    last_panic: Option<String>,
+    last_panic_at: Option<u64>,
+}
+
+// responds 503 once any supervised thread has been given up on, so that
+// whatever is watching edicast can restart it
+fn health() -> Response<Full<Bytes>> {
+    let mut threads = supervise::status().into_iter()
+        .map(|child| ThreadHealth {
+            name: child.name,
+            state: match child.state {
+                State::Running => "running",
+                State::Restarting => "restarting",
+                State::Stalled => "stalled",
+                State::Failed => "failed",
+            },
+            restarts: child.restarts,
+            last_panic: child.last_panic,
+            last_panic_at: child.last_panic_at.map(unix_secs),
+        })
+        .collect::<Vec<_>>();
+
+    threads.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let failed = threads.iter().any(|thread| thread.state == "failed" || thread.state == "stalled");
+
+    let health = Health {
+        status: if failed { "degraded" } else { "ok" },
+        crashes: crash::count(),
+        threads,
+    };
+
+    let mut response = common::json(&health);
+
+    if failed {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    response
+}
+
+#[derive(Serialize)]
+struct SourceSummary {
+    name: String,
+    live: bool,
+    title: Option<String>,
+    level: u16,
+    live_since: Option<u64>,
+    last_offline_at: Option<u64>,
+    uptime_secs: u64,
+    restarts: u32,
+}
+
+fn list_sources(edicast: &Edicast) -> Response<Full<Bytes>> {
+    let mut sources = edicast.sources.names().into_iter()
+        .filter_map(|name| {
+            let status = *edicast.sources.status(&name)?.borrow();
+            let title = edicast.sources.metadata(&name)?.borrow().title.clone();
+            let level = edicast.sources.level(&name)?;
+            let uptime = edicast.sources.uptime(&name)?;
+            let restarts = edicast.sources.restarts(&name)?;
+
+            Some(SourceSummary {
+                live: status == SourceStatus::Live,
+                title,
+                level,
+                live_since: uptime.live_since.map(unix_secs),
+                last_offline_at: uptime.last_offline_at.map(unix_secs),
+                uptime_secs: elapsed_secs(uptime.live_since),
+                restarts,
+                name,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    sources.sort_by(|a, b| a.name.cmp(&b.name));
+    common::json(&sources)
+}
+
+#[derive(Serialize)]
+struct StreamSummary {
+    name: String,
+    host: Option<String>,
+    path: String,
+    listen_url: String,
+    source: String,
+    station_name: Option<String>,
+    description: Option<String>,
+    genre: Option<String>,
+    url: Option<String>,
+    public: bool,
+    private: bool,
+    serve: bool,
+    paused: bool,
+    source_lost: bool,
+    listeners: usize,
+    // listeners across every server in the cluster, if clustering
+    cluster_listeners: Option<usize>,
+    started_at: Option<u64>,
+    uptime_secs: u64,
+    relays: Vec<RelaySummary>,
+    sinks: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct RelaySummary {
+    url: String,
+    state: &'static str,
+    connected_at: Option<u64>,
+    uptime_secs: u64,
+    bytes_sent: u64,
+    reconnects: u64,
+    last_error: Option<String>,
+}
+
+fn list_streams(edicast: &Edicast) -> Response<Full<Bytes>> {
+    let listeners = edicast.listeners.list();
+
+    let mut streams = edicast.streams.list().into_iter()
+        .map(|(name, config)| StreamSummary {
+            paused: edicast.streams.is_paused(&name),
+            source_lost: edicast.streams.is_source_lost(&name),
+            listeners: listeners.iter().filter(|listener| listener.stream() == name).count(),
+            cluster_listeners: None,
+            started_at: edicast.streams.started_at(&name).map(unix_secs),
+            uptime_secs: elapsed_secs(edicast.streams.started_at(&name)),
+            relays: edicast.streams.relays(&name).into_iter()
+                .map(|relay| RelaySummary {
+                    state: relay.state.as_str(),
+                    connected_at: relay.connected_at.map(unix_secs),
+                    uptime_secs: elapsed_secs(relay.connected_at),
+                    url: relay.url,
+                    bytes_sent: relay.bytes_sent,
+                    reconnects: relay.reconnects,
+                    last_error: relay.last_error,
+                })
+                .collect(),
+            sinks: edicast.streams.sinks(&name),
+            listen_url: edicast.config.stream_url(&config),
+            host: config.host,
+            path: config.path,
+            source: config.source,
+            station_name: config.name,
+            description: config.description,
+            genre: config.genre,
+            url: config.url,
+            public: config.public,
+            private: config.private,
+            serve: config.serve,
+            name,
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(cluster) = &edicast.cluster {
+        for stream in &mut streams {
+            stream.cluster_listeners = Some(stream.listeners + cluster.remote_listeners(&stream.name));
+        }
+    }
+
+    streams.sort_by(|a, b| a.name.cmp(&b.name));
+    common::json(&streams)
+}
+
+fn stats_query(url: &str, name_param: &str) -> Result<stats::Query, &'static str> {
+    let timestamp = |param| match common::query_param(url, param) {
+        Some(value) => value.parse().map(Some).map_err(|_| "Invalid timestamp"),
+        None => Ok(None),
+    };
+
+    Ok(stats::Query {
+        since: timestamp("since")?,
+        until: timestamp("until")?,
+        name: common::query_param(url, name_param),
+    })
+}
+
+fn query_stats<T: serde::Serialize>(
+    req: &Request,
+    log: Logger,
+    edicast: &Edicast,
+    name_param: &str,
+    query_fn: fn(&Stats, &stats::Query) -> Result<Vec<T>, rusqlite::Error>,
+) -> Response<Full<Bytes>> {
+    let stats = match &edicast.stats {
+        Some(stats) => stats,
+        None => { return common::not_found(); }
+    };
+
+    let query = match stats_query(req.url(), name_param) {
+        Ok(query) => query,
+        Err(message) => { return common::bad_request(message); }
+    };
+
+    match query_fn(stats, &query) {
+        Ok(rows) => common::json(&rows),
+        Err(e) => {
+            slog::error!(log, "Could not query stats"; "error" => e.to_string());
+            common::internal_server_error()
+        }
+    }
+}
+
+fn stats_summary(req: &Request, log: Logger, edicast: &Edicast) -> Response<Full<Bytes>> {
+    let stats = match &edicast.stats {
+        Some(stats) => stats,
+        None => { return common::not_found(); }
+    };
+
+    let period = match common::query_param(req.url(), "period") {
+        Some(name) => match stats::Period::from_name(&name) {
+            Some(period) => period,
+            None => { return common::bad_request("Invalid period"); }
+        }
+        None => stats::Period::Day,
+    };
+
+    let query = match stats_query(req.url(), "stream") {
+        Ok(query) => query,
+        Err(message) => { return common::bad_request(message); }
+    };
+
+    let until = query.until.unwrap_or_else(stats::unix_now);
+    let periods = (until - query.since.unwrap_or(until)) / period.length();
+
+    if periods > stats::MAX_SUMMARY_PERIODS {
+        return common::bad_request("Time range too long");
+    }
+
+    match stats.summary(&query, period) {
+        Ok(summary) => common::json(&summary),
+        Err(e) => {
+            slog::error!(log, "Could not query stats"; "error" => e.to_string());
+            common::internal_server_error()
+        }
+    }
+}
+
+fn journal_query(url: &str) -> Result<journal::Query, &'static str> {
+    let timestamp = |param| match common::query_param(url, param) {
+        Some(value) => value.parse().map(Some).map_err(|_| "Invalid timestamp"),
+        None => Ok(None),
+    };
+
+    let limit = match common::query_param(url, "limit") {
+        Some(value) => Some(value.parse().map_err(|_| "Invalid limit")?),
+        None => None,
+    };
+
+    Ok(journal::Query {
+        since: timestamp("since")?,
+        until: timestamp("until")?,
+        kind: common::query_param(url, "kind"),
+        name: common::query_param(url, "name"),
+        limit,
+    })
+}
+
+fn query_journal(req: &Request, log: Logger, edicast: &Edicast) -> Response<Full<Bytes>> {
+    let journal = match &edicast.journal {
+        Some(journal) => journal,
+        None => { return common::not_found(); }
+    };
+
+    let query = match journal_query(req.url()) {
+        Ok(query) => query,
+        Err(message) => { return common::bad_request(message); }
+    };
+
+    match journal.query(&query) {
+        Ok(entries) => common::json(&entries),
+        Err(e) => {
+            slog::error!(log, "Could not query event journal"; "error" => e.to_string());
+            common::internal_server_error()
+        }
+    }
+}
+
+#[cfg(feature = "admin-ui")]
+fn admin_ui() -> Response<Full<Bytes>> {
+    Response::builder()
+        .header("content-type", "text/html; charset=utf-8")
+        .body(Full::new(Bytes::from_static(include_str!("admin_ui.html").as_bytes())))
+        .expect("build response")
+}
+
+fn add_stream(req: &Request, log: Logger, edicast: &Edicast, stream: &str)
+    -> Response<Full<Bytes>>
+{
+    let config = match serde_json::from_slice::<StreamConfig>(&req.body) {
+        Ok(config) => config,
+        Err(e) => { return common::bad_request(&e.to_string()); }
+    };
+
+    let path = config.route_key();
+
+    match edicast.streams.add_stream(stream, config, &edicast.sources) {
+        Ok(()) => {
+            slog::info!(log, "Added stream"; "stream" => stream, "path" => path);
+            common::no_content()
+        }
+        Err(AddStreamError::AlreadyExists) => {
+            common::conflict()
+        }
+        Err(AddStreamError::PathInUse) => {
+            common::conflict()
+        }
+        Err(AddStreamError::NoSuchSource) => {
+            common::bad_request("No such source")
+        }
+    }
+}
+
+fn remove_stream(log: Logger, edicast: &Edicast, stream: &str)
+    -> Response<Full<Bytes>>
+{
+    if edicast.streams.remove_stream(stream) {
+        slog::info!(log, "Removed stream"; "stream" => stream);
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
+
+fn add_source(req: &Request, log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    let config = match serde_json::from_slice::<SourceConfig>(&req.body) {
+        Ok(config) => config,
+        Err(e) => { return common::bad_request(&e.to_string()); }
+    };
+
+    match edicast.sources.add_source(source, &config) {
+        Ok(()) => {
+            slog::info!(log, "Added source"; "source" => source);
+            common::no_content()
+        }
+        Err(AddSourceError::AlreadyExists) => {
+            common::conflict()
+        }
+    }
+}
+
+fn remove_source(log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    // refuse to remove sources which are still feeding streams, those
+    // streams must be removed or rewired first
+    let streams = edicast.streams.streams_for_source(source);
+
+    if !streams.is_empty() {
+        slog::warn!(log, "Refusing to remove source in use by streams";
+            "source" => source,
+            "streams" => streams.join(", "),
+        );
+
+        return common::conflict();
+    }
+
+    if edicast.sources.remove_source(source) {
+        slog::info!(log, "Removed source"; "source" => source);
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
+
+fn rewire_stream(req: &Request, log: Logger, edicast: &Edicast, stream: &str)
+    -> Response<Full<Bytes>>
+{
+    let source = match common::query_param(req.url(), "source") {
+        Some(source) => source,
+        None => { return common::bad_request("Missing source"); }
+    };
+
+    let previous = match edicast.rewire_stream(stream, &source) {
+        Ok(previous) => previous,
+        Err(RewireStreamError::NoSuchStream) => {
+            return common::not_found();
+        }
+        Err(RewireStreamError::NoSuchSource) => {
+            return common::bad_request("No such source");
+        }
+    };
+
+    slog::info!(log, "Rewired stream";
+        "stream" => stream,
+        "from_source" => previous,
+        "to_source" => &source,
+    );
+
+    common::no_content()
+}
+
+fn reload(log: Logger, edicast: &Edicast) -> Response<Full<Bytes>> {
+    match edicast.reload() {
+        Ok(summary) => common::json(&summary),
+        Err(e) => {
+            slog::error!(log, "Could not reload config"; "error" => e.to_string());
+            common::bad_request(&e.to_string())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LogLevels {
+    level: &'static str,
+    filters: Vec<LogFilter>,
+}
+
+#[derive(Serialize)]
+struct LogFilter {
+    target: String,
+    level: &'static str,
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Critical => "critical",
+        Level::Error => "error",
+        Level::Warning => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn log_levels(edicast: &Edicast) -> Response<Full<Bytes>> {
+    let summary = edicast.log_levels.summary();
+
+    common::json(&LogLevels {
+        level: level_name(summary.level),
+        filters: summary.filters.into_iter()
+            .map(|(target, level)| LogFilter { target, level: level_name(level) })
+            .collect(),
+    })
+}
+
+// the error is the message for a bad request response
+fn level_param(req: &Request) -> Result<Level, &'static str> {
+    let level = common::query_param(req.url(), "level").ok_or("Missing level")?;
+    level.parse().map_err(|()| "Invalid level")
+}
+
+// levels set here last until changed again, or until a reload finds the
+// config file's levels have changed
+fn set_log_level(req: &Request, log: Logger, edicast: &Edicast) -> Response<Full<Bytes>> {
+    let level = match level_param(req) {
+        Ok(level) => level,
+        Err(message) => { return common::bad_request(message); }
+    };
+
+    edicast.log_levels.set_level(level);
+    slog::info!(log, "Changed log level"; "level" => level_name(level));
+    common::no_content()
+}
+
+fn set_log_filter(req: &Request, log: Logger, edicast: &Edicast, target: &str)
+    -> Response<Full<Bytes>>
+{
+    let level = match level_param(req) {
+        Ok(level) => level,
+        Err(message) => { return common::bad_request(message); }
+    };
+
+    edicast.log_levels.set_filter(target, level);
+    slog::info!(log, "Changed log filter"; "target" => target, "level" => level_name(level));
+    common::no_content()
+}
+
+fn remove_log_filter(log: Logger, edicast: &Edicast, target: &str)
+    -> Response<Full<Bytes>>
+{
+    if edicast.log_levels.remove_filter(target) {
+        slog::info!(log, "Removed log filter"; "target" => target);
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
+
+fn set_paused(log: Logger, edicast: &Edicast, stream: &str, paused: bool)
+    -> Response<Full<Bytes>>
+{
+    if !edicast.streams.set_paused(stream, paused) {
+        return common::not_found();
+    }
+
+    if paused {
+        slog::warn!(log, "Paused stream"; "stream" => stream);
+    } else {
+        slog::info!(log, "Resumed stream"; "stream" => stream);
+    }
+
+    common::no_content()
+}
+
+#[derive(Serialize)]
+struct MoveListenersResult {
+    moved: usize,
+}
+
+fn move_listeners(req: &Request, log: Logger, edicast: &Edicast, from: &str)
+    -> Response<Full<Bytes>>
+{
+    let to = match common::query_param(req.url(), "to") {
+        Some(to) => to,
+        None => { return common::bad_request("Missing destination stream"); }
+    };
+
+    let (from_config, to_config) = match (edicast.streams.config(from), edicast.streams.config(&to)) {
+        (Some(from_config), Some(to_config)) => (from_config, to_config),
+        _ => { return common::not_found(); }
+    };
+
+    // listeners are moved without any new response headers being sent,
+    // so the destination stream must be encoded identically
+    if from_config.codec != to_config.codec {
+        return common::bad_request("Streams do not share the same codec");
+    }
+
+    let mut moved = 0;
+
+    for listener in edicast.listeners.list() {
+        if listener.stream() != from {
+            continue;
+        }
+
+        let stream_move = match public::stream_move(edicast, &to) {
+            Some(stream_move) => stream_move,
+            None => break,
+        };
+
+        if listener.move_to(stream_move) {
+            moved += 1;
+        }
+    }
+
+    slog::info!(log, "Moved listeners";
+        "from" => from,
+        "to" => &to,
+        "count" => moved,
+    );
+
+    common::json(&MoveListenersResult { moved })
+}
+
+fn kick_source(log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    match edicast.sources.kick_source(source) {
+        Ok(()) => {
+            slog::info!(log, "Kicked source client"; "source" => source);
+            common::no_content()
+        }
+        Err(KickSourceError::NoSuchSource) |
+        Err(KickSourceError::NotConnected) => {
+            common::not_found()
+        }
+    }
+}
+
+fn update_metadata(req: &Request, log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    let title = common::query_param(req.url(), "title");
+
+    let title = match &edicast.script {
+        Some(script) => script.rewrite_title(source, title),
+        None => title,
+    };
+
+    slog::info!(log, "Updating source metadata";
+        "source" => source,
+        "title" => &title,
+    );
+
+    if edicast.sources.update_metadata(source, |metadata| metadata.title = title) {
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
+
+fn start_ad_break(req: &Request, log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    let duration = match common::query_param(req.url(), "duration_ms") {
+        Some(millis) => match millis.parse() {
+            Ok(millis) => Some(Duration::from_millis(millis)),
+            Err(_) => { return common::bad_request("Invalid duration_ms"); }
+        }
+        None => None,
+    };
+
+    slog::info!(log, "Starting ad break";
+        "source" => source,
+        "duration_ms" => duration.map(|duration| duration.as_millis() as u64),
+    );
+
+    let ad_break = AdBreak { duration };
+
+    if edicast.sources.update_metadata(source, |metadata| metadata.ad_break = Some(ad_break)) {
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
+
+fn end_ad_break(log: Logger, edicast: &Edicast, source: &str)
+    -> Response<Full<Bytes>>
+{
+    slog::info!(log, "Ending ad break"; "source" => source);
+
+    if edicast.sources.update_metadata(source, |metadata| metadata.ad_break = None) {
+        common::no_content()
+    } else {
+        common::not_found()
+    }
+}
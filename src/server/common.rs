@@ -1,22 +1,22 @@
-use std::io;
 use std::net::SocketAddr;
 
 use bytes::Bytes;
+#[cfg(feature = "control")]
+use percent_encoding::percent_decode;
+#[cfg(feature = "control")]
+use serde::Serialize;
 use slog::OwnedKVList;
-use tiny_http::{Request, Response};
-use hyper::StatusCode;
+use ipnet::IpNet;
+use hyper::{Response, StatusCode};
+#[cfg(any(test, feature = "control"))]
+use hyper::header::HeaderValue;
 use http_body_util::Full;
 
+use crate::config::{ForwardedHeader, ListenConfig};
+use crate::net::forwarded;
 use crate::net::SocketPeer;
-
-pub fn request_log_keys(request: &Request) -> OwnedKVList {
-    (slog::o!{
-        "method" => request.method().to_string(),
-        "url" => request.url().to_string(),
-        "http_version" => request.http_version().to_string(),
-        "remote_addr" => request.remote_addr().map(|a| a.to_string()).unwrap_or_default(),
-    }).into()
-}
+#[cfg(feature = "control")]
+use crate::net::ConnectionPeer;
 
 pub fn remote_addr<T>(request: &hyper::Request<T>) -> Option<SocketAddr> {
     request.extensions()
@@ -24,7 +24,40 @@ pub fn remote_addr<T>(request: &hyper::Request<T>) -> Option<SocketAddr> {
         .map(|SocketPeer(addr)| *addr)
 }
 
-pub fn request_log_keys_hyper(request: &hyper::Request<impl hyper::body::Body>) -> OwnedKVList {
+// the address of the connection a request arrived on, from the socket or a
+// PROXY protocol header but never from request headers, for access control
+#[cfg(feature = "control")]
+pub fn connection_peer<T>(request: &hyper::Request<T>) -> Option<SocketAddr> {
+    request.extensions()
+        .get::<ConnectionPeer>()
+        .map(|ConnectionPeer(addr)| *addr)
+}
+
+// records both the connection's peer and the client the request is
+// attributed to, which may be forwarded by a trusted proxy
+pub fn set_peer<T>(request: &mut hyper::Request<T>, peer: SocketAddr, listen: &ListenConfig) {
+    let client = client_addr(request.headers(), peer, listen.forwarded_header, &listen.trusted_proxies);
+    #[cfg(feature = "control")]
+    request.extensions_mut().insert(ConnectionPeer(peer));
+    request.extensions_mut().insert(SocketPeer(client));
+}
+
+// the hostname a request was made for, without any port. HTTP/2 and HTTP/3
+// requests carry it in the URI, HTTP/1.1 in the Host header
+pub fn request_host<T>(request: &hyper::Request<T>) -> Option<&str> {
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => request.headers().get("host")?.to_str().ok()?,
+    };
+
+    // IPv6 literals are bracketed, and the port comes after the bracket
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => Some(name),
+        _ => Some(host),
+    }
+}
+
+pub fn request_log_keys(request: &hyper::Request<impl hyper::body::Body>) -> OwnedKVList {
     (slog::o!{
         "method" => request.method().to_string(),
         "url" => request.uri().to_string(),
@@ -33,32 +66,187 @@ pub fn request_log_keys_hyper(request: &hyper::Request<impl hyper::body::Body>)
     }).into()
 }
 
-pub fn not_found(req: Request) -> Result<(), io::Error> {
-    req.respond(Response::from_string("Not found")
-        .with_status_code(404))
+// the address to attribute a request to, which is the connecting peer
+// unless that's one of the trusted proxies
+fn client_addr(headers: &hyper::HeaderMap, peer: SocketAddr, header: ForwardedHeader, trusted_proxies: &[IpNet])
+    -> SocketAddr
+{
+    let values = headers.get_all(header.name()).iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+
+    let value = Some(values.join(",")).filter(|joined| !joined.is_empty());
+
+    forwarded::client_addr(peer, header, value.as_deref(), trusted_proxies)
+}
+
+#[cfg(feature = "control")]
+pub fn url_path(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+#[cfg(feature = "control")]
+pub fn decode_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    percent_decode(component.as_bytes()).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(feature = "control")]
+pub fn query_param(url: &str, key: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+
+    query.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+        if decode_component(name) == key {
+            Some(decode_component(value))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(feature = "control")]
+pub fn json(value: &impl Serialize) -> Response<Full<Bytes>> {
+    let body = match serde_json::to_string(value) {
+        Ok(body) => body,
+        Err(_) => { return internal_server_error(); }
+    };
+
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .expect("build response")
+}
+
+#[cfg(feature = "control")]
+pub fn no_content() -> Response<Full<Bytes>> {
+    status(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "control")]
+pub fn bad_request(message: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Full::new(Bytes::from(message.to_owned())))
+        .expect("build response")
+}
+
+#[cfg(feature = "control")]
+pub fn unauthorized() -> Response<Full<Bytes>> {
+    let mut response = status(StatusCode::UNAUTHORIZED);
+
+    response.headers_mut()
+        .insert("www-authenticate", HeaderValue::from_static("Bearer"));
+
+    response
+}
+
+// whether a control request carrying the given token may go ahead. with no
+// token configured, only requests from this machine are let through
+#[cfg(feature = "control")]
+pub fn authorized(remote_addr: Option<SocketAddr>, token: Option<&str>, given: Option<&str>) -> bool {
+    match token {
+        Some(token) => given.map(|given| constant_time_eq(given.as_bytes(), token.as_bytes())).unwrap_or(false),
+        None => remote_addr.map(|addr| is_local(addr.ip())).unwrap_or(false),
+    }
+}
+
+// loopback, including IPv4 loopback connecting to an IPv6 socket
+#[cfg(feature = "control")]
+fn is_local(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V6(ip) => ip.is_loopback() || ip.to_ipv4_mapped().map_or(false, |ip| ip.is_loopback()),
+        ip => ip.is_loopback(),
+    }
+}
+
+// takes as long to reject a token as to accept one, so that response times
+// don't give away how much of a guess was right. only the length leaks
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
 }
 
-pub fn status(code: StatusCode) -> hyper::Response<Full<Bytes>> {
-    let text = code.canonical_reason().unwrap_or_default();
+pub fn not_found() -> Response<Full<Bytes>> {
+    status(StatusCode::NOT_FOUND)
+}
+
+#[cfg(feature = "control")]
+pub fn internal_server_error() -> Response<Full<Bytes>> {
+    status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    // no content responses must not have a body
+    let text = match code {
+        StatusCode::NO_CONTENT => "",
+        _ => code.canonical_reason().unwrap_or_default(),
+    };
+
     let body = Full::new(Bytes::from_static(text.as_bytes()));
 
-    hyper::Response::builder()
+    Response::builder()
         .status(code)
         .body(body)
         .unwrap()
 }
 
-pub fn method_not_allowed(req: Request) -> Result<(), io::Error> {
-    req.respond(Response::from_string("Method not allowed")
-        .with_status_code(405))
+pub fn method_not_allowed() -> Response<Full<Bytes>> {
+    status(StatusCode::METHOD_NOT_ALLOWED)
 }
 
-pub fn conflict(req: Request) -> Result<(), io::Error> {
-    req.respond(Response::from_string("Conflict")
-        .with_status_code(409))
+#[cfg(feature = "control")]
+pub fn conflict() -> Response<Full<Bytes>> {
+    status(StatusCode::CONFLICT)
 }
 
-pub fn unsupported_media_type(req: Request) -> Result<(), io::Error> {
-    req.respond(Response::from_string("Unsupported media type")
-        .with_status_code(415))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn reads_only_the_configured_forwarding_header() {
+        let peer = "10.0.0.1:1234".parse().unwrap();
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("forwarded", HeaderValue::from_static("for=198.51.100.1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.1"));
+
+        assert_eq!(Some(client_addr(&headers, peer, ForwardedHeader::XForwardedFor, &trusted)), addr("203.0.113.1:0"));
+        assert_eq!(Some(client_addr(&headers, peer, ForwardedHeader::Forwarded, &trusted)), addr("198.51.100.1:0"));
+
+        headers.remove("forwarded");
+        assert_eq!(client_addr(&headers, peer, ForwardedHeader::Forwarded, &trusted), peer);
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn checks_the_token() {
+        let remote = addr("192.0.2.1:1234");
+
+        assert!(authorized(remote, Some("secret"), Some("secret")));
+        assert!(!authorized(remote, Some("secret"), Some("secreT")));
+        assert!(!authorized(remote, Some("secret"), Some("secret2")));
+        assert!(!authorized(remote, Some("secret"), None));
+    }
+
+    #[test]
+    #[cfg(feature = "control")]
+    fn only_allows_localhost_without_a_token() {
+        assert!(authorized(addr("127.0.0.1:1234"), None, None));
+        assert!(authorized(addr("[::1]:1234"), None, None));
+        assert!(authorized(addr("[::ffff:127.0.0.1]:1234"), None, None));
+        assert!(!authorized(addr("192.0.2.1:1234"), None, None));
+        assert!(!authorized(addr("[2001:db8::1]:1234"), None, None));
+        assert!(!authorized(None, None, None));
+    }
 }
@@ -1,157 +1,178 @@
-use std::io::{self, Read};
-use std::str;
-
-use percent_encoding::percent_decode;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
 use slog::Logger;
-use tiny_http::{Method, Response, Request};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
 use uuid::Uuid;
 
-use crate::audio::decode::{self, PcmRead};
-use crate::source::ConnectSourceError;
+use crate::config::ControlConfig;
+use crate::net::{self, proxy, tls};
+use super::admin::{self, ControlResponse};
 use super::common;
 use super::Edicast;
 
-fn get_header<'a>(req: &'a Request, header_name: &'static str) -> Option<&'a str> {
-    req.headers().iter()
-        .find(|hdr| hdr.field.equiv(header_name))
-        .map(|hdr| hdr.value.as_str())
-}
-
-enum MediaType {
-    Mp3,
-    Ogg,
-}
+#[cfg(feature = "ingest-icecast")]
+mod ingest;
 
-fn init_decoder(media_type: MediaType, io: impl Read + Send + 'static)
-    -> Result<Box<dyn PcmRead + Send>, String>
+pub async fn start(address: SocketAddr, tls: Option<tls::Acceptor>, edicast: Arc<Edicast>)
+    -> Result<impl Future<Output = ()>, net::BindError>
 {
-    use decode::{Mp3, Ogg};
-
-    match media_type {
-        MediaType::Mp3 =>
-            Ok(Box::new(Mp3::new(io)) as Box<dyn PcmRead + Send>),
-        MediaType::Ogg => {
-            match Ogg::new(io) {
-                Ok(ogg) => Ok(Box::new(ogg) as Box<dyn PcmRead + Send>),
-                Err(err) => Err(err.to_string()),
-            }
+    let listener = net::bind(address, edicast.config.listen.interface.control.as_deref()).await?;
+
+    Ok(crate::thread::spawn_worker("edicast/control", async move {
+        loop {
+            let log = slog_scope::logger().new(slog::o!("service" => "control"));
+
+            let (mut stream, peer) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        slog::warn!(log, "error accepting connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = edicast.shutdown.draining() => break,
+            };
+
+            let tls = tls.clone();
+            let edicast = edicast.clone();
+
+            tokio::task::spawn_local(async move {
+                let peer = if edicast.config.listen.proxy_protocol.control {
+                    match proxy::read_header(&mut stream).await {
+                        Ok(source) => source.unwrap_or(peer),
+                        Err(err) => {
+                            slog::debug!(log, "error reading PROXY header: {}", err);
+                            return;
+                        }
+                    }
+                } else {
+                    peer
+                };
+
+                let result = match tls {
+                    Some(tls) => {
+                        let stream = match tls.accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(err) => {
+                                slog::debug!(log, "error in TLS handshake: {}", err);
+                                return;
+                            }
+                        };
+
+                        serve_connection(stream, peer, log.clone(), edicast).await
+                    }
+                    None => serve_connection(stream, peer, log.clone(), edicast).await,
+                };
+
+                match result {
+                    Ok(()) => {}
+                    Err(err) => {
+                        slog::warn!(log, "error serving connection: {}", err);
+                    }
+                }
+            });
         }
-    }
-}
 
-enum SourceKind {
-    IcecastLegacy,
-    Icecast24Put,
+        drop(listener);
+        edicast.shutdown.finished().await;
+    }))
 }
 
-pub fn dispatch(req: Request, log: Logger, edicast: &Edicast) {
-    let request_id = Uuid::new_v4();
-    let log = log.new(slog::o!("request_id" => request_id));
+// bounds the threads handling admin requests. requests beyond the pool's
+// size wait for a free worker, and are turned away once the queue is full
+pub struct WorkerPool {
+    workers: Semaphore,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
 
-    let url = req.url();
+pub enum PoolError {
+    Full,
+    Panicked(JoinError),
+}
 
-    if url.starts_with("/source/") {
-        let source_kind = match req.method() {
-            // SOURCE is sent by legacy icecast clients
-            Method::NonStandard(method) if method == "SOURCE" => {
-                SourceKind::IcecastLegacy
-            }
-            Method::Put => {
-                SourceKind::Icecast24Put
-            }
-            _ => {
-                let _ = common::method_not_allowed(req);
-                return;
-            }
-        };
+impl WorkerPool {
+    pub fn new(config: &ControlConfig) -> Self {
+        WorkerPool {
+            workers: Semaphore::new(config.workers.max(1)),
+            queued: AtomicUsize::new(0),
+            max_queued: config.queue,
+        }
+    }
 
-        let source_name_enc = &url["/source/".len()..];
-        let source_name_dec = percent_decode(source_name_enc.as_bytes());
-        let source_name = match source_name_dec.decode_utf8() {
-            Ok(name) => name,
+    pub async fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static)
+        -> Result<T, PoolError>
+    {
+        let _permit = match self.workers.try_acquire() {
+            Ok(permit) => permit,
             Err(_) => {
-                // if we couldn't decode the source name as valid UTF-8, it
-                // cannot possibly be a valid source name
-                let _ = common::not_found(req);
-                return;
-            }
-        };
-
-        let log = log.new(slog::o!("source" => source_name.to_string()));
-        slog::info!(log, "Live source connecting";
-            common::request_log_keys(&req));
-
-        let content_type = get_header(&req, "Content-Type")
-            .and_then(|val| val.split(';').nth(0));
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    return Err(PoolError::Full);
+                }
 
-        // verify content type is legit before proceeding
-        let media_type = match content_type {
-            Some("audio/mpeg") | Some("audio/mp3") => MediaType::Mp3,
-            Some("audio/ogg") | Some("application/ogg") => MediaType::Ogg,
-            _ => {
-                slog::warn!(log, "Unsupported media type for source stream";
-                    "content_type" => content_type);
+                // leaves the queue even if the client goes away while waiting
+                let _queued = Queued(&self.queued);
 
-                let _ = common::unsupported_media_type(req);
-                return;
+                self.workers.acquire().await
+                    .expect("control worker pool closed")
             }
         };
 
-        let source = match edicast.sources.connect_source(&source_name, log.clone()) {
-            Ok(source) => source,
-            Err(ConnectSourceError::NoSuchSource) => {
-                slog::warn!(log, "Source does not exist");
-
-                let _ = common::not_found(req);
-                return;
-            }
-            Err(ConnectSourceError::AlreadyConnected) => {
-                slog::warn!(log, "Source is already live");
-
-                let _ = common::conflict(req);
-                return;
-            }
-        };
+        tokio::task::spawn_blocking(f).await
+            .map_err(PoolError::Panicked)
+    }
+}
 
-        let decoder_result = match source_kind {
-            SourceKind::IcecastLegacy => {
-                // responding with connection upgrade is not strictly
-                // necessary per the legacy protocol, but is needed to
-                // enable the non-standard protocol to work properly
-                // through proxies which expect conforming requests
-                eprintln!("---> legacy");
-                let io = req.upgrade("icecast", Response::empty(200));
-                init_decoder(media_type, io)
-            }
-            SourceKind::Icecast24Put => {
-                // tiny-http automatically response 100-Continue for us:
-                init_decoder(media_type, RequestBody(req))
-            }
-        };
+struct Queued<'a>(&'a AtomicUsize);
 
-        let decoder = match decoder_result {
-            Ok(decoder) => decoder,
-            Err(msg) => {
-                slog::error!(log, "Error initialising decoder";
-                    "error" => msg);
-                return;
-            }
-        };
-
-        match source.start(decoder) {
-            Ok(()) => {}
-            Err(()) => panic!("the source thread must have died or something?"),
-        }
-    } else {
-        let _ = common::not_found(req);
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
-struct RequestBody(tiny_http::Request);
+async fn serve_connection<IO>(stream: IO, peer: SocketAddr, log: Logger, edicast: Arc<Edicast>)
+    -> Result<(), io::Error>
+    where IO: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    #[cfg(feature = "ingest-icecast")]
+    let stream = match ingest::serve_legacy(stream, peer, &log, &edicast).await? {
+        Some(stream) => stream,
+        None => return Ok(()),
+    };
+
+    let service = hyper::service::service_fn(move |mut req| {
+        common::set_peer(&mut req, peer, &edicast.config.listen);
+        dispatch(req, log.clone(), edicast.clone())
+    });
+
+    http1::Builder::new()
+        .serve_connection(stream, service)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+async fn dispatch(req: hyper::Request<Incoming>, log: Logger, edicast: Arc<Edicast>)
+    -> Result<ControlResponse, Infallible>
+{
+    let request_id = Uuid::new_v4();
+    let log = log.new(slog::o!("request_id" => request_id));
 
-impl Read for RequestBody {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let reader = self.0.as_reader();
-        reader.read(buf)
+    #[cfg(feature = "ingest-icecast")]
+    if let Some(source_name) = req.uri().path().strip_prefix("/source/") {
+        let source_name = source_name.to_owned();
+        return Ok(ingest::put(req, source_name, log, edicast).await);
     }
+
+    Ok(admin::dispatch(req, log, edicast).await)
 }
@@ -0,0 +1,434 @@
+// source clients sending audio to the control server, with either an
+// HTTP PUT to /source/<name> or icecast's older SOURCE method
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::{Method, StatusCode};
+use percent_encoding::percent_decode;
+use slog::Logger;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::sync::{oneshot, watch};
+use uuid::Uuid;
+
+use crate::audio::decode::{self, DecoderType};
+use crate::event::Event;
+use crate::net::base64;
+use crate::source::{interruptible, ConnectSourceError, DumpRead, StartSource};
+use crate::server::admin::ControlResponse;
+use crate::server::common;
+use crate::server::Edicast;
+
+// legacy source request heads longer than this are rejected
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// legacy icecast source clients send a SOURCE request with no body length
+// and stream audio straight after its head, expecting a plain 200 response.
+// hyper can't serve that, so connections are checked for a SOURCE request
+// first. returns the connection to hand to hyper along with whatever was
+// read checking, or None if there's nothing left for hyper to serve
+pub async fn serve_legacy<IO>(mut stream: IO, peer: SocketAddr, log: &Logger, edicast: &Arc<Edicast>)
+    -> Result<Option<Rewind<IO>>, io::Error>
+    where IO: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    const LEGACY_METHOD: &[u8] = b"SOURCE ";
+
+    let mut prefix = BytesMut::new();
+
+    while prefix.len() < LEGACY_METHOD.len() {
+        if stream.read_buf(&mut prefix).await? == 0 {
+            return Ok(None);
+        }
+
+        if !LEGACY_METHOD.starts_with(&prefix[..prefix.len().min(LEGACY_METHOD.len())]) {
+            break;
+        }
+    }
+
+    let stream = Rewind { prefix: prefix.freeze(), io: stream };
+
+    if stream.prefix.starts_with(LEGACY_METHOD) {
+        legacy_source(stream, peer, log.clone(), edicast.clone()).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(stream))
+}
+
+// a PUT to /source/<source_name>
+pub async fn put(req: hyper::Request<Incoming>, source_name: String, log: Logger, edicast: Arc<Edicast>)
+    -> ControlResponse
+{
+    // SOURCE requests are only recognised at the start of a connection
+    if req.method() != Method::PUT {
+        return boxed(common::method_not_allowed());
+    }
+
+    let source_name = match percent_decode(source_name.as_bytes()).decode_utf8() {
+        Ok(name) => name.into_owned(),
+        Err(_) => {
+            // if we couldn't decode the source name as valid UTF-8, it
+            // cannot possibly be a valid source name
+            return boxed(common::not_found());
+        }
+    };
+
+    let log = log.new(slog::o!("source" => source_name.clone()));
+    slog::info!(log, "Live source connecting";
+        common::request_log_keys(&req));
+
+    let header = |name: &str| req.headers().get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let content_type = header("content-type");
+    let dj = dj_name(header("authorization").as_deref(), header("ice-name").as_deref());
+
+    let connected = match connect(&source_name, content_type.as_deref(), dj.as_deref(), common::remote_addr(&req), &log, &edicast) {
+        Ok(connected) => connected,
+        Err(status) => { return boxed(common::status(status)); }
+    };
+
+    // hyper sends 100 Continue once the body is first read. the response is
+    // held back until the source client is done sending, which is signalled
+    // by the body being dropped
+    let (done, finished) = oneshot::channel();
+
+    let (close, closed) = watch::channel(false);
+
+    let body = BlockingBody {
+        runtime: Handle::current(),
+        body: req.into_body(),
+        chunk: Bytes::new(),
+        closed,
+        _done: done,
+    };
+
+    go_live(&source_name, connected, body, close, dj, &log, &edicast);
+
+    let _ = finished.await;
+    boxed(common::no_content())
+}
+
+fn boxed(response: hyper::Response<http_body_util::Full<Bytes>>) -> ControlResponse {
+    response.map(|body| body.boxed_unsync())
+}
+
+async fn legacy_source<IO>(mut stream: Rewind<IO>, peer: SocketAddr, log: Logger, edicast: Arc<Edicast>)
+    -> Result<(), io::Error>
+    where IO: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    let head = match read_head(&mut stream).await? {
+        Some(head) => head,
+        None => {
+            stream.write_all(b"HTTP/1.0 400 Bad Request\r\n\r\n").await?;
+            return Ok(());
+        }
+    };
+
+    let request_id = Uuid::new_v4();
+
+    let log = log.new(slog::o!(
+        "request_id" => request_id,
+        "method" => "SOURCE",
+        "url" => head.path.clone(),
+        "remote_addr" => peer.to_string(),
+    ));
+
+    let source_name = head.path.strip_prefix("/source/")
+        .and_then(|name| percent_decode(name.as_bytes()).decode_utf8().ok())
+        .map(|name| name.into_owned());
+
+    let source_name = match source_name {
+        Some(name) => name,
+        None => {
+            stream.write_all(b"HTTP/1.0 404 Not Found\r\n\r\n").await?;
+            return Ok(());
+        }
+    };
+
+    let log = log.new(slog::o!("source" => source_name.clone()));
+    slog::info!(log, "Live source connecting");
+
+    let dj = dj_name(head.authorization.as_deref(), head.ice_name.as_deref());
+
+    let connected = match connect(&source_name, head.content_type.as_deref(), dj.as_deref(), Some(peer), &log, &edicast) {
+        Ok(connected) => connected,
+        Err(status) => {
+            let response = format!("HTTP/1.0 {} {}\r\n\r\n",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default());
+
+            stream.write_all(response.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await?;
+    stream.flush().await?;
+
+    let (close, closed) = watch::channel(false);
+    let io = BlockingRead { runtime: Handle::current(), io: stream, closed };
+    go_live(&source_name, connected, io, close, dj, &log, &edicast);
+    Ok(())
+}
+
+struct LegacyHead {
+    path: String,
+    content_type: Option<String>,
+    authorization: Option<String>,
+    ice_name: Option<String>,
+}
+
+// reads the head of a legacy SOURCE request, leaving anything after it in
+// the stream's prefix. returns None if the head is malformed
+async fn read_head<IO>(stream: &mut Rewind<IO>) -> Result<Option<LegacyHead>, io::Error>
+    where IO: AsyncRead + Unpin
+{
+    let mut buffer = BytesMut::from(&stream.prefix[..]);
+
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+
+        if stream.io.read_buf(&mut buffer).await? == 0 {
+            return Ok(None);
+        }
+    };
+
+    let head = buffer.split_to(end + 4);
+    stream.prefix = buffer.freeze();
+
+    let head = match str::from_utf8(&head) {
+        Ok(head) => head,
+        Err(_) => { return Ok(None); }
+    };
+
+    let mut lines = head.split("\r\n");
+
+    let path = match lines.next().map(|line| line.split(' ').collect::<Vec<_>>()).as_deref() {
+        Some(["SOURCE", path, _version]) => path.to_string(),
+        _ => { return Ok(None); }
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .collect::<Vec<_>>();
+
+    let header = |header: &str| headers.iter()
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case(header))
+        .map(|(_, value)| value.trim().to_owned());
+
+    Ok(Some(LegacyHead {
+        path,
+        content_type: header("content-type"),
+        authorization: header("authorization"),
+        ice_name: header("ice-name"),
+    }))
+}
+
+// names the DJ behind a source client for recordings and events. icecast
+// clients log in as "source" by default, so a username is only taken to be
+// the DJ's when it's something else, and the stream name the client
+// announces is used otherwise
+fn dj_name(authorization: Option<&str>, ice_name: Option<&str>) -> Option<String> {
+    let username = authorization
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| base64::decode(value.trim()))
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(username, _)| username.to_owned()))
+        .filter(|username| !username.is_empty() && username != "source");
+
+    username.or_else(|| {
+        ice_name.map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+    })
+}
+
+// a source reserved for a client, and the format it's sending
+struct Connected {
+    source: StartSource,
+    decoder_type: Arc<dyn DecoderType>,
+}
+
+// reserves the source for a connecting client, returning the status to
+// respond with if it can't go live
+fn connect(source_name: &str, content_type: Option<&str>, dj: Option<&str>, address: Option<SocketAddr>,
+    log: &Logger, edicast: &Edicast) -> Result<Connected, StatusCode>
+{
+    let content_type = content_type
+        .and_then(|val| val.split(';').next())
+        .map(str::trim);
+
+    // verify content type is legit before proceeding
+    let decoder_type = match content_type.and_then(decode::for_media_type) {
+        Some(decoder_type) => decoder_type,
+        None => {
+            slog::warn!(log, "Unsupported media type for source stream";
+                "content_type" => content_type);
+
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    };
+
+    if let Some(script) = &edicast.script {
+        if !script.accept_source(source_name, dj, address) {
+            slog::warn!(log, "Source turned away by script");
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    match edicast.sources.connect_source(source_name, log.clone()) {
+        Ok(source) => Ok(Connected { source, decoder_type }),
+        Err(ConnectSourceError::NoSuchSource) => {
+            slog::warn!(log, "Source does not exist");
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(ConnectSourceError::AlreadyConnected) => {
+            slog::warn!(log, "Source is already live");
+            Err(StatusCode::CONFLICT)
+        }
+    }
+}
+
+// close is sent true when the source is kicked, which fails the blocked read
+// on io so that the connection is dropped
+fn go_live(source_name: &str, connected: Connected, io: impl Read + Send + 'static,
+    close: watch::Sender<bool>, dj: Option<String>, log: &Logger, edicast: &Edicast)
+{
+    let Connected { source, decoder_type } = connected;
+    let dump = edicast.sources.config(source_name).and_then(|config| config.dump);
+    let io = DumpRead::new(io, dump.as_deref(), source_name, decoder_type.extension(), log);
+    let (io, interrupt) = interruptible(source_name, io, move || { let _ = close.send(true); });
+
+    let decoder = match decoder_type.open(Box::new(io)) {
+        Ok(decoder) => decoder,
+        Err(msg) => {
+            slog::error!(log, "Error initialising decoder";
+                "error" => &msg);
+
+            edicast.events.publish(Event::SourceError {
+                source: source_name.to_owned(),
+                error: msg,
+            });
+            return;
+        }
+    };
+
+    if source.start(decoder, interrupt, dj).is_err() {
+        slog::error!(log, "Source went away before it could go live");
+    }
+}
+
+// replays bytes already read from a connection before reading any more
+pub struct Rewind<IO> {
+    prefix: Bytes,
+    io: IO,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Rewind<IO> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Rewind<IO> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+// source clients are read on a dedicated thread by the decoder, these
+// adapt async connections and bodies for reading there
+struct BlockingRead<IO> {
+    runtime: Handle,
+    io: IO,
+    closed: watch::Receiver<bool>,
+}
+
+impl<IO: AsyncRead + Unpin> Read for BlockingRead<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let io = &mut self.io;
+        let closed = &mut self.closed;
+
+        self.runtime.block_on(async {
+            tokio::select! {
+                result = io.read(buf) => result,
+                _ = closed.wait_for(|closed| *closed) => Err(kicked()),
+            }
+        })
+    }
+}
+
+struct BlockingBody {
+    runtime: Handle,
+    body: Incoming,
+    chunk: Bytes,
+    closed: watch::Receiver<bool>,
+    // dropped along with the body, once the source client is finished
+    _done: oneshot::Sender<()>,
+}
+
+impl Read for BlockingBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            let body = &mut self.body;
+            let closed = &mut self.closed;
+
+            let frame = self.runtime.block_on(async {
+                tokio::select! {
+                    frame = body.frame() => Ok(frame),
+                    _ = closed.wait_for(|closed| *closed) => Err(kicked()),
+                }
+            })?;
+
+            let frame = match frame {
+                Some(frame) => frame.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+                None => { return Ok(0); }
+            };
+
+            if let Ok(data) = frame.into_data() {
+                self.chunk = data;
+            }
+        }
+
+        let n = self.chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+fn kicked() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "source client kicked")
+}
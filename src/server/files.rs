@@ -0,0 +1,303 @@
+// serves finished recordings read only, with range requests so that players
+// can seek and "listen again" links can point at edicast itself
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::Stream;
+use http_body_util::Full;
+use hyper::body::{Body, Frame};
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode;
+use slog::Logger;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+use tokio_util::io::ReaderStream;
+
+use crate::config::FilesConfig;
+use super::common;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// the part of path to look up in the files directory, if path is under it
+pub fn request_path<'a>(config: &FilesConfig, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(config.path.trim_end_matches('/'))?
+        .strip_prefix('/')
+}
+
+// why a file couldn't be served
+pub enum FileError {
+    MethodNotAllowed,
+    NotFound,
+    // carries the file's length for the content-range header
+    RangeNotSatisfiable(u64),
+}
+
+impl FileError {
+    pub fn response(self) -> Response<Full<Bytes>> {
+        match self {
+            FileError::MethodNotAllowed => common::method_not_allowed(),
+            FileError::NotFound => common::not_found(),
+            FileError::RangeNotSatisfiable(length) => {
+                Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header("content-range", format!("bytes */{}", length))
+                    .body(Full::new(Bytes::new()))
+                    .expect("build response")
+            }
+        }
+    }
+}
+
+pub async fn response<B>(req: &Request<B>, config: &FilesConfig, file_path: &str, log: &Logger)
+    -> Result<Response<FileBody>, FileError>
+{
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err(FileError::MethodNotAllowed);
+    }
+
+    let path = resolve(&config.dir, file_path)
+        .ok_or(FileError::NotFound)?;
+
+    // there are no directory listings, only files
+    let mut file = File::open(&path).await.map_err(|_| FileError::NotFound)?;
+    let metadata = file.metadata().await.map_err(|_| FileError::NotFound)?;
+
+    if !metadata.is_file() {
+        return Err(FileError::NotFound);
+    }
+
+    let length = metadata.len();
+
+    let range = req.headers().get("range")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| parse_range(value, length))
+        .unwrap_or(Range::Whole);
+
+    let (status, start, end) = match range {
+        Range::Whole => (StatusCode::OK, 0, length),
+        Range::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Range::Unsatisfiable => {
+            return Err(FileError::RangeNotSatisfiable(length));
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header("content-type", mime_type(&path))
+        .header("content-length", end - start)
+        .header("accept-ranges", "bytes");
+
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header("content-range", format!("bytes {}-{}/{}", start, end - 1, length));
+    }
+
+    let body = if req.method() == Method::HEAD {
+        FileBody { log: log.clone(), chunks: None, remaining: 0 }
+    } else {
+        slog::debug!(log, "Serving file";
+            "path" => path.display(),
+            "start" => start,
+            "end" => end,
+        );
+
+        file.seek(SeekFrom::Start(start)).await.map_err(|_| FileError::NotFound)?;
+
+        FileBody {
+            log: log.clone(),
+            chunks: Some(ReaderStream::with_capacity(file.take(end - start), CHUNK_SIZE)),
+            remaining: end - start,
+        }
+    };
+
+    Ok(response.body(body).expect("build response"))
+}
+
+// maps a request path onto a file in dir, refusing anything which could
+// reach outside it, as well as hidden files
+fn resolve(dir: &Path, file_path: &str) -> Option<PathBuf> {
+    let mut path = dir.to_owned();
+
+    for segment in file_path.split('/') {
+        let segment = percent_decode(segment.as_bytes()).decode_utf8().ok()?;
+
+        if segment.is_empty() || segment.starts_with('.') || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+
+        path.push(&*segment);
+    }
+
+    Some(path)
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("json") => "application/json",
+        Some("cue") => "application/x-cue",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Range {
+    Whole,
+    // start inclusive, end exclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// only single byte ranges are supported. anything else, or anything
+// malformed, is ignored and the whole file sent, which clients have to accept
+fn parse_range(value: &str, length: u64) -> Range {
+    let spec = match value.trim().split_once('=') {
+        Some((unit, spec)) if unit.eq_ignore_ascii_case("bytes") && !spec.contains(',') => spec,
+        _ => return Range::Whole,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return Range::Whole,
+    };
+
+    // "-n" is the last n bytes
+    if start.is_empty() {
+        return match digits(end) {
+            Some(0) => Range::Unsatisfiable,
+            Some(_) if length == 0 => Range::Unsatisfiable,
+            Some(suffix) => Range::Partial(length.saturating_sub(suffix), length),
+            None => Range::Whole,
+        };
+    }
+
+    let start = match digits(start) {
+        Some(start) => start,
+        None => return Range::Whole,
+    };
+
+    // "n-" runs to the end of the file
+    let end = if end.is_empty() {
+        None
+    } else {
+        match digits(end) {
+            Some(end) if end >= start => Some(end),
+            _ => return Range::Whole,
+        }
+    };
+
+    if start >= length {
+        return Range::Unsatisfiable;
+    }
+
+    match end {
+        Some(end) => Range::Partial(start, end.saturating_add(1).min(length)),
+        None => Range::Partial(start, length),
+    }
+}
+
+// positions are plain digits, without the sign u64's parser also takes
+fn digits(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok()
+}
+
+// file contents are read a chunk at a time as the client takes them, each
+// read going through tokio's blocking pool. a read error cuts the response
+// short, which the client sees as an error
+pub struct FileBody {
+    log: Logger,
+    chunks: Option<ReaderStream<Take<File>>>,
+    remaining: u64,
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>>
+    {
+        let chunks = match &mut self.chunks {
+            Some(chunks) => chunks,
+            None => return Poll::Ready(None),
+        };
+
+        match futures::ready!(Pin::new(chunks).poll_next(cx)) {
+            Some(Ok(chunk)) => {
+                self.remaining -= chunk.len() as u64;
+                Poll::Ready(Some(Ok(Frame::data(chunk))))
+            }
+            Some(Err(e)) => {
+                slog::warn!(self.log, "Error reading file"; "error" => e.to_string());
+                self.chunks = None;
+                Poll::Ready(None)
+            }
+            None => {
+                if self.remaining > 0 {
+                    slog::warn!(self.log, "Error reading file"; "error" => "file was truncated");
+                }
+
+                self.chunks = None;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-499", 10000), Range::Partial(0, 500));
+        assert_eq!(parse_range("bytes=500-999", 10000), Range::Partial(500, 1000));
+        assert_eq!(parse_range("bytes=0-0", 10000), Range::Partial(0, 1));
+        assert_eq!(parse_range("bytes=9500-", 10000), Range::Partial(9500, 10000));
+        assert_eq!(parse_range("bytes=-500", 10000), Range::Partial(9500, 10000));
+        assert_eq!(parse_range("Bytes=0-499", 10000), Range::Partial(0, 500));
+    }
+
+    #[test]
+    fn clamps_ranges_running_past_the_end() {
+        // a last byte past the end, or a suffix longer than the file, is
+        // the rest of the file
+        assert_eq!(parse_range("bytes=9500-20000", 10000), Range::Partial(9500, 10000));
+        assert_eq!(parse_range("bytes=0-18446744073709551615", 10000), Range::Partial(0, 10000));
+        assert_eq!(parse_range("bytes=-20000", 10000), Range::Partial(0, 10000));
+    }
+
+    #[test]
+    fn refuses_ranges_starting_past_the_end() {
+        assert_eq!(parse_range("bytes=10000-", 10000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=10000-10001", 10000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_ranges_it_doesnt_understand() {
+        // an invalid range set is ignored, and so are multiple ranges
+        assert_eq!(parse_range("bytes=500-499", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=20000-10", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=0-1,5-6", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=-", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=+1-2", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=1-+2", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=abc-", 10000), Range::Whole);
+        assert_eq!(parse_range("bytes=1-2-3", 10000), Range::Whole);
+        assert_eq!(parse_range("items=0-1", 10000), Range::Whole);
+        assert_eq!(parse_range("0-1", 10000), Range::Whole);
+    }
+}
@@ -0,0 +1,136 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::Future;
+use h3::error::Code;
+use h3::server::RequestStream;
+use h3_quinn::BidiStream;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Response};
+use slog::Logger;
+
+use crate::net::{self, tls};
+use super::common;
+use super::public;
+use super::Edicast;
+
+// bound along with the TCP listeners, while edicast may still be root
+pub fn bind(address: SocketAddr) -> Result<UdpSocket, net::BindError> {
+    UdpSocket::bind(address)
+        .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+        .map_err(|error| net::BindError { address, error })
+}
+
+// experimental HTTP/3 listener for public streams. requests are handled by
+// the same dispatch as the TCP listener, only the transport differs. the
+// endpoint starts its driver as soon as it's made, so this is called with
+// the socket from bind once privileges are dropped
+pub fn start(address: SocketAddr, socket: UdpSocket, tls: tls::Acceptor, edicast: Arc<Edicast>)
+    -> Result<impl Future<Output = ()>, net::BindError>
+{
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls.quic_server_config()));
+
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    ).map_err(|error| net::BindError { address, error })?;
+
+    Ok(crate::thread::spawn_worker("edicast/http3", async move {
+        let log = slog_scope::logger().new(slog::o!("service" => "http3"));
+
+        loop {
+            let connecting = tokio::select! {
+                connecting = endpoint.accept() => match connecting {
+                    Some(connecting) => connecting,
+                    None => break,
+                },
+                _ = edicast.shutdown.draining() => break,
+            };
+
+            let log = log.clone();
+            let edicast = edicast.clone();
+
+            tokio::task::spawn_local(async move {
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        slog::debug!(log, "error accepting connection: {}", err);
+                        return;
+                    }
+                };
+
+                let peer = connection.remote_address();
+
+                if let Err(err) = serve_connection(connection, peer, log.clone(), edicast).await {
+                    slog::warn!(log, "error serving connection: {}", err);
+                }
+            });
+        }
+
+        // refuse new connections, but keep serving existing ones on this
+        // thread until listeners have drained
+        endpoint.set_server_config(None);
+        edicast.shutdown.finished().await;
+    }))
+}
+
+async fn serve_connection(connection: quinn::Connection, peer: SocketAddr, log: Logger, edicast: Arc<Edicast>)
+    -> Result<(), h3::Error>
+{
+    let mut connection = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+
+    while let Some((req, stream)) = connection.accept().await? {
+        let log = log.clone();
+        let edicast = edicast.clone();
+
+        tokio::task::spawn_local(async move {
+            if let Err(err) = serve_request(req, stream, peer, log.clone(), edicast).await {
+                slog::debug!(log, "error serving request: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_request(
+    req: Request<()>,
+    mut stream: RequestStream<BidiStream<Bytes>, Bytes>,
+    peer: SocketAddr,
+    log: Logger,
+    edicast: Arc<Edicast>,
+) -> Result<(), h3::Error> {
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, Empty::<Bytes>::new());
+    common::set_peer(&mut req, peer, &edicast.config.listen);
+
+    let response = match public::dispatch(req, log, edicast).await {
+        Ok(response) => response,
+        Err(public::ClientLagged) => return Ok(()),
+    };
+
+    let (parts, mut body) = response.into_parts();
+    stream.send_response(Response::from_parts(parts, ())).await?;
+
+    // a lagging listener ends the body with an error, which the stream body
+    // has already logged. the stream is reset rather than finished, so that
+    // the client can tell its response was cut short
+    while let Some(frame) = body.frame().await {
+        match frame {
+            Ok(frame) => {
+                if let Ok(data) = frame.into_data() {
+                    stream.send_data(data).await?;
+                }
+            }
+            Err(public::ClientLagged) => {
+                stream.stop_stream(Code::H3_REQUEST_CANCELLED);
+                return Ok(());
+            }
+        }
+    }
+
+    stream.finish().await
+}
@@ -0,0 +1,72 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Response, StatusCode};
+
+use crate::config::{PlayerConfig, StreamConfig};
+
+pub const PATH_SUFFIX: &str = "/player";
+
+// src is the URL the player loads the stream from
+pub fn response(stream_name: &str, stream: &StreamConfig, player: &PlayerConfig, src: &str)
+    -> Response<Full<Bytes>>
+{
+    let html = render(stream_name, stream, player, src);
+
+    Response::builder()
+        .header("content-type", "text/html; charset=utf-8")
+        .header("cache-control", "no-cache")
+        .status(StatusCode::OK)
+        .body(Full::new(Bytes::from(html)))
+        .expect("build response")
+}
+
+fn render(stream_name: &str, stream: &StreamConfig, player: &PlayerConfig, src: &str) -> String {
+    let title = player.title.as_deref()
+        .or(stream.name.as_deref())
+        .unwrap_or(stream_name);
+
+    let title = escape(title);
+    let src = escape(src);
+
+    let stylesheet = match &player.stylesheet {
+        Some(href) => format!("<link rel=\"stylesheet\" href=\"{}\">", escape(href)),
+        None => String::new(),
+    };
+
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em auto; max-width: 40em; text-align: center; }}
+audio {{ width: 100%; }}
+</style>
+{stylesheet}
+</head>
+<body>
+<h1 class="edicast-title">{title}</h1>
+<audio class="edicast-player" src="{src}" controls preload="none"></audio>
+<p class="edicast-link"><a href="{src}">{src}</a></p>
+</body>
+</html>
+"#)
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
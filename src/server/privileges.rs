@@ -0,0 +1,215 @@
+// switching to the configured user and group once listening sockets are
+// bound, so that edicast can listen on ports below 1024 without running as
+// root for the rest of its life
+use std::path::{Path, PathBuf};
+
+use slog::Logger;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PrivilegeError {
+    #[error("user and group are only supported on unix")]
+    Unsupported,
+    #[error("no such user: {0}")]
+    NoSuchUser(String),
+    #[error("no such group: {0}")]
+    NoSuchGroup(String),
+    #[error("could not look up {name}: {error}")]
+    Lookup { name: String, error: std::io::Error },
+    #[error("could not switch to {name}: {error}")]
+    Switch { name: String, error: std::io::Error },
+    #[error("could not hand {} over: {error}", path.display())]
+    Chown { path: PathBuf, error: std::io::Error },
+}
+
+// the group defaults to the user's own. does nothing if edicast is already
+// running as them, as it is after an upgrade
+#[cfg(unix)]
+pub fn drop_to(log: &Logger, user: Option<&str>, group: Option<&str>) -> Result<(), PrivilegeError> {
+    use std::ffi::CString;
+    use std::io;
+
+    let (user, gid) = match lookup(user, group)? {
+        Some(ids) => ids,
+        None => return Ok(()),
+    };
+
+    // safety: these only read the calling process's ids
+    let (current_uid, current_gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+    let uid = user.as_ref().map_or(current_uid, |user| user.uid);
+
+    if uid == current_uid && gid == current_gid {
+        return Ok(());
+    }
+
+    let switch_error = |name: &str| PrivilegeError::Switch {
+        name: name.to_owned(),
+        error: io::Error::last_os_error(),
+    };
+
+    // supplementary groups first, while still root. glibc and musl apply
+    // setgid and setuid to every thread in the process, not only the
+    // calling one
+    //
+    // safety: the name is a valid C string and the gid list has one entry
+    unsafe {
+        let result = match &user {
+            Some(user) => {
+                let name = CString::new(user.name.as_str()).map_err(|_| PrivilegeError::NoSuchUser(user.name.clone()))?;
+                libc::initgroups(name.as_ptr(), gid as _)
+            }
+            None => libc::setgroups(1, &gid),
+        };
+
+        if result < 0 {
+            return Err(switch_error("supplementary groups"));
+        }
+
+        if libc::setgid(gid) < 0 {
+            return Err(switch_error(group.unwrap_or("the user's group")));
+        }
+
+        if let Some(user) = &user {
+            if libc::setuid(user.uid) < 0 {
+                return Err(switch_error(&user.name));
+            }
+        }
+    }
+
+    slog::info!(log, "Dropped privileges";
+        "user" => user.as_ref().map(|user| user.name.clone()),
+        "uid" => uid,
+        "gid" => gid,
+    );
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_to(_log: &Logger, user: Option<&str>, group: Option<&str>) -> Result<(), PrivilegeError> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    Err(PrivilegeError::Unsupported)
+}
+
+// gives a file made while still root to the user and group edicast switches
+// to, so that it can write to it again afterwards, as a new process does to
+// the pidfile on upgrade. does nothing without a user or group
+#[cfg(unix)]
+pub fn chown_to(path: &Path, user: Option<&str>, group: Option<&str>) -> Result<(), PrivilegeError> {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (user, gid) = match lookup(user, group)? {
+        Some(ids) => ids,
+        None => return Ok(()),
+    };
+
+    let chown_error = |error| PrivilegeError::Chown { path: path.to_owned(), error };
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| chown_error(e.into()))?;
+
+    // -1 leaves the owner as it is when only a group is configured
+    let uid = user.map_or(libc::uid_t::MAX, |user| user.uid);
+
+    // safety: the path is a valid C string
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } < 0 {
+        return Err(chown_error(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn chown_to(_path: &Path, user: Option<&str>, group: Option<&str>) -> Result<(), PrivilegeError> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    Err(PrivilegeError::Unsupported)
+}
+
+// the user, if one is configured, and the group to switch to, which
+// defaults to the user's own. None if neither is configured
+#[cfg(unix)]
+fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Option<(Option<User>, libc::gid_t)>, PrivilegeError> {
+    let user = user.map(lookup_user).transpose()?;
+
+    let gid = match (group, &user) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some(user)) => user.gid,
+        (None, None) => return Ok(None),
+    };
+
+    Ok(Some((user, gid)))
+}
+
+#[cfg(unix)]
+struct User {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+// the passwd and group entries are read into a buffer this size, which is
+// plenty for all but enormous groups
+#[cfg(unix)]
+const LOOKUP_BUFFER_SIZE: usize = 64 * 1024;
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<User, PrivilegeError> {
+    use std::ffi::CString;
+    use std::{io, mem, ptr};
+
+    let c_name = CString::new(name).map_err(|_| PrivilegeError::NoSuchUser(name.to_owned()))?;
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+
+    // safety: getpwnam_r fills passwd, pointing into buffer, which outlives
+    // the fields read from it here
+    unsafe {
+        let mut passwd: libc::passwd = mem::zeroed();
+        let mut result = ptr::null_mut();
+
+        let error = libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result);
+
+        if result.is_null() {
+            return match error {
+                0 => Err(PrivilegeError::NoSuchUser(name.to_owned())),
+                error => Err(PrivilegeError::Lookup { name: name.to_owned(), error: io::Error::from_raw_os_error(error) }),
+            };
+        }
+
+        Ok(User { name: name.to_owned(), uid: passwd.pw_uid, gid: passwd.pw_gid })
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<libc::gid_t, PrivilegeError> {
+    use std::ffi::CString;
+    use std::{io, mem, ptr};
+
+    let c_name = CString::new(name).map_err(|_| PrivilegeError::NoSuchGroup(name.to_owned()))?;
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+
+    // safety: as for getpwnam_r above
+    unsafe {
+        let mut group: libc::group = mem::zeroed();
+        let mut result = ptr::null_mut();
+
+        let error = libc::getgrnam_r(c_name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result);
+
+        if result.is_null() {
+            return match error {
+                0 => Err(PrivilegeError::NoSuchGroup(name.to_owned())),
+                error => Err(PrivilegeError::Lookup { name: name.to_owned(), error: io::Error::from_raw_os_error(error) }),
+            };
+        }
+
+        Ok(group.gr_gid)
+    }
+}
@@ -1,55 +1,90 @@
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::Future;
-use http_body_util::BodyExt;
-use http_body_util::combinators::BoxBody;
-use hyper::body::{self, Body, Frame};
+use http_body_util::{BodyExt, Full};
+use http_body_util::combinators::UnsyncBoxBody;
+use hyper::body::{Body, Frame};
 use hyper::server::conn::http1;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
 use slog::Logger;
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, Sleep};
 use uuid::Uuid;
 
 use crate::audio::encode;
-use crate::net;
+use crate::config::{PacingConfig, SourceOfflineAction, StreamConfig};
+use crate::listener::{ListenerHandle, ListenerInfo, SourceOffline, StreamMove};
+use crate::metadata::{Metadata, ICY_METAINT};
+use crate::net::{self, proxy, tls};
+#[cfg(feature = "tls")]
+use crate::net::acme;
+use crate::plugin::Plugin;
+use crate::source::SourceStatus;
 use crate::stream::StreamSubscription;
 use super::common;
+use super::files;
+use super::player;
 use super::Edicast;
 
-pub async fn start(address: SocketAddr, edicast: Arc<Edicast>)
+pub async fn start(address: SocketAddr, tls: Option<tls::Acceptor>, edicast: Arc<Edicast>)
     -> Result<impl Future<Output = ()>, net::BindError>
 {
-    let listener = net::bind(address).await?;
+    let listener = net::bind(address, edicast.config.listen.interface.public.as_deref()).await?;
 
-    let _ = crate::thread::spawn_worker("edicast/public", async move {
+    Ok(crate::thread::spawn_worker("edicast/public", async move {
         loop {
             let log = slog_scope::logger().new(slog::o!("service" => "public"));
 
-            let (stream, peer) = match listener.accept().await {
-                Ok(result) => result,
-                Err(err) => {
-                    slog::warn!(log, "error accepting connection: {}", err);
-                    continue;
-                }
+            let (mut stream, peer) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        slog::warn!(log, "error accepting connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = edicast.shutdown.draining() => break,
             };
 
-            let service = hyper::service::service_fn({
-                let log = log.clone();
-                let edicast = edicast.clone();
-                move |mut req| {
-                    req.extensions_mut().insert(net::SocketPeer(peer));
-                    dispatch(req, log.clone(), edicast.clone())
-                }
-            });
+            let tls = tls.clone();
+            let edicast = edicast.clone();
 
             tokio::task::spawn_local(async move {
-                let result = http1::Builder::new()
-                    .serve_connection(stream, service)
-                    .await;
+                let peer = if edicast.config.listen.proxy_protocol.public {
+                    match proxy::read_header(&mut stream).await {
+                        Ok(source) => source.unwrap_or(peer),
+                        Err(err) => {
+                            slog::debug!(log, "error reading PROXY header: {}", err);
+                            return;
+                        }
+                    }
+                } else {
+                    peer
+                };
+
+                let result = match tls {
+                    Some(tls) => {
+                        let stream = match tls.accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(err) => {
+                                slog::debug!(log, "error in TLS handshake: {}", err);
+                                return;
+                            }
+                        };
+
+                        serve_connection(stream, peer, log.clone(), edicast).await
+                    }
+                    None => serve_connection(stream, peer, log.clone(), edicast).await,
+                };
 
                 match result {
                     Ok(()) => {}
@@ -59,81 +94,598 @@ pub async fn start(address: SocketAddr, edicast: Arc<Edicast>)
                 }
             });
         }
+
+        // stop accepting, but keep serving connections on this thread until
+        // listeners have drained
+        drop(listener);
+        edicast.shutdown.finished().await;
+    }))
+}
+
+async fn serve_connection<IO>(stream: IO, peer: SocketAddr, log: Logger, edicast: Arc<Edicast>)
+    -> Result<(), hyper::Error>
+    where IO: AsyncRead + AsyncWrite + Unpin + 'static
+{
+    let service = hyper::service::service_fn(move |mut req| {
+        common::set_peer(&mut req, peer, &edicast.config.listen);
+        dispatch(req, log.clone(), edicast.clone())
     });
 
-    // accept loop in worker thread never terminates
-    Ok(futures::future::pending::<()>())
+    http1::Builder::new()
+        .serve_connection(stream, service)
+        .await
 }
 
-type DispatchResponse = Response<BoxBody<Bytes, ClientLagged>>;
+pub type DispatchResponse = Response<UnsyncBoxBody<Bytes, ClientLagged>>;
+
+fn boxed(response: Response<Full<Bytes>>) -> DispatchResponse {
+    response.map(|body| body.map_err(|_| -> ClientLagged { unreachable!() }).boxed_unsync())
+}
 
 fn not_found() -> DispatchResponse {
-    common::status(StatusCode::NOT_FOUND)
-        .map(|body| body.map_err(|_| -> ClientLagged { unreachable!() }).boxed())
+    boxed(common::status(StatusCode::NOT_FOUND))
+}
+
+fn overflow(edicast: &Edicast, redirect: Option<&str>) -> DispatchResponse {
+    match redirect {
+        Some(location) => {
+            // redirects to other streams on this server may be given as a
+            // path only
+            let response = Response::builder()
+                .status(StatusCode::FOUND)
+                .header("location", edicast.config.public_url_for(location))
+                .header("cache-control", "no-store")
+                .body(Full::new(Bytes::new()))
+                .expect("build response");
+
+            boxed(response)
+        }
+        None => boxed(common::status(StatusCode::SERVICE_UNAVAILABLE)),
+    }
+}
+
+// station details for players and directories which read icy-* headers.
+// values which can't be sent as a header are left out
+fn station_headers(config: &StreamConfig) -> Vec<(&'static str, HeaderValue)> {
+    let fields = [
+        ("icy-name", config.name.as_deref()),
+        ("icy-description", config.description.as_deref()),
+        ("icy-genre", config.genre.as_deref()),
+        ("icy-url", config.url.as_deref()),
+        ("icy-pub", Some(if config.public { "1" } else { "0" })),
+    ];
+
+    fields.into_iter()
+        .filter_map(|(name, value)| Some((name, HeaderValue::from_str(value?).ok()?)))
+        .collect()
 }
 
-async fn dispatch(req: Request<body::Incoming>, log: Logger, edicast: Arc<Edicast>)
+fn origin_authorized<B>(req: &Request<B>, edicast: &Edicast) -> bool {
+    let secret = match &edicast.config.origin {
+        Some(origin) => &origin.secret,
+        None => { return false; }
+    };
+
+    req.headers().get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| common::constant_time_eq(value.trim().as_bytes(), secret.as_bytes()))
+        .unwrap_or(false)
+}
+
+fn player_page(edicast: &Edicast, host: Option<&str>, stream_path: &str) -> Option<DispatchResponse> {
+    let (stream_id, config) = edicast.streams.route(host, stream_path)?;
+
+    if config.private || !config.serve {
+        return None;
+    }
+
+    let player = config.player.as_ref()?;
+    let src = edicast.config.stream_url(&config);
+    Some(boxed(player::response(&stream_id, &config, player, &src)))
+}
+
+// generic over the request body so that other transports can share this,
+// request bodies are never read
+pub async fn dispatch<B: Body>(req: Request<B>, log: Logger, edicast: Arc<Edicast>)
     -> Result<DispatchResponse, ClientLagged>
 {
     let request_id = Uuid::new_v4();
     let log = log.new(slog::o!("request_id" => request_id));
 
+    let host = common::request_host(&req);
     let path = req.uri().path();
 
-    let stream_id = match edicast.public_routes.get(path) {
-        Some(stream_id) => stream_id,
-        None => { return Ok(not_found()); }
+    #[cfg(feature = "tls")]
+    if let Some(token) = path.strip_prefix(acme::CHALLENGE_PATH) {
+        if let Some(key_authorization) = edicast.acme_challenges.get(token) {
+            let response = Response::builder()
+                .header("content-type", "application/octet-stream")
+                .status(StatusCode::OK)
+                .body(Full::new(Bytes::from(key_authorization)))
+                .expect("build response");
+
+            return Ok(boxed(response));
+        }
+    }
+
+    if let Some(config) = &edicast.config.files {
+        if let Some(file_path) = files::request_path(config, path) {
+            return Ok(match files::response(&req, config, file_path, &log).await {
+                Ok(response) => response.map(|body| body.map_err(|e: Infallible| -> ClientLagged { match e {} }).boxed_unsync()),
+                Err(error) => boxed(error.response()),
+            });
+        }
+    }
+
+    if let Some(stream_path) = path.strip_suffix(player::PATH_SUFFIX) {
+        // the player for a stream at the root of its host is at /player
+        let stream_path = if stream_path.is_empty() { "/" } else { stream_path };
+
+        if let Some(response) = player_page(&edicast, host, stream_path) {
+            return Ok(response);
+        }
+    }
+
+    let (stream_id, stream_config) = match edicast.streams.route(host, path) {
+        Some(route) if route.1.serve => route,
+        _ => { return Ok(not_found()); }
+    };
+
+    // private streams aren't let on to exist to anyone but edge servers
+    if stream_config.private && !origin_authorized(&req, &edicast) {
+        return Ok(not_found());
+    }
+
+    let content_type = encode::mime_type_from_config(&stream_config.codec);
+
+    let wants_icy_metadata = req.headers().get("icy-metadata")
+        .map(|value| value == "1")
+        .unwrap_or(false);
+
+    // metadata is only interleaved if the source has any to send
+    let metadata = if wants_icy_metadata {
+        edicast.sources.metadata(&stream_config.source)
+    } else {
+        None
     };
 
-    let content_type = encode::mime_type_from_config(
-        &edicast.config.stream[stream_id].codec);
+    let mut response = Response::builder()
+        .header("content-type", content_type)
+        .header("cache-control", "no-store");
+
+    if metadata.is_some() {
+        response = response.header("icy-metaint", ICY_METAINT.to_string());
+    }
+
+    for (name, value) in station_headers(&stream_config) {
+        response = response.header(name, value);
+    }
+
+    // tell clients they can switch to HTTP/3 for future requests
+    #[cfg(feature = "http3")]
+    if let Some(address) = edicast.config.listen.public_quic {
+        response = response.header("alt-svc", format!("h3=\":{}\"; ma=86400", address.port()));
+    }
+
+    // HEAD requests get the same headers a listener would, without
+    // registering a listener or subscribing to the stream. the body's length
+    // is unknown as a listener's is, so no content-length is sent
+    if req.method() == Method::HEAD {
+        let body = http_body_util::StreamBody::new(futures::stream::empty());
+
+        let response = response
+            .status(StatusCode::OK)
+            .body(body.boxed_unsync())
+            .expect("build response");
+
+        return Ok(response);
+    }
+
+    if edicast.streams.is_paused(&stream_id) {
+        slog::info!(log, "Turning away listener while stream is paused";
+            "stream" => &stream_id,
+            common::request_log_keys(&req),
+        );
+
+        return Ok(boxed(common::status(StatusCode::SERVICE_UNAVAILABLE)));
+    }
+
+    let user_agent = req.headers().get("user-agent")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    if let Some(script) = &edicast.script {
+        if !script.accept_listener(&stream_id, common::remote_addr(&req), user_agent.as_deref()) {
+            slog::info!(log, "Turning away listener by script";
+                "stream" => &stream_id,
+                common::request_log_keys(&req),
+            );
+
+            return Ok(boxed(common::status(StatusCode::FORBIDDEN)));
+        }
+    }
+
+    let referer = req.headers().get("referer")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let request = format!("{} {} {:?}", req.method(),
+        req.uri().path_and_query().map_or(path, |path_and_query| path_and_query.as_str()),
+        req.version());
+
+    let (moves_tx, moves) = mpsc::unbounded_channel();
+
+    let listener_info = ListenerInfo::new(
+        request_id,
+        stream_id.to_string(),
+        common::remote_addr(&req),
+        user_agent,
+        referer,
+        request,
+        moves_tx);
+
+    let listener = match edicast.listeners.register(listener_info, edicast.config.limits.max_listeners) {
+        Ok(listener) => listener,
+        Err(_) => {
+            slog::warn!(log, "Listener limit reached";
+                "stream" => &stream_id,
+                "redirect" => &stream_config.overflow_redirect,
+                common::request_log_keys(&req),
+            );
 
-    let stream = match edicast.streams.subscribe_stream(stream_id) {
+            return Ok(overflow(&edicast, stream_config.overflow_redirect.as_deref()));
+        }
+    };
+
+    let stream = match edicast.streams.subscribe_stream(&stream_id) {
         Some(stream) => stream,
         None => { return Ok(not_found()); }
     };
 
+    let source_offline = match stream_config.on_source_offline {
+        SourceOfflineAction::Hold => None,
+        SourceOfflineAction::Close => {
+            let status = edicast.sources.status(&stream_config.source);
+
+            if status.as_ref().map(|status| *status.borrow()) != Some(SourceStatus::Live) {
+                slog::info!(log, "Turning away listener while source is offline";
+                    "stream" => &stream_id,
+                    common::request_log_keys(&req),
+                );
+
+                return Ok(boxed(common::status(StatusCode::SERVICE_UNAVAILABLE)));
+            }
+
+            status.map(wait_offline)
+        }
+    };
+
+    let session_limit = stream_config.max_session_mins.map(|mins| {
+        Box::pin(tokio::time::sleep(Duration::from_secs(mins * 60)))
+    });
+
+    let pacing = stream_config.pacing.as_ref().map(|pacing| {
+        Pacing::new(pacing, encode::bitrate_from_config(&stream_config.codec))
+    });
+
+    let intro = edicast.streams.intro(&stream_id);
+
+    let icy = metadata.map(|metadata| IcyMetadata::new(metadata, edicast.streams.plugin(&stream_id)));
+
     slog::info!(log, "Listener connected";
-        "stream" => stream_id,
-        common::request_log_keys_hyper(&req),
+        "stream" => &stream_id,
+        common::request_log_keys(&req),
     );
 
-    let response = Response::builder()
-        .header("content-type", content_type)
-        .header("cache-control", "no-store")
+    let response = response
         .status(StatusCode::OK)
-        .body(StreamBody(stream).boxed())
+        .body(StreamBody {
+            listener,
+            intro,
+            icy,
+            log: log.new(slog::o!("stream" => stream_id.to_string())),
+            request_log: log,
+            moves,
+            pacing,
+            session_limit,
+            source_offline,
+            stream,
+        }.boxed_unsync())
         .expect("build response");
 
     Ok(response)
 }
 
+fn wait_offline(mut status: watch::Receiver<SourceStatus>) -> SourceOffline {
+    Box::pin(async move {
+        loop {
+            if *status.borrow_and_update() == SourceStatus::Offline {
+                return;
+            }
+
+            if status.changed().await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+// what a listener needs to carry on with another stream, or with the same
+// stream once it's been rewired to another source
+pub fn stream_move(edicast: &Edicast, stream: &str) -> Option<StreamMove> {
+    let config = edicast.streams.config(stream)?;
+    let subscription = edicast.streams.subscribe_stream(stream)?;
+
+    let source_offline = match config.on_source_offline {
+        SourceOfflineAction::Hold => None,
+        SourceOfflineAction::Close => edicast.sources.status(&config.source).map(wait_offline),
+    };
+
+    Some(StreamMove {
+        stream: stream.to_owned(),
+        subscription,
+        metadata: edicast.sources.metadata(&config.source),
+        plugin: edicast.streams.plugin(stream),
+        source_offline,
+        bitrate: encode::bitrate_from_config(&config.codec),
+        pacing: config.pacing,
+    })
+}
+
 #[derive(Error, Debug)]
 #[error("client lagged too far behind stream")]
 pub struct ClientLagged;
 
-struct StreamBody(StreamSubscription);
+struct StreamBody {
+    listener: ListenerHandle,
+    intro: Option<Bytes>,
+    icy: Option<IcyMetadata>,
+    log: Logger,
+    // without the stream key, which changes as the listener is moved
+    request_log: Logger,
+    moves: mpsc::UnboundedReceiver<StreamMove>,
+    pacing: Option<Pacing>,
+    session_limit: Option<Pin<Box<Sleep>>>,
+    source_offline: Option<SourceOffline>,
+    stream: StreamSubscription,
+}
+
+impl StreamBody {
+    fn data_frame(&mut self, data: Bytes) -> Frame<Bytes> {
+        self.listener.add_bytes_sent(data.len());
+
+        if let Some(pacing) = &mut self.pacing {
+            pacing.sent += data.len() as u64;
+        }
+
+        match &mut self.icy {
+            Some(icy) => Frame::data(icy.interleave(data)),
+            None => Frame::data(data),
+        }
+    }
+}
+
+// listeners are sent data slightly faster than the nominal stream bitrate so
+// that any backlog beyond the initial burst still drains over time
+const PACING_RATE_FACTOR: f64 = 1.1;
+
+struct Pacing {
+    epoch: Instant,
+    burst_bytes: u64,
+    bytes_per_sec: f64,
+    sent: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacing {
+    fn new(config: &PacingConfig, bitrate_kbps: usize) -> Self {
+        Pacing {
+            epoch: Instant::now(),
+            burst_bytes: config.burst_kb * 1024,
+            bytes_per_sec: (bitrate_kbps.max(1) * 1000 / 8) as f64 * PACING_RATE_FACTOR,
+            sent: 0,
+            delay: None,
+        }
+    }
+
+    // a listener moved to another stream already has its burst buffered, so
+    // it carries on at the new stream's rate without another
+    fn moved(config: &PacingConfig, bitrate_kbps: usize) -> Self {
+        let mut pacing = Pacing::new(config, bitrate_kbps);
+        pacing.sent = pacing.burst_bytes;
+        pacing
+    }
+
+    // pending while the listener has been sent more than its allowance
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                futures::ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            let paced_bytes = self.sent.saturating_sub(self.burst_bytes);
+            let allowed_at = self.epoch + Duration::from_secs_f64(paced_bytes as f64 / self.bytes_per_sec);
+
+            if allowed_at <= Instant::now() {
+                return Poll::Ready(());
+            }
+
+            self.delay = Some(Box::pin(tokio::time::sleep_until(allowed_at)));
+        }
+    }
+}
+
+struct IcyMetadata {
+    metadata: watch::Receiver<Metadata>,
+    // the stream's plugin, which may rewrite titles
+    plugin: Option<Arc<Plugin>>,
+    last_sent: Option<Metadata>,
+    // bytes of audio data remaining until the next metadata block is due
+    remaining: usize,
+}
+
+impl IcyMetadata {
+    fn new(metadata: watch::Receiver<Metadata>, plugin: Option<Arc<Plugin>>) -> Self {
+        IcyMetadata { metadata, plugin, last_sent: None, remaining: ICY_METAINT }
+    }
+
+    fn interleave(&mut self, mut data: Bytes) -> Bytes {
+        let mut out = BytesMut::with_capacity(data.len() + 1);
+
+        while data.len() >= self.remaining {
+            out.extend_from_slice(&data.split_to(self.remaining));
+            out.extend_from_slice(&self.next_block());
+            self.remaining = ICY_METAINT;
+        }
+
+        self.remaining -= data.len();
+        out.extend_from_slice(&data);
+        out.freeze()
+    }
+
+    // metadata is only sent in full when it has changed, otherwise an
+    // empty block is sent
+    fn next_block(&mut self) -> Bytes {
+        let current = self.metadata.borrow().clone();
+
+        if self.last_sent.as_ref() == Some(&current) {
+            return Bytes::from_static(&[0]);
+        }
+
+        let mut sent = current.clone();
+
+        if let (Some(plugin), Some(title)) = (&self.plugin, &current.title) {
+            if let Some(title) = plugin.rewrite_title(title) {
+                sent.title = Some(title);
+            }
+        }
+
+        self.last_sent = Some(current);
+        sent.icy_block()
+    }
+}
 
 impl Body for StreamBody {
     type Data = Bytes;
     type Error = ClientLagged;
 
-    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>)
         -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>>
     {
         use tokio::sync::broadcast::error::RecvError;
 
+        let self_ = self.get_mut();
+
+        while let Poll::Ready(Some(stream_move)) = self_.moves.poll_recv(cx) {
+            self_.log = self_.request_log.new(slog::o!("stream" => stream_move.stream));
+            slog::info!(self_.log, "Listener moved to another stream");
+            self_.stream = stream_move.subscription;
+            self_.source_offline = stream_move.source_offline;
+            self_.pacing = stream_move.pacing.as_ref()
+                .map(|pacing| Pacing::moved(pacing, stream_move.bitrate));
+
+            if let Some(icy) = &mut self_.icy {
+                if let Some(metadata) = stream_move.metadata {
+                    icy.metadata = metadata;
+                }
+
+                // the new stream's plugin may rewrite the title differently
+                icy.plugin = stream_move.plugin;
+                icy.last_sent = None;
+            }
+        }
+
+        if let Some(source_offline) = &mut self_.source_offline {
+            if source_offline.as_mut().poll(cx).is_ready() {
+                slog::info!(self_.log, "Closing listener as source went offline");
+                return Poll::Ready(None);
+            }
+        }
+
+        if let Some(session_limit) = &mut self_.session_limit {
+            if session_limit.as_mut().poll(cx).is_ready() {
+                slog::info!(self_.log, "Closing listener as session limit was reached");
+                return Poll::Ready(None);
+            }
+        }
+
+        if let Some(pacing) = &mut self_.pacing {
+            futures::ready!(pacing.poll_ready(cx));
+        }
+
+        // send the intro before any live data, if there is one
+        if let Some(intro) = self_.intro.take() {
+            return Poll::Ready(Some(Ok(self_.data_frame(intro))));
+        }
+
         // recv is cancel-safe, so it's safe to call it again on every poll
-        let mut self_ = self.as_mut();
-        let recv = self_.0.recv();
-        futures::pin_mut!(recv);
-
-        recv.poll(cx).map(|result| {
-            match result {
-                Ok(bytes) => Some(Ok(Frame::data(bytes))),
-                Err(RecvError::Closed) => None,
-                Err(RecvError::Lagged(_)) => Some(Err(ClientLagged)),
+        let result = {
+            let recv = self_.stream.recv();
+            futures::pin_mut!(recv);
+            futures::ready!(recv.poll(cx))
+        };
+
+        Poll::Ready(match result {
+            Ok(chunk) => {
+                if let Some(session) = chunk.session {
+                    if self_.listener.heard(session) {
+                        slog::debug!(self_.log, "Listener reached by source session"; "source_session" => session);
+                    }
+                }
+
+                Some(Ok(self_.data_frame(chunk.data)))
             }
+            Err(RecvError::Closed) => None,
+            Err(RecvError::Lagged(_)) => Some(Err(ClientLagged)),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(title: &str) -> Metadata {
+        Metadata { title: Some(title.to_owned()), ad_break: None }
+    }
+
+    #[test]
+    fn icy_blocks_are_interleaved_every_metaint_bytes() {
+        let (_tx, rx) = watch::channel(metadata("one"));
+        let mut icy = IcyMetadata::new(rx, None);
+        let block = metadata("one").icy_block();
+
+        // the first block comes after exactly ICY_METAINT bytes, however
+        // the audio is split up
+        let first = icy.interleave(Bytes::from(vec![1; ICY_METAINT - 10]));
+        assert_eq!(first.len(), ICY_METAINT - 10);
+
+        let second = icy.interleave(Bytes::from(vec![2; 20]));
+        assert_eq!(second.len(), 20 + block.len());
+        assert_eq!(&second[..10], &[2; 10]);
+        assert_eq!(&second[10..10 + block.len()], &block[..]);
+        assert_eq!(&second[10 + block.len()..], &[2; 10]);
+    }
+
+    #[test]
+    fn icy_blocks_are_empty_until_metadata_changes() {
+        let (tx, rx) = watch::channel(metadata("one"));
+        let mut icy = IcyMetadata::new(rx, None);
+
+        let audio = Bytes::from(vec![0; ICY_METAINT * 2]);
+        let out = icy.interleave(audio);
+        let block = metadata("one").icy_block();
+
+        // the metadata in full, then an empty block as it hasn't changed
+        assert_eq!(out.len(), ICY_METAINT * 2 + block.len() + 1);
+        assert_eq!(&out[ICY_METAINT..][..block.len()], &block[..]);
+        assert_eq!(out[ICY_METAINT * 2 + block.len()], 0);
+
+        tx.send_replace(metadata("two"));
+
+        let out = icy.interleave(Bytes::from(vec![0; ICY_METAINT]));
+        assert_eq!(&out[ICY_METAINT..], &metadata("two").icy_block()[..]);
+    }
+}
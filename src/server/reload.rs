@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use serde_derive::Serialize;
+
+use crate::config::{self, Config, StreamConfig};
+use crate::event::Event;
+use crate::source::AddSourceError;
+use super::Edicast;
+
+#[derive(Serialize, Default)]
+pub struct ReloadSummary {
+    pub sources_added: Vec<String>,
+    pub sources_removed: Vec<String>,
+    pub sources_restarted: Vec<String>,
+    pub streams_added: Vec<String>,
+    pub streams_removed: Vec<String>,
+    pub streams_restarted: Vec<String>,
+    pub streams_rewired: Vec<String>,
+}
+
+impl Edicast {
+    // reloads sources, streams and log levels from the config file, applying
+    // only what has changed so that listeners on unchanged streams stay
+    // connected. other sections of the config file require a restart
+    pub fn reload(&self) -> Result<ReloadSummary, config::Error> {
+        let _guard = self.reload_lock.lock().expect("lock reload");
+        let log = &self.log;
+
+        let config = match Config::load_with_overlays(&self.config_path, &self.config_overlays) {
+            Ok(config) => config,
+            Err(e) => {
+                self.events.publish(Event::ConfigReloadFailed { error: e.to_string() });
+                return Err(e);
+            }
+        };
+        let mut summary = ReloadSummary::default();
+
+        slog::info!(log, "Reloading config"; "path" => self.config_path.display());
+
+        if self.log_levels.configure(&config.log) {
+            slog::info!(log, "Applied log level and filters from config");
+        }
+
+        // add new sources and restart changed ones first, so that streams
+        // can be wired to them
+        for (name, source_config) in &config.source {
+            match self.sources.config(name) {
+                None => {
+                    match self.sources.add_source(name, source_config) {
+                        Ok(()) => summary.sources_added.push(name.clone()),
+                        Err(AddSourceError::AlreadyExists) => {}
+                    }
+                }
+                Some(running) if running != *source_config => {
+                    // streams on the old source wait to be rewired to the
+                    // new one, so their listeners stay connected
+                    self.sources.remove_source(name);
+
+                    if let Err(AddSourceError::AlreadyExists) = self.sources.add_source(name, source_config) {
+                        slog::warn!(log, "Source was re-added while restarting"; "source" => name);
+                    }
+
+                    for stream in self.streams.streams_for_source(name) {
+                        let _ = self.rewire_stream(&stream, name);
+                    }
+
+                    summary.sources_restarted.push(name.clone());
+                }
+                Some(_) => {}
+            }
+        }
+
+        let running_streams = self.streams.list().into_iter().collect::<HashMap<_, _>>();
+
+        for (name, running) in &running_streams {
+            let new = match config.stream.get(name) {
+                Some(new) => new,
+                None => {
+                    self.streams.remove_stream(name);
+                    summary.streams_removed.push(name.clone());
+                    continue;
+                }
+            };
+
+            if new == running {
+                continue;
+            }
+
+            let only_source_changed = StreamConfig { source: running.source.clone(), ..new.clone() } == *running;
+
+            if only_source_changed {
+                match self.rewire_stream(name, &new.source) {
+                    Ok(_) => summary.streams_rewired.push(name.clone()),
+                    Err(e) => {
+                        slog::error!(log, "Could not rewire stream";
+                            "stream" => name,
+                            "error" => format!("{:?}", e),
+                        );
+                    }
+                }
+
+                continue;
+            }
+
+            // anything else about the stream changing means restarting it,
+            // which disconnects its listeners
+            self.streams.remove_stream(name);
+
+            match self.streams.add_stream(name, new.clone(), &self.sources) {
+                Ok(()) => summary.streams_restarted.push(name.clone()),
+                Err(e) => {
+                    slog::error!(log, "Could not restart stream";
+                        "stream" => name,
+                        "error" => format!("{:?}", e),
+                    );
+                }
+            }
+        }
+
+        for (name, new) in &config.stream {
+            if running_streams.contains_key(name) {
+                continue;
+            }
+
+            match self.streams.add_stream(name, new.clone(), &self.sources) {
+                Ok(()) => summary.streams_added.push(name.clone()),
+                Err(e) => {
+                    slog::error!(log, "Could not add stream";
+                        "stream" => name,
+                        "error" => format!("{:?}", e),
+                    );
+                }
+            }
+        }
+
+        // sources are removed last, once no stream in the new config is
+        // wired to them
+        for name in self.sources.names() {
+            if config.source.contains_key(&name) {
+                continue;
+            }
+
+            let streams = self.streams.streams_for_source(&name);
+
+            if !streams.is_empty() {
+                slog::warn!(log, "Not removing source in use by streams";
+                    "source" => &name,
+                    "streams" => streams.join(", "),
+                );
+                continue;
+            }
+
+            self.sources.remove_source(&name);
+            summary.sources_removed.push(name);
+        }
+
+        slog::info!(log, "Reloaded config";
+            "sources_added" => summary.sources_added.join(", "),
+            "sources_removed" => summary.sources_removed.join(", "),
+            "sources_restarted" => summary.sources_restarted.join(", "),
+            "streams_added" => summary.streams_added.join(", "),
+            "streams_removed" => summary.streams_removed.join(", "),
+            "streams_restarted" => summary.streams_restarted.join(", "),
+            "streams_rewired" => summary.streams_rewired.join(", "),
+        );
+
+        self.events.publish(Event::ConfigReloaded {
+            sources_added: summary.sources_added.clone(),
+            sources_removed: summary.sources_removed.clone(),
+            sources_restarted: summary.sources_restarted.clone(),
+            streams_added: summary.streams_added.clone(),
+            streams_removed: summary.streams_removed.clone(),
+            streams_restarted: summary.streams_restarted.clone(),
+            streams_rewired: summary.streams_rewired.clone(),
+        });
+
+        Ok(summary)
+    }
+}
+
+#[cfg(unix)]
+pub fn on_sighup(edicast: Arc<Edicast>) -> Result<(), std::io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    tokio::task::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let edicast = edicast.clone();
+
+            // reloading may decode intros and jingles, keep that off the
+            // runtime thread
+            thread::Builder::new()
+                .name("edicast/reload".to_owned())
+                .spawn(move || {
+                    if let Err(e) = edicast.reload() {
+                        slog::error!(edicast.log, "Could not reload config"; "error" => e.to_string());
+                    }
+                })
+                .expect("spawn edicast reload thread");
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+use tokio::sync::watch;
+
+use crate::audio::decode;
+use super::Edicast;
+
+// how long to wait for listener connections to finish once their streams
+// have ended, before exiting regardless
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    Draining,
+    Finished,
+}
+
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (phase, _) = watch::channel(Phase::Running);
+        Shutdown { phase }
+    }
+
+    pub fn stop_accepting(&self) {
+        self.phase.send_if_modified(|phase| {
+            if *phase < Phase::Draining {
+                *phase = Phase::Draining;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    // resolves once edicast stops accepting connections, for shutdown or
+    // upgrade. accept loops close their listeners then
+    pub async fn draining(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    // resolves once listeners have been disconnected, or given up on
+    pub async fn finished(&self) {
+        self.reached(Phase::Finished).await
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut current = self.phase.subscribe();
+
+        loop {
+            if *current.borrow_and_update() >= phase {
+                return;
+            }
+
+            if current.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// resolves on SIGTERM or SIGINT
+#[cfg(unix)]
+pub fn terminate_signal() -> Result<impl Future<Output = ()>, io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+    })
+}
+
+// resolves on ctrl-c or ctrl-break, or the console window being closed, or
+// the user logging off or the machine shutting down while edicast is
+// running in a console. windows gives a process a few seconds to exit after
+// the last three, long enough for most of a graceful shutdown
+#[cfg(windows)]
+pub fn terminate_signal() -> Result<impl Future<Output = ()>, io::Error> {
+    use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_logoff, ctrl_shutdown};
+
+    let mut interrupt = ctrl_c()?;
+    let mut interrupt_break = ctrl_break()?;
+    let mut close = ctrl_close()?;
+    let mut logoff = ctrl_logoff()?;
+    let mut shutdown = ctrl_shutdown()?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = interrupt_break.recv() => {}
+            _ = close.recv() => {}
+            _ = logoff.recv() => {}
+            _ = shutdown.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn terminate_signal() -> Result<impl Future<Output = ()>, io::Error> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+pub async fn shutdown(log: Logger, edicast: &Edicast) {
+    slog::info!(log, "Shutting down";
+        "listeners" => edicast.listeners.count(),
+        "grace_secs" => edicast.config.shutdown.grace_secs,
+        "goodbye" => edicast.config.shutdown.goodbye.as_ref().map(|path| path.display().to_string()),
+    );
+
+    edicast.shutdown.stop_accepting();
+
+    // decoding the goodbye blocks, keep it off the runtime thread
+    let goodbye = tokio::task::spawn_blocking({
+        let log = log.clone();
+        let goodbye = edicast.config.shutdown.goodbye.clone();
+
+        move || {
+            let path = goodbye?;
+
+            match decode::read_file(&path) {
+                Ok(pcm) => Some(Arc::new(pcm)),
+                Err(e) => {
+                    slog::error!(log, "Could not load shutdown goodbye";
+                        "path" => path.display(),
+                        "error" => e.to_string(),
+                    );
+                    None
+                }
+            }
+        }
+    }).await;
+
+    let goodbye = match goodbye {
+        Ok(goodbye) => goodbye,
+        Err(e) => {
+            slog::error!(log, "Could not load shutdown goodbye"; "error" => e.to_string());
+            None
+        }
+    };
+
+    let grace = Duration::from_secs(edicast.config.shutdown.grace_secs);
+    edicast.streams.shutdown(goodbye, grace).await;
+    edicast.sources.shutdown().await;
+
+    // listeners are sent the end of their stream by now, wait for their
+    // connections to finish sending it
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+
+    while edicast.listeners.count() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    let remaining = edicast.listeners.count();
+
+    if remaining > 0 {
+        slog::warn!(log, "Listeners still connected after draining"; "listeners" => remaining);
+    }
+
+    edicast.shutdown.phase.send_replace(Phase::Finished);
+
+    slog::info!(log, "Shutdown complete");
+}
@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::net::handoff;
+use super::shutdown;
+use super::Edicast;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// on SIGUSR2, starts the edicast binary again and hands it every listening
+// socket, so that an upgraded binary can take over without refusing any
+// connections. once the new process is ready, this one stops accepting and
+// keeps serving its listeners until they leave or upgrade_drain_secs
+// passes, then shuts down. if the new process exits first, this one carries
+// on as before. the returned future resolves once shutdown is complete
+pub fn on_sigusr2(log: Logger, edicast: Arc<Edicast>) -> Result<impl Future<Output = ()>, io::Error> {
+    let mut upgrade = signal(SignalKind::user_defined2())?;
+
+    Ok(async move {
+        loop {
+            if upgrade.recv().await.is_none() {
+                return futures::future::pending().await;
+            }
+
+            // the QUIC socket isn't handed over, so the new process would
+            // fail to bind it
+            #[cfg(feature = "http3")]
+            if edicast.config.listen.public_quic.is_some() {
+                slog::error!(log, "Can't hand over to a new process while listen.public_quic is set, restart edicast instead");
+                continue;
+            }
+
+            let successor = match handoff::spawn_successor() {
+                Ok(successor) => successor,
+                Err(e) => {
+                    slog::error!(log, "Could not start new process for upgrade"; "error" => e.to_string());
+                    continue;
+                }
+            };
+
+            let pid = successor.id();
+            slog::info!(log, "Started new process for upgrade, waiting until it's ready"; "pid" => pid);
+
+            match tokio::task::spawn_blocking(move || successor.wait_ready()).await {
+                Ok(true) => {
+                    slog::info!(log, "Handed listening sockets to new process"; "pid" => pid);
+                    break;
+                }
+                _ => {
+                    slog::error!(log, "New process exited before it was ready, carrying on"; "pid" => pid);
+                }
+            }
+        }
+
+        edicast.shutdown.stop_accepting();
+
+        let drain = Duration::from_secs(edicast.config.shutdown.upgrade_drain_secs);
+        let deadline = Instant::now() + drain;
+
+        slog::info!(log, "Draining listeners before shutting down";
+            "listeners" => edicast.listeners.count(),
+            "drain_secs" => drain.as_secs(),
+        );
+
+        while edicast.listeners.count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        shutdown::shutdown(log, &edicast).await;
+    })
+}
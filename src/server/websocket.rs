@@ -0,0 +1,281 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use http_body_util::Full;
+use hyper::{body, Request, Response, StatusCode};
+use hyper::server::conn::http1;
+use hyper::upgrade::Upgraded;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use slog::Logger;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::metadata::{AdBreak, Metadata};
+use crate::net::{self, proxy};
+use crate::source::KickSourceError;
+use super::admin;
+use super::common;
+use super::Edicast;
+
+// the control WebSocket carries both command/response pairs and pushed
+// server events on a single connection, on its own listener
+pub async fn start(address: SocketAddr, edicast: Arc<Edicast>)
+    -> Result<impl Future<Output = ()>, net::BindError>
+{
+    let listener = net::bind(address, edicast.config.listen.interface.control.as_deref()).await?;
+
+    Ok(crate::thread::spawn_worker("edicast/websocket", async move {
+        loop {
+            let log = slog_scope::logger().new(slog::o!("service" => "websocket"));
+
+            let (mut stream, peer) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        slog::warn!(log, "error accepting connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = edicast.shutdown.draining() => break,
+            };
+
+            let edicast = edicast.clone();
+
+            tokio::task::spawn_local(async move {
+                let peer = if edicast.config.listen.proxy_protocol.control {
+                    match proxy::read_header(&mut stream).await {
+                        Ok(source) => source.unwrap_or(peer),
+                        Err(err) => {
+                            slog::debug!(log, "error reading PROXY header: {}", err);
+                            return;
+                        }
+                    }
+                } else {
+                    peer
+                };
+
+                let service = hyper::service::service_fn({
+                    let log = log.clone();
+                    move |mut req| {
+                        common::set_peer(&mut req, peer, &edicast.config.listen);
+                        dispatch(req, log.clone(), edicast.clone())
+                    }
+                });
+
+                let result = http1::Builder::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await;
+
+                match result {
+                    Ok(()) => {}
+                    Err(err) => {
+                        slog::warn!(log, "error serving connection: {}", err);
+                    }
+                }
+            });
+        }
+
+        drop(listener);
+        edicast.shutdown.finished().await;
+    }))
+}
+
+async fn dispatch(req: Request<body::Incoming>, log: Logger, edicast: Arc<Edicast>)
+    -> Result<Response<Full<Bytes>>, Infallible>
+{
+    let log = log.new(slog::o!(common::request_log_keys(&req)));
+
+    if !authorized(&req, &edicast) {
+        slog::warn!(log, "Unauthorized control request");
+        return Ok(common::status(StatusCode::UNAUTHORIZED));
+    }
+
+    let is_upgrade = req.headers().get("upgrade")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+
+    let key = match req.headers().get("sec-websocket-key") {
+        Some(key) if is_upgrade => derive_accept_key(key.as_bytes()),
+        _ => { return Ok(common::status(StatusCode::BAD_REQUEST)); }
+    };
+
+    tokio::task::spawn_local(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                session(ws, log, edicast).await;
+            }
+            Err(e) => {
+                slog::warn!(log, "Could not upgrade control connection"; "error" => e.to_string());
+            }
+        }
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-accept", key)
+        .body(Full::new(Bytes::new()))
+        .expect("build response");
+
+    Ok(response)
+}
+
+// browsers can't set headers on WebSocket requests, so the token may also
+// be passed in the query string
+fn authorized(req: &Request<body::Incoming>, edicast: &Edicast) -> bool {
+    let header = req.headers().get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim().to_owned());
+
+    let query = req.uri().query()
+        .and_then(|query| common::query_param(&format!("?{}", query), "token"));
+
+    common::authorized(common::connection_peer(req), edicast.config.control.token.as_deref(), header.or(query).as_deref())
+}
+
+async fn session(mut ws: WebSocketStream<Upgraded>, log: Logger, edicast: Arc<Edicast>) {
+    let mut events = edicast.events.subscribe();
+
+    slog::info!(log, "Control WebSocket client connected");
+
+    let result = loop {
+        let message = tokio::select! {
+            message = ws.next() => match message {
+                Some(Ok(Message::Text(text))) => command(&text, &log, &edicast),
+                Some(Ok(Message::Close(_))) | None => break Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => break Err(e),
+            },
+            event = events.recv() => match event {
+                Ok(event) => json!({ "event": event }),
+                Err(RecvError::Lagged(count)) => json!({ "lagged": count }),
+                Err(RecvError::Closed) => break Ok(()),
+            },
+        };
+
+        if let Err(e) = ws.send(Message::Text(message.to_string())).await {
+            break Err(e);
+        }
+    };
+
+    match result {
+        Ok(()) => slog::info!(log, "Control WebSocket client disconnected"),
+        Err(e) => slog::info!(log, "Control WebSocket client disconnected"; "error" => e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CommandMessage {
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    ListListeners { stream: Option<String> },
+    PauseStream { stream: String },
+    ResumeStream { stream: String },
+    KickSource { source: String },
+    UpdateMetadata { source: String, title: Option<String> },
+    StartAdBreak { source: String, duration_ms: Option<u64> },
+    EndAdBreak { source: String },
+}
+
+fn command(text: &str, log: &Logger, edicast: &Edicast) -> Value {
+    let message = match serde_json::from_str::<CommandMessage>(text) {
+        Ok(message) => message,
+        Err(e) => { return json!({ "id": null, "ok": false, "error": e.to_string() }); }
+    };
+
+    match run_command(message.command, log, edicast) {
+        Ok(result) => json!({ "id": message.id, "ok": true, "result": result }),
+        Err(error) => json!({ "id": message.id, "ok": false, "error": error }),
+    }
+}
+
+fn run_command(command: Command, log: &Logger, edicast: &Edicast) -> Result<Value, &'static str> {
+    match command {
+        Command::ListListeners { stream } => {
+            let listeners = admin::listener_summaries(edicast, stream.as_deref());
+            Ok(json!(listeners))
+        }
+        Command::PauseStream { stream } => {
+            if !edicast.streams.set_paused(&stream, true) {
+                return Err("No such stream");
+            }
+
+            slog::warn!(log, "Paused stream"; "stream" => &stream);
+            Ok(Value::Null)
+        }
+        Command::ResumeStream { stream } => {
+            if !edicast.streams.set_paused(&stream, false) {
+                return Err("No such stream");
+            }
+
+            slog::info!(log, "Resumed stream"; "stream" => &stream);
+            Ok(Value::Null)
+        }
+        Command::KickSource { source } => {
+            match edicast.sources.kick_source(&source) {
+                Ok(()) => {
+                    slog::info!(log, "Kicked source client"; "source" => &source);
+                    Ok(Value::Null)
+                }
+                Err(KickSourceError::NoSuchSource) => Err("No such source"),
+                Err(KickSourceError::NotConnected) => Err("Source not connected"),
+            }
+        }
+        Command::UpdateMetadata { source, title } => {
+            let title = match &edicast.script {
+                Some(script) => script.rewrite_title(&source, title),
+                None => title,
+            };
+
+            slog::info!(log, "Updating source metadata";
+                "source" => &source,
+                "title" => &title,
+            );
+
+            update_metadata(edicast, &source, |metadata| metadata.title = title)
+        }
+        Command::StartAdBreak { source, duration_ms } => {
+            slog::info!(log, "Starting ad break";
+                "source" => &source,
+                "duration_ms" => duration_ms,
+            );
+
+            let ad_break = AdBreak { duration: duration_ms.map(Duration::from_millis) };
+            update_metadata(edicast, &source, |metadata| metadata.ad_break = Some(ad_break))
+        }
+        Command::EndAdBreak { source } => {
+            slog::info!(log, "Ending ad break"; "source" => &source);
+            update_metadata(edicast, &source, |metadata| metadata.ad_break = None)
+        }
+    }
+}
+
+fn update_metadata(edicast: &Edicast, source: &str, f: impl FnOnce(&mut Metadata))
+    -> Result<Value, &'static str>
+{
+    if edicast.sources.update_metadata(source, f) {
+        Ok(Value::Null)
+    } else {
+        Err("No such source")
+    }
+}
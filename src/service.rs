@@ -0,0 +1,290 @@
+// runs edicast as a windows service. install registers a service which
+// starts edicast with the options and config file given, the service
+// manager then starts it with service run, which hands over to the
+// dispatcher and runs edicast until the service is stopped
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_dispatcher;
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+use crate::Args;
+
+const USAGE: &str = "\
+usage: edicast service install [--name <name>] [options] <config file>
+       edicast service uninstall [--name <name>]";
+
+const DEFAULT_NAME: &str = "edicast";
+
+// how long the service manager is told a graceful shutdown may take, it's
+// only a hint and edicast carries on shutting down past it
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+// the service's name and args, handed from service run to service_main,
+// which the dispatcher calls on a thread of its own
+static SERVICE: Mutex<Option<(String, Args)>> = Mutex::new(None);
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+pub fn main(args: Vec<OsString>) -> i32 {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.to_string_lossy().into_owned(), args.to_vec()),
+        None => {
+            eprintln!("{}", USAGE);
+            return 1;
+        }
+    };
+
+    let (name, args) = match take_name(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("edicast service: {}", e);
+            eprintln!("{}", USAGE);
+            return 1;
+        }
+    };
+
+    let result = match command.as_str() {
+        "install" => install(&name, args),
+        "uninstall" if args.is_empty() => uninstall(&name),
+        "run" => dispatch(name, args),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("edicast service: {}", e);
+            1
+        }
+    }
+}
+
+// --name is taken from the front, the rest are edicast's own args
+fn take_name(args: Vec<OsString>) -> Result<(String, Vec<OsString>), String> {
+    let mut args = args.into_iter().peekable();
+
+    let name = match args.peek().and_then(|arg| arg.to_str()) {
+        Some("--name") => {
+            args.next();
+            args.next()
+                .and_then(|name| name.into_string().ok())
+                .ok_or_else(|| "missing value for --name".to_owned())?
+        }
+        Some(arg) if arg.starts_with("--name=") => {
+            let name = arg["--name=".len()..].to_owned();
+            args.next();
+            name
+        }
+        _ => DEFAULT_NAME.to_owned(),
+    };
+
+    if name.is_empty() {
+        return Err("--name can't be empty".to_owned());
+    }
+
+    Ok((name, args.collect()))
+}
+
+fn install(name: &str, args: Vec<OsString>) -> Result<(), String> {
+    // the service starts in the system directory, so paths given relative
+    // to here won't be found from there
+    let args = absolute_paths(args).map_err(|e| format!("could not resolve paths: {}", e))?;
+    let parsed = Args::parse(args.clone())?;
+
+    // the service manager keeps track of the process itself
+    if parsed.daemon {
+        return Err("--daemon can't be used with a service".to_owned());
+    }
+
+    let mut launch_arguments = vec![
+        OsString::from("service"),
+        OsString::from("run"),
+        OsString::from("--name"),
+        OsString::from(name),
+    ];
+    launch_arguments.extend(args);
+
+    let executable_path = env::current_exe()
+        .map_err(|e| format!("could not find edicast executable: {}", e))?;
+
+    let info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from(format!("edicast ({})", name)),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .map_err(|e| format!("could not connect to the service manager: {}", e))?;
+
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|e| format!("could not install service {}: {}", name, e))?;
+
+    let _ = service.set_description(format!("edicast streaming server, running {}", parsed.config_path.display()));
+
+    println!("installed service {}, start it with: sc start {}", name, name);
+    Ok(())
+}
+
+fn uninstall(name: &str) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| format!("could not connect to the service manager: {}", e))?;
+
+    let service = manager.open_service(name, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .map_err(|e| format!("could not open service {}: {}", name, e))?;
+
+    let status = service.query_status()
+        .map_err(|e| format!("could not query service {}: {}", name, e))?;
+
+    // the service is only removed once it's stopped, stopping it first
+    // saves that being left to the next reboot
+    if status.current_state != ServiceState::Stopped {
+        service.stop()
+            .map_err(|e| format!("could not stop service {}: {}", name, e))?;
+    }
+
+    service.delete()
+        .map_err(|e| format!("could not uninstall service {}: {}", name, e))?;
+
+    println!("uninstalled service {}", name);
+    Ok(())
+}
+
+// hands over to the service dispatcher, which returns once the service has
+// stopped. fails if edicast wasn't started by the service manager
+fn dispatch(name: String, args: Vec<OsString>) -> Result<(), String> {
+    let args = Args::parse(args)?;
+
+    *SERVICE.lock().expect("lock service") = Some((name.clone(), args));
+
+    service_dispatcher::start(&name, ffi_service_main)
+        .map_err(|e| format!("could not start service dispatcher, edicast service run is only for the service manager: {}", e))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (name, args) = match SERVICE.lock().expect("lock service").take() {
+        Some(service) => service,
+        None => return,
+    };
+
+    let stop = Arc::new(Notify::new());
+
+    let status = service_control_handler::register(&name, {
+        let stop = stop.clone();
+
+        move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    });
+
+    // nothing to report a failure to without a status handle, the service
+    // manager logs the service stopping unexpectedly
+    let status = match status {
+        Ok(status) => status,
+        Err(_) => return,
+    };
+
+    set_status(status, ServiceState::Running, ServiceExitCode::Win32(0));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .thread_name("edicast/service")
+        .build();
+
+    let result = match runtime {
+        Ok(runtime) => runtime.block_on(crate::run(args, None, Some(Box::pin(async move {
+            stop.notified().await;
+            set_status(status, ServiceState::StopPending, ServiceExitCode::Win32(0));
+        })))),
+        Err(_) => Err(()),
+    };
+
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(()) => ServiceExitCode::ServiceSpecific(1),
+    };
+
+    set_status(status, ServiceState::Stopped, exit_code);
+}
+
+fn set_status(status: ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+
+    let wait_hint = match state {
+        ServiceState::StopPending => STOP_WAIT_HINT,
+        _ => Duration::default(),
+    };
+
+    let _ = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+}
+
+// options whose values are paths, which install makes absolute
+const PATH_FLAGS: &[&str] = &["--overlay", "--pidfile"];
+
+// makes the config file and the paths given to options in args absolute.
+// every option takes a value, so anything else is the config file
+fn absolute_paths(args: Vec<OsString>) -> Result<Vec<OsString>, io::Error> {
+    let current_dir = env::current_dir()?;
+    let absolute = |path: &OsString| OsString::from(current_dir.join(PathBuf::from(path)));
+
+    let mut resolved = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some(flag) if flag.starts_with("--") => {
+                let (flag, value) = match flag.split_once('=') {
+                    Some((flag, value)) => (flag.to_owned(), Some(OsString::from(value))),
+                    None => (flag.to_owned(), args.next()),
+                };
+
+                let value = match value {
+                    Some(value) if PATH_FLAGS.contains(&flag.as_str()) => Some(absolute(&value)),
+                    value => value,
+                };
+
+                resolved.push(OsString::from(flag));
+                resolved.extend(value);
+            }
+            _ => resolved.push(absolute(&arg)),
+        }
+    }
+
+    Ok(resolved)
+}
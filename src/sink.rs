@@ -0,0 +1,60 @@
+// everything a stream's encoded output is sent to besides its listeners.
+// each sink reads its own subscription to the stream on its own thread, so
+// one which falls behind only loses chunks itself
+use slog::Logger;
+
+use crate::archive;
+use crate::config::{SinkConfig, StreamConfig};
+use crate::hls;
+use crate::relay::{self, RelayStatus, SharedRelayStatus};
+use crate::rtp;
+use crate::source::SourceSet;
+use crate::stream::StreamSubscription;
+
+pub enum Sink {
+    Archive,
+    Relay(SharedRelayStatus),
+    Hls,
+    Rtp,
+}
+
+impl Sink {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Sink::Archive => "archive",
+            Sink::Relay(_) => "relay",
+            Sink::Hls => "hls",
+            Sink::Rtp => "rtp",
+        }
+    }
+
+    pub fn relay_status(&self) -> Option<RelayStatus> {
+        match self {
+            Sink::Relay(status) => Some(status.lock().expect("lock relay status").clone()),
+            _ => None,
+        }
+    }
+}
+
+// sinks run until the stream is removed, which closes their subscriptions
+pub fn start(log: &Logger, name: &str, stream: &StreamConfig, config: SinkConfig,
+    subscription: StreamSubscription, source_set: &SourceSet) -> Sink
+{
+    match config {
+        SinkConfig::Archive(archive) => {
+            archive::start(log.clone(), name, stream.clone(), archive, subscription, source_set);
+            Sink::Archive
+        }
+        SinkConfig::Relay(relay) => {
+            Sink::Relay(relay::start(log.clone(), name, stream.clone(), relay, subscription))
+        }
+        SinkConfig::Hls(hls) => {
+            hls::start(log.clone(), name, stream.clone(), hls, subscription, source_set.metadata(&stream.source));
+            Sink::Hls
+        }
+        SinkConfig::Rtp(rtp) => {
+            rtp::start(log.clone(), name, rtp, subscription);
+            Sink::Rtp
+        }
+    }
+}
@@ -1,58 +1,176 @@
 use std::collections::HashMap;
 use std::io;
-use std::sync::Arc;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use std::thread;
-use std::time::{Instant, Duration};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::time::{Instant, Duration, SystemTime};
 
-use num_rational::Ratio;
+use futures::future::BoxFuture;
 use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::{oneshot, watch};
+use uuid::Uuid;
 
 use crate::audio::PcmData;
 use crate::audio::decode::{PcmRead, PcmReadError};
+use crate::audio::pool::Samples;
 use crate::config::{OfflineBehaviour, SourceConfig};
-use crate::fanout::{live_channel, LivePublisher, LiveSubscriber};
+use crate::event::{Event, EventBus};
+use crate::fanout::{live_channel, LivePublisher, LiveReceiver, LiveSubscriber};
+use crate::metadata::Metadata;
+use crate::supervise::{self, Heartbeat, Supervised};
 use crate::sync::{rendezvous, RendezvousReceiver, RendezvousSender, RecvError, RecvTimeoutError, SendError};
+use crate::thread::priority::Scheduling;
+
+mod clock;
+#[cfg(feature = "ingest-icecast")]
+mod dump;
+mod interrupt;
+mod jitter;
+#[cfg(feature = "ingest-icecast")]
+pub use self::dump::DumpRead;
+#[cfg(any(feature = "ingest-icecast", feature = "ingest-pull"))]
+pub use self::interrupt::interruptible;
+pub use self::interrupt::Interrupt;
+use self::clock::{Pacer, SystemClock};
+use self::jitter::{JitterBuffer, JitterStats, Pop};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceStatus {
+    Offline,
+    Live,
+}
+
+// times a source last changed state, for answering how long it has been up
+// or when it last dropped out
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SourceUptime {
+    pub live_since: Option<SystemTime>,
+    pub last_offline_at: Option<SystemTime>,
+}
+
+pub struct SourceBufferStats {
+    pub name: String,
+    // streams subscribed to the source's fanout
+    pub subscribers: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+    // streams cut off for not keeping up, under the disconnect policy
+    pub disconnected: u64,
+    // samples read from the client and not yet chunked for publishing
+    pub buffered_samples: usize,
+    // audio held in the jitter buffer, and how much it's aiming to hold
+    pub jitter_depth_ms: usize,
+    pub jitter_target_ms: usize,
+    pub jitter_underruns: u64,
+}
 
 pub enum ConnectSourceError {
     AlreadyConnected,
     NoSuchSource,
 }
 
+pub enum KickSourceError {
+    NoSuchSource,
+    NotConnected,
+}
+
+pub enum AddSourceError {
+    AlreadyExists,
+}
+
 pub struct SourceSet {
-    sources: HashMap<String, Source>
+    events: EventBus,
+    log: Logger,
+    runtime: Handle,
+    sources: Arc<RwLock<HashMap<String, Source>>>,
+}
+
+// subscribes to sources by name, held by streams so that they can
+// find their source again after it has been replaced
+#[derive(Clone)]
+pub struct SourceSubscriber {
+    sources: Arc<RwLock<HashMap<String, Source>>>,
+}
+
+impl SourceSubscriber {
+    pub fn subscribe(&self, name: &str) -> Option<LiveReceiver<Arc<PcmData>>> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .and_then(|source| source.output.subscribe().ok())
+    }
 }
 
 impl SourceSet {
-    pub fn new(log: Logger, config: &HashMap<String, SourceConfig>) -> Self {
-        let mut sources = HashMap::new();
+    pub fn new(log: Logger, events: EventBus, config: &HashMap<String, SourceConfig>) -> Self {
+        let runtime = Handle::current();
 
-        for (name, config) in config.iter() {
-            let (cmd_send, cmd_recv) = rendezvous();
-            let (publisher, subscriber) = live_channel();
+        let sources = config.iter()
+            .map(|(name, config)| (name.to_string(), spawn_source(&runtime, &log, &events, name, config)))
+            .collect();
 
-            let thread_context = SourceThreadContext {
-                name: name.clone(),
-                command: cmd_recv,
-                config: config.clone(),
-                log: log.clone(),
-                output: publisher,
-            };
+        SourceSet { events, log, runtime, sources: Arc::new(RwLock::new(sources)) }
+    }
 
-            let source = Source {
-                command: cmd_send,
-                output: subscriber,
-            };
+    pub fn add_source(&self, name: &str, config: &SourceConfig) -> Result<(), AddSourceError> {
+        let mut sources = self.sources.write().expect("write sources");
 
-            thread::Builder::new()
-                .name(format!("edicast/source: {}", name))
-                .spawn(move || source_thread_main(thread_context))
-                .expect("spawn edicast source thread");
+        if sources.contains_key(name) {
+            return Err(AddSourceError::AlreadyExists);
+        }
 
-            sources.insert(name.to_string(), source);
+        sources.insert(name.to_string(), spawn_source(&self.runtime, &self.log, &self.events, name, config));
+        Ok(())
+    }
+
+    // the source task exits once it notices its command channel has been
+    // disconnected, kicking any connected client first so that it does.
+    // returns false if there is no source by this name
+    pub fn remove_source(&self, name: &str) -> bool {
+        let source = self.sources.write().expect("write sources")
+            .remove(name);
+
+        match source {
+            Some(source) => {
+                if let Some(interrupt) = source.client.lock().expect("lock source client").take() {
+                    interrupt.interrupt();
+                }
+
+                true
+            }
+            None => false,
         }
+    }
+
+    // kicks every source client and waits for the source tasks to finish,
+    // for edicast shutting down
+    pub async fn shutdown(&self) {
+        let sources = std::mem::take(&mut *self.sources.write().expect("write sources"));
+
+        let tasks = sources.into_values()
+            .map(|source| {
+                if let Some(interrupt) = source.client.lock().expect("lock source client").take() {
+                    interrupt.interrupt();
+                }
+
+                source.task.join()
+            })
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(tasks).await;
+    }
+
+    pub fn config(&self, name: &str) -> Option<SourceConfig> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| source.config.clone())
+    }
 
-        SourceSet { sources }
+    pub fn names(&self) -> Vec<String> {
+        self.sources.read().expect("read sources")
+            .keys()
+            .cloned()
+            .collect()
     }
 
     // This method does not start the source stream directly, but instead
@@ -62,64 +180,287 @@ impl SourceSet {
     // and allows the HTTP server to respond with the right headers in the case
     // that a source stream could not begin without upgrading the connection.
     pub fn connect_source(&self, name: &str, log: Logger) -> Result<StartSource, ConnectSourceError> {
-        let source = self.sources.get(name)
+        let sources = self.sources.read().expect("read sources");
+
+        let source = sources.get(name)
             .ok_or(ConnectSourceError::NoSuchSource)?;
 
-        let (tx, rx) = sync_channel(0);
+        let (tx, rx) = oneshot::channel();
 
         match source.command.send(NewSource { log, rx }) {
             Ok(()) => {
-                // the source thread is reserved busy for us
+                // the source task is reserved busy for us
                 // return a handle to the connecting source to proceed and
                 // begin sending audio
-                Ok(StartSource { send: tx })
+                Ok(StartSource { client: Arc::clone(&source.client), send: tx })
             }
             Err(SendError::Busy) => Err(ConnectSourceError::AlreadyConnected),
-            Err(SendError::Disconnected) => panic!("source thread died! wtf! we should restart it!"),
+            // source tasks are restarted after a panic, so they only exit
+            // once the source has been removed or has failed for good
+            Err(SendError::Disconnected) => Err(ConnectSourceError::NoSuchSource),
         }
     }
 
-    pub fn source_stream(&self, name: &str) -> Option<Receiver<Arc<PcmData>>> {
-        self.sources.get(name)
-            .and_then(|source| source.output.subscribe().ok())
+    pub fn source_stream(&self, name: &str) -> Option<LiveReceiver<Arc<PcmData>>> {
+        self.subscriber().subscribe(name)
+    }
+
+    pub fn subscriber(&self) -> SourceSubscriber {
+        SourceSubscriber { sources: Arc::clone(&self.sources) }
+    }
+
+    pub fn kick_source(&self, name: &str) -> Result<(), KickSourceError> {
+        let client = {
+            let sources = self.sources.read().expect("read sources");
+
+            let source = sources.get(name)
+                .ok_or(KickSourceError::NoSuchSource)?;
+
+            let client = source.client.lock()
+                .expect("lock source client")
+                .take();
+
+            client
+        };
+
+        match client {
+            Some(interrupt) => {
+                interrupt.interrupt();
+                self.events.publish(Event::SourceKicked { source: name.to_owned() });
+                Ok(())
+            }
+            None => Err(KickSourceError::NotConnected),
+        }
+    }
+
+    pub fn status(&self, name: &str) -> Option<watch::Receiver<SourceStatus>> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| source.status.clone())
+    }
+
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    // peak sample level of the most recent audio from the source client,
+    // or zero if no client is connected
+    pub fn level(&self, name: &str) -> Option<u16> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| source.level.load(Ordering::Relaxed))
+    }
+
+    pub fn uptime(&self, name: &str) -> Option<SourceUptime> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| *source.uptime.lock().expect("lock source uptime"))
+    }
+
+    // times the source task has been restarted after panicking
+    pub fn restarts(&self, name: &str) -> Option<u32> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| source.task.restarts())
+    }
+
+    pub fn buffer_stats(&self) -> Vec<SourceBufferStats> {
+        self.sources.read().expect("read sources")
+            .iter()
+            .map(|(name, source)| SourceBufferStats {
+                name: name.clone(),
+                subscribers: source.output.subscriber_count(),
+                capacity: source.output.capacity(),
+                dropped: source.output.dropped(),
+                disconnected: source.output.disconnected(),
+                buffered_samples: source.buffered.load(Ordering::Relaxed),
+                jitter_depth_ms: source.jitter.depth_ms.load(Ordering::Relaxed),
+                jitter_target_ms: source.jitter.target_ms.load(Ordering::Relaxed),
+                jitter_underruns: source.jitter.underruns.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub fn metadata(&self, name: &str) -> Option<watch::Receiver<Metadata>> {
+        self.sources.read().expect("read sources")
+            .get(name)
+            .map(|source| source.metadata.subscribe())
+    }
+
+    // returns false if there is no source by this name
+    pub fn update_metadata(&self, name: &str, f: impl FnOnce(&mut Metadata)) -> bool {
+        let metadata = match self.sources.read().expect("read sources").get(name) {
+            Some(source) => {
+                source.metadata.send_modify(f);
+                source.metadata.borrow().clone()
+            }
+            None => { return false; }
+        };
+
+        self.events.publish(Event::MetadataUpdated {
+            source: name.to_owned(),
+            title: metadata.title,
+            ad_break: metadata.ad_break.is_some(),
+        });
+
+        true
+    }
+}
+
+fn spawn_source(runtime: &Handle, log: &Logger, events: &EventBus, name: &str, config: &SourceConfig) -> Source {
+    let (cmd_send, cmd_recv) = rendezvous();
+    let (publisher, subscriber) = live_channel(&config.fanout);
+    let (status_send, status_recv) = watch::channel(SourceStatus::Offline);
+    let client = Arc::new(Mutex::new(None));
+    let level = Arc::new(AtomicU16::new(0));
+    let uptime = Arc::new(Mutex::new(SourceUptime::default()));
+    let buffered = Arc::new(AtomicUsize::new(0));
+    let jitter = Arc::new(JitterStats::default());
+
+    let context = SourceContext {
+        name: name.to_owned(),
+        buffered: Arc::clone(&buffered),
+        client: Arc::clone(&client),
+        config: config.clone(),
+        events: events.clone(),
+        jitter: Arc::clone(&jitter),
+        level: Arc::clone(&level),
+        log: log.clone(),
+        output: publisher,
+        scheduling: Scheduling::new(config.scheduling.clone(), log.clone()),
+        status: status_send,
+        uptime: Arc::clone(&uptime),
+    };
+
+    let state = SourceTask {
+        command: cmd_recv,
+        source: Arc::new(context),
+    };
+
+    let (metadata, _) = watch::channel(Metadata::default());
+
+    let task = supervise::spawn(runtime, log.clone(), events.clone(), format!("edicast/source: {}", name), state, run_source_task);
+
+    Source {
+        buffered,
+        client,
+        command: cmd_send,
+        config: config.clone(),
+        jitter,
+        level,
+        metadata,
+        output: subscriber,
+        status: status_recv,
+        task,
+        uptime,
     }
 }
 
 pub struct StartSource {
-    send: SyncSender<Box<dyn PcmRead + Send>>,
+    client: Arc<Mutex<Option<Interrupt>>>,
+    send: oneshot::Sender<SourceClient>,
 }
 
 impl StartSource {
-    pub fn start(self, io: Box<dyn PcmRead + Send>) -> Result<(), ()> {
-        self.send.send(io).map_err(|_| ())
+    // interrupt is used to forcibly disconnect the source client, and should
+    // be connected to the InterruptibleRead that io is reading from. dj is
+    // who the client identified itself as, if anyone
+    pub fn start(self, io: Box<dyn PcmRead + Send>, interrupt: Interrupt, dj: Option<String>) -> Result<(), ()> {
+        *self.client.lock().expect("lock source client") = Some(interrupt);
+
+        self.send.send(SourceClient { io, dj }).map_err(|_| {
+            *self.client.lock().expect("lock source client") = None;
+        })
     }
 }
 
+struct SourceClient {
+    io: Box<dyn PcmRead + Send>,
+    dj: Option<String>,
+}
+
 struct NewSource {
     log: Logger,
-    rx: Receiver<Box<dyn PcmRead + Send>>
+    rx: oneshot::Receiver<SourceClient>
 }
 
 struct Source {
+    buffered: Arc<AtomicUsize>,
+    client: Arc<Mutex<Option<Interrupt>>>,
     command: RendezvousSender<NewSource>,
+    config: SourceConfig,
+    jitter: Arc<JitterStats>,
+    level: Arc<AtomicU16>,
+    metadata: watch::Sender<Metadata>,
     output: LiveSubscriber<Arc<PcmData>>,
+    status: watch::Receiver<SourceStatus>,
+    task: Supervised,
+    uptime: Arc<Mutex<SourceUptime>>,
 }
 
-struct SourceThreadContext {
-    name: String,
+struct SourceTask {
     command: RendezvousReceiver<NewSource>,
+    // shared with the blocking thread reading from a connected client
+    source: Arc<SourceContext>,
+}
+
+struct SourceContext {
+    name: String,
+    buffered: Arc<AtomicUsize>,
+    client: Arc<Mutex<Option<Interrupt>>>,
     config: SourceConfig,
+    events: EventBus,
+    jitter: Arc<JitterStats>,
+    level: Arc<AtomicU16>,
     log: Logger,
     output: LivePublisher<Arc<PcmData>>,
+    scheduling: Scheduling,
+    status: watch::Sender<SourceStatus>,
+    uptime: Arc<Mutex<SourceUptime>>,
+}
+
+fn run_source_task<'a>(task: &'a mut SourceTask, panic: Option<&'a str>) -> BoxFuture<'a, ()> {
+    if let Some(panic) = panic {
+        source_restarted(&task.source, panic);
+    }
+
+    Box::pin(source_main(task))
 }
 
-fn source_thread_main(source: SourceThreadContext) {
+// a source task is restarted after a panic with its command channel and
+// fanout intact, so streams stay subscribed and the next source client can
+// connect as usual. whoever was connected is gone though, reflect that
+fn source_restarted(source: &SourceContext, panic: &str) {
+    source.status.send_replace(SourceStatus::Offline);
+    source.level.store(0, Ordering::Relaxed);
+    source.buffered.store(0, Ordering::Relaxed);
+    source.jitter.depth_ms.store(0, Ordering::Relaxed);
+    *source.client.lock().expect("lock source client") = None;
+
+    {
+        let mut uptime = source.uptime.lock().expect("lock source uptime");
+
+        if uptime.live_since.take().is_some() {
+            uptime.last_offline_at = Some(SystemTime::now());
+        }
+    }
+
+    source.events.publish(Event::SourceError {
+        source: source.name.clone(),
+        error: format!("source task panicked: {}", panic),
+    });
+}
+
+async fn source_main(task: &mut SourceTask) {
+    let source = Arc::clone(&task.source);
+
     slog::info!(source.log, "Starting source"; "source" => &source.name);
 
     match source.config.offline {
         OfflineBehaviour::Silence => {
             let silence_duration = Duration::from_millis(source.config.buffer_ms as u64);
-            let silence = Arc::new(PcmData::silence(silence_duration));
+            let silence = PcmData::silence(silence_duration,
+                source.config.sample_rate, source.config.channels);
 
             loop {
                 let epoch = Instant::now();
@@ -128,16 +469,26 @@ fn source_thread_main(source: SourceThreadContext) {
                 'silence_timer: loop {
                     duration += silence_duration;
 
-                    match source.command.recv_deadline(epoch + duration) {
-                        Ok(cmd) => match incoming_source(&source, &cmd) {
-                            Ok(()) => break 'silence_timer,
-                            Err(()) => {}
+                    match task.command.recv_deadline(epoch + duration).await {
+                        Ok(mut cmd) => {
+                            if let Ok(()) = incoming_source(&source, &mut cmd).await {
+                                break 'silence_timer;
+                            }
                         }
                         Err(RecvTimeoutError::Timeout) => {
-                            source.output.publish(Arc::clone(&silence));
+                            let silence = Arc::new(PcmData {
+                                pts: duration - silence_duration,
+                                ..silence.clone()
+                            });
+
+                            // publishing can wait on slow streams under the
+                            // block backpressure policy
+                            let source = Arc::clone(&source);
+                            let _ = tokio::task::spawn_blocking(move || source.output.publish(silence)).await;
                         }
                         Err(RecvTimeoutError::Disconnected) => {
-                            // command sender end disconnected, exit thread
+                            // command sender end disconnected, exit task
+                            slog::info!(source.log, "Stopping source"; "source" => &source.name);
                             return;
                         }
                     }
@@ -146,12 +497,13 @@ fn source_thread_main(source: SourceThreadContext) {
         }
         OfflineBehaviour::Inactive => {
             loop {
-                match source.command.recv() {
-                    Ok(cmd) => {
-                        let _ = incoming_source(&source, &cmd);
+                match task.command.recv().await {
+                    Ok(mut cmd) => {
+                        let _ = incoming_source(&source, &mut cmd).await;
                     }
                     Err(RecvError::Disconnected) => {
-                        // sender end disconnected, exit thread
+                        // sender end disconnected, exit task
+                        slog::info!(source.log, "Stopping source"; "source" => &source.name);
                         return;
                     }
                 }
@@ -160,26 +512,92 @@ fn source_thread_main(source: SourceThreadContext) {
     }
 }
 
-fn incoming_source(source: &SourceThreadContext, new_source: &NewSource) -> Result<(), ()> {
-    match new_source.rx.recv() {
-        Ok(mut io) => {
+async fn incoming_source(source: &Arc<SourceContext>, new_source: &mut NewSource) -> Result<(), ()> {
+    match (&mut new_source.rx).await {
+        Ok(SourceClient { io, dj }) => {
             let epoch = Instant::now();
 
-            let result = run_source(source, epoch, &mut *io);
+            // identifies this client's time on air, its audio carries it
+            // through to streams and listeners so that their logs can be
+            // tied back to it
+            let session = Uuid::new_v4();
+            let log = new_source.log.new(slog::o!("source_session" => session));
 
+            let last_offline_at = {
+                let mut uptime = source.uptime.lock().expect("lock source uptime");
+                uptime.live_since = Some(SystemTime::now());
+                uptime.last_offline_at
+            };
+
+            let offline_secs = last_offline_at
+                .and_then(|time| time.elapsed().ok())
+                .map(|duration| duration.as_secs());
+
+            slog::info!(log, "Source live";
+                "offline_sec" => offline_secs,
+                "dj" => dj.as_deref(),
+            );
+
+            source.status.send_replace(SourceStatus::Live);
+            source.events.publish(Event::SourceConnected {
+                source: source.name.clone(),
+                session: session.to_string(),
+                dj,
+            });
+
+            // reading from the client and decoding block, so a connected
+            // source holds a thread from the blocking pool until it leaves
+            let heartbeat = supervise::heartbeat();
+
+            let result = tokio::task::spawn_blocking({
+                let source = Arc::clone(source);
+                let heartbeat = heartbeat.clone();
+                let log = log.clone();
+                let mut io = io;
+                move || run_source(&source, session, &log, &mut *io, &heartbeat)
+            }).await;
+
+            heartbeat.idle();
+
+            let result = match result {
+                Ok(result) => result,
+                // let the supervisor see the panic
+                Err(e) => supervise::resume_panic(e),
+            };
+
+            source.status.send_replace(SourceStatus::Offline);
+            source.level.store(0, Ordering::Relaxed);
+            source.buffered.store(0, Ordering::Relaxed);
+
+            {
+                let mut uptime = source.uptime.lock().expect("lock source uptime");
+                uptime.live_since = None;
+                uptime.last_offline_at = Some(SystemTime::now());
+            }
+
+            *source.client.lock().expect("lock source client") = None;
             let duration = Instant::now() - epoch;
 
-            match result {
+            let error = match result {
                 Ok(()) => {
-                    slog::info!(new_source.log, "Live source finished"; "duration_sec" => duration.as_secs());
+                    slog::info!(log, "Live source finished"; "duration_sec" => duration.as_secs());
+                    None
                 }
                 Err(e) => {
-                    slog::error!(new_source.log, "I/O error reading from live source";
+                    slog::error!(log, "I/O error reading from live source";
                         "error" => e.to_string(),
                         "duration_sec" => duration.as_secs(),
                     );
+                    Some(e.to_string())
                 }
-            }
+            };
+
+            source.events.publish(Event::SourceDisconnected {
+                source: source.name.clone(),
+                session: session.to_string(),
+                duration_secs: duration.as_secs(),
+                error,
+            });
 
             Ok(())
         }
@@ -187,45 +605,123 @@ fn incoming_source(source: &SourceThreadContext, new_source: &NewSource) -> Resu
     }
 }
 
-fn sleep_until(deadline: Instant) {
-    let now = Instant::now();
+struct Decoded {
+    jitter: JitterBuffer,
+    // set once the source client has gone away
+    finished: Option<Result<(), io::Error>>,
+}
+
+// a live source is decoded on one thread into its jitter buffer, while this
+// one publishes from the buffer at the rate the audio plays at
+fn run_source(source: &SourceContext, session: Uuid, log: &Logger,
+    io: &mut (dyn PcmRead + Send), heartbeat: &Heartbeat)
+    -> Result<(), io::Error>
+{
+    let decoded = Mutex::new(Decoded {
+        jitter: JitterBuffer::new(
+            Duration::from_millis(source.config.jitter_min_ms as u64),
+            Duration::from_millis(source.config.jitter_max_ms as u64),
+            Duration::from_millis(source.config.buffer_ms as u64),
+        ),
+        finished: None,
+    });
+
+    let ready = Condvar::new();
+
+    // this thread is borrowed from the blocking pool, its settings are put
+    // back once the source client leaves
+    let _scheduled = source.scheduling.enter();
+
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name(format!("edicast/decode: {}", source.name))
+            .spawn_scoped(scope, || {
+                let _scheduled = source.scheduling.enter();
+                let mut finish = Finish { decoded: &decoded, ready: &ready, result: None };
+                finish.result = Some(decode(source, session, log, io, heartbeat, &decoded, &ready));
+            })?;
+
+        play_out(source, log, &decoded, &ready)
+    })
+}
+
+// marks decoding finished even if the decoder panics, so that publishing
+// stops waiting for it. the panic itself is picked up when the scope ends
+struct Finish<'a> {
+    decoded: &'a Mutex<Decoded>,
+    ready: &'a Condvar,
+    result: Option<Result<(), io::Error>>,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        let result = self.result.take()
+            .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::Other, "decoder panicked")));
 
-    if deadline > now {
-        thread::sleep(deadline - now);
+        self.decoded.lock().expect("lock decoded audio").finished = Some(result);
+        self.ready.notify_all();
     }
 }
 
-fn run_source(source: &SourceThreadContext, epoch: Instant, io: &mut dyn PcmRead)
+fn decode(source: &SourceContext, session: Uuid, log: &Logger,
+    io: &mut (dyn PcmRead + Send), heartbeat: &Heartbeat,
+    decoded: &Mutex<Decoded>, ready: &Condvar)
     -> Result<(), io::Error>
 {
-    let mut elapsed = Ratio::new(0u64, 1u64);
     let mut buffer = Vec::new();
+    // presentation time of the first sample in buffer
+    let mut buffer_pts = Duration::ZERO;
+    let mut warned_format = false;
 
     loop {
-        let elapsed_nanos = (elapsed * Ratio::new(1_000_000_000, 1)).to_integer();
-        sleep_until(epoch + Duration::from_nanos(elapsed_nanos));
+        heartbeat.pet();
 
         match io.read() {
             Ok(pcm) => {
-                buffer.extend(pcm.samples.into_iter());
+                let format_matches = pcm.sample_rate == source.config.sample_rate
+                    && pcm.channels == source.config.channels;
+
+                if !format_matches && !warned_format {
+                    slog::warn!(log, "Live source format differs from configured format";
+                        "source" => &source.name,
+                        "sample_rate" => pcm.sample_rate,
+                        "channels" => pcm.channels,
+                        "configured_sample_rate" => source.config.sample_rate,
+                        "configured_channels" => source.config.channels,
+                    );
+
+                    warned_format = true;
+                }
+
+                if buffer.is_empty() {
+                    buffer_pts = pcm.pts;
+                }
+
+                buffer.extend_from_slice(&pcm.samples);
 
                 let buffer_samples = source.config.buffer_ms * pcm.sample_rate / 1000;
 
                 while buffer.len() > buffer_samples {
-                    let chonk = buffer.drain(0..buffer_samples)
-                        .collect::<Vec<_>>()
-                        .into_boxed_slice();
+                    let chonk = Samples::from_slice(&buffer[..buffer_samples]);
+                    buffer.drain(..buffer_samples);
 
-                    source.output.publish(Arc::new(PcmData {
+                    let chonk = PcmData {
                         channels: pcm.channels,
                         sample_rate: pcm.sample_rate,
                         samples: chonk,
-                    }));
+                        pts: buffer_pts,
+                        session: Some(session),
+                    };
+
+                    buffer_pts += chonk.duration();
+
+                    let mut decoded = decoded.lock().expect("lock decoded audio");
+                    decoded.jitter.push(chonk);
+                    decoded.jitter.record(&source.jitter);
+                    ready.notify_all();
                 }
 
-                elapsed += Ratio::<u64>::new(
-                    (pcm.samples.len() / pcm.channels) as u64,
-                    pcm.sample_rate as u64);
+                source.buffered.store(buffer.len(), Ordering::Relaxed);
             }
             Err(PcmReadError::Eof) => {
                 return Ok(());
@@ -239,3 +735,68 @@ fn run_source(source: &SourceThreadContext, epoch: Instant, io: &mut dyn PcmRead
         }
     }
 }
+
+fn play_out(source: &SourceContext, log: &Logger, decoded: &Mutex<Decoded>, ready: &Condvar)
+    -> Result<(), io::Error>
+{
+    let mut pacer = Pacer::new(SystemClock);
+
+    loop {
+        let mut state = decoded.lock().expect("lock decoded audio");
+
+        // once the client is gone, play out whatever it left behind
+        if let Some(result) = state.finished.take() {
+            let remaining = std::iter::from_fn(|| state.jitter.drain()).collect::<Vec<_>>();
+            drop(state);
+
+            for chunk in remaining {
+                publish(source, log, &mut pacer, chunk);
+            }
+
+            source.jitter.depth_ms.store(0, Ordering::Relaxed);
+            return result;
+        }
+
+        let pop = state.jitter.pop();
+        state.jitter.record(&source.jitter);
+
+        match pop {
+            Pop::Chunk(chunk) => {
+                drop(state);
+                publish(source, log, &mut pacer, chunk);
+            }
+            Pop::Filling | Pop::Underrun => {
+                if let Pop::Underrun = pop {
+                    source.jitter.underruns.fetch_add(1, Ordering::Relaxed);
+
+                    slog::warn!(log, "Live source buffer ran dry";
+                        "source" => &source.name,
+                        "target_ms" => source.jitter.target_ms.load(Ordering::Relaxed),
+                    );
+                }
+
+                drop(ready.wait(state).expect("lock decoded audio"));
+
+                // nothing was published while waiting, so the schedule
+                // starts over when audio resumes
+                pacer = Pacer::new(SystemClock);
+            }
+        }
+    }
+}
+
+fn publish(source: &SourceContext, log: &Logger, pacer: &mut Pacer<SystemClock>, chunk: PcmData) {
+    if let Some(lag) = pacer.wait() {
+        slog::warn!(log, "Live source fell behind, skipping ahead";
+            "source" => &source.name,
+            "lag_ms" => lag.as_millis() as u64,
+        );
+    }
+
+    pacer.advance(
+        (chunk.samples.len() / chunk.channels.max(1)) as u64,
+        chunk.sample_rate as u64);
+
+    source.level.store(chunk.peak(), Ordering::Relaxed);
+    source.output.publish(Arc::new(chunk));
+}
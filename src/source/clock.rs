@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use num_rational::Ratio;
+
+// a pacer this far behind its schedule starts over from the current time
+// rather than reading as fast as it can to catch up
+const MAX_LAG: Duration = Duration::from_secs(2);
+
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+}
+
+// a clock which only moves when slept on or advanced, for driving pacing
+// without waiting in real time
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[allow(dead_code)]
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("lock mock clock") += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("lock mock clock")
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.lock().expect("lock mock clock");
+        *now = (*now).max(deadline);
+    }
+}
+
+// paces reads from a source so that audio is published at the rate it
+// plays at. the schedule is kept as an exact count of media time since the
+// epoch, so rounding in sleeps never accumulates
+pub struct Pacer<C: Clock> {
+    clock: C,
+    epoch: Instant,
+    elapsed: Ratio<u64>,
+}
+
+impl<C: Clock> Pacer<C> {
+    pub fn new(clock: C) -> Self {
+        let epoch = clock.now();
+        Pacer { clock, epoch, elapsed: Ratio::new(0, 1) }
+    }
+
+    // records that frames of audio at sample_rate have been read
+    pub fn advance(&mut self, frames: u64, sample_rate: u64) {
+        self.elapsed += Ratio::new(frames, sample_rate.max(1));
+    }
+
+    // sleeps until the audio read so far is due. a source which stalled,
+    // or whose encoder's clock runs slow against ours, leaves the schedule
+    // behind the clock. small lags are caught up by reading without
+    // sleeping, but past MAX_LAG the schedule is corrected to the clock
+    // instead, so that a burst of catching up can't overrun listeners.
+    // returns how far the schedule was moved forward, if it was
+    pub fn wait(&mut self) -> Option<Duration> {
+        let deadline = self.deadline();
+        let now = self.clock.now();
+
+        if now > deadline + MAX_LAG {
+            let lag = now - deadline;
+            self.epoch += lag;
+            return Some(lag);
+        }
+
+        self.clock.sleep_until(deadline);
+        None
+    }
+
+    fn deadline(&self) -> Instant {
+        let elapsed_nanos = (self.elapsed * Ratio::new(1_000_000_000, 1)).to_integer();
+        self.epoch + Duration::from_nanos(elapsed_nanos)
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use slog::Logger;
+
+use crate::schedule;
+
+// copies everything a source client sends to a file as it's read, before
+// any decoding, for debugging misbehaving encoders or keeping a lossless
+// master. problems with the file are logged but never interrupt the source
+pub struct DumpRead<R> {
+    io: R,
+    file: Option<File>,
+    log: Logger,
+}
+
+impl<R: Read> DumpRead<R> {
+    // starts a new file in dir for this session, or passes reads straight
+    // through if there's no dir
+    pub fn new(io: R, dir: Option<&Path>, source: &str, extension: &str, log: &Logger) -> Self {
+        let file = dir.and_then(|dir| {
+            let name = format!("{}-{}.{}",
+                source, schedule::format_utc("%Y-%m-%dT%H-%M-%S", SystemTime::now()), extension);
+
+            let path = dir.join(name);
+
+            match create(dir, &path) {
+                Ok(file) => {
+                    slog::info!(log, "Dumping source client to file"; "path" => path.display());
+                    Some(file)
+                }
+                Err(e) => {
+                    slog::warn!(log, "Could not create source dump file";
+                        "path" => path.display(),
+                        "error" => e.to_string(),
+                    );
+                    None
+                }
+            }
+        });
+
+        DumpRead { io, file, log: log.clone() }
+    }
+}
+
+fn create(dir: &Path, path: &Path) -> Result<File, io::Error> {
+    fs::create_dir_all(dir)?;
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+impl<R: Read> Read for DumpRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n = self.io.read(buf)?;
+
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(&buf[..n]) {
+                slog::warn!(self.log, "Could not write source dump, no longer dumping";
+                    "error" => e.to_string());
+
+                self.file = None;
+            }
+        }
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,116 @@
+// source clients and pulls are read through these, which builds with
+// neither ingest feature have no use for
+#![cfg_attr(not(any(feature = "ingest-icecast", feature = "ingest-pull")), allow(dead_code))]
+
+use std::io::{self, Read};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+const READ_SIZE: usize = 8192;
+const QUEUE_DEPTH: usize = 4;
+
+// an empty chunk signals end of stream
+type Chunk = Result<Vec<u8>, io::Error>;
+
+// source client connections are read on a dedicated thread, with data passed
+// to the source thread over a channel. this lets the source thread be
+// interrupted even while the client connection is hung and a read on it
+// would otherwise block indefinitely
+pub struct InterruptibleRead {
+    rx: Receiver<Chunk>,
+    buffer: Vec<u8>,
+    offset: usize,
+    eof: bool,
+}
+
+#[derive(Clone)]
+pub struct Interrupt {
+    tx: SyncSender<Chunk>,
+    close: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl Interrupt {
+    // fails the source's next read, and closes the connection so that the
+    // client is disconnected and the reader thread blocked on it exits
+    pub fn interrupt(&self) {
+        let error = io::Error::new(io::ErrorKind::ConnectionAborted, "source client kicked");
+
+        // a full queue means the source is still behind on reads, it sees
+        // the connection close once it catches up instead
+        let _ = self.tx.try_send(Err(error));
+
+        (self.close)();
+    }
+}
+
+// close is called on interrupt, and shuts down the connection io reads from
+pub fn interruptible(name: &str, mut io: impl Read + Send + 'static, close: impl Fn() + Send + Sync + 'static)
+    -> (InterruptibleRead, Interrupt)
+{
+    let (tx, rx) = sync_channel(QUEUE_DEPTH);
+    let interrupt = Interrupt { tx: tx.clone(), close: Arc::new(close) };
+
+    // the reader thread exits once the connection closes or once the
+    // InterruptibleRead is dropped, whichever is noticed first
+    thread::Builder::new()
+        .name(format!("edicast/source-io: {}", name))
+        .spawn(move || loop {
+            let mut buffer = vec![0; READ_SIZE];
+
+            match io.read(&mut buffer) {
+                Ok(0) => {
+                    let _ = tx.send(Ok(Vec::new()));
+                    return;
+                }
+                Ok(n) => {
+                    buffer.truncate(n);
+
+                    if tx.send(Ok(buffer)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            }
+        })
+        .expect("spawn edicast source io thread");
+
+    let read = InterruptibleRead {
+        rx,
+        buffer: Vec::new(),
+        offset: 0,
+        eof: false,
+    };
+
+    (read, interrupt)
+}
+
+impl Read for InterruptibleRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.buffer.len() {
+            if self.eof {
+                return Ok(0);
+            }
+
+            match self.rx.recv() {
+                Ok(Ok(chunk)) if chunk.is_empty() => { self.eof = true; }
+                Ok(Ok(chunk)) => {
+                    self.buffer = chunk;
+                    self.offset = 0;
+                }
+                Ok(Err(e)) => { return Err(e); }
+                Err(_) => { self.eof = true; }
+            }
+        }
+
+        let available = &self.buffer[self.offset..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::audio::PcmData;
+
+// how long a source must go without an underrun before its buffer is
+// allowed to shrink by a step
+const STABLE_PERIOD: Duration = Duration::from_secs(30);
+
+// live audio is held back by an adaptive amount before being published, so
+// that bursty delivery from source clients on poor connections doesn't
+// reach listeners as gaps. the target depth grows by a step whenever the
+// buffer runs dry and shrinks by a step after a stable period
+pub struct JitterBuffer {
+    chunks: VecDeque<PcmData>,
+    depth: Duration,
+    target: Duration,
+    min: Duration,
+    max: Duration,
+    step: Duration,
+    stable_since: Instant,
+    // set after an underrun until the buffer reaches its target again
+    filling: bool,
+}
+
+// readings of a source's jitter buffer for stats, shared with the source set
+#[derive(Default)]
+pub struct JitterStats {
+    pub depth_ms: AtomicUsize,
+    pub target_ms: AtomicUsize,
+    pub underruns: AtomicU64,
+}
+
+pub enum Pop {
+    Chunk(PcmData),
+    // still filling up to the target depth, nothing to publish yet
+    Filling,
+    // ran dry, the buffer has grown and will fill up again
+    Underrun,
+}
+
+impl JitterBuffer {
+    pub fn new(min: Duration, max: Duration, step: Duration) -> Self {
+        JitterBuffer {
+            chunks: VecDeque::new(),
+            depth: Duration::ZERO,
+            target: min,
+            min,
+            max: max.max(min),
+            step: step.max(Duration::from_millis(1)),
+            stable_since: Instant::now(),
+            filling: true,
+        }
+    }
+
+    pub fn push(&mut self, chunk: PcmData) {
+        self.depth += chunk.duration();
+        self.chunks.push_back(chunk);
+
+        if self.filling && self.depth >= self.target {
+            self.filling = false;
+        }
+    }
+
+    pub fn pop(&mut self) -> Pop {
+        if self.filling {
+            return Pop::Filling;
+        }
+
+        if self.stable_since.elapsed() >= STABLE_PERIOD && self.target > self.min {
+            self.target = self.target.saturating_sub(self.step).max(self.min);
+            self.stable_since = Instant::now();
+        }
+
+        // a client sending faster than real time, or a target which has just
+        // shrunk, leaves more audio buffered than needed. skip some to bring
+        // latency back down
+        while self.depth > self.target + self.step && self.chunks.len() > 1 {
+            self.take();
+        }
+
+        match self.take() {
+            Some(chunk) => Pop::Chunk(chunk),
+            None => {
+                self.target = (self.target + self.step).min(self.max);
+                self.stable_since = Instant::now();
+                self.filling = true;
+                Pop::Underrun
+            }
+        }
+    }
+
+    // anything still buffered once the source client has gone away
+    pub fn drain(&mut self) -> Option<PcmData> {
+        self.take()
+    }
+
+    pub fn record(&self, stats: &JitterStats) {
+        stats.depth_ms.store(self.depth.as_millis() as usize, Ordering::Relaxed);
+        stats.target_ms.store(self.target.as_millis() as usize, Ordering::Relaxed);
+    }
+
+    fn take(&mut self) -> Option<PcmData> {
+        let chunk = self.chunks.pop_front()?;
+        self.depth = self.depth.saturating_sub(chunk.duration());
+        Some(chunk)
+    }
+}
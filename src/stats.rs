@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+use serde_derive::Serialize;
+use slog::Logger;
+
+use crate::config::StatsConfig;
+use crate::event::{Event, EventBus};
+use crate::listener::ListenerRegistry;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+const WEEK_SECS: i64 = 7 * DAY_SECS;
+
+// the unix epoch fell on a thursday, weeks are aligned to start on monday
+const WEEK_ALIGN_SECS: i64 = 4 * DAY_SECS;
+
+#[cfg(feature = "control")]
+// upper bound on the number of periods a single summary query may span
+pub const MAX_SUMMARY_PERIODS: i64 = 400;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS listener_sessions (
+        id TEXT PRIMARY KEY,
+        stream TEXT NOT NULL,
+        remote_addr TEXT,
+        connected_at INTEGER NOT NULL,
+        disconnected_at INTEGER,
+        bytes_sent INTEGER
+    );
+
+    CREATE INDEX IF NOT EXISTS listener_sessions_connected_at
+        ON listener_sessions (connected_at);
+
+    CREATE TABLE IF NOT EXISTS source_sessions (
+        source TEXT NOT NULL,
+        connected_at INTEGER NOT NULL,
+        disconnected_at INTEGER NOT NULL,
+        error TEXT
+    );
+
+    CREATE INDEX IF NOT EXISTS source_sessions_connected_at
+        ON source_sessions (connected_at);
+
+    CREATE TABLE IF NOT EXISTS listener_samples (
+        time INTEGER NOT NULL,
+        stream TEXT NOT NULL,
+        listeners INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS listener_samples_time
+        ON listener_samples (time);
+";
+
+pub struct Stats {
+    config: StatsConfig,
+}
+
+// filters shared by all stats queries. times are unix timestamps in seconds,
+// name filters by stream or source depending on the query
+#[derive(Default)]
+pub struct Query {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ListenerSession {
+    pub id: String,
+    pub stream: String,
+    pub remote_addr: Option<String>,
+    pub connected_at: i64,
+    pub disconnected_at: Option<i64>,
+    pub bytes_sent: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SourceSession {
+    pub source: String,
+    pub connected_at: i64,
+    pub disconnected_at: i64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ListenerSample {
+    pub time: i64,
+    pub stream: String,
+    pub listeners: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "day" => Some(Period::Day),
+            "week" => Some(Period::Week),
+            _ => None,
+        }
+    }
+
+    pub fn length(self) -> i64 {
+        match self {
+            Period::Day => DAY_SECS,
+            Period::Week => WEEK_SECS,
+        }
+    }
+
+    // start of the UTC day or week containing time
+    pub fn start(self, time: i64) -> i64 {
+        match self {
+            Period::Day => time.div_euclid(DAY_SECS) * DAY_SECS,
+            Period::Week => {
+                (time - WEEK_ALIGN_SECS).div_euclid(WEEK_SECS) * WEEK_SECS + WEEK_ALIGN_SECS
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeriodSummary {
+    pub stream: String,
+    pub period_start: i64,
+    pub period_end: i64,
+    pub listener_hours: f64,
+    pub peak_listeners: i64,
+}
+
+fn connect(path: &Path) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+
+    // WAL lets control requests read while the stats thread is writing
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+
+    Ok(conn)
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+impl Stats {
+    pub fn open(config: &StatsConfig) -> Result<Self, rusqlite::Error> {
+        let conn = connect(&config.database)?;
+        conn.execute_batch(SCHEMA)?;
+
+        // any sessions still open were cut short by the previous run ending
+        // without a clean shutdown. close them off at the last point we know
+        // edicast was running so they don't count towards listener hours
+        conn.execute("
+            UPDATE listener_sessions
+            SET disconnected_at = MAX(connected_at, COALESCE((SELECT MAX(time) FROM listener_samples), 0))
+            WHERE disconnected_at IS NULL
+        ", [])?;
+
+        Ok(Stats { config: config.clone() })
+    }
+
+    // spawns the thread which records events and periodic listener counts
+    // to the database
+    pub fn start(&self, log: Logger, events: &EventBus, listeners: Arc<ListenerRegistry>)
+        -> Result<(), rusqlite::Error>
+    {
+        let stats = StatsThreadContext {
+            conn: connect(&self.config.database)?,
+            config: self.config.clone(),
+            events: events.subscribe_unbounded(),
+            listeners,
+            log,
+        };
+
+        thread::Builder::new()
+            .name("edicast/stats".to_owned())
+            .spawn(move || stats_thread_main(stats))
+            .expect("spawn edicast stats thread");
+
+        Ok(())
+    }
+
+    pub fn listener_sessions(&self, query: &Query) -> Result<Vec<ListenerSession>, rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+
+        let mut stmt = conn.prepare("
+            SELECT id, stream, remote_addr, connected_at, disconnected_at, bytes_sent
+            FROM listener_sessions
+            WHERE (?1 IS NULL OR connected_at >= ?1)
+              AND (?2 IS NULL OR connected_at < ?2)
+              AND (?3 IS NULL OR stream = ?3)
+            ORDER BY connected_at
+        ")?;
+
+        let rows = stmt.query_map(params![query.since, query.until, query.name], |row| {
+            Ok(ListenerSession {
+                id: row.get(0)?,
+                stream: row.get(1)?,
+                remote_addr: row.get(2)?,
+                connected_at: row.get(3)?,
+                disconnected_at: row.get(4)?,
+                bytes_sent: row.get(5)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    pub fn source_sessions(&self, query: &Query) -> Result<Vec<SourceSession>, rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+
+        let mut stmt = conn.prepare("
+            SELECT source, connected_at, disconnected_at, error
+            FROM source_sessions
+            WHERE (?1 IS NULL OR connected_at >= ?1)
+              AND (?2 IS NULL OR connected_at < ?2)
+              AND (?3 IS NULL OR source = ?3)
+            ORDER BY connected_at
+        ")?;
+
+        let rows = stmt.query_map(params![query.since, query.until, query.name], |row| {
+            Ok(SourceSession {
+                source: row.get(0)?,
+                connected_at: row.get(1)?,
+                disconnected_at: row.get(2)?,
+                error: row.get(3)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    pub fn listener_samples(&self, query: &Query) -> Result<Vec<ListenerSample>, rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+
+        let mut stmt = conn.prepare("
+            SELECT time, stream, listeners
+            FROM listener_samples
+            WHERE (?1 IS NULL OR time >= ?1)
+              AND (?2 IS NULL OR time < ?2)
+              AND (?3 IS NULL OR stream = ?3)
+            ORDER BY time
+        ")?;
+
+        let rows = stmt.query_map(params![query.since, query.until, query.name], |row| {
+            Ok(ListenerSample {
+                time: row.get(0)?,
+                stream: row.get(1)?,
+                listeners: row.get(2)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    // listener hours are calculated exactly from sessions, counting only
+    // the part of each session that falls within the period. peak listeners
+    // come from the periodic samples, so are only as precise as sample_secs
+    pub fn summary(&self, query: &Query, period: Period) -> Result<Vec<PeriodSummary>, rusqlite::Error> {
+        let conn = connect(&self.config.database)?;
+        let now = unix_now();
+
+        let until = query.until.unwrap_or(now);
+        let since = query.since.unwrap_or(until - 30 * DAY_SECS);
+
+        let mut hours_stmt = conn.prepare("
+            SELECT stream, SUM(MAX(0, MIN(COALESCE(disconnected_at, ?3), ?2) - MAX(connected_at, ?1)))
+            FROM listener_sessions
+            WHERE connected_at < ?2
+              AND COALESCE(disconnected_at, ?3) > ?1
+              AND (?4 IS NULL OR stream = ?4)
+            GROUP BY stream
+        ")?;
+
+        let mut peak_stmt = conn.prepare("
+            SELECT stream, MAX(listeners)
+            FROM listener_samples
+            WHERE time >= ?1
+              AND time < ?2
+              AND (?3 IS NULL OR stream = ?3)
+            GROUP BY stream
+        ")?;
+
+        let mut summaries = Vec::new();
+        let mut period_start = period.start(since);
+
+        while period_start < until {
+            let period_end = period_start + period.length();
+            let mut streams = BTreeMap::<String, (i64, i64)>::new();
+
+            let hours = hours_stmt.query_map(
+                params![period_start, period_end, now, query.name],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+
+            for row in hours {
+                let (stream, secs) = row?;
+                streams.entry(stream).or_default().0 = secs;
+            }
+
+            let peaks = peak_stmt.query_map(
+                params![period_start, period_end, query.name],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+
+            for row in peaks {
+                let (stream, peak) = row?;
+                streams.entry(stream).or_default().1 = peak;
+            }
+
+            for (stream, (secs, peak)) in streams {
+                summaries.push(PeriodSummary {
+                    stream,
+                    period_start,
+                    period_end,
+                    listener_hours: secs as f64 / 3600.0,
+                    peak_listeners: peak,
+                });
+            }
+
+            period_start = period_end;
+        }
+
+        Ok(summaries)
+    }
+}
+
+struct StatsThreadContext {
+    conn: Connection,
+    config: StatsConfig,
+    events: mpsc::Receiver<Event>,
+    listeners: Arc<ListenerRegistry>,
+    log: Logger,
+}
+
+fn stats_thread_main(stats: StatsThreadContext) {
+    let sample_interval = Duration::from_secs(stats.config.sample_secs.max(1));
+    let mut next_sample = Instant::now() + sample_interval;
+    let mut next_prune = Instant::now();
+
+    slog::info!(stats.log, "Recording stats";
+        "database" => stats.config.database.display(),
+    );
+
+    loop {
+        // events are recorded as they come, waking up in between for
+        // whichever of sampling and pruning is due next
+        let timeout = next_sample.min(next_prune).saturating_duration_since(Instant::now());
+
+        match stats.events.recv_timeout(timeout) {
+            Ok(event) => {
+                if let Err(e) = record_event(&stats.conn, &event) {
+                    slog::error!(stats.log, "Could not record event"; "error" => e.to_string());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+
+        if now >= next_sample {
+            next_sample += sample_interval;
+
+            if let Err(e) = record_sample(&stats.conn, &stats.listeners) {
+                slog::error!(stats.log, "Could not record listener counts"; "error" => e.to_string());
+            }
+        }
+
+        if now >= next_prune {
+            next_prune = now + PRUNE_INTERVAL;
+
+            if let Err(e) = prune(&stats.conn, stats.config.retention_days) {
+                slog::error!(stats.log, "Could not prune old stats"; "error" => e.to_string());
+            }
+        }
+    }
+}
+
+fn record_event(conn: &Connection, event: &Event) -> Result<(), rusqlite::Error> {
+    let now = unix_now();
+
+    match event {
+        Event::ListenerConnected { listener, stream, remote_addr } => {
+            conn.execute("
+                INSERT OR REPLACE INTO listener_sessions (id, stream, remote_addr, connected_at)
+                VALUES (?1, ?2, ?3, ?4)
+            ", params![listener, stream, remote_addr, now])?;
+        }
+        Event::ListenerDisconnected { listener, stream, bytes_sent, .. } => {
+            // listeners may have been moved to another stream since they
+            // connected, record the stream they finished on
+            conn.execute("
+                UPDATE listener_sessions
+                SET stream = ?2, disconnected_at = ?3, bytes_sent = ?4
+                WHERE id = ?1
+            ", params![listener, stream, now, *bytes_sent as i64])?;
+        }
+        Event::SourceDisconnected { source, duration_secs, error, .. } => {
+            conn.execute("
+                INSERT INTO source_sessions (source, connected_at, disconnected_at, error)
+                VALUES (?1, ?2, ?3, ?4)
+            ", params![source, now - *duration_secs as i64, now, error])?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// streams with no listeners are not recorded, a missing sample means zero
+fn record_sample(conn: &Connection, listeners: &ListenerRegistry) -> Result<(), rusqlite::Error> {
+    let now = unix_now();
+    let mut counts = HashMap::<String, i64>::new();
+
+    for listener in listeners.list() {
+        *counts.entry(listener.stream()).or_default() += 1;
+    }
+
+    let mut stmt = conn.prepare_cached("
+        INSERT INTO listener_samples (time, stream, listeners) VALUES (?1, ?2, ?3)
+    ")?;
+
+    for (stream, count) in counts {
+        stmt.execute(params![now, stream, count])?;
+    }
+
+    Ok(())
+}
+
+fn prune(conn: &Connection, retention_days: u64) -> Result<(), rusqlite::Error> {
+    let cutoff = unix_now() - (retention_days * 24 * 60 * 60) as i64;
+
+    conn.execute("DELETE FROM listener_sessions WHERE connected_at < ?1", params![cutoff])?;
+    conn.execute("DELETE FROM source_sessions WHERE connected_at < ?1", params![cutoff])?;
+    conn.execute("DELETE FROM listener_samples WHERE time < ?1", params![cutoff])?;
+
+    Ok(())
+}
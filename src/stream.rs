@@ -1,96 +1,799 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::mpsc::{Receiver, RecvError};
-use std::thread;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
+use futures::future::BoxFuture;
 use slog::Logger;
 use bytes::Bytes;
-use tokio::sync::broadcast;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
 
 use crate::audio::PcmData;
-use crate::audio::encode;
-use crate::config::StreamConfig;
-use crate::source::SourceSet;
+use crate::audio::decode::{self, PcmReadError};
+use crate::audio::encode::{self, Codec};
+use crate::audio::pool::Samples;
+use crate::config::{CodecConfig, SourceOfflineAction, StreamConfig};
+use crate::event::{Event, EventBus};
+use crate::fanout::LiveReceiver;
+use crate::jingle::Jingle;
+use crate::plugin::Plugin;
+use crate::relay::RelayStatus;
+use crate::sink::{self, Sink};
+use crate::source::{SourceSet, SourceSubscriber};
+use crate::supervise::{self, Supervised};
+use crate::thread::priority::Scheduling;
 
 const BUFFER_SIZE: usize = 8;
 
-pub type StreamSubscription = broadcast::Receiver<Bytes>;
+// how often a stream without a source sends silence, and the size of the
+// chunks it plays goodbyes out in
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// how often a stream which has lost its source tries to subscribe
+// to it again
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// encoded audio for listeners, stamped with the presentation time, peak
+// level and source session of the audio it was encoded from
+#[derive(Clone)]
+pub struct EncodedChunk {
+    pub data: Bytes,
+    pub pts: Duration,
+    pub peak: u16,
+    pub session: Option<Uuid>,
+}
+
+pub type StreamSubscription = broadcast::Receiver<EncodedChunk>;
 
 pub struct StreamSet {
-    stream_outputs: HashMap<String, broadcast::Sender<Bytes>>,
+    log: Logger,
+    events: EventBus,
+    runtime: Handle,
+    stream_outputs: RwLock<HashMap<String, StreamOutput>>,
+}
+
+struct StreamOutput {
+    config: StreamConfig,
+    broadcast: broadcast::Sender<EncodedChunk>,
+    commands: mpsc::UnboundedSender<StreamCommand>,
+    intro: Option<Intro>,
+    paused: Arc<AtomicBool>,
+    plugin: Option<Arc<Plugin>>,
+    sinks: Vec<Sink>,
+    source_lost: Arc<AtomicBool>,
+    started_at: SystemTime,
+    task: Supervised,
+}
+
+// an intro encoded ahead of time, only sent while the live audio is in the
+// same format, as mp3 frames can't change sample rate or channels midway.
+// until the stream has had any audio, it's assumed to match
+struct Intro {
+    audio: Bytes,
+    format: (usize, usize),
+    matches: Arc<AtomicBool>,
+}
+
+enum StreamCommand {
+    Stop,
+    Rewire { source: String, input: LiveReceiver<Arc<PcmData>> },
+    Shutdown { goodbye: Option<Arc<PcmData>>, grace: Duration },
+}
+
+pub struct StreamBufferStats {
+    pub name: String,
+    // encoded chunks held in the broadcast buffer for the slowest listener
+    pub queued: usize,
+    pub capacity: usize,
+    pub receivers: usize,
+}
+
+#[derive(Debug)]
+pub enum AddStreamError {
+    AlreadyExists,
+    PathInUse,
+    NoSuchSource,
+}
+
+#[derive(Debug)]
+pub enum RewireStreamError {
+    NoSuchStream,
+    NoSuchSource,
 }
 
 impl StreamSet {
-    pub fn new(log: Logger, config: &HashMap<String, StreamConfig>, source_set: &SourceSet) -> Self {
-        let mut stream_outputs = HashMap::new();
+    pub fn new(log: Logger, events: EventBus, config: &HashMap<String, StreamConfig>, source_set: &SourceSet) -> Self {
+        let stream_set = StreamSet {
+            log,
+            events,
+            runtime: Handle::current(),
+            stream_outputs: RwLock::new(HashMap::new()),
+        };
 
         for (name, config) in config.iter() {
-            let (broadcast, _) = broadcast::channel(BUFFER_SIZE);
+            // this should never fail routinely, we've already validated that
+            // all streams are wired to valid sources and have unique paths.
+            // the only way this could happen is if a source task dies in
+            // between us setting it up and this stream being set up
+            if let Err(e) = stream_set.add_stream(name, config.clone(), source_set) {
+                panic!("could not set up stream {:?}: {:?}", name, e);
+            }
+        }
 
-            let input = match source_set.source_stream(&config.source) {
-                Some(source) => source,
-                None => {
-                    // this should never happen routinely, we've already
-                    // validated that all streams are wired to valid sources.
-                    // the only way this could happen is if a source thread
-                    // dies in between us setting it up and this stream being
-                    // set up
-                    panic!("could not get source stream: {:?}", &config.source);
+        stream_set
+    }
+
+    fn check_conflicts(outputs: &HashMap<String, StreamOutput>, name: &str, config: &StreamConfig)
+        -> Result<(), AddStreamError>
+    {
+        if outputs.contains_key(name) {
+            return Err(AddStreamError::AlreadyExists);
+        }
+
+        if outputs.values().any(|output| output.config.route_key() == config.route_key()) {
+            return Err(AddStreamError::PathInUse);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_stream(&self, name: &str, config: StreamConfig, source_set: &SourceSet)
+        -> Result<(), AddStreamError>
+    {
+        let log = &self.log;
+
+        Self::check_conflicts(&self.stream_outputs.read().expect("read streams"), name, &config)?;
+
+        let input = source_set.source_stream(&config.source)
+            .ok_or(AddStreamError::NoSuchSource)?;
+
+        // intro, jingle and plugin are loaded before taking the write lock,
+        // as decoding or compiling them may take a while
+        let intro = config.intro.as_ref().and_then(|path| {
+            match encode_intro(path, &config.codec) {
+                Ok(intro) => Some(intro),
+                Err(e) => {
+                    slog::error!(log, "Could not load stream intro";
+                        "stream" => name,
+                        "path" => path.display(),
+                        "error" => e.to_string(),
+                    );
+                    None
                 }
-            };
+            }
+        });
 
-            let source = StreamThreadContext {
-                config: config.clone(),
-                input: input,
-                log: log.clone(),
-                name: name.clone(),
-                output: broadcast.clone(),
-            };
+        let jingle = config.jingle.as_ref().and_then(|jingle| {
+            match Jingle::load(log.new(slog::o!("stream" => name.to_owned())), jingle) {
+                Ok(jingle) => Some(jingle),
+                Err(e) => {
+                    slog::error!(log, "Could not load stream jingle";
+                        "stream" => name,
+                        "path" => jingle.path.display(),
+                        "error" => e.to_string(),
+                    );
+                    None
+                }
+            }
+        });
+
+        let plugin = config.plugin.as_ref().and_then(|plugin| {
+            match Plugin::load(log.new(slog::o!("stream" => name.to_owned())), plugin) {
+                Ok(plugin) => Some(Arc::new(plugin)),
+                Err(e) => {
+                    slog::error!(log, "Could not load stream plugin";
+                        "stream" => name,
+                        "path" => plugin.path.display(),
+                        "error" => e.to_string(),
+                    );
+                    None
+                }
+            }
+        });
+
+        let mut stream_outputs = self.stream_outputs.write().expect("write streams");
+
+        // check again now that we hold the write lock
+        Self::check_conflicts(&stream_outputs, name, &config)?;
+
+        let (broadcast, _) = broadcast::channel(BUFFER_SIZE);
+        let (commands, command_recv) = mpsc::unbounded_channel();
+
+        let sinks = config.sinks().into_iter()
+            .map(|sink_config| sink::start(log, name, &config, sink_config, broadcast.subscribe(), source_set))
+            .collect();
 
-            thread::Builder::new()
-                .name(format!("edicast/stream: {}", name))
-                .spawn(move || stream_thread_main(source))
-                .expect("spawn edicast stream thread");
+        let paused = Arc::new(AtomicBool::new(false));
+        let source_lost = Arc::new(AtomicBool::new(false));
+
+        let intro = intro.map(|(audio, format)| Intro { audio, format, matches: Arc::new(AtomicBool::new(true)) });
+
+        let stream = StreamContext {
+            commands: command_recv,
+            config: config.clone(),
+            events: self.events.clone(),
+            format: None,
+            intro: intro.as_ref().map(|intro| (intro.format, Arc::clone(&intro.matches))),
+            input,
+            jingle,
+            log: log.clone(),
+            name: name.to_owned(),
+            output: broadcast.clone(),
+            paused: Arc::clone(&paused),
+            plugin: plugin.clone(),
+            position: Duration::ZERO,
+            session: None,
+            source_lost: Arc::clone(&source_lost),
+            sources: source_set.subscriber(),
+        };
+
+        let task = supervise::spawn(&self.runtime, log.clone(), self.events.clone(), format!("edicast/stream: {}", name), stream, run_stream);
+
+        stream_outputs.insert(name.to_owned(), StreamOutput {
+            config,
+            broadcast,
+            commands,
+            intro,
+            paused,
+            plugin,
+            sinks,
+            source_lost,
+            started_at: SystemTime::now(),
+            task,
+        });
+
+        Ok(())
+    }
 
-            stream_outputs.insert(name.to_string(), broadcast);
+    // stops the stream task, which in turn disconnects all listeners.
+    // returns false if there is no stream by this name
+    pub fn remove_stream(&self, name: &str) -> bool {
+        let output = self.stream_outputs.write().expect("write streams")
+            .remove(name);
+
+        match output {
+            Some(output) => {
+                let _ = output.commands.send(StreamCommand::Stop);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // stops every stream for edicast shutting down, after playing goodbye to
+    // their listeners, or grace of silence if there's no goodbye. waits for
+    // the stream tasks to finish, by which time all listeners have been
+    // sent the end of their stream
+    pub async fn shutdown(&self, goodbye: Option<Arc<PcmData>>, grace: Duration) {
+        let outputs = std::mem::take(&mut *self.stream_outputs.write().expect("write streams"));
+
+        let tasks = outputs.into_values()
+            .filter_map(|output| {
+                output.commands.send(StreamCommand::Shutdown { goodbye: goodbye.clone(), grace }).ok()?;
+                Some(output.task.join())
+            })
+            .collect::<Vec<_>>();
+
+        // streams play their goodbyes out concurrently
+        futures::future::join_all(tasks).await;
+    }
+
+    // points the stream at a different source without interrupting its
+    // listeners. returns the name of the previous source
+    pub fn rewire_stream(&self, name: &str, source: &str, source_set: &SourceSet)
+        -> Result<String, RewireStreamError>
+    {
+        let mut stream_outputs = self.stream_outputs.write().expect("write streams");
+
+        let output = stream_outputs.get_mut(name)
+            .ok_or(RewireStreamError::NoSuchStream)?;
+
+        let input = source_set.source_stream(source)
+            .ok_or(RewireStreamError::NoSuchSource)?;
+
+        let command = StreamCommand::Rewire { source: source.to_owned(), input };
+
+        // if the stream task has gone away there's nothing to rewire, but
+        // we record the new source anyway so the config reflects intent
+        let _ = output.commands.send(command);
+
+        Ok(std::mem::replace(&mut output.config.source, source.to_owned()))
+    }
+
+    // while paused, a stream's source audio is discarded and nothing is sent
+    // to listeners. returns false if there is no stream by this name
+    pub fn set_paused(&self, name: &str, paused: bool) -> bool {
+        match self.stream_outputs.read().expect("read streams").get(name) {
+            Some(output) => {
+                output.paused.store(paused, Ordering::SeqCst);
+                true
+            }
+            None => false,
         }
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.paused.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+
+    // streams for the requested host take precedence over streams without
+    // a host on the same path
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<(String, StreamConfig)> {
+        let outputs = self.stream_outputs.read().expect("read streams");
+
+        let mut candidates = outputs.iter()
+            .filter(|(_, output)| output.config.path == path);
+
+        candidates.clone()
+            .find(|(_, output)| output.config.serves_host(host))
+            .or_else(|| candidates.find(|(_, output)| output.config.host.is_none()))
+            .map(|(name, output)| (name.clone(), output.config.clone()))
+    }
 
-        StreamSet { stream_outputs }
+    pub fn list(&self) -> Vec<(String, StreamConfig)> {
+        self.stream_outputs.read().expect("read streams")
+            .iter()
+            .map(|(name, output)| (name.clone(), output.config.clone()))
+            .collect()
+    }
+
+    // whether the stream's source has gone away and the stream is waiting
+    // for it to come back
+    pub fn is_source_lost(&self, name: &str) -> bool {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.source_lost.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    pub fn buffer_stats(&self) -> Vec<StreamBufferStats> {
+        self.stream_outputs.read().expect("read streams")
+            .iter()
+            .map(|(name, output)| StreamBufferStats {
+                name: name.clone(),
+                queued: output.broadcast.len(),
+                capacity: BUFFER_SIZE,
+                receivers: output.broadcast.receiver_count(),
+            })
+            .collect()
+    }
+
+    pub fn streams_for_source(&self, source: &str) -> Vec<String> {
+        self.stream_outputs.read().expect("read streams")
+            .iter()
+            .filter(|(_, output)| output.config.source == source)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn config(&self, name: &str) -> Option<StreamConfig> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.config.clone())
     }
 
     pub fn subscribe_stream(&self, name: &str) -> Option<StreamSubscription> {
-        self.stream_outputs.get(name)
-            .map(|subscriber| subscriber.subscribe())
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.broadcast.subscribe())
+    }
+
+    // when the stream began encoding, streams encode continuously from
+    // being added until they are removed
+    pub fn started_at(&self, name: &str) -> Option<SystemTime> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.started_at)
+    }
+
+    pub fn relays(&self, name: &str) -> Vec<RelayStatus> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| {
+                output.sinks.iter()
+                    .filter_map(Sink::relay_status)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn sinks(&self, name: &str) -> Vec<&'static str> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .map(|output| output.sinks.iter().map(Sink::kind).collect())
+            .unwrap_or_default()
+    }
+
+    // the stream's intro, unless the live audio isn't in the intro's format
+    pub fn intro(&self, name: &str) -> Option<Bytes> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .and_then(|output| output.intro.as_ref())
+            .filter(|intro| intro.matches.load(Ordering::SeqCst))
+            .map(|intro| intro.audio.clone())
+    }
+
+    pub fn plugin(&self, name: &str) -> Option<Arc<Plugin>> {
+        self.stream_outputs.read().expect("read streams")
+            .get(name)
+            .and_then(|output| output.plugin.clone())
+    }
+}
+
+// intros are encoded once up front with a dedicated codec instance, so that
+// every listener can be sent the same bytes before joining the live stream
+fn encode_intro(path: &Path, codec_config: &CodecConfig) -> Result<(Bytes, (usize, usize)), io::Error> {
+    let mut decoder = decode::open_file(path)?;
+    let mut codec = encode::from_config(codec_config);
+    let mut encoded = Vec::new();
+    let mut format = None;
+
+    loop {
+        match decoder.read() {
+            Ok(pcm) => {
+                let pcm_format = (pcm.sample_rate, pcm.channels);
+
+                if *format.get_or_insert(pcm_format) != pcm_format {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "intro changes sample rate or channels partway through"));
+                }
+
+                encoded.extend_from_slice(&codec.encode(&pcm));
+            }
+            Err(PcmReadError::Eof) => break,
+            Err(PcmReadError::SkippedData) => {}
+            Err(PcmReadError::Io(e)) => return Err(e),
+        }
     }
+
+    let format = format.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "intro has no audio"))?;
+
+    // the intro's last frames are still inside the encoder
+    encoded.extend_from_slice(&codec.flush());
+
+    Ok((encoded.into(), format))
 }
 
-pub struct StreamThreadContext {
+pub struct StreamContext {
+    commands: mpsc::UnboundedReceiver<StreamCommand>,
     config: StreamConfig,
-    input: Receiver<Arc<PcmData>>,
+    events: EventBus,
+    // the format of the most recent audio, for producing silence in the
+    // same format when there's no source audio
+    format: Option<(usize, usize)>,
+    // the intro's format, and whether the live audio is in it
+    intro: Option<((usize, usize), Arc<AtomicBool>)>,
+    input: LiveReceiver<Arc<PcmData>>,
+    jingle: Option<Jingle>,
     log: Logger,
     name: String,
-    output: broadcast::Sender<Bytes>,
+    output: broadcast::Sender<EncodedChunk>,
+    paused: Arc<AtomicBool>,
+    plugin: Option<Arc<Plugin>>,
+    // presentation time at the end of the most recently encoded audio, for
+    // stamping audio the stream makes up itself
+    position: Duration,
+    // the source session of the most recent source audio
+    session: Option<Uuid>,
+    source_lost: Arc<AtomicBool>,
+    sources: SourceSubscriber,
+}
+
+// encoding is CPU bound, so it runs on the blocking pool rather than holding
+// up the runtime, under the stream's scheduling settings. the codec is only
+// shared with that one blocking task
+struct Encoder {
+    codec: Box<dyn Codec>,
+    plugin: Option<Arc<Plugin>>,
+    scheduling: Scheduling,
 }
 
-fn stream_thread_main(stream: StreamThreadContext) {
-    let mut codec = encode::from_config(&stream.config.codec);
+type SharedCodec = Arc<Mutex<Encoder>>;
+
+async fn encode(codec: &SharedCodec, pcm: Arc<PcmData>) -> EncodedChunk {
+    let codec = Arc::clone(codec);
+    let pts = pcm.pts;
+    let session = pcm.session;
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut encoder = codec.lock().expect("lock codec");
+        let _scheduled = encoder.scheduling.enter();
+
+        // the plugin runs here rather than on the runtime, as it may well
+        // be as CPU bound as encoding. peaks are of what listeners hear
+        let pcm = match &encoder.plugin {
+            Some(plugin) => plugin.process(pcm),
+            None => pcm,
+        };
+
+        (encoder.codec.encode(&pcm), pcm.peak())
+    }).await;
+
+    match result {
+        Ok((encoded, peak)) => EncodedChunk { data: encoded, pts, peak, session },
+        // let the supervisor see the encoder's panic
+        Err(e) => supervise::resume_panic(e),
+    }
+}
+
+async fn flush(codec: &SharedCodec, pts: Duration) -> EncodedChunk {
+    let codec = Arc::clone(codec);
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut encoder = codec.lock().expect("lock codec");
+        let _scheduled = encoder.scheduling.enter();
+        encoder.codec.flush()
+    }).await;
+
+    match result {
+        Ok(encoded) => EncodedChunk { data: encoded, pts, peak: 0, session: None },
+        Err(e) => supervise::resume_panic(e),
+    }
+}
+
+fn run_stream<'a>(stream: &'a mut StreamContext, _panic: Option<&'a str>) -> BoxFuture<'a, ()> {
+    // a stream restarted after a panic starts over with a new encoder, but
+    // keeps its input and listeners
+    Box::pin(stream_main(stream))
+}
+
+async fn stream_main(stream: &mut StreamContext) {
+    let codec: SharedCodec = Arc::new(Mutex::new(Encoder {
+        codec: encode::from_config(&stream.config.codec),
+        plugin: stream.plugin.clone(),
+        scheduling: Scheduling::new(stream.config.scheduling.clone(), stream.log.clone()),
+    }));
+    let started_at = Instant::now();
 
     slog::info!(stream.log, "Starting stream";
-        "codec" => codec.describe(),
-        "path" => stream.config.path,
-        "source" => stream.config.source,
-        "stream" => stream.name,
+        "codec" => codec.lock().expect("lock codec").codec.describe(),
+        "host" => stream.config.host.as_deref(),
+        "path" => &stream.config.path,
+        "source" => &stream.config.source,
+        "stream" => &stream.name,
     );
 
+    let heartbeat = supervise::heartbeat();
+
     loop {
-        match stream.input.recv() {
-            Ok(pcm) => {
-                let encoded = codec.encode(&pcm);
-                let _ = stream.output.send(encoded.into());
+        // waiting for audio or commands isn't watched, only handling them
+        heartbeat.idle();
+
+        tokio::select! {
+            // commands take priority over audio, as they did when they were
+            // polled for between reads
+            biased;
+
+            command = stream.commands.recv() => {
+                let command = command.unwrap_or(StreamCommand::Stop);
+
+                if !handle_command(stream, &codec, command, started_at).await {
+                    return;
+                }
             }
-            Err(RecvError) => {
-                panic!("source stream terminated unexpectedly!");
+            pcm = stream.input.recv() => match pcm {
+                Some(pcm) if stream.paused.load(Ordering::SeqCst) => {
+                    set_format(stream, (pcm.sample_rate, pcm.channels));
+                }
+                Some(pcm) => {
+                    heartbeat.pet();
+                    set_format(stream, (pcm.sample_rate, pcm.channels));
+
+                    if pcm.session.is_some() && pcm.session != stream.session {
+                        stream.session = pcm.session;
+
+                        slog::info!(stream.log, "Stream fed by source session";
+                            "stream" => &stream.name,
+                            "source" => &stream.config.source,
+                            "source_session" => pcm.session,
+                        );
+                    }
+
+                    let pcm = match &mut stream.jingle {
+                        Some(jingle) => jingle.process(pcm),
+                        None => vec![pcm],
+                    };
+
+                    for pcm in pcm {
+                        stream.position = pcm.pts + pcm.duration();
+                        let _ = stream.output.send(encode(&codec, pcm).await);
+                    }
+                }
+                None => {
+                    if !wait_for_source(stream, &codec, started_at).await {
+                        return;
+                    }
+                }
+            },
+        }
+    }
+}
+
+// checks the intro against the live audio only when its format changes, and
+// warns once each time they stop matching
+fn set_format(stream: &mut StreamContext, format: (usize, usize)) {
+    if stream.format == Some(format) {
+        return;
+    }
+
+    stream.format = Some(format);
+
+    if let Some((intro_format, matches)) = &stream.intro {
+        let now_matches = *intro_format == format;
+
+        if matches.swap(now_matches, Ordering::SeqCst) && !now_matches {
+            slog::warn!(stream.log, "Skipping stream intro, its audio format doesn't match the stream's";
+                "stream" => &stream.name,
+                "intro_sample_rate" => intro_format.0,
+                "intro_channels" => intro_format.1,
+                "stream_sample_rate" => format.0,
+                "stream_channels" => format.1,
+            );
+        }
+    }
+}
+
+// sources go away while streams are still wired to them when they are
+// removed or replaced. until the stream is rewired, or a source by the same
+// name comes back, listeners either hear silence or are disconnected by the
+// public server according to on_source_offline. returns false if the stream
+// should exit
+async fn wait_for_source(stream: &mut StreamContext, codec: &SharedCodec, started_at: Instant) -> bool {
+    slog::warn!(stream.log, "Stream source went away, waiting for it to return";
+        "stream" => &stream.name,
+        "source" => &stream.config.source,
+    );
+
+    stream.source_lost.store(true, Ordering::Relaxed);
+
+    stream.events.publish(Event::StreamSourceLost {
+        stream: stream.name.clone(),
+        source: stream.config.source.clone(),
+    });
+
+    let silence = match (&stream.config.on_source_offline, stream.format) {
+        (SourceOfflineAction::Hold, Some((sample_rate, channels))) => {
+            Some(PcmData::silence(COMMAND_POLL_INTERVAL, sample_rate, channels))
+        }
+        _ => None,
+    };
+
+    let mut tick = tokio::time::interval(COMMAND_POLL_INTERVAL);
+    let mut last_attempt = Instant::now();
+    let heartbeat = supervise::heartbeat();
+
+    loop {
+        heartbeat.idle();
+
+        tokio::select! {
+            command = stream.commands.recv() => {
+                let command = command.unwrap_or(StreamCommand::Stop);
+                let rewired = matches!(command, StreamCommand::Rewire { .. });
+
+                if !handle_command(stream, codec, command, started_at).await {
+                    return false;
+                }
+
+                if rewired {
+                    break;
+                }
+
+                continue;
+            }
+            _ = tick.tick() => {}
+        }
+
+        if last_attempt.elapsed() >= RESUBSCRIBE_INTERVAL {
+            last_attempt = Instant::now();
+
+            if let Some(input) = stream.sources.subscribe(&stream.config.source) {
+                slog::info!(stream.log, "Stream source returned";
+                    "stream" => &stream.name,
+                    "source" => &stream.config.source,
+                );
+
+                stream.input = input;
+                break;
+            }
+        }
+
+        if let Some(silence) = &silence {
+            if !stream.paused.load(Ordering::SeqCst) {
+                heartbeat.pet();
+
+                let silence = Arc::new(PcmData { pts: stream.position, ..silence.clone() });
+                stream.position += silence.duration();
+                let _ = stream.output.send(encode(codec, silence).await);
+            }
+        }
+    }
+
+    stream.source_lost.store(false, Ordering::Relaxed);
+
+    // whether the source came back or the stream was rewired to another
+    stream.events.publish(Event::StreamSourceReturned {
+        stream: stream.name.clone(),
+        source: stream.config.source.clone(),
+    });
+
+    true
+}
+
+// returns false if the stream should exit
+async fn handle_command(stream: &mut StreamContext, codec: &SharedCodec, command: StreamCommand, started_at: Instant)
+    -> bool
+{
+    match command {
+        StreamCommand::Stop => {
+            slog::info!(stream.log, "Stopping stream";
+                "stream" => &stream.name,
+                "uptime_sec" => started_at.elapsed().as_secs(),
+            );
+
+            false
+        }
+        StreamCommand::Rewire { source, input } => {
+            slog::info!(stream.log, "Rewiring stream";
+                "stream" => &stream.name,
+                "from_source" => &stream.config.source,
+                "to_source" => &source,
+            );
+
+            stream.config.source = source;
+            stream.input = input;
+            true
+        }
+        StreamCommand::Shutdown { goodbye, grace } => {
+            slog::info!(stream.log, "Shutting down stream";
+                "stream" => &stream.name,
+                "uptime_sec" => started_at.elapsed().as_secs(),
+                "goodbye" => goodbye.is_some(),
+            );
+
+            let farewell = goodbye.or_else(|| {
+                let (sample_rate, channels) = stream.format?;
+                Some(Arc::new(PcmData::silence(grace, sample_rate, channels)))
+            });
+
+            if let Some(farewell) = farewell {
+                play_out(stream, codec, &farewell).await;
             }
+
+            let _ = stream.output.send(flush(codec, stream.position).await);
+            false
         }
     }
 }
+
+// sends audio to listeners at its natural rate rather than all at once, so
+// that they hear it in full before their connections close
+async fn play_out(stream: &mut StreamContext, codec: &SharedCodec, pcm: &PcmData) {
+    let chunk_frames = (pcm.sample_rate as u128 * COMMAND_POLL_INTERVAL.as_millis() / 1000) as usize;
+    let chunk_samples = (chunk_frames * pcm.channels).max(1);
+    let epoch = Instant::now();
+    let heartbeat = supervise::heartbeat();
+
+    for (index, samples) in pcm.samples.chunks(chunk_samples).enumerate() {
+        heartbeat.pet();
+
+        let chunk = Arc::new(PcmData {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels,
+            samples: Samples::from_slice(samples),
+            pts: stream.position,
+            session: None,
+        });
+
+        stream.position += chunk.duration();
+        let _ = stream.output.send(encode(codec, chunk).await);
+
+        let deadline = epoch + COMMAND_POLL_INTERVAL * (index as u32 + 1);
+        tokio::time::sleep(deadline.saturating_duration_since(Instant::now())).await;
+    }
+}
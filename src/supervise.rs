@@ -0,0 +1,330 @@
+// a small supervision layer for edicast's long running tasks and threads.
+// supervised tasks are restarted with backoff when they panic, and given up
+// on if they keep panicking, which marks them failed in the health endpoint.
+// supervised tasks can also pet a watchdog while they're busy, which reports
+// them stalled if they stop making progress. panics are caught as they
+// unwind, so edicast mustn't be built with panic = "abort"
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use slog::Logger;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::config::WatchdogConfig;
+use crate::event::{Event, EventBus};
+use crate::thread::panic_message;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// more than this many panics or watchdog restarts within the window is
+// given up on
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(300);
+
+// children are forgotten once their handle is dropped, unless they have
+// failed, which stays visible until edicast is restarted
+static CHILDREN: Mutex<Vec<Arc<Child>>> = Mutex::new(Vec::new());
+
+tokio::task_local! {
+    static HEARTBEAT: Heartbeat;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    // waiting out the backoff after a panic
+    Restarting,
+    // busy without petting the watchdog for too long
+    Stalled,
+    // panicked too often, no longer running
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChildStatus {
+    pub name: String,
+    pub state: State,
+    pub restarts: u32,
+    pub last_panic: Option<String>,
+    pub last_panic_at: Option<SystemTime>,
+}
+
+struct Child {
+    status: Mutex<ChildStatus>,
+    // when the child last petted the watchdog, None while it's idle
+    last_pet: Mutex<Option<Instant>>,
+    kill: Notify,
+}
+
+impl Child {
+    fn register(name: &str) -> Arc<Child> {
+        let child = Arc::new(Child {
+            status: Mutex::new(ChildStatus {
+                name: name.to_owned(),
+                state: State::Running,
+                restarts: 0,
+                last_panic: None,
+                last_panic_at: None,
+            }),
+            last_pet: Mutex::new(None),
+            kill: Notify::new(),
+        });
+
+        let mut children = CHILDREN.lock().expect("lock supervised children");
+        children.retain(Child::is_live);
+        children.push(Arc::clone(&child));
+
+        child
+    }
+
+    fn is_live(self: &Arc<Self>) -> bool {
+        Arc::strong_count(self) > 1
+            || self.status.lock().expect("lock child status").state == State::Failed
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ChildStatus)) {
+        f(&mut self.status.lock().expect("lock child status"))
+    }
+
+    fn set_last_pet(&self, at: Option<Instant>) {
+        *self.last_pet.lock().expect("lock child last pet") = at;
+    }
+}
+
+// a supervised task, which is forgotten once this is dropped
+pub struct Supervised {
+    child: Arc<Child>,
+    task: JoinHandle<()>,
+}
+
+impl Supervised {
+    pub fn restarts(&self) -> u32 {
+        self.child.status.lock().expect("lock child status").restarts
+    }
+
+    pub async fn join(self) {
+        let _ = self.task.await;
+    }
+}
+
+// runs f as a task on the runtime, calling it again after a backoff
+// whenever it panics. f is passed the previous panic's message when it's
+// restarted, so that it can clean up after it. the task finishes once the
+// future returned by f completes
+pub fn spawn<S>(
+    runtime: &Handle,
+    log: Logger,
+    events: EventBus,
+    name: String,
+    mut state: S,
+    f: for<'a> fn(&'a mut S, Option<&'a str>) -> BoxFuture<'a, ()>,
+) -> Supervised
+    where S: Send + 'static
+{
+    let child = Child::register(&name);
+
+    let task = runtime.spawn({
+        let child = Arc::clone(&child);
+
+        async move {
+            let mut previous_panic: Option<String> = None;
+            let mut backoff = INITIAL_BACKOFF;
+            let mut recent_panics = VecDeque::new();
+
+            loop {
+                let started_at = Instant::now();
+
+                let heartbeat = Heartbeat(Some(Arc::clone(&child)));
+                let run = HEARTBEAT.scope(heartbeat, f(&mut state, previous_panic.as_deref()));
+
+                let result = tokio::select! {
+                    result = AssertUnwindSafe(run).catch_unwind() => {
+                        result.map_err(|panic| panic_message(&*panic))
+                    }
+                    // the watchdog gave up waiting for the child to make
+                    // progress, so it's restarted as though it panicked
+                    _ = child.kill.notified() => {
+                        Err("stalled, restarted by watchdog".to_owned())
+                    }
+                };
+
+                child.set_last_pet(None);
+
+                let message = match result {
+                    Ok(()) => return,
+                    Err(message) => message,
+                };
+
+                let now = Instant::now();
+
+                // a child which ran happily for a while starts over
+                if now - started_at > RESTART_WINDOW {
+                    backoff = INITIAL_BACKOFF;
+                }
+
+                recent_panics.push_back(now);
+                recent_panics.retain(|at| now - *at < RESTART_WINDOW);
+
+                let failed = recent_panics.len() > MAX_RESTARTS;
+
+                child.update(|status| {
+                    status.state = if failed { State::Failed } else { State::Restarting };
+                    status.last_panic = Some(message.clone());
+                    status.last_panic_at = Some(SystemTime::now());
+                });
+
+                if failed {
+                    slog::crit!(log, "Task keeps panicking, giving up on it";
+                        "task" => &name,
+                        "error" => &message,
+                        "panics" => recent_panics.len(),
+                    );
+
+                    events.publish(Event::TaskFailed { task: name.clone(), error: message });
+                    return;
+                }
+
+                slog::error!(log, "Task panicked, restarting";
+                    "task" => &name,
+                    "error" => &message,
+                    "backoff_secs" => backoff.as_secs(),
+                );
+
+                events.publish(Event::TaskRestarted { task: name.clone(), error: message.clone() });
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+
+                child.update(|status| {
+                    status.state = State::Running;
+                    status.restarts += 1;
+                });
+
+                previous_panic = Some(message);
+            }
+        }
+    });
+
+    Supervised { child, task }
+}
+
+// a supervised task's handle on the watchdog, which does nothing outside of
+// supervised tasks. it can be moved to a blocking thread doing the task's work
+#[derive(Clone, Default)]
+pub struct Heartbeat(Option<Arc<Child>>);
+
+impl Heartbeat {
+    // the task is busy and making progress
+    pub fn pet(&self) {
+        if let Some(child) = &self.0 {
+            child.set_last_pet(Some(Instant::now()));
+        }
+    }
+
+    // the task is waiting on something which may legitimately take a while,
+    // so isn't watched until it's next petted
+    pub fn idle(&self) {
+        if let Some(child) = &self.0 {
+            child.set_last_pet(None);
+        }
+    }
+}
+
+// the current supervised task's heartbeat
+pub fn heartbeat() -> Heartbeat {
+    HEARTBEAT.try_with(Heartbeat::clone).unwrap_or_default()
+}
+
+// carries a panic in a blocking task over to the supervised task awaiting
+// it, so that the supervisor restarts the task. a blocking task is only
+// cancelled if the runtime shuts down before it starts
+pub fn resume_panic(error: JoinError) -> ! {
+    match error.try_into_panic() {
+        Ok(panic) => panic::resume_unwind(panic),
+        Err(error) => panic!("blocking task did not run: {}", error),
+    }
+}
+
+// periodically checks supervised tasks for ones which have been busy for
+// longer than the configured timeout without petting the watchdog
+pub async fn watchdog(log: Logger, config: WatchdogConfig) {
+    let timeout = Duration::from_secs(config.timeout_secs.max(1));
+    let mut tick = tokio::time::interval((timeout / 4).max(Duration::from_secs(1)));
+
+    loop {
+        tick.tick().await;
+
+        let children = {
+            let mut children = CHILDREN.lock().expect("lock supervised children");
+            children.retain(Child::is_live);
+            children.clone()
+        };
+
+        for child in children {
+            let stalled = child.last_pet.lock().expect("lock child last pet")
+                .map(|at| at.elapsed() > timeout)
+                .unwrap_or(false);
+
+            let mut status = child.status.lock().expect("lock child status");
+
+            match (status.state, stalled) {
+                (State::Running, true) => {
+                    status.state = State::Stalled;
+
+                    slog::crit!(log, "Task stopped making progress";
+                        "task" => &status.name,
+                        "timeout_secs" => timeout.as_secs(),
+                        "restart" => config.restart,
+                    );
+
+                    if config.restart {
+                        child.kill.notify_one();
+                    }
+                }
+                (State::Stalled, false) => {
+                    status.state = State::Running;
+                    slog::info!(log, "Task making progress again"; "task" => &status.name);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// registers a thread which isn't restarted, for server workers whose
+// panics are escalated to the whole process
+pub fn watch(name: &str) -> Watched {
+    Watched { child: Child::register(name) }
+}
+
+pub struct Watched {
+    child: Arc<Child>,
+}
+
+impl Watched {
+    pub fn failed(&self, message: &str) {
+        self.child.update(|status| {
+            status.state = State::Failed;
+            status.last_panic = Some(message.to_owned());
+            status.last_panic_at = Some(SystemTime::now());
+        });
+    }
+}
+
+#[cfg(feature = "control")]
+// every supervised task or thread still running or failed
+pub fn status() -> Vec<ChildStatus> {
+    let mut children = CHILDREN.lock().expect("lock supervised children");
+    children.retain(Child::is_live);
+
+    children.iter()
+        .map(|child| child.status.lock().expect("lock child status").clone())
+        .collect()
+}
@@ -1,19 +1,20 @@
 use std::ops::{Deref, DerefMut, Drop};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{self, sync_channel, SyncSender, Receiver};
 use std::time::Instant;
 
+use tokio::sync::mpsc::{self, error::TrySendError};
+
 #[derive(Debug)]
 pub struct RendezvousSender<T> {
     ready: Arc<AtomicBool>,
-    send: SyncSender<T>,
+    send: mpsc::Sender<T>,
 }
 
 #[derive(Debug)]
 pub struct RendezvousReceiver<T> {
     ready: Arc<AtomicBool>,
-    recv: Receiver<T>,
+    recv: mpsc::Receiver<T>,
 }
 
 pub enum SendError {
@@ -32,7 +33,10 @@ pub enum RecvTimeoutError {
 
 pub fn rendezvous<T>() -> (RendezvousSender<T>, RendezvousReceiver<T>) {
     let ready = Arc::new(AtomicBool::new(true));
-    let (send, recv) = sync_channel(0);
+
+    // readiness ensures there's at most one value in flight, so a buffer of
+    // one never blocks the sender
+    let (send, recv) = mpsc::channel(1);
 
     (RendezvousSender { ready: Arc::clone(&ready), send },
         RendezvousReceiver { ready: Arc::clone(&ready), recv })
@@ -48,9 +52,14 @@ impl<T> RendezvousSender<T> {
             return Err(SendError::Busy);
         }
 
-        match self.send.send(value) {
+        match self.send.try_send(value) {
             Ok(()) => Ok(()),
-            Err(_) => {
+            Err(TrySendError::Full(_)) => {
+                // a previous value hasn't been received yet, the receiver
+                // restores readiness once it's done with it
+                Err(SendError::Busy)
+            }
+            Err(TrySendError::Closed(_)) => {
                 // restore the readiness of the disconnected channel so that
                 // subsequent sends see Disconnected, not Busy.
                 // there is a race here where other threads might see Busy
@@ -64,34 +73,30 @@ impl<T> RendezvousSender<T> {
 }
 
 impl<T> RendezvousReceiver<T> {
-    pub fn recv<'a>(&'a self) -> Result<RendezvousHandle<'a, T>, RecvError> {
-        match self.recv.recv() {
-            Ok(value) => Ok(RendezvousHandle { value, recv: self }),
-            Err(_) => Err(RecvError::Disconnected),
+    pub async fn recv(&mut self) -> Result<RendezvousHandle<T>, RecvError> {
+        match self.recv.recv().await {
+            Some(value) => Ok(RendezvousHandle { value, ready: Arc::clone(&self.ready) }),
+            None => Err(RecvError::Disconnected),
         }
     }
 
-    pub fn recv_deadline<'a>(&'a self, deadline: Instant) -> Result<RendezvousHandle<'a, T>, RecvTimeoutError> {
-        let now = Instant::now();
+    pub async fn recv_deadline(&mut self, deadline: Instant) -> Result<RendezvousHandle<T>, RecvTimeoutError> {
+        let deadline = tokio::time::Instant::from_std(deadline);
 
-        if now >= deadline {
-            return Err(RecvTimeoutError::Timeout);
-        }
-
-        match self.recv.recv_timeout(deadline - now) {
-            Ok(value) => Ok(RendezvousHandle { value, recv: self }),
-            Err(mpsc::RecvTimeoutError::Timeout) => Err(RecvTimeoutError::Timeout),
-            Err(mpsc::RecvTimeoutError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+        match tokio::time::timeout_at(deadline, self.recv.recv()).await {
+            Ok(Some(value)) => Ok(RendezvousHandle { value, ready: Arc::clone(&self.ready) }),
+            Ok(None) => Err(RecvTimeoutError::Disconnected),
+            Err(_) => Err(RecvTimeoutError::Timeout),
         }
     }
 }
 
-pub struct RendezvousHandle<'a, T> {
+pub struct RendezvousHandle<T> {
     value: T,
-    recv: &'a RendezvousReceiver<T>,
+    ready: Arc<AtomicBool>,
 }
 
-impl<'a, T> Deref for RendezvousHandle<'a, T> {
+impl<T> Deref for RendezvousHandle<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -99,14 +104,14 @@ impl<'a, T> Deref for RendezvousHandle<'a, T> {
     }
 }
 
-impl<'a, T> DerefMut for RendezvousHandle<'a, T> {
+impl<T> DerefMut for RendezvousHandle<T> {
     fn deref_mut(&mut self) -> &mut T {
         &mut self.value
     }
 }
 
-impl<'a, T> Drop for RendezvousHandle<'a, T> {
+impl<T> Drop for RendezvousHandle<T> {
     fn drop(&mut self) {
-        self.recv.ready.store(true, Ordering::Relaxed)
+        self.ready.store(true, Ordering::Relaxed)
     }
 }
@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::future::Future;
 use std::panic::{self, AssertUnwindSafe};
 use std::thread;
@@ -6,6 +7,10 @@ use tokio::runtime::Handle;
 use tokio::task::LocalSet;
 use tokio::sync::oneshot;
 
+use crate::supervise;
+
+pub mod priority;
+
 pub async fn spawn_worker<T: Send + 'static>(
     name: &str,
     fut: impl Future<Output = T> + Send + 'static,
@@ -13,6 +18,10 @@ pub async fn spawn_worker<T: Send + 'static>(
     let runtime = Handle::current();
     let (tx, rx) = oneshot::channel();
 
+    // server workers aren't restarted, a panic in one is escalated to the
+    // whole server after it's recorded for the health endpoint
+    let watched = supervise::watch(name);
+
     // we can't use tokio's own spawn_blocking function because it has no
     // facility to name the new thread. do it the manual way instead
     let result = thread::Builder::new()
@@ -37,10 +46,24 @@ pub async fn spawn_worker<T: Send + 'static>(
     match rx.await {
         Ok(Ok(val)) => val,
         Ok(Err(panic)) => {
-            panic!("thread {} panicked: {:?}", name, panic);
+            let message = panic_message(&*panic);
+            watched.failed(&message);
+            panic!("thread {} panicked: {}", name, message);
         }
         Err(_) => {
+            watched.failed("unexpectedly terminated");
             panic!("thread {} unexpectedly terminated", name);
         }
     }
 }
+
+// the message a panic was raised with, for logging
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
@@ -0,0 +1,187 @@
+// scheduling priority and cpu pinning for the threads doing a source or
+// stream's audio work, so that other load on a busy machine doesn't delay
+// decoding and encoding enough for listeners to hear it
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use slog::Logger;
+
+use crate::config::SchedulingConfig;
+
+pub struct Scheduling {
+    config: Option<SchedulingConfig>,
+    log: Logger,
+    // these settings usually fail for want of privileges, which won't
+    // change, so failing is only warned about once
+    warned: AtomicBool,
+}
+
+impl Scheduling {
+    pub fn new(config: Option<SchedulingConfig>, log: Logger) -> Self {
+        Scheduling { config, log, warned: AtomicBool::new(false) }
+    }
+
+    // applies the settings to the calling thread until the guard returned is
+    // dropped. audio work mostly runs on tokio's shared blocking pool, which
+    // mustn't keep them for whatever it runs next
+    pub fn enter(&self) -> Scheduled {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Scheduled { saved: None },
+        };
+
+        let saved = match save() {
+            Ok(saved) => saved,
+            Err(e) => {
+                self.warn(e);
+                return Scheduled { saved: None };
+            }
+        };
+
+        // settings applied before a failure are still put back
+        let scheduled = Scheduled { saved: Some(saved) };
+
+        if let Err(e) = set(config) {
+            self.warn(e);
+        }
+
+        scheduled
+    }
+
+    fn warn(&self, error: io::Error) {
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            slog::warn!(self.log, "Could not apply thread scheduling settings";
+                "error" => error.to_string(),
+            );
+        }
+    }
+}
+
+pub struct Scheduled {
+    saved: Option<Saved>,
+}
+
+impl Drop for Scheduled {
+    fn drop(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            restore(&saved);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Saved {
+    policy: libc::c_int,
+    param: libc::sched_param,
+    nice: libc::c_int,
+    cpus: libc::cpu_set_t,
+}
+
+// passing 0 as the pid to these calls means the calling thread on linux,
+// where scheduling, niceness and affinity are all per thread
+#[cfg(target_os = "linux")]
+fn save() -> Result<Saved, io::Error> {
+    use std::mem;
+
+    // safety: these only read the calling thread's settings into the
+    // zeroed structs passed to them
+    unsafe {
+        let policy = libc::sched_getscheduler(0);
+
+        if policy < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut param: libc::sched_param = mem::zeroed();
+
+        if libc::sched_getparam(0, &mut param) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // getpriority can legitimately return -1, and can't fail for the
+        // calling thread anyway
+        let nice = libc::getpriority(libc::PRIO_PROCESS, 0);
+
+        let mut cpus: libc::cpu_set_t = mem::zeroed();
+
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut cpus) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Saved { policy, param, nice, cpus })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set(config: &SchedulingConfig) -> Result<(), io::Error> {
+    use std::mem;
+
+    // safety: these only change the calling thread's settings, from structs
+    // initialised here
+    unsafe {
+        if let Some(nice) = config.nice {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, nice as libc::c_int) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if let Some(priority) = config.realtime_priority {
+            let param = libc::sched_param { sched_priority: priority as libc::c_int };
+
+            if libc::sched_setscheduler(0, libc::SCHED_RR, &param) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if !config.cpus.is_empty() {
+            let mut cpus: libc::cpu_set_t = mem::zeroed();
+            libc::CPU_ZERO(&mut cpus);
+
+            for cpu in &config.cpus {
+                if *cpu >= libc::CPU_SETSIZE as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("cpu {} is out of range", cpu)));
+                }
+
+                libc::CPU_SET(*cpu, &mut cpus);
+            }
+
+            if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpus) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// best effort. without privileges a thread can't lower its niceness again
+// after raising it, and there's nothing more useful to do about that than
+// to carry on
+#[cfg(target_os = "linux")]
+fn restore(saved: &Saved) {
+    use std::mem;
+
+    // safety: as for set, from settings read by save
+    unsafe {
+        libc::sched_setscheduler(0, saved.policy, &saved.param);
+        libc::setpriority(libc::PRIO_PROCESS, 0, saved.nice);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &saved.cpus);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Saved;
+
+#[cfg(not(target_os = "linux"))]
+fn save() -> Result<Saved, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread scheduling settings are not supported on this platform"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set(_: &SchedulingConfig) -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restore(_: &Saved) {}
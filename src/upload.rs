@@ -0,0 +1,166 @@
+// uploads finished recordings to object storage in the background, so that
+// the streaming machine's disk only needs to hold what's still recording
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use slog::Logger;
+
+use crate::config::UploadConfig;
+use crate::net::s3::Bucket;
+
+// retries back off up to this long between attempts
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(3600);
+
+// files sent here are uploaded in order, one at a time. files in dir with
+// one of extensions which were left over from before a restart, and haven't
+// been uploaded, go first. the upload thread exits once every sender has
+// been dropped and the queue is empty
+pub fn start(log: Logger, name: &str, config: UploadConfig, dir: PathBuf, extensions: Vec<&'static str>)
+    -> mpsc::Sender<PathBuf>
+{
+    let (tx, rx) = mpsc::channel();
+
+    // anything written from now on is queued as it's finished
+    let started = SystemTime::now();
+
+    thread::Builder::new()
+        .name(format!("edicast/upload: {}", name))
+        .spawn(move || {
+            let leftover = match leftover(&dir, &extensions, started) {
+                Ok(leftover) => leftover,
+                Err(e) => {
+                    slog::warn!(log, "Could not look for recordings left to upload";
+                        "path" => dir.display(),
+                        "error" => e.to_string(),
+                    );
+                    Vec::new()
+                }
+            };
+
+            if !leftover.is_empty() {
+                slog::info!(log, "Uploading recordings left from before starting"; "count" => leftover.len());
+            }
+
+            upload_main(log, config, leftover.into_iter().chain(rx))
+        })
+        .expect("spawn edicast upload thread");
+
+    tx
+}
+
+// whether path has been uploaded and kept on disk
+pub fn is_uploaded(path: &Path) -> bool {
+    marker_path(path).exists()
+}
+
+// once path itself has been removed
+pub fn unmark(path: &Path) {
+    let _ = fs::remove_file(marker_path(path));
+}
+
+fn marker_path(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".uploaded");
+    PathBuf::from(marker)
+}
+
+// oldest first, as they would have been uploaded
+fn leftover(dir: &Path, extensions: &[&str], started: SystemTime) -> Result<Vec<PathBuf>, io::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // nothing has been archived yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut leftover = Vec::new();
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        let ours = path.extension()
+            .map_or(false, |ext| extensions.iter().any(|extension| ext == *extension));
+
+        if !ours || is_uploaded(&path) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let modified = metadata.modified()?;
+
+        if metadata.is_file() && modified < started {
+            leftover.push((modified, path));
+        }
+    }
+
+    leftover.sort();
+    Ok(leftover.into_iter().map(|(_, path)| path).collect())
+}
+
+fn upload_main(log: Logger, config: UploadConfig, queue: impl Iterator<Item = PathBuf>) {
+    let bucket = match Bucket::new(config.clone()) {
+        Ok(bucket) => bucket,
+        Err(e) => {
+            slog::error!(log, "Could not set up uploads, recordings will stay on disk";
+                "error" => e.to_string());
+            return;
+        }
+    };
+
+    for path in queue {
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+
+        let key = format!("{}{}", config.prefix, file_name);
+        let log = log.new(slog::o!("path" => path.display().to_string(), "key" => key.clone()));
+
+        let mut wait = Duration::from_secs(config.retry_secs);
+        let mut attempt = 0;
+
+        loop {
+            match bucket.put_file(&key, &path) {
+                Ok(()) => {
+                    slog::info!(log, "Uploaded recording");
+
+                    // so that it isn't uploaded again after a restart, if
+                    // it's kept or can't be removed
+                    if let Err(e) = File::create(marker_path(&path)) {
+                        slog::warn!(log, "Could not mark recording as uploaded"; "error" => e.to_string());
+                    }
+
+                    if !config.keep_local {
+                        match fs::remove_file(&path) {
+                            Ok(()) => unmark(&path),
+                            Err(e) => {
+                                slog::warn!(log, "Could not remove uploaded recording"; "error" => e.to_string());
+                            }
+                        }
+                    }
+
+                    break;
+                }
+                Err(e) if attempt < config.retries => {
+                    slog::warn!(log, "Upload failed, retrying";
+                        "error" => e.to_string(),
+                        "retry_in_secs" => wait.as_secs(),
+                    );
+
+                    thread::sleep(wait);
+                    wait = (wait * 2).min(MAX_RETRY_WAIT);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    slog::error!(log, "Upload failed, recording will stay on disk"; "error" => e.to_string());
+                    break;
+                }
+            }
+        }
+    }
+}
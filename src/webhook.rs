@@ -0,0 +1,103 @@
+// posts JSON to webhook URLs from a thread of its own, retrying a few times,
+// so that a slow or unreachable endpoint never holds up whatever fired it
+use std::io::{self, Write};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use slog::Logger;
+
+use crate::config::HttpUrl;
+use crate::net::client;
+use crate::net::tls::ClientConfig;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const ATTEMPTS: u32 = 3;
+const RETRY_WAIT: Duration = Duration::from_secs(5);
+
+// only the status is needed from responses
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+struct Request {
+    url: HttpUrl,
+    tls: Option<Arc<ClientConfig>>,
+    body: String,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Webhooks {
+    pub fn post(&self, url: &HttpUrl, tls: Option<Arc<ClientConfig>>, body: &serde_json::Value) {
+        let _ = self.requests.send(Request {
+            url: url.clone(),
+            tls,
+            body: body.to_string(),
+        });
+    }
+}
+
+pub fn start(log: Logger) -> Webhooks {
+    let (requests, requests_rx) = mpsc::channel::<Request>();
+
+    thread::Builder::new()
+        .name("edicast/webhook".to_owned())
+        .spawn(move || {
+            for request in requests_rx {
+                deliver(&log, &request);
+            }
+        })
+        .expect("spawn edicast webhook thread");
+
+    Webhooks { requests }
+}
+
+fn deliver(log: &Logger, request: &Request) {
+    for attempt in 1..=ATTEMPTS {
+        match post(request) {
+            Ok(()) => return,
+            Err(e) if attempt < ATTEMPTS => {
+                slog::warn!(log, "Could not deliver webhook, retrying";
+                    "url" => request.url.to_string(),
+                    "error" => e.to_string(),
+                    "retry_in_secs" => RETRY_WAIT.as_secs(),
+                );
+
+                thread::sleep(RETRY_WAIT);
+            }
+            Err(e) => {
+                slog::error!(log, "Could not deliver webhook, giving up";
+                    "url" => request.url.to_string(),
+                    "error" => e.to_string(),
+                    "attempts" => ATTEMPTS,
+                );
+            }
+        }
+    }
+}
+
+fn post(request: &Request) -> Result<(), io::Error> {
+    let url = &request.url;
+
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: edicast\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.authority(), request.body.len());
+
+    let mut connection = client::connect(url, request.tls.as_ref(), TIMEOUT)?;
+    connection.write_all(head.as_bytes())?;
+    connection.write_all(request.body.as_bytes())?;
+    connection.flush()?;
+
+    let (status, _) = client::read_head(&mut connection)?;
+
+    if !(200..300).contains(&status) {
+        let body = client::read_body(&mut connection, MAX_RESPONSE_SIZE).unwrap_or_default();
+        let message = format!("{} {}", status, String::from_utf8_lossy(&body).trim());
+        return Err(io::Error::new(io::ErrorKind::Other, message));
+    }
+
+    Ok(())
+}